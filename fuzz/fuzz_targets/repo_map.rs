@@ -0,0 +1,16 @@
+//! Repository maps come from the network and from users, so reading and
+//! linting any text must give an error rather than panic.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use regenerate::repo_wrapper;
+
+fuzz_target!(|data: &[u8]| {
+    let text = match std::str::from_utf8(data) {
+        Ok(text) => text,
+        Err(_) => return,
+    };
+    if let Ok(map) = repo_wrapper::parse_map(text, "fuzz") {
+        let _ = repo_wrapper::parse_entries(&map, "fuzz");
+    }
+    let _ = repo_wrapper::lint_map(text, "fuzz");
+});
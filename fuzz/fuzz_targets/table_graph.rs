@@ -0,0 +1,35 @@
+//! Build a dependency graph from fuzzed tables. The input is a series of
+//! tables, each starting with a line "=== PRODUCT", and the graph is built
+//! from the first of them.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use std::collections::HashMap;
+use tempdir::TempDir;
+
+fuzz_target!(|data: &[u8]| {
+    let text = match std::str::from_utf8(data) {
+        Ok(text) => text,
+        Err(_) => return,
+    };
+    let mut tables: HashMap<String, String> = HashMap::new();
+    let mut root = None;
+    let mut current = None;
+    for line in text.lines() {
+        if line.starts_with("=== ") {
+            let name = line[4..].trim().to_string();
+            root.get_or_insert_with(|| name.clone());
+            tables.entry(name.clone()).or_insert_with(String::new);
+            current = Some(name);
+        } else if let Some(name) = current.as_ref() {
+            let table = tables.get_mut(name).unwrap();
+            table.push_str(line);
+            table.push('\n');
+        }
+    }
+    let root = match root {
+        Some(root) => root,
+        None => return,
+    };
+    let dir = TempDir::new("regenerate-fuzz").unwrap();
+    let _ = regenerate::regenerate::graph_from_tables(&root, &tables, dir.path());
+});
@@ -0,0 +1,114 @@
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use log::debug;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+const HEADER_EXTENSIONS: [&str; 7] = ["h", "hh", "hpp", "hxx", "h++", "inc", "tcc"];
+
+fn is_header(path: &Path) -> bool {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => HEADER_EXTENSIONS.contains(&ext),
+        None => false,
+    }
+}
+
+fn is_shared_library(path: &Path) -> bool {
+    match path.file_name().and_then(|n| n.to_str()) {
+        Some(name) => name.contains(".so") || name.ends_with(".dylib"),
+        None => false,
+    }
+}
+
+/// Collect the files below dir, relative to root, which satisfy keep
+fn collect(root: &Path, dir: &Path, keep: &dyn Fn(&Path) -> bool, out: &mut Vec<PathBuf>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        let file_type = match entry.file_type() {
+            Ok(t) => t,
+            Err(_) => continue,
+        };
+        if file_type.is_dir() {
+            collect(root, &path, keep, out);
+        } else if keep(&path) {
+            if let Ok(relative) = path.strip_prefix(root) {
+                out.push(relative.to_path_buf());
+            }
+        }
+    }
+}
+
+/// Hash of the parts of an install which consumers compile and link against,
+/// the headers under include and the names of the shared libraries under lib.
+/// Products with neither have no ABI to speak of and give None.
+pub fn abi_hash(product_dir: &Path) -> Option<String> {
+    let mut headers = vec![];
+    collect(
+        product_dir,
+        &product_dir.join("include"),
+        &is_header,
+        &mut headers,
+    );
+    let mut libraries = vec![];
+    collect(
+        product_dir,
+        &product_dir.join("lib"),
+        &is_shared_library,
+        &mut libraries,
+    );
+    if headers.is_empty() && libraries.is_empty() {
+        return None;
+    }
+    headers.sort();
+    libraries.sort();
+    let mut hasher = Sha1::new();
+    for header in headers.iter() {
+        hasher.input_str(&header.to_string_lossy());
+        if let Ok(contents) = std::fs::read(product_dir.join(header)) {
+            hasher.input(&contents);
+        }
+    }
+    // the sonames, along with where versioned links point, are what a
+    // consumer records when it links
+    for library in libraries.iter() {
+        hasher.input_str(&library.to_string_lossy());
+        if let Ok(target) = std::fs::read_link(product_dir.join(library)) {
+            hasher.input_str(&target.to_string_lossy());
+        }
+    }
+    let hash = hasher.result_str();
+    debug!(
+        "ABI of {} from {} headers and {} libraries is {}",
+        product_dir.display(),
+        headers.len(),
+        libraries.len(),
+        hash
+    );
+    Some(hash)
+}
+
+/// Compare the dependency ABIs a product was built against with those of the
+/// dependencies as currently installed, describing each that differs.
+/// Dependencies whose current ABI is not known can not be checked and are
+/// passed over.
+pub fn mismatches(
+    built_against: &BTreeMap<String, String>,
+    current: &BTreeMap<String, String>,
+) -> Vec<String> {
+    let mut problems = vec![];
+    for (dependency, recorded) in built_against.iter() {
+        match current.get(dependency) {
+            Some(hash) if hash != recorded => problems.push(format!(
+                "it was built against {} with ABI {}, but the installed {} has ABI {}",
+                dependency, recorded, dependency, hash
+            )),
+            Some(_) => (),
+            None => debug!("Can not check the ABI of {}", dependency),
+        }
+    }
+    problems
+}
@@ -0,0 +1,513 @@
+use crate::log_shipping::LogDestination;
+use crate::store::Store;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use log::{debug, info};
+use std::collections::HashSet;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+/// Smallest chunk a file is cut into, other than the last
+const MIN_CHUNK: usize = 16 * 1024;
+/// Largest chunk a file is cut into
+const MAX_CHUNK: usize = 256 * 1024;
+/// Hash bits which must be zero to end a chunk, giving chunks of about 64KiB
+const CHUNK_MASK: u64 = (1 << 16) - 1;
+/// Directory of the cache chunks are stored in by their sha256
+const CHUNK_DIR: &str = "chunks";
+/// Directory of the cache the index of each store entry is stored in by the
+/// name of the entry
+const ENTRY_DIR: &str = "entries";
+/// Directory of the cache holding, for each product, the name of the entry
+/// of it pushed last
+const LATEST_DIR: &str = "latest";
+
+/// One thing in a store entry, as recorded in its index
+#[derive(Debug, PartialEq)]
+enum Item {
+    Dir {
+        path: String,
+        mode: u32,
+    },
+    /// A file and the sha256 and length of each chunk making it up, in order
+    File {
+        path: String,
+        mode: u32,
+        mtime: i64,
+        chunks: Vec<(String, usize)>,
+    },
+    Link {
+        path: String,
+        target: String,
+    },
+}
+
+/// Pseudo random value of a byte for the rolling hash, from splitmix64
+fn gear(byte: u8) -> u64 {
+    let mut z = (byte as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Ends of the chunks data is cut into. Chunks end where a gear hash of the
+/// bytes before has its low bits zero, so they follow the content rather
+/// than offsets, and an edit only changes the chunks around it.
+fn chunk_boundaries(data: &[u8]) -> Vec<usize> {
+    let mut boundaries = vec![];
+    let mut start = 0;
+    let mut hash: u64 = 0;
+    for (i, byte) in data.iter().enumerate() {
+        hash = (hash << 1).wrapping_add(gear(*byte));
+        let length = i + 1 - start;
+        if (length >= MIN_CHUNK && hash & CHUNK_MASK == 0) || length >= MAX_CHUNK {
+            boundaries.push(i + 1);
+            start = i + 1;
+            hash = 0;
+        }
+    }
+    if start < data.len() {
+        boundaries.push(data.len());
+    }
+    boundaries
+}
+
+fn sha256(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(data);
+    hasher.result_str()
+}
+
+/// A path or link target as it is written to an index, which separates
+/// fields with tabs and items with newlines
+fn index_field(text: &Path) -> Result<String, String> {
+    match text.to_str() {
+        Some(s) if !s.contains('\t') && !s.contains('\n') => Ok(s.to_string()),
+        _ => Err(format!("{} can not be stored in the cache", text.display())),
+    }
+}
+
+/// Record everything below dir in items, handing each chunk of each file to
+/// store_chunk along with its sha256
+fn walk(
+    root: &Path,
+    dir: &Path,
+    items: &mut Vec<Item>,
+    store_chunk: &mut dyn FnMut(&str, &[u8]) -> Result<(), String>,
+) -> Result<(), String> {
+    let mut entries: Vec<PathBuf> = std::fs::read_dir(dir)
+        .map_err(|e| format!("Could not read {}: {}", dir.display(), e))?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .collect();
+    entries.sort();
+    for path in entries {
+        let relative = index_field(path.strip_prefix(root).unwrap_or(&path))?;
+        let metadata = std::fs::symlink_metadata(&path)
+            .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        let mode = metadata.permissions().mode() & 0o7777;
+        if metadata.file_type().is_symlink() {
+            let target = std::fs::read_link(&path)
+                .map_err(|e| format!("Could not read link {}: {}", path.display(), e))?;
+            items.push(Item::Link {
+                path: relative,
+                target: index_field(&target)?,
+            });
+        } else if metadata.is_dir() {
+            items.push(Item::Dir {
+                path: relative,
+                mode,
+            });
+            walk(root, &path, items, store_chunk)?;
+        } else if metadata.is_file() {
+            let contents = std::fs::read(&path)
+                .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+            let mut chunks = vec![];
+            let mut start = 0;
+            for end in chunk_boundaries(&contents) {
+                let chunk = &contents[start..end];
+                let digest = sha256(chunk);
+                store_chunk(&digest, chunk)?;
+                chunks.push((digest, chunk.len()));
+                start = end;
+            }
+            let mtime = filetime::FileTime::from_last_modification_time(&metadata);
+            items.push(Item::File {
+                path: relative,
+                mode,
+                mtime: mtime.unix_seconds(),
+                chunks,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// The index of a store entry, a line per item of its kind and fields
+/// separated by tabs, the chunks of a file given as sha256:length
+fn index_text(items: &[Item]) -> String {
+    let mut out = String::new();
+    for item in items.iter() {
+        let line = match item {
+            Item::Dir { path, mode } => format!("dir\t{:o}\t{}", mode, path),
+            Item::File {
+                path,
+                mode,
+                mtime,
+                chunks,
+            } => format!(
+                "file\t{:o}\t{}\t{}\t{}",
+                mode,
+                mtime,
+                path,
+                chunks
+                    .iter()
+                    .map(|(digest, length)| format!("{}:{}", digest, length))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+            Item::Link { path, target } => format!("link\t{}\t{}", path, target),
+        };
+        out.push_str(&line);
+        out.push('\n');
+    }
+    out
+}
+
+fn parse_mode(text: &str) -> Result<u32, String> {
+    u32::from_str_radix(text, 8).map_err(|_| format!("{} is not a file mode", text))
+}
+
+fn parse_chunks(text: &str) -> Result<Vec<(String, usize)>, String> {
+    text.split(',')
+        .filter(|c| !c.is_empty())
+        .map(|chunk| {
+            let mut parts = chunk.splitn(2, ':');
+            let digest = parts.next().unwrap_or_default();
+            let length = parts.next().and_then(|l| l.parse().ok());
+            match length {
+                Some(length)
+                    if digest.len() == 64 && digest.chars().all(|c| c.is_ascii_hexdigit()) =>
+                {
+                    Ok((digest.to_string(), length))
+                }
+                _ => Err(format!("{} is not a chunk", chunk)),
+            }
+        })
+        .collect()
+}
+
+fn parse_index(text: &str) -> Result<Vec<Item>, String> {
+    let mut items = vec![];
+    for (number, line) in text.lines().enumerate() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let item = match fields.as_slice() {
+            ["dir", mode, path] => Item::Dir {
+                path: path.to_string(),
+                mode: parse_mode(mode)?,
+            },
+            ["file", mode, mtime, path, chunks] => Item::File {
+                path: path.to_string(),
+                mode: parse_mode(mode)?,
+                mtime: mtime
+                    .parse()
+                    .map_err(|_| format!("{} is not a modification time", mtime))?,
+                chunks: parse_chunks(chunks)?,
+            },
+            ["link", path, target] => Item::Link {
+                path: path.to_string(),
+                target: target.to_string(),
+            },
+            _ => return Err(format!("Line {} of the index is not an item", number + 1)),
+        };
+        items.push(item);
+    }
+    Ok(items)
+}
+
+/// Where path of an index goes below dir, refusing anything which would
+/// leave it
+fn unpack_path(dir: &Path, path: &str) -> Result<PathBuf, String> {
+    let relative = Path::new(path);
+    let plain = relative.components().all(|c| match c {
+        Component::Normal(_) => true,
+        _ => false,
+    });
+    match plain && !path.is_empty() {
+        true => Ok(dir.join(relative)),
+        false => Err(format!("The cached path {} is not below the entry", path)),
+    }
+}
+
+fn entry_name(entry: &Path) -> Result<String, String> {
+    entry
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .ok_or_else(|| format!("{} is not a store entry", entry.display()))
+}
+
+/// The chunks the cache is known to hold from the entry of product pushed
+/// last, none if it can not be read
+fn latest_chunks(cache: &LogDestination, product: &str) -> HashSet<String> {
+    let latest = || -> Result<Vec<Item>, String> {
+        let name = match cache.fetch(&format!("{}/{}", LATEST_DIR, product))? {
+            Some(name) => String::from_utf8_lossy(&name).trim().to_string(),
+            None => return Ok(vec![]),
+        };
+        match cache.fetch(&format!("{}/{}", ENTRY_DIR, name))? {
+            Some(index) => parse_index(&String::from_utf8_lossy(&index)),
+            None => Ok(vec![]),
+        }
+    };
+    match latest() {
+        Ok(items) => items
+            .into_iter()
+            .filter_map(|item| match item {
+                Item::File { chunks, .. } => Some(chunks),
+                _ => None,
+            })
+            .flatten()
+            .map(|(digest, _)| digest)
+            .collect(),
+        Err(e) => {
+            debug!("Not using the last cached entry of {}: {}", product, e);
+            HashSet::new()
+        }
+    }
+}
+
+/// Push a complete store entry of product to the cache. Chunks of the entry
+/// of product pushed last are not sent again, so pushing a rebuild of a
+/// product only sends what changed in it. The index goes last, so an entry
+/// is never seen before all of its chunks are stored.
+pub fn push(cache: &LogDestination, product: &str, entry: &Path) -> Result<(), String> {
+    let name = entry_name(entry)?;
+    let index_name = format!("{}/{}", ENTRY_DIR, name);
+    if cache.fetch(&index_name)?.is_some() {
+        debug!("{} is already in the binary cache", name);
+        return Ok(());
+    }
+    let mut stored = latest_chunks(cache, product);
+    let mut total = 0;
+    let mut sent = 0;
+    let mut items = vec![];
+    walk(entry, entry, &mut items, &mut |digest, chunk| {
+        total += chunk.len();
+        if stored.insert(digest.to_string()) {
+            cache.upload(&format!("{}/{}", CHUNK_DIR, digest), chunk)?;
+            sent += chunk.len();
+        }
+        Ok(())
+    })?;
+    cache.upload(&index_name, index_text(&items).as_bytes())?;
+    cache.upload(&format!("{}/{}", LATEST_DIR, product), name.as_bytes())?;
+    info!(
+        "Pushed {} to the binary cache, sending {} of its {} bytes",
+        name, sent, total
+    );
+    Ok(())
+}
+
+/// Write the items of an index below dir, fetching and checking each chunk
+fn unpack(cache: &LogDestination, dir: &Path, items: &[Item]) -> Result<(), String> {
+    // nothing is written through a link the index made, which could point
+    // anywhere
+    let mut links = HashSet::new();
+    for item in items.iter() {
+        let item_path = match item {
+            Item::Dir { path, .. } | Item::File { path, .. } | Item::Link { path, .. } => path,
+        };
+        if Path::new(item_path).ancestors().any(|a| links.contains(a)) {
+            return Err(format!("The cached path {} is below a link", item_path));
+        }
+        match item {
+            Item::Dir { path, .. } => {
+                let path = unpack_path(dir, path)?;
+                std::fs::create_dir_all(&path)
+                    .map_err(|e| format!("Could not create {}: {}", path.display(), e))?;
+            }
+            Item::File {
+                path,
+                mode,
+                mtime,
+                chunks,
+            } => {
+                let path = unpack_path(dir, path)?;
+                let mut contents = vec![];
+                for (digest, length) in chunks.iter() {
+                    let chunk = cache
+                        .fetch(&format!("{}/{}", CHUNK_DIR, digest))?
+                        .ok_or_else(|| format!("The cache has lost chunk {}", digest))?;
+                    if chunk.len() != *length || &sha256(&chunk) != digest {
+                        return Err(format!("Chunk {} of the cache is corrupt", digest));
+                    }
+                    contents.extend_from_slice(&chunk);
+                }
+                std::fs::write(&path, contents)
+                    .map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(*mode))
+                    .map_err(|e| format!("Could not set the mode of {}: {}", path.display(), e))?;
+                let time = filetime::FileTime::from_unix_time(*mtime, 0);
+                filetime::set_file_times(&path, time, time)
+                    .map_err(|e| format!("Could not set times of {}: {}", path.display(), e))?;
+            }
+            Item::Link { path, target } => {
+                let path = unpack_path(dir, path)?;
+                std::os::unix::fs::symlink(target, &path)
+                    .map_err(|e| format!("Could not link {}: {}", path.display(), e))?;
+                links.insert(Path::new(item_path));
+            }
+        }
+    }
+    // directories get their modes last, as one without write permission
+    // could not have been filled
+    for item in items.iter().rev() {
+        if let Item::Dir { path, mode } = item {
+            let path = unpack_path(dir, path)?;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(*mode))
+                .map_err(|e| format!("Could not set the mode of {}: {}", path.display(), e))?;
+        }
+    }
+    Ok(())
+}
+
+/// Pull the entry of id built as variant from the cache into the store,
+/// returning false if the cache does not have it. It is unpacked beside the
+/// entry, under a name the store passes over, and only moved into place
+/// once whole and found to be a complete build of id.
+pub fn pull(
+    cache: &LogDestination,
+    store: &Store,
+    id: &str,
+    variant: &str,
+) -> Result<bool, String> {
+    let entry = store.entry_path(id, variant);
+    let name = entry_name(&entry)?;
+    let index = match cache.fetch(&format!("{}/{}", ENTRY_DIR, name))? {
+        Some(index) => index,
+        None => return Ok(false),
+    };
+    let items = parse_index(&String::from_utf8_lossy(&index))?;
+    let _lock = store.lock(&entry)?;
+    if Store::is_complete(&entry, id) {
+        return Ok(true);
+    }
+    let partial = store.root.join(format!(".{}.pull", name));
+    for dir in [&partial, &entry].iter() {
+        if dir.exists() {
+            std::fs::remove_dir_all(dir)
+                .map_err(|e| format!("Could not remove {}: {}", dir.display(), e))?;
+        }
+    }
+    std::fs::create_dir_all(&partial)
+        .map_err(|e| format!("Could not create {}: {}", partial.display(), e))?;
+    let unpacked = unpack(cache, &partial, &items).and_then(|_| {
+        if !Store::is_complete(&partial, id) {
+            return Err(format!(
+                "The cached entry {} is not a build of {}",
+                name, id
+            ));
+        }
+        std::fs::rename(&partial, &entry)
+            .map_err(|e| format!("Could not move {} into place: {}", name, e))
+    });
+    if let Err(e) = unpacked {
+        let _ = std::fs::remove_dir_all(&partial);
+        return Err(e);
+    }
+    info!("Pulled {} from the binary cache", name);
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes which look random enough to chunk like real build output
+    fn noise(length: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..length)
+            .map(|_| {
+                // xorshift64
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn chunks(data: &[u8]) -> Vec<&[u8]> {
+        let mut start = 0;
+        chunk_boundaries(data)
+            .into_iter()
+            .map(|end| {
+                let chunk = &data[start..end];
+                start = end;
+                chunk
+            })
+            .collect()
+    }
+
+    #[test]
+    fn chunks_cover_the_data_within_bounds() {
+        let data = noise(4 << 20, 31);
+        let pieces = chunks(&data);
+        assert_eq!(pieces.concat(), data);
+        assert!(pieces.len() > 1);
+        for piece in pieces[..pieces.len() - 1].iter() {
+            assert!(piece.len() >= MIN_CHUNK && piece.len() <= MAX_CHUNK);
+        }
+        assert!(chunk_boundaries(&[]).is_empty());
+        assert_eq!(chunk_boundaries(b"short"), vec![5]);
+    }
+
+    #[test]
+    fn an_insertion_only_changes_nearby_chunks() {
+        let data = noise(4 << 20, 31);
+        let mut edited = data[..100_000].to_vec();
+        edited.extend_from_slice(b"a few inserted bytes");
+        edited.extend_from_slice(&data[100_000..]);
+        let before = chunks(&data);
+        let after = chunks(&edited);
+        let changed = after.iter().filter(|c| !before.contains(c)).count();
+        assert!(
+            changed <= 2,
+            "{} of {} chunks changed",
+            changed,
+            after.len()
+        );
+    }
+
+    #[test]
+    fn an_index_reads_back_and_stays_below_the_entry() {
+        let items = vec![
+            Item::Dir {
+                path: "lib".to_string(),
+                mode: 0o755,
+            },
+            Item::File {
+                path: "lib/libfoo.so".to_string(),
+                mode: 0o644,
+                mtime: 1_500_000_000,
+                chunks: vec![(sha256(b"a"), 1), (sha256(b"b"), 1)],
+            },
+            Item::File {
+                path: "empty".to_string(),
+                mode: 0o600,
+                mtime: 0,
+                chunks: vec![],
+            },
+            Item::Link {
+                path: "lib/libfoo.so.1".to_string(),
+                target: "libfoo.so".to_string(),
+            },
+        ];
+        assert_eq!(parse_index(&index_text(&items)).unwrap(), items);
+        let dir = Path::new("/store/entry");
+        assert!(unpack_path(dir, "lib/libfoo.so").is_ok());
+        assert!(unpack_path(dir, "../other").is_err());
+        assert!(unpack_path(dir, "/etc/passwd").is_err());
+        assert!(unpack_path(dir, "").is_err());
+    }
+}
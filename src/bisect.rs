@@ -0,0 +1,248 @@
+use crate::graph_export::GraphSnapshot;
+use git2::Repository;
+use log::{debug, info};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// How the commits of several changed products are put into one sequence
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BisectOrder {
+    /// All of the commits of one product before those of the next, in
+    /// dependency order
+    PerProduct,
+    /// Commits of all products interleaved by commit time
+    ByDate,
+}
+
+/// One commit made to a product between the good and bad states
+#[derive(Clone, Debug)]
+pub struct Change {
+    pub product: String,
+    pub sha: String,
+    pub time: i64,
+    pub summary: String,
+}
+
+/// The outcome of testing one state of the stack
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verdict {
+    Good,
+    Bad,
+    /// The state could not be tested, for instance it did not build
+    Skip,
+}
+
+#[derive(Clone, Debug)]
+pub struct BisectResult {
+    /// Changes which may have introduced the regression, a single change
+    /// unless skipped states left it ambiguous
+    pub suspects: Vec<Change>,
+    /// Number of states which were tested
+    pub steps: usize,
+}
+
+impl BisectResult {
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        match self.suspects.len() {
+            0 => out.push_str("No change between the good and bad states is to blame\n"),
+            1 => out.push_str("The first bad commit is:\n"),
+            n => out.push_str(&format!(
+                "Skipped states left {} commits which may be the first bad one:\n",
+                n
+            )),
+        }
+        for change in self.suspects.iter() {
+            out.push_str(&format!(
+                "  {} {} {}\n",
+                change.product, change.sha, change.summary
+            ));
+        }
+        out.push_str(&format!("Tested {} states\n", self.steps));
+        out
+    }
+}
+
+/// Commits reachable from bad_sha but not good_sha in a clone, oldest first
+fn product_changes(
+    repo: &Repository,
+    product: &str,
+    good_sha: Option<&str>,
+    bad_sha: &str,
+) -> Result<Vec<Change>, String> {
+    let oid = |sha: &str| {
+        git2::Oid::from_str(sha).map_err(|e| format!("{} is not a valid sha: {}", sha, e))
+    };
+    let mut walk = repo
+        .revwalk()
+        .map_err(|e| format!("Could not walk history of {}: {}", product, e))?;
+    walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE);
+    walk.push(oid(bad_sha)?)
+        .map_err(|e| format!("{} does not have {}: {}", product, bad_sha, e))?;
+    match good_sha {
+        Some(sha) => walk
+            .hide(oid(sha)?)
+            .map_err(|e| format!("{} does not have {}: {}", product, sha, e))?,
+        // a product new in the bad state is only bisected as a whole
+        None => {
+            let commit = repo
+                .find_commit(oid(bad_sha)?)
+                .map_err(|e| format!("{} does not have {}: {}", product, bad_sha, e))?;
+            return Ok(vec![Change {
+                product: product.to_string(),
+                sha: bad_sha.to_string(),
+                time: commit.time().seconds(),
+                summary: format!("(added) {}", commit.summary().unwrap_or("")),
+            }]);
+        }
+    }
+    let mut changes = vec![];
+    for id in walk {
+        let id = id.map_err(|e| format!("Could not walk history of {}: {}", product, e))?;
+        let commit = repo
+            .find_commit(id)
+            .map_err(|e| format!("Could not read commit {} of {}: {}", id, product, e))?;
+        changes.push(Change {
+            product: product.to_string(),
+            sha: format!("{}", id),
+            time: commit.time().seconds(),
+            summary: commit.summary().unwrap_or("").to_string(),
+        });
+    }
+    Ok(changes)
+}
+
+/// Lay out the commits separating two saved graphs as one sequence. Clones of
+/// every changed product must be under clone_root and contain both shas.
+pub fn changes(
+    clone_root: &Path,
+    good: &GraphSnapshot,
+    bad: &GraphSnapshot,
+    order: BisectOrder,
+) -> Result<Vec<Change>, String> {
+    let mut sequence = vec![];
+    // nodes are walked dependencies first so per product order puts changes
+    // to low level products before those of their consumers
+    for product in dependency_order(bad).iter() {
+        let bad_node = &bad.nodes[product];
+        let good_sha = good.nodes.get(product).map(|n| n.sha.as_str());
+        if good_sha == Some(bad_node.sha.as_str()) {
+            continue;
+        }
+        let repo = Repository::open(clone_root.join(product))
+            .map_err(|e| format!("Could not open the clone of {}: {}", product, e))?;
+        let product_changes = product_changes(&repo, product, good_sha, &bad_node.sha)?;
+        debug!("{} has {} changes", product, product_changes.len());
+        sequence.extend(product_changes);
+    }
+    if order == BisectOrder::ByDate {
+        // stable, so commits of one product keep their topological order
+        // when they share a time
+        sequence.sort_by_key(|c| c.time);
+    }
+    Ok(sequence)
+}
+
+/// Products of a graph ordered so each comes after its dependencies
+fn dependency_order(graph: &GraphSnapshot) -> Vec<String> {
+    let mut order: Vec<String> = vec![];
+    let mut remaining: Vec<&String> = graph.nodes.keys().collect();
+    while !remaining.is_empty() {
+        let before = remaining.len();
+        remaining.retain(|product| {
+            let ready = graph
+                .edges
+                .iter()
+                .filter(|(from, _)| from == *product)
+                .all(|(_, dep)| order.contains(dep) || !graph.nodes.contains_key(dep));
+            if ready {
+                order.push((*product).clone());
+            }
+            !ready
+        });
+        // a cycle can not be ordered, take the rest as they are
+        if remaining.len() == before {
+            order.extend(remaining.drain(..).cloned());
+        }
+    }
+    order
+}
+
+/// The sha of every product once the first n changes have been applied on
+/// top of the good state
+pub fn state_at(good: &GraphSnapshot, changes: &[Change], n: usize) -> BTreeMap<String, String> {
+    let mut state: BTreeMap<String, String> = good
+        .nodes
+        .iter()
+        .map(|(product, node)| (product.clone(), node.sha.clone()))
+        .collect();
+    for change in changes[..n].iter() {
+        state.insert(change.product.clone(), change.sha.clone());
+    }
+    state
+}
+
+/// Binary search the states between good and bad for the first change which
+/// makes check report Bad. The good state, with no changes applied, and the
+/// bad state, with all of them, are taken as already known. check is given
+/// the number of changes applied along with the resulting shas.
+pub fn bisect<F>(
+    good: &GraphSnapshot,
+    changes: &[Change],
+    mut check: F,
+) -> Result<BisectResult, String>
+where
+    F: FnMut(usize, &BTreeMap<String, String>) -> Result<Verdict, String>,
+{
+    // lo is known good and hi known bad
+    let mut lo = 0;
+    let mut hi = changes.len();
+    let mut skipped = vec![];
+    let mut steps = 0;
+    loop {
+        // pick the untested state nearest the middle
+        let middle = (lo + hi) / 2;
+        let candidate = (lo + 1..hi)
+            .filter(|n| !skipped.contains(n))
+            .min_by_key(|n| (*n as i64 - middle as i64).abs());
+        let n = match candidate {
+            Some(n) => n,
+            None => break,
+        };
+        let state = state_at(good, changes, n);
+        info!(
+            "Testing with {} of {} changes applied, up to {} {}",
+            n,
+            changes.len(),
+            changes[n - 1].product,
+            changes[n - 1].sha
+        );
+        steps += 1;
+        match check(n, &state)? {
+            Verdict::Good => lo = n,
+            Verdict::Bad => hi = n,
+            Verdict::Skip => skipped.push(n),
+        }
+    }
+    Ok(BisectResult {
+        suspects: changes[lo..hi].to_vec(),
+        steps,
+    })
+}
+
+/// Run a test command, where exit status 0 means good, 125 means the state
+/// can not be tested, and anything else means bad, as with git bisect run
+pub fn run_check(command: &str, env: &[(String, String)]) -> Result<Verdict, String> {
+    debug!("Running bisect check {}", command);
+    let status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .status()
+        .map_err(|e| format!("Could not run {}: {}", command, e))?;
+    Ok(match status.code() {
+        Some(0) => Verdict::Good,
+        Some(125) => Verdict::Skip,
+        _ => Verdict::Bad,
+    })
+}
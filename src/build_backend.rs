@@ -0,0 +1,474 @@
+use crate::regenerate::reups;
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use log::debug;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The abstract phases of a build, in the order they are run. Each backend
+/// maps the phases it supports to its own commands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Phase {
+    Fetch,
+    Prep,
+    Config,
+    Build,
+    Test,
+    Install,
+    Docs,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 7] = [
+        Phase::Fetch,
+        Phase::Prep,
+        Phase::Config,
+        Phase::Build,
+        Phase::Test,
+        Phase::Install,
+        Phase::Docs,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Phase::Fetch => "fetch",
+            Phase::Prep => "prep",
+            Phase::Config => "config",
+            Phase::Build => "build",
+            Phase::Test => "test",
+            Phase::Install => "install",
+            Phase::Docs => "docs",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Phase, String> {
+        Phase::ALL
+            .iter()
+            .find(|p| p.name() == name)
+            .cloned()
+            .ok_or(format!("Unknown build phase {}", name))
+    }
+
+    /// Optional phases only run when asked for
+    fn is_optional(&self) -> bool {
+        match self {
+            Phase::Test | Phase::Docs => true,
+            _ => false,
+        }
+    }
+}
+
+/// How the phases of one product's build are to be carried out
+#[derive(Clone, Debug, Default)]
+pub struct PhaseSettings {
+    /// Phases not to run even though the backend supports them
+    pub skip: Vec<Phase>,
+    /// Phases which must be run, failing if the backend can not
+    pub require: Vec<Phase>,
+    /// Extra arguments added to the commands of a phase
+    pub extra_args: HashMap<Phase, Vec<String>>,
+    /// Run the test phase where it is supported
+    pub run_tests: bool,
+    /// The phases to run in place of those of the backend, in this order.
+    /// Empty to run the phases of the backend in phase order.
+    pub sequence: Vec<Phase>,
+}
+
+/// Everything a backend needs to know to build one product
+pub struct BuildContext<'a> {
+    pub product: &'a str,
+    pub version: &'a str,
+    /// Directory the product is installed to
+    pub product_dir: &'a Path,
+    /// Directory holding the source being built
+    pub build_path: &'a Path,
+    /// Build tool configured for eupspkg style builds
+    pub build_tool: &'a str,
+    /// Number of parallel jobs for backends which support it, or the tool's
+    /// own default
+    pub jobs: Option<usize>,
+    /// CMake toolchain file to configure with
+    pub toolchain_file: Option<&'a Path>,
+}
+
+/// One command run as part of a build, identified by the verb it carries out
+#[derive(Clone, Debug)]
+pub struct BuildStep {
+    pub verb: String,
+    pub program: String,
+    /// Arguments are kept as OsStrings so paths which are not valid unicode
+    /// reach the build tool unchanged
+    pub args: Vec<OsString>,
+    /// How long the command may run before it is killed
+    pub timeout: Option<Duration>,
+}
+
+impl BuildStep {
+    pub fn new(verb: &str, program: &str, args: Vec<OsString>) -> BuildStep {
+        BuildStep {
+            verb: verb.to_string(),
+            program: program.to_string(),
+            args,
+            timeout: None,
+        }
+    }
+}
+
+fn strings(args: &[&str]) -> Vec<OsString> {
+    args.iter().map(OsString::from).collect()
+}
+
+/// An argument of the form prefix followed by a path, such as PREFIX=/path
+fn path_arg(prefix: &str, path: &Path) -> OsString {
+    let mut arg = OsString::from(prefix);
+    arg.push(path);
+    arg
+}
+
+/// A way of building products. The commands of each supported phase are run
+/// in phase order in the build path, and once they have all succeeded the
+/// backend may finish off the install, for instance by generating a table
+/// file.
+pub trait BuildBackend {
+    fn name(&self) -> &'static str;
+
+    /// The phases this backend has commands for
+    fn supported_phases(&self) -> &'static [Phase];
+
+    /// The commands carrying out a supported phase
+    fn phase_steps(&self, phase: Phase, context: &BuildContext) -> Vec<BuildStep>;
+
+    /// Complete the install after all of the steps have run
+    fn finish(&self, _context: &BuildContext) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Anything in the source beyond its sha which should change the id of
+    /// the product, such as a lock file
+    fn id_inputs(&self, _source: &Path) -> Vec<String> {
+        vec![]
+    }
+}
+
+/// Decide which phases of a build to run, checking the backend can carry out
+/// every required phase
+pub fn select_phases(
+    backend: &dyn BuildBackend,
+    settings: &PhaseSettings,
+) -> Result<Vec<Phase>, String> {
+    let supported = backend.supported_phases();
+    let mut problems = vec![];
+    for phase in settings.require.iter() {
+        if !supported.contains(phase) {
+            problems.push(format!(
+                "the {} backend has no {} phase",
+                backend.name(),
+                phase.name()
+            ));
+        } else if settings.skip.contains(phase) {
+            problems.push(format!("{} is both required and skipped", phase.name()));
+        }
+    }
+    if !supported.contains(&Phase::Install) {
+        problems.push(format!("the {} backend can not install", backend.name()));
+    }
+    if !settings.sequence.is_empty() {
+        for phase in settings.sequence.iter() {
+            if !supported.contains(phase) {
+                problems.push(format!(
+                    "the {} backend has no {} phase",
+                    backend.name(),
+                    phase.name()
+                ));
+            }
+        }
+        for phase in settings.require.iter() {
+            if !settings.sequence.contains(phase) {
+                problems.push(format!(
+                    "{} is required but not in the phases",
+                    phase.name()
+                ));
+            }
+        }
+        if !settings.sequence.contains(&Phase::Install) {
+            problems.push("the phases do not include install".to_string());
+        }
+    }
+    if !problems.is_empty() {
+        return Err(problems.join(", "));
+    }
+    if !settings.sequence.is_empty() {
+        let mut phases: Vec<Phase> = settings
+            .sequence
+            .iter()
+            .cloned()
+            .filter(|p| !settings.skip.contains(p))
+            .collect();
+        // running tests adds them to the end of a sequence which lacks them
+        let test = Phase::Test;
+        if settings.run_tests && supported.contains(&test) && !phases.contains(&test) {
+            phases.push(test);
+        }
+        return Ok(phases);
+    }
+    Ok(Phase::ALL
+        .iter()
+        .cloned()
+        .filter(|p| supported.contains(p) && !settings.skip.contains(p))
+        .filter(|p| {
+            !p.is_optional()
+                || settings.require.contains(p)
+                || (*p == Phase::Test && settings.run_tests)
+        })
+        .collect())
+}
+
+/// Translate the selected phases of a build into the commands to run, with
+/// any extra arguments for each phase appended
+pub fn build_steps(
+    backend: &dyn BuildBackend,
+    settings: &PhaseSettings,
+    context: &BuildContext,
+) -> Result<Vec<BuildStep>, String> {
+    let mut steps = vec![];
+    for phase in select_phases(backend, settings)? {
+        for mut step in backend.phase_steps(phase, context) {
+            if let Some(extra) = settings.extra_args.get(&phase) {
+                step.args.extend(extra.iter().map(OsString::from));
+            }
+            steps.push(step);
+        }
+    }
+    Ok(steps)
+}
+
+/// Install the table from the source into the product directory, adding any
+/// of the given environment entries the table lacks
+fn install_table(context: &BuildContext, entries: &[(&str, &str)]) -> Result<(), String> {
+    let table = format!("{}.table", context.product);
+    let source = context.build_path.join("ups").join(&table);
+    let mut text = std::fs::read_to_string(&source)
+        .map_err(|e| format!("Could not read {}: {}", source.display(), e))?;
+    for (variable, path) in entries.iter() {
+        let entry = format!("envPrepend({}, ${{PRODUCT_DIR}}/{})", variable, path);
+        if !text.contains(&entry) {
+            debug!("Adding {} to the table of {}", entry, context.product);
+            if !text.ends_with('\n') && !text.is_empty() {
+                text.push('\n');
+            }
+            text.push_str(&entry);
+            text.push('\n');
+        }
+    }
+    let ups = context.product_dir.join("ups");
+    std::fs::create_dir_all(&ups)
+        .map_err(|e| format!("Could not create {}: {}", ups.display(), e))?;
+    std::fs::write(ups.join(&table), text)
+        .map_err(|e| format!("Could not write table for {}: {}", context.product, e))
+}
+
+/// Builds products with eupspkg, or a compatible build tool, running each of
+/// its verbs in turn
+pub struct EupspkgBackend;
+
+impl BuildBackend for EupspkgBackend {
+    fn name(&self) -> &'static str {
+        "eupspkg"
+    }
+
+    fn supported_phases(&self) -> &'static [Phase] {
+        &[
+            Phase::Fetch,
+            Phase::Prep,
+            Phase::Config,
+            Phase::Build,
+            Phase::Install,
+        ]
+    }
+
+    fn phase_steps(&self, phase: Phase, context: &BuildContext) -> Vec<BuildStep> {
+        vec![BuildStep::new(
+            phase.name(),
+            context.build_tool,
+            vec![
+                format!("PRODUCT={}", context.product).into(),
+                format!("VERSION={}", context.version).into(),
+                format!("FLAVOR={}", reups::SYSTEM_OS).into(),
+                path_arg("PREFIX=", context.product_dir),
+                phase.name().into(),
+            ],
+        )]
+    }
+}
+
+/// Builds pure python products by letting pip build and install a wheel into
+/// the product directory. Anything pip can build from a pyproject.toml or
+/// setup.py works, including poetry projects.
+pub struct PipBackend;
+
+impl PipBackend {
+    fn target(product_dir: &Path) -> PathBuf {
+        let mut target = PathBuf::from(product_dir);
+        target.push("lib");
+        target.push("python");
+        target
+    }
+}
+
+impl BuildBackend for PipBackend {
+    fn name(&self) -> &'static str {
+        "pip"
+    }
+
+    fn supported_phases(&self) -> &'static [Phase] {
+        &[Phase::Install]
+    }
+
+    fn phase_steps(&self, phase: Phase, context: &BuildContext) -> Vec<BuildStep> {
+        let mut args = strings(&["-m", "pip", "install", "--no-deps", "--upgrade", "--target"]);
+        args.push(PipBackend::target(context.product_dir).into_os_string());
+        args.push(".".into());
+        vec![BuildStep::new(phase.name(), "python", args)]
+    }
+
+    /// Install the table from the source, adding the environment entries
+    /// which make the installed package importable if the table lacks them
+    fn finish(&self, context: &BuildContext) -> Result<(), String> {
+        install_table(
+            context,
+            &[("PYTHONPATH", "lib/python"), ("PATH", "lib/python/bin")],
+        )
+    }
+}
+
+/// Builds rust products with cargo. Binaries are installed with cargo
+/// install, and any libraries produced by the release build are copied into
+/// the lib directory of the product.
+pub struct CargoBackend;
+
+impl BuildBackend for CargoBackend {
+    fn name(&self) -> &'static str {
+        "cargo"
+    }
+
+    fn supported_phases(&self) -> &'static [Phase] {
+        &[Phase::Build, Phase::Test, Phase::Install, Phase::Docs]
+    }
+
+    fn phase_steps(&self, phase: Phase, context: &BuildContext) -> Vec<BuildStep> {
+        let mut args = match phase {
+            Phase::Build => strings(&["build", "--release"]),
+            Phase::Test => strings(&["test", "--release"]),
+            Phase::Docs => strings(&["doc", "--no-deps"]),
+            _ => strings(&["install", "--force", "--path", ".", "--root"]),
+        };
+        if phase == Phase::Install {
+            args.push(context.product_dir.as_os_str().to_os_string());
+        } else if let Some(jobs) = context.jobs {
+            args.push("--jobs".into());
+            args.push(jobs.to_string().into());
+        }
+        vec![BuildStep::new(phase.name(), "cargo", args)]
+    }
+
+    fn finish(&self, context: &BuildContext) -> Result<(), String> {
+        let release = context.build_path.join("target").join("release");
+        let lib = context.product_dir.join("lib");
+        if let Ok(entries) = std::fs::read_dir(&release) {
+            for entry in entries.filter_map(|e| e.ok()) {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let is_library = name.starts_with("lib")
+                    && [".so", ".a", ".dylib", ".rlib"]
+                        .iter()
+                        .any(|ext| name.ends_with(ext));
+                if !is_library || !entry.path().is_file() {
+                    continue;
+                }
+                std::fs::create_dir_all(&lib)
+                    .map_err(|e| format!("Could not create {}: {}", lib.display(), e))?;
+                std::fs::copy(entry.path(), lib.join(&name))
+                    .map_err(|e| format!("Could not install {}: {}", name, e))?;
+            }
+        }
+        install_table(context, &[("PATH", "bin"), ("LD_LIBRARY_PATH", "lib")])
+    }
+
+    /// Dependency updates only show up in the lock file, so it is part of
+    /// the id
+    fn id_inputs(&self, source: &Path) -> Vec<String> {
+        match std::fs::read_to_string(source.join("Cargo.lock")) {
+            Ok(text) => {
+                let mut hasher = Sha1::new();
+                hasher.input_str(&text);
+                vec![hasher.result_str()]
+            }
+            Err(_) => vec![],
+        }
+    }
+}
+
+/// Builds products with cmake in a separate build directory, installing to
+/// the product directory
+pub struct CMakeBackend;
+
+/// Build directory used within the source of cmake products
+const CMAKE_BUILD_DIR: &str = "build-regenerate";
+
+impl BuildBackend for CMakeBackend {
+    fn name(&self) -> &'static str {
+        "cmake"
+    }
+
+    fn supported_phases(&self) -> &'static [Phase] {
+        &[Phase::Config, Phase::Build, Phase::Test, Phase::Install]
+    }
+
+    fn phase_steps(&self, phase: Phase, context: &BuildContext) -> Vec<BuildStep> {
+        let step = match phase {
+            Phase::Config => {
+                let mut configure = strings(&["-S", ".", "-B", CMAKE_BUILD_DIR]);
+                configure.push(path_arg("-DCMAKE_INSTALL_PREFIX=", context.product_dir));
+                configure.push("-DCMAKE_BUILD_TYPE=Release".into());
+                if let Some(toolchain) = context.toolchain_file {
+                    configure.push(path_arg("-DCMAKE_TOOLCHAIN_FILE=", toolchain));
+                }
+                BuildStep::new(phase.name(), "cmake", configure)
+            }
+            Phase::Build => {
+                let mut build = strings(&["--build", CMAKE_BUILD_DIR, "--parallel"]);
+                if let Some(jobs) = context.jobs {
+                    build.push(jobs.to_string().into());
+                }
+                BuildStep::new(phase.name(), "cmake", build)
+            }
+            Phase::Test => BuildStep::new(
+                phase.name(),
+                "ctest",
+                strings(&["--test-dir", CMAKE_BUILD_DIR, "--output-on-failure"]),
+            ),
+            _ => BuildStep::new(
+                phase.name(),
+                "cmake",
+                strings(&["--install", CMAKE_BUILD_DIR]),
+            ),
+        };
+        vec![step]
+    }
+
+    fn finish(&self, context: &BuildContext) -> Result<(), String> {
+        install_table(context, &[("PATH", "bin"), ("LD_LIBRARY_PATH", "lib")])
+    }
+}
+
+/// Look up a backend by the name used in the repository map
+pub fn backend_for_name(name: Option<&str>) -> Result<Box<dyn BuildBackend>, String> {
+    match name {
+        None | Some("eupspkg") => Ok(Box::new(EupspkgBackend)),
+        Some("pip") => Ok(Box::new(PipBackend)),
+        Some("cargo") => Ok(Box::new(CargoBackend)),
+        Some("cmake") => Ok(Box::new(CMakeBackend)),
+        Some(other) => Err(format!("Unknown build backend {}", other)),
+    }
+}
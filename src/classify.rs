@@ -0,0 +1,96 @@
+use regex::Regex;
+use std::collections::BTreeMap;
+
+/// Something which scans the output of build verbs, keeping per product
+/// counts of whatever it looks for
+pub trait OutputProcessor {
+    /// Look at one line of output produced by a verb of a product
+    fn process_line(&mut self, product: &str, verb: &str, line: &str);
+
+    /// Counts gathered so far, keyed by product then by what was counted
+    fn counts(&self) -> BTreeMap<String, BTreeMap<String, u64>>;
+}
+
+/// Patterns counted when none are configured
+pub fn default_patterns() -> BTreeMap<String, String> {
+    let mut patterns = BTreeMap::new();
+    patterns.insert("warnings".to_string(), r"(?i)\bwarning:".to_string());
+    patterns.insert("errors".to_string(), r"(?i)\berror:".to_string());
+    patterns.insert("deprecations".to_string(), r"(?i)deprecat".to_string());
+    patterns.insert(
+        "undefined_references".to_string(),
+        r"undefined reference to".to_string(),
+    );
+    patterns
+}
+
+/// Counts lines of output matching named regular expressions
+pub struct Classifiers {
+    patterns: Vec<(String, Regex)>,
+    counts: BTreeMap<String, BTreeMap<String, u64>>,
+}
+
+impl Classifiers {
+    pub fn new(patterns: &BTreeMap<String, String>) -> Result<Classifiers, String> {
+        let mut compiled = vec![];
+        for (name, pattern) in patterns.iter() {
+            let regex = Regex::new(pattern)
+                .map_err(|e| format!("Invalid pattern for classifier {}: {}", name, e))?;
+            compiled.push((name.clone(), regex));
+        }
+        Ok(Classifiers {
+            patterns: compiled,
+            counts: BTreeMap::new(),
+        })
+    }
+}
+
+impl OutputProcessor for Classifiers {
+    fn process_line(&mut self, product: &str, _verb: &str, line: &str) {
+        for (name, regex) in self.patterns.iter() {
+            if regex.is_match(line) {
+                *self
+                    .counts
+                    .entry(product.to_string())
+                    .or_insert_with(BTreeMap::new)
+                    .entry(name.clone())
+                    .or_insert(0) += 1;
+            }
+        }
+    }
+
+    fn counts(&self) -> BTreeMap<String, BTreeMap<String, u64>> {
+        self.counts.clone()
+    }
+}
+
+/// Feed captured output through a set of processors, line by line
+pub fn process_output(
+    processors: &mut [Box<dyn OutputProcessor>],
+    product: &str,
+    verb: &str,
+    output: &[u8],
+) {
+    let text = String::from_utf8_lossy(output);
+    for line in text.lines() {
+        for processor in processors.iter_mut() {
+            processor.process_line(product, verb, line);
+        }
+    }
+}
+
+/// Merge the counts of every processor
+pub fn merged_counts(
+    processors: &[Box<dyn OutputProcessor>],
+) -> BTreeMap<String, BTreeMap<String, u64>> {
+    let mut merged: BTreeMap<String, BTreeMap<String, u64>> = BTreeMap::new();
+    for processor in processors.iter() {
+        for (product, counts) in processor.counts() {
+            let entry = merged.entry(product).or_insert_with(BTreeMap::new);
+            for (name, count) in counts {
+                *entry.entry(name).or_insert(0) += count;
+            }
+        }
+    }
+    merged
+}
@@ -0,0 +1,2253 @@
+use crate::bisect::{self, BisectOrder, Verdict};
+use crate::closure::{self, ClosureBaseline, ClosureCheck};
+use crate::compile_db;
+use crate::completions::{self, Shell};
+use crate::config::Config;
+use crate::credentials;
+use crate::database::ProductDatabase;
+use crate::demo;
+use crate::disk_space;
+use crate::env_diff;
+use crate::explore::Explorer;
+use crate::graph_export;
+use crate::holds::{self, Holds};
+use crate::host_keys::HostKeyPolicy;
+use crate::log_tail;
+use crate::map_cache::MapCache;
+use crate::overlay;
+use crate::profile::Profile;
+use crate::promote::{self, PromoteOptions};
+use crate::refresh;
+use crate::regenerate::*;
+use crate::release_manifest;
+use crate::repo_wrapper;
+use crate::restore;
+use crate::safety;
+use crate::settings::WorkspaceSettings;
+use crate::store::{self, Store};
+use crate::table_lint::Severity;
+use crate::workspace::Workspace;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use log::{debug, error, info, warn};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tempdir::TempDir;
+
+const DEFAULT_REMOTE_URL: &str =
+    "https://raw.githubusercontent.com/lsst/repos/master/etc/repos.yaml";
+
+/// Subcommands offered by shell completion
+pub const SUBCOMMANDS: [&str; 29] = [
+    "auth",
+    "bisect",
+    "check-overlay",
+    "check-reproducible",
+    "clean",
+    "closure-check",
+    "completions",
+    "config",
+    "declare-only",
+    "demo",
+    "env-diff",
+    "explore",
+    "graph",
+    "graph-diff",
+    "hold",
+    "holds",
+    "ide-setup",
+    "install",
+    "plan",
+    "profile",
+    "promote",
+    "refresh-clones",
+    "replay",
+    "restore-db",
+    "rollback",
+    "store-gc",
+    "tail",
+    "unhold",
+    "validate",
+];
+
+/// Subcommands whose arguments complete to product names
+pub const PRODUCT_COMMANDS: [&str; 14] = [
+    "bisect",
+    "check-reproducible",
+    "clean",
+    "closure-check",
+    "declare-only",
+    "env-diff",
+    "explore",
+    "graph",
+    "ide-setup",
+    "install",
+    "plan",
+    "rollback",
+    "tail",
+    "unhold",
+];
+
+/// Arguments locating the workspace, accepted by every subcommand which
+/// reads or writes one
+fn workspace_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("workspace")
+            .long("workspace")
+            .short("w")
+            .takes_value(true)
+            .value_name("NAME")
+            .help("Use a workspace named in the configuration file, overriding --install-root and --db"),
+        Arg::with_name("install-root")
+            .long("install-root")
+            .takes_value(true)
+            .value_name("DIR")
+            .default_value("resources/install/")
+            .help("Directory products are installed into"),
+        Arg::with_name("db")
+            .long("db")
+            .takes_value(true)
+            .value_name("PATH")
+            .default_value("resources/test.json")
+            .help("Database products are declared into"),
+    ]
+}
+
+/// Arguments mapping onto RegenOptions, accepted by every subcommand which
+/// resolves or builds products
+fn regen_args() -> Vec<Arg<'static, 'static>> {
+    let mut args = workspace_args();
+    args.extend(vec![
+        Arg::with_name("branch")
+            .long("branch")
+            .short("b")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("BRANCH")
+            .help("Branch to check out where it exists, in order of preference"),
+        Arg::with_name("local-yaml")
+            .long("local-yaml")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("PATH")
+            .default_value("resources/local_repo_list.yaml")
+            .help("Local repository map, or directory of map fragments, consulted before the \
+                   remote one. Later maps take precedence over earlier ones"),
+        Arg::with_name("remote-url")
+            .long("remote-url")
+            .takes_value(true)
+            .value_name("URL")
+            .default_value(DEFAULT_REMOTE_URL)
+            .help("Url of the remote repository map"),
+        Arg::with_name("remote-mirror")
+            .long("remote-mirror")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("URL")
+            .help("Copy of the remote repository map, tried if it can not be fetched"),
+        Arg::with_name("no-remote")
+            .long("no-remote")
+            .help("Resolve products from the local repository map only"),
+        Arg::with_name("allow-missing-remote")
+            .long("allow-missing-remote")
+            .help("Continue with the local map if the remote one can not be fetched"),
+        Arg::with_name("clone-root")
+            .long("clone-root")
+            .takes_value(true)
+            .value_name("DIR")
+            .default_value("resources/clones/")
+            .help("Directory repositories are cloned into"),
+        Arg::with_name("build-version")
+            .long("build-version")
+            .takes_value(true)
+            .value_name("VERSION")
+            .default_value("test_version")
+            .help("Version products are declared with"),
+        Arg::with_name("build-tool")
+            .long("build-tool")
+            .takes_value(true)
+            .value_name("PATH")
+            .default_value("eupspkg.sh")
+            .help("Build tool run for each verb"),
+        Arg::with_name("tag")
+            .long("tag")
+            .short("t")
+            .takes_value(true)
+            .value_name("TAG")
+            .help("Tag installed products with this tag"),
+        Arg::with_name("atomic-tag")
+            .long("atomic-tag")
+            .help("Only move the tag once every product has installed"),
+        Arg::with_name("clone-backend")
+            .long("clone-backend")
+            .takes_value(true)
+            .possible_values(&["git2", "system"])
+            .default_value("git2")
+            .help("How repositories are cloned"),
+        Arg::with_name("clone-filter")
+            .long("clone-filter")
+            .takes_value(true)
+            .value_name("SPEC")
+            .help("Partial clone filter used by the system backend, e.g. blob:none"),
+        Arg::with_name("max-rate")
+            .long("max-rate")
+            .takes_value(true)
+            .value_name("BYTES")
+            .help("Cap network transfers at this many bytes per second"),
+        Arg::with_name("depth")
+            .long("depth")
+            .takes_value(true)
+            .value_name("COMMITS")
+            .help("Clone only this many commits of history, fetching more if needed"),
+        Arg::with_name("max-clone-age")
+            .long("max-clone-age")
+            .takes_value(true)
+            .value_name("SECONDS")
+            .help("Only fetch clones which have not been refreshed for this long"),
+        Arg::with_name("host-auth")
+            .long("host-auth")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("HOST=METHOD")
+            .help("Authenticate to a git host with agent, key:PATH, or token:VAR"),
+        Arg::with_name("product-auth")
+            .long("product-auth")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("PRODUCT=METHOD")
+            .help("Authenticate to the remote of a product with agent, key:PATH, or token:VAR"),
+        Arg::with_name("fetch-remote")
+            .long("fetch-remote")
+            .takes_value(true)
+            .value_name("NAME")
+            .default_value("origin")
+            .help("Remote existing clones are fetched from before checking out"),
+        Arg::with_name("mirror-root")
+            .long("mirror-root")
+            .takes_value(true)
+            .value_name("DIR")
+            .help("Shared mirrors to clone from, keeping only private checkouts in the clone root"),
+        Arg::with_name("offline")
+            .long("offline")
+            .help("Do not fetch or clone, using the remote product map as last cached"),
+        Arg::with_name("skip-lfs")
+            .long("skip-lfs")
+            .help("Leave git LFS files as pointer files rather than fetching their content"),
+        Arg::with_name("fresh")
+            .long("fresh")
+            .help("Ignore the products an interrupted run completed rather than resuming it"),
+        Arg::with_name("strict-host-keys")
+            .long("strict-host-keys")
+            .help("Refuse ssh hosts whose keys have not been recorded"),
+        Arg::with_name("conda-prefix")
+            .long("conda-prefix")
+            .takes_value(true)
+            .value_name("DIR")
+            .help("Conda environment satisfying external dependencies"),
+        Arg::with_name("environment-spec")
+            .long("environment-spec")
+            .takes_value(true)
+            .value_name("PATH")
+            .help("Environment specification to provision before building"),
+        Arg::with_name("store")
+            .long("store")
+            .takes_value(true)
+            .value_name("DIR")
+            .help("Install into a content addressed store, linking versions to it"),
+        Arg::with_name("jobs")
+            .long("jobs")
+            .short("j")
+            .takes_value(true)
+            .value_name("N")
+            .help("Number of parallel jobs the build tool may use"),
+        Arg::with_name("workers")
+            .long("workers")
+            .takes_value(true)
+            .value_name("N")
+            .default_value("1")
+            .help("Number of independent products to build at the same time"),
+        Arg::with_name("only")
+            .long("only")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("PATTERN")
+            .help("Only build products of the graph matching this pattern, e.g. 'meas_*'"),
+        Arg::with_name("exclude")
+            .long("exclude")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("PATTERN")
+            .help("Do not build products matching this pattern, they must be reusable"),
+        Arg::with_name("run-tests")
+            .long("run-tests")
+            .alias("with-tests")
+            .help("Run the tests of each product after building it"),
+        Arg::with_name("fail-on-tests")
+            .long("fail-on-tests")
+            .requires("run-tests")
+            .help("Fail the install of a product whose tests fail rather than only reporting it"),
+        Arg::with_name("phases")
+            .long("phases")
+            .takes_value(true)
+            .value_name("PHASE,PHASE...")
+            .help("Build phases to run for every product, in order, e.g. fetch,prep,config,build,install"),
+        Arg::with_name("build-timeout")
+            .long("build-timeout")
+            .takes_value(true)
+            .value_name("SECONDS")
+            .help("Kill any build verb which runs for longer than this"),
+        Arg::with_name("verb-timeout")
+            .long("verb-timeout")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("VERB=SECONDS")
+            .help("Timeout of one build verb, taking precedence over --build-timeout"),
+        Arg::with_name("product-phases")
+            .long("product-phases")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("PRODUCT=PHASE,PHASE...")
+            .help("Build phases to run for one product, taking precedence over --phases"),
+        Arg::with_name("cmake-toolchain")
+            .long("cmake-toolchain")
+            .takes_value(true)
+            .value_name("PATH")
+            .help("CMake toolchain file passed to cmake based products"),
+        Arg::with_name("wrapper")
+            .long("wrapper")
+            .takes_value(true)
+            .value_name("COMMAND")
+            .help("Command the build tool is run under, e.g. \"nice -n 19\""),
+        Arg::with_name("retry")
+            .long("retry")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("VERB=N")
+            .help("Retry a build verb up to N times"),
+        Arg::with_name("restage-on-retry")
+            .long("restage-on-retry")
+            .help("Stage the source again before retrying a verb"),
+        Arg::with_name("normalize-mtimes")
+            .long("normalize-mtimes")
+            .help("Set the mtimes of checked out files to the current time"),
+        Arg::with_name("compile-commands")
+            .long("compile-commands")
+            .help("Collect compile_commands.json of each product"),
+        Arg::with_name("strict-tables")
+            .long("strict-tables")
+            .help("Fail on table files with lint errors"),
+        Arg::with_name("table-fallback")
+            .long("table-fallback")
+            .takes_value(true)
+            .possible_values(&["abort", "read-installed", "rebuild"])
+            .default_value("abort")
+            .help("What to do when the table of a reused product can not be read"),
+        Arg::with_name("abi-check")
+            .long("abi-check")
+            .takes_value(true)
+            .possible_values(&["off", "rebuild", "fail"])
+            .default_value("rebuild")
+            .help("What to do when a reused product's dependencies changed ABI"),
+        Arg::with_name("host-check")
+            .long("host-check")
+            .takes_value(true)
+            .possible_values(&["off", "warn", "rebuild", "fail"])
+            .default_value("warn")
+            .help("What to do when a reused product was built on a host it may not run on"),
+        Arg::with_name("missing-installs")
+            .long("missing-installs")
+            .takes_value(true)
+            .possible_values(&["rebuild", "fail"])
+            .default_value("rebuild")
+            .help("What to do when a reused product's install has been deleted"),
+        Arg::with_name("optional-dependencies")
+            .long("optional-dependencies")
+            .takes_value(true)
+            .possible_values(&["ignore", "build-if-available", "require"])
+            .default_value("ignore")
+            .help("Whether the optional dependencies of tables are built"),
+        Arg::with_name("duplicate-urls")
+            .long("duplicate-urls")
+            .takes_value(true)
+            .possible_values(&["share", "fail"])
+            .default_value("share")
+            .help("Whether products cloned from the same url share a clone or fail the run"),
+        Arg::with_name("build-id")
+            .long("build-id")
+            .takes_value(true)
+            .value_name("bNNNN")
+            .help("Build id of the run, recorded with each build and accepted when reusing"),
+        Arg::with_name("product-build-id")
+            .long("product-build-id")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("PRODUCT=bNNNN")
+            .help("Build id of one product, taking precedence over --build-id"),
+        Arg::with_name("build-id-map")
+            .long("build-id-map")
+            .takes_value(true)
+            .value_name("FILE")
+            .help(
+                "File of PRODUCT CONTENT_ID bNNNN lines, reusing installs declared with a build \
+                 id for the content id it was built from",
+            ),
+        Arg::with_name("env")
+            .long("env")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("KEY=VALUE")
+            .help("Set a variable in every build environment, over anything tables set"),
+        Arg::with_name("implicit-dependency")
+            .long("implicit-dependency")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("PRODUCT[:PATTERN,...]")
+            .help(
+                "Build products matching the patterns, or all products, against PRODUCT, \
+                   patterns starting with ! exclude products, replacing scipipe_conda",
+            ),
+        Arg::with_name("exact-manifest")
+            .long("exact-manifest")
+            .takes_value(true)
+            .value_name("PATH")
+            .help("Check every product out at the sha an lsst_build manifest gives it"),
+        Arg::with_name("no-implicit-dependencies")
+            .long("no-implicit-dependencies")
+            .conflicts_with("implicit-dependency")
+            .help("Only build products against the dependencies in their tables"),
+        Arg::with_name("fail-on-file-conflicts")
+            .long("fail-on-file-conflicts")
+            .help("Fail when two products install the same file"),
+        Arg::with_name("report")
+            .long("report")
+            .takes_value(true)
+            .value_name("PATH")
+            .help("Write a report of the run to this file or directory"),
+        Arg::with_name("report-format")
+            .long("report-format")
+            .takes_value(true)
+            .possible_values(&["markdown", "html"])
+            .default_value("markdown"),
+        Arg::with_name("email-to")
+            .long("email-to")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("ADDRESS")
+            .help("Email the report of the run to this address"),
+        Arg::with_name("email-from")
+            .long("email-from")
+            .takes_value(true)
+            .value_name("ADDRESS")
+            .requires("email-to")
+            .help("Address the report is sent from, the smtp user by default"),
+        Arg::with_name("smtp-server")
+            .long("smtp-server")
+            .takes_value(true)
+            .value_name("HOST[:PORT]")
+            .default_value("localhost")
+            .help("Smtp server to send the report through"),
+        Arg::with_name("smtp-security")
+            .long("smtp-security")
+            .takes_value(true)
+            .possible_values(&["starttls", "tls", "plain"])
+            .default_value("starttls")
+            .help("How the connection to the smtp server is encrypted"),
+        Arg::with_name("smtp-user")
+            .long("smtp-user")
+            .takes_value(true)
+            .value_name("USER")
+            .help(
+                "Authenticate to the smtp server as this user, with the password in \
+                 REGENERATE_SMTP_PASSWORD or the keyring token of the server",
+            ),
+        Arg::with_name("progress-socket")
+            .long("progress-socket")
+            .takes_value(true)
+            .value_name("PATH")
+            .conflicts_with("progress-fifo")
+            .help("Stream progress events to a listening unix socket"),
+        Arg::with_name("progress-fifo")
+            .long("progress-fifo")
+            .takes_value(true)
+            .value_name("PATH")
+            .help("Stream progress events to a named pipe"),
+        Arg::with_name("build-stream")
+            .long("build-stream")
+            .takes_value(true)
+            .value_name("DIR")
+            .help("Write lsst_build style events and manifest into this directory"),
+        Arg::with_name("write-manifest")
+            .long("write-manifest")
+            .takes_value(true)
+            .value_name("PATH")
+            .help("Write an lsst_build style manifest of the run here once it succeeds"),
+        Arg::with_name("log-upload")
+            .long("log-upload")
+            .takes_value(true)
+            .value_name("URL")
+            .help("Upload product logs to an s3://, gs://, or http(s) url as they finish"),
+        Arg::with_name("binary-cache")
+            .long("binary-cache")
+            .takes_value(true)
+            .value_name("URL")
+            .requires("store")
+            .help("Experimental: push and pull store entries to an s3://, gs://, or http(s) cache"),
+        Arg::with_name("min-free-space")
+            .long("min-free-space")
+            .takes_value(true)
+            .value_name("SIZE")
+            .help("Pause builds while less than this is free, e.g. 20G"),
+        Arg::with_name("min-free-inodes")
+            .long("min-free-inodes")
+            .takes_value(true)
+            .value_name("COUNT")
+            .help("Pause builds while fewer than this many inodes are free"),
+        Arg::with_name("disk-space-wait")
+            .long("disk-space-wait")
+            .takes_value(true)
+            .value_name("SECONDS")
+            .default_value("600")
+            .help("How long to wait for free space before stopping"),
+        Arg::with_name("skip-preflight")
+            .long("skip-preflight")
+            .help("Start without checking roots, the build tool, the remote map, and disk space"),
+        Arg::with_name("strict-reproducibility")
+            .long("strict-reproducibility")
+            .help("Rebuild everything when the build tools or compilers change"),
+        Arg::with_name("reproducible")
+            .long("reproducible")
+            .help("Normalize the time, locale, timezone, and umask builds see"),
+        Arg::with_name("yes")
+            .long("yes")
+            .short("y")
+            .help("Do not ask before large rebuilds"),
+        Arg::with_name("confirm-threshold")
+            .long("confirm-threshold")
+            .takes_value(true)
+            .value_name("N")
+            .default_value("20")
+            .help("Ask before runs building at least this many products, 0 never asks"),
+    ]);
+    args
+}
+
+fn products_arg(help: &'static str) -> Arg<'static, 'static> {
+    Arg::with_name("products")
+        .required(true)
+        .multiple(true)
+        .value_name("PRODUCT")
+        .help(help)
+}
+
+fn product_arg() -> Arg<'static, 'static> {
+    Arg::with_name("product")
+        .required(true)
+        .value_name("PRODUCT")
+}
+
+/// The command line interface
+pub fn app() -> App<'static, 'static> {
+    App::new("regenerate")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Clone, build, and declare stacks of eups products")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(
+            Arg::with_name("verbose")
+                .long("verbose")
+                .short("v")
+                .multiple(true)
+                .global(true)
+                .help("Log more, may be given twice"),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .long("quiet")
+                .short("q")
+                .global(true)
+                .conflicts_with("verbose")
+                .help("Only log warnings and errors"),
+        )
+        .subcommand(
+            SubCommand::with_name("install")
+                .about("Build and declare products along with their dependencies")
+                .args(&regen_args())
+                .arg(
+                    Arg::with_name("keep-going")
+                        .long("keep-going")
+                        .short("k")
+                        .help(
+                            "Skip the products depending on one which can not be installed, \
+                             building everything else, and list what succeeded, failed, \
+                             and was skipped",
+                        ),
+                )
+                .arg(Arg::with_name("dry-run").long("dry-run").short("n").help(
+                    "Show what would be built or reused, then stop. Nothing is cloned or \
+                         fetched, so every product must already have a clone",
+                ))
+                .arg(products_arg("Products or groups to install")),
+        )
+        .subcommand(
+            SubCommand::with_name("replay")
+                .about("Build a resolved manifest at its shas, without reading tables")
+                .args(&regen_args())
+                .arg(
+                    Arg::with_name("manifest")
+                        .required(true)
+                        .value_name("MANIFEST")
+                        .help("Lines of PRODUCT SHA [DEPENDENCY,...], dependencies first"),
+                )
+                .arg(
+                    Arg::with_name("keep-going")
+                        .long("keep-going")
+                        .short("k")
+                        .help("Skip the products depending on one which can not be installed"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("demo")
+                .about("Build a miniature stack of products to try out regenerate")
+                .args(&regen_args())
+                .arg(
+                    Arg::with_name("dir")
+                        .long("dir")
+                        .takes_value(true)
+                        .value_name("DIR")
+                        .help("Create the demo in this empty directory and keep it"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("plan")
+                .about("Show what installing a product would do, without building")
+                .args(&regen_args())
+                .arg(product_arg())
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("Print the plan as json"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("graph")
+                .about("Resolve the dependency graph of a product")
+                .args(&regen_args())
+                .arg(product_arg())
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .help("Save the graph as json for graph-diff or bisect"),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["dot", "json"])
+                        .default_value("dot")
+                        .help("How to print the graph when it is not saved"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("closure-check")
+                .about("Compare the transitive dependencies of products with a baseline")
+                .args(&regen_args())
+                .arg(products_arg("Products to check"))
+                .arg(
+                    Arg::with_name("baseline")
+                        .long("baseline")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .help("Baseline to compare with, by default the one of the workspace"),
+                )
+                .arg(
+                    Arg::with_name("record")
+                        .long("record")
+                        .help("Accept the current dependencies as the baseline"),
+                )
+                .arg(
+                    Arg::with_name("warn-only")
+                        .long("warn-only")
+                        .conflicts_with("record")
+                        .help("Only warn when new dependencies appear rather than failing"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("check-overlay")
+                .about("Compare the entries of the local map with the remote map")
+                .args(&regen_args())
+                .arg(
+                    Arg::with_name("skip-refs")
+                        .long("skip-refs")
+                        .help("Do not ask repositories whether the refs of entries still exist"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("check-reproducible")
+                .about("Build a product twice in reproducible mode and compare the installs")
+                .args(&regen_args())
+                .arg(product_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("explore")
+                .about("Walk the dependency graph of a product interactively")
+                .args(&regen_args())
+                .arg(product_arg())
+                .arg(
+                    Arg::with_name("graph")
+                        .long("graph")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .help("Explore a graph saved by graph --output rather than resolving it"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("graph-diff")
+                .about("Compare two saved graphs, printing graphviz dot")
+                .arg(Arg::with_name("old").required(true).value_name("OLD"))
+                .arg(Arg::with_name("new").required(true).value_name("NEW")),
+        )
+        .subcommand(
+            SubCommand::with_name("clean")
+                .about("Remove the clones of products, or every clone")
+                .args(&workspace_args())
+                .arg(
+                    Arg::with_name("clone-root")
+                        .long("clone-root")
+                        .takes_value(true)
+                        .value_name("DIR")
+                        .default_value("resources/clones/"),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .short("n")
+                        .help("Only list what would be removed"),
+                )
+                .arg(
+                    Arg::with_name("products")
+                        .multiple(true)
+                        .value_name("PRODUCT")
+                        .help("Products or groups whose clones to remove"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("refresh-clones")
+                .about("Fetch every clone from its remote")
+                .args(&workspace_args())
+                .arg(
+                    Arg::with_name("clone-root")
+                        .long("clone-root")
+                        .takes_value(true)
+                        .value_name("DIR")
+                        .default_value("resources/clones/"),
+                )
+                .arg(
+                    Arg::with_name("remote")
+                        .long("remote")
+                        .takes_value(true)
+                        .default_value("origin"),
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .long("jobs")
+                        .short("j")
+                        .takes_value(true)
+                        .value_name("N")
+                        .default_value("4"),
+                )
+                .arg(
+                    Arg::with_name("max-rate")
+                        .long("max-rate")
+                        .takes_value(true)
+                        .value_name("BYTES"),
+                )
+                .arg(Arg::with_name("strict-host-keys").long("strict-host-keys")),
+        )
+        .subcommand(
+            SubCommand::with_name("store-gc")
+                .about("Remove store entries no version links to")
+                .args(&workspace_args())
+                .arg(
+                    Arg::with_name("store")
+                        .long("store")
+                        .takes_value(true)
+                        .value_name("DIR")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("also-root")
+                        .long("also-root")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("DIR")
+                        .help("Another install root sharing the store, besides the configured workspaces"),
+                )
+                .arg(Arg::with_name("dry-run").long("dry-run").short("n")),
+        )
+        .subcommand(
+            SubCommand::with_name("rollback")
+                .about("Point the version link of a product at an earlier store build")
+                .args(&workspace_args())
+                .arg(product_arg())
+                .arg(
+                    Arg::with_name("store")
+                        .long("store")
+                        .takes_value(true)
+                        .value_name("DIR")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("build-version")
+                        .long("build-version")
+                        .takes_value(true)
+                        .value_name("VERSION")
+                        .default_value("test_version"),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .takes_value(true)
+                        .value_name("ID")
+                        .help("Product id, or a prefix of one, to roll back to"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("promote")
+                .about("Promote tagged products from this workspace into another")
+                .args(&workspace_args())
+                .arg(Arg::with_name("tag").required(true).value_name("TAG"))
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .takes_value(true)
+                        .value_name("WORKSPACE")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("production-tag")
+                        .long("production-tag")
+                        .takes_value(true)
+                        .value_name("TAG"),
+                )
+                .arg(
+                    Arg::with_name("link")
+                        .long("link")
+                        .help("Link installs instead of copying them"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restore-db")
+                .about("Declare installs found on disk which the database has lost")
+                .args(&workspace_args())
+                .arg(Arg::with_name("dry-run").long("dry-run").short("n")),
+        )
+        .subcommand(
+            SubCommand::with_name("declare-only")
+                .about("Declare an install built elsewhere, such as by CI, without building it")
+                .args(&workspace_args())
+                .arg(product_arg())
+                .arg(
+                    Arg::with_name("prod-dir")
+                        .long("prod-dir")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .required(true)
+                        .help("Directory holding the install, with its table in ups"),
+                )
+                .arg(
+                    Arg::with_name("build-version")
+                        .long("build-version")
+                        .alias("version")
+                        .takes_value(true)
+                        .value_name("VERSION")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("id")
+                        .long("id")
+                        .takes_value(true)
+                        .value_name("ID")
+                        .help("Id to declare the install with, by default the id it recorded"),
+                )
+                .arg(
+                    Arg::with_name("tag")
+                        .long("tag")
+                        .takes_value(true)
+                        .value_name("TAG"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("hold")
+                .about("Pin products at a revision until released")
+                .args(&workspace_args())
+                .arg(
+                    Arg::with_name("specs")
+                        .required(true)
+                        .multiple(true)
+                        .value_name("PRODUCT@REVISION"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("unhold")
+                .about("Release held products")
+                .args(&workspace_args())
+                .arg(products_arg("Products to release")),
+        )
+        .subcommand(
+            SubCommand::with_name("profile")
+                .about("Show where the time of a run went, by product and phase")
+                .args(&workspace_args())
+                .arg(
+                    Arg::with_name("run-id")
+                        .required(true)
+                        .value_name("RUN_ID")
+                        .help("Id the run printed when it started"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("holds")
+                .about("List held products")
+                .args(&workspace_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("validate")
+                .about("Check a repository map for entries which would stop it from loading")
+                .arg(
+                    Arg::with_name("map")
+                        .required(true)
+                        .value_name("PATH")
+                        .help("The repos.yaml file to check"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("tail")
+                .about("Show the build log of a product, following it while it builds")
+                .args(&workspace_args())
+                .arg(product_arg())
+                .arg(
+                    Arg::with_name("build-version")
+                        .long("build-version")
+                        .takes_value(true)
+                        .value_name("VERSION")
+                        .default_value("test_version"),
+                )
+                .arg(
+                    Arg::with_name("lines")
+                        .long("lines")
+                        .short("n")
+                        .takes_value(true)
+                        .value_name("COUNT")
+                        .help("Only replay this many lines of what was already written"),
+                )
+                .arg(
+                    Arg::with_name("no-follow")
+                        .long("no-follow")
+                        .help("Stop after what was already written"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("ide-setup")
+                .about("Link the collected compile_commands.json into a checkout")
+                .args(&workspace_args())
+                .arg(product_arg())
+                .arg(
+                    Arg::with_name("clone-root")
+                        .long("clone-root")
+                        .takes_value(true)
+                        .value_name("DIR")
+                        .default_value("resources/clones/"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("env-diff")
+                .about("Compare the build environments of two products")
+                .args(&regen_args())
+                .arg(Arg::with_name("first").required(true).value_name("PRODUCT"))
+                .arg(
+                    Arg::with_name("second")
+                        .required(true)
+                        .value_name("PRODUCT"),
+                )
+                .arg(Arg::with_name("json").long("json")),
+        )
+        .subcommand(
+            SubCommand::with_name("bisect")
+                .about("Find the commit between two saved graphs which broke a test")
+                .args(&regen_args())
+                .arg(product_arg())
+                .arg(
+                    Arg::with_name("good")
+                        .long("good")
+                        .takes_value(true)
+                        .value_name("GRAPH")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("bad")
+                        .long("bad")
+                        .takes_value(true)
+                        .value_name("GRAPH")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("test")
+                        .long("test")
+                        .takes_value(true)
+                        .value_name("COMMAND")
+                        .required(true)
+                        .help("Exits 0 when good, 125 to skip, anything else when bad"),
+                )
+                .arg(
+                    Arg::with_name("order")
+                        .long("order")
+                        .takes_value(true)
+                        .possible_values(&["product", "date"])
+                        .default_value("product"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("auth")
+                .about("Manage tokens for git hosts")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("login")
+                        .arg(Arg::with_name("host").required(true).value_name("HOST")),
+                )
+                .subcommand(
+                    SubCommand::with_name("logout")
+                        .arg(Arg::with_name("host").required(true).value_name("HOST")),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("config")
+                .about("Manage defaults stored in the workspace")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("set")
+                        .about("Store a default, used unless overridden by a flag")
+                        .args(&workspace_args())
+                        .arg(Arg::with_name("key").required(true).value_name("KEY"))
+                        .arg(
+                            Arg::with_name("values")
+                                .required(true)
+                                .multiple(true)
+                                .value_name("VALUE"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("unset")
+                        .about("Remove a stored default")
+                        .args(&workspace_args())
+                        .arg(Arg::with_name("key").required(true).value_name("KEY")),
+                )
+                .subcommand(
+                    SubCommand::with_name("show")
+                        .about("List the stored defaults")
+                        .args(&workspace_args()),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("completions")
+                .about("Print a shell completion script")
+                .arg(
+                    Arg::with_name("shell")
+                        .required(true)
+                        .possible_values(&["bash", "zsh", "fish"]),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("__complete")
+                .setting(AppSettings::Hidden)
+                .arg(Arg::with_name("kind").required(true))
+                .arg(
+                    Arg::with_name("local-yaml")
+                        .long("local-yaml")
+                        .takes_value(true)
+                        .default_value("resources/local_repo_list.yaml"),
+                ),
+        )
+}
+
+/// Level to log at given the verbosity flags
+pub fn log_level(matches: &ArgMatches) -> log::LevelFilter {
+    if matches.is_present("quiet") {
+        return log::LevelFilter::Warn;
+    }
+    match matches.occurrences_of("verbose") {
+        0 => log::LevelFilter::Info,
+        1 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    }
+}
+
+fn parse_opt<T>(matches: &ArgMatches, name: &str) -> Result<Option<T>, String>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    match matches.value_of(name) {
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|e| format!("Invalid value {} for --{}: {}", value, name, e)),
+        None => Ok(None),
+    }
+}
+
+fn path_of(matches: &ArgMatches, name: &str) -> Option<PathBuf> {
+    matches.value_of(name).map(PathBuf::from)
+}
+
+/// Every value given for an argument which may be repeated
+fn values(matches: &ArgMatches, name: &str) -> Vec<String> {
+    matches
+        .values_of(name)
+        .map(|v| v.map(String::from).collect())
+        .unwrap_or_default()
+}
+
+fn workspace(matches: &ArgMatches, config: &Config) -> Result<Workspace, String> {
+    match matches.value_of("workspace") {
+        Some(name) => config
+            .workspace(name)
+            .ok_or(format!("No workspace named {} is configured", name)),
+        None => Ok(Workspace::new(
+            matches.value_of("install-root").unwrap_or_default(),
+            matches.value_of("db").unwrap_or_default(),
+        )),
+    }
+}
+
+fn products(matches: &ArgMatches, config: &Config) -> Result<Vec<String>, String> {
+    let names: Vec<String> = match matches.values_of("products") {
+        Some(values) => values.map(|v| v.to_string()).collect(),
+        None => vec![],
+    };
+    config.expand_products(&names)
+}
+
+fn verb_retries(matches: &ArgMatches) -> Result<HashMap<String, u32>, String> {
+    let mut retries = HashMap::new();
+    for spec in matches.values_of("retry").into_iter().flatten() {
+        let pos = spec
+            .find('=')
+            .ok_or(format!("{} is not of the form verb=count", spec))?;
+        let count = spec[pos + 1..]
+            .parse()
+            .map_err(|e| format!("Invalid retry count in {}: {}", spec, e))?;
+        retries.insert(spec[..pos].to_string(), count);
+    }
+    Ok(retries)
+}
+
+/// Credentials given as NAME=METHOD for each value of an argument
+fn auth_specs(matches: &ArgMatches, name: &str) -> Result<HashMap<String, GitAuth>, String> {
+    let mut auth = HashMap::new();
+    for spec in values(matches, name) {
+        let pos = spec
+            .find('=')
+            .ok_or(format!("{} is not of the form name=method", spec))?;
+        auth.insert(spec[..pos].to_string(), GitAuth::parse(&spec[pos + 1..])?);
+    }
+    Ok(auth)
+}
+
+/// The value of an argument, where a value given on the command line wins
+/// over the workspace setting, which wins over the default of the argument
+fn setting(
+    matches: &ArgMatches,
+    name: &str,
+    settings: &WorkspaceSettings,
+    key: &str,
+) -> Option<String> {
+    if matches.occurrences_of(name) == 0 {
+        if let Some(value) = settings.get(key) {
+            debug!("Using {} {} from the workspace settings", key, value);
+            return Some(value.clone());
+        }
+    }
+    matches.value_of(name).map(|v| v.to_string())
+}
+
+/// How to email the report to the given addresses
+fn email_settings(matches: &ArgMatches, to: Vec<String>) -> Result<EmailSettings, String> {
+    let security = match matches.value_of("smtp-security") {
+        Some("tls") => SmtpSecurity::Tls,
+        Some("plain") => SmtpSecurity::Plain,
+        _ => SmtpSecurity::StartTls,
+    };
+    let server = matches.value_of("smtp-server").unwrap_or("localhost");
+    let (server, port) = match server.rfind(':') {
+        Some(pos) => (
+            &server[..pos],
+            server[pos + 1..]
+                .parse()
+                .map_err(|_| format!("{} is not a valid smtp port", &server[pos + 1..]))?,
+        ),
+        None => (server, security.default_port()),
+    };
+    let credentials = match matches.value_of("smtp-user") {
+        Some(user) => {
+            let password = std::env::var("REGENERATE_SMTP_PASSWORD")
+                .ok()
+                .or_else(|| credentials::lookup_token(server))
+                .ok_or(format!(
+                    "No REGENERATE_SMTP_PASSWORD or keyring token to authenticate to {} with",
+                    server
+                ))?;
+            Some((user.to_string(), password))
+        }
+        None => None,
+    };
+    let from = match (matches.value_of("email-from"), credentials.as_ref()) {
+        (Some(from), _) => from.to_string(),
+        (None, Some((user, _))) => user.clone(),
+        (None, None) => return Err("--email-from is needed to email the report".to_string()),
+    };
+    Ok(EmailSettings {
+        server: server.to_string(),
+        port,
+        security,
+        credentials,
+        from,
+        to,
+        subject: "Report of the regenerate run".to_string(),
+    })
+}
+
+fn clone_root(matches: &ArgMatches, workspace: &Workspace) -> Result<PathBuf, String> {
+    let settings = WorkspaceSettings::open(&workspace.install_root)?;
+    Ok(PathBuf::from(
+        setting(matches, "clone-root", &settings, "clone_root").unwrap_or_default(),
+    ))
+}
+
+/// Build the options of a run from the command line, falling back to the
+/// workspace settings and then the user configuration
+fn regen_options(
+    matches: &ArgMatches,
+    config: &Config,
+    workspace: &Workspace,
+) -> Result<RegenOptions, String> {
+    let settings = WorkspaceSettings::open(&workspace.install_root)?;
+    let branches = match (
+        matches.occurrences_of("branch"),
+        settings.get_list("branches"),
+    ) {
+        (0, Some(branches)) => {
+            debug!("Using branches {:?} from the workspace settings", branches);
+            Some(branches.clone())
+        }
+        _ => matches
+            .values_of("branch")
+            .map(|v| v.map(|b| b.to_string()).collect()),
+    };
+    let local_yaml = match (
+        matches.occurrences_of("local-yaml"),
+        settings.get_list("local_yaml"),
+    ) {
+        (0, Some(maps)) => {
+            debug!("Using local maps {:?} from the workspace settings", maps);
+            maps.iter().map(PathBuf::from).collect()
+        }
+        _ => values(matches, "local-yaml")
+            .iter()
+            .map(PathBuf::from)
+            .collect(),
+    };
+    let clone_backend = match matches.value_of("clone-backend") {
+        Some("system") => BackendKind::SystemGit {
+            filter: matches.value_of("clone-filter").map(|f| f.to_string()),
+        },
+        _ => BackendKind::Git2,
+    };
+    let remote_package_url = match matches.is_present("no-remote") {
+        true => None,
+        false => setting(matches, "remote-url", &settings, "remote_url"),
+    };
+    let progress_sink = match (
+        path_of(matches, "progress-socket"),
+        path_of(matches, "progress-fifo"),
+    ) {
+        (Some(path), _) => Some(ProgressSink::UnixSocket(path)),
+        (None, Some(path)) => Some(ProgressSink::Fifo(path)),
+        (None, None) => None,
+    };
+    let email = match values(matches, "email-to") {
+        ref to if to.is_empty() => None,
+        to => Some(email_settings(matches, to)?),
+    };
+    let report = match (path_of(matches, "report"), email) {
+        (None, None) => None,
+        (path, email) => Some(ReportOptions {
+            format: match matches.value_of("report-format") {
+                Some("html") => ReportFormat::Html,
+                _ => ReportFormat::Markdown,
+            },
+            path,
+            email,
+        }),
+    };
+    let table_fallback = match matches.value_of("table-fallback") {
+        Some("read-installed") => TableFallback::ReadInstalled,
+        Some("rebuild") => TableFallback::Rebuild,
+        _ => TableFallback::Abort,
+    };
+    let abi_check = match matches.value_of("abi-check") {
+        Some("off") => AbiCheck::Off,
+        Some("fail") => AbiCheck::Fail,
+        _ => AbiCheck::Rebuild,
+    };
+    let mut product_build_ids = HashMap::new();
+    for spec in values(matches, "product-build-id").iter() {
+        let (product, id) = BuildIds::parse_spec(spec)?;
+        product_build_ids.insert(product, id);
+    }
+    let phase_list = |text: &str| -> Result<Vec<Phase>, String> {
+        text.split(',')
+            .filter(|p| !p.is_empty())
+            .map(Phase::from_name)
+            .collect()
+    };
+    let mut product_phases = HashMap::new();
+    for spec in values(matches, "product-phases").iter() {
+        let pos = spec
+            .find('=')
+            .ok_or(format!("{} is not of the form PRODUCT=PHASE,PHASE", spec))?;
+        product_phases.insert(spec[..pos].to_string(), phase_list(&spec[pos + 1..])?);
+    }
+    let mut verb_timeouts = HashMap::new();
+    for spec in values(matches, "verb-timeout").iter() {
+        let pos = spec
+            .find('=')
+            .ok_or(format!("{} is not of the form VERB=SECONDS", spec))?;
+        let secs: u64 = spec[pos + 1..]
+            .parse()
+            .map_err(|e| format!("Invalid timeout in {}: {}", spec, e))?;
+        verb_timeouts.insert(spec[..pos].to_string(), Duration::from_secs(secs));
+    }
+    let mut extra_env = HashMap::new();
+    for spec in values(matches, "env").iter() {
+        match spec.find('=') {
+            Some(pos) if pos > 0 => {
+                extra_env.insert(spec[..pos].to_string(), spec[pos + 1..].to_string())
+            }
+            _ => return Err(format!("{} is not of the form KEY=VALUE", spec)),
+        };
+    }
+    let build_id_map = path_of(matches, "build-id-map");
+    let identity: Box<dyn IdentityBackend> = match (
+        matches.value_of("build-id"),
+        product_build_ids.is_empty(),
+        build_id_map,
+    ) {
+        (None, true, None) => Box::new(ContentIds),
+        (run, _, map) => {
+            let mut ids = BuildIds::new(run, product_build_ids)?;
+            if let Some(path) = map {
+                ids.accept_from(&path)?;
+            }
+            Box::new(ids)
+        }
+    };
+    let implicit_dependencies = match (
+        matches.is_present("no-implicit-dependencies"),
+        matches.values_of("implicit-dependency"),
+    ) {
+        (true, _) => vec![],
+        (false, Some(specs)) => specs.map(ImplicitDep::parse).collect::<Result<_, _>>()?,
+        (false, None) => default_implicit_dependencies(),
+    };
+    let optional_dependencies = match matches.value_of("optional-dependencies") {
+        Some("build-if-available") => OptionalPolicy::BuildIfAvailable,
+        Some("require") => OptionalPolicy::Require,
+        _ => OptionalPolicy::Ignore,
+    };
+    Ok(RegenOptions {
+        branches,
+        local_yaml,
+        clone_root: setting(matches, "clone-root", &settings, "clone_root").unwrap_or_default(),
+        install_root: workspace
+            .install_root
+            .to_str()
+            .ok_or("The install root is not valid unicode")?
+            .to_string(),
+        version: setting(matches, "build-version", &settings, "version").unwrap_or_default(),
+        build_tool: setting(matches, "build-tool", &settings, "build_tool").unwrap_or_default(),
+        tag: setting(matches, "tag", &settings, "tag"),
+        remote_package_url,
+        remote_package_mirrors: values(matches, "remote-mirror"),
+        allow_missing_remote: matches.is_present("allow-missing-remote"),
+        clone_backend,
+        host_clone_backends: HashMap::new(),
+        clone_limits: CloneLimits {
+            max_rate: parse_opt(matches, "max-rate")?,
+            ..CloneLimits::default()
+        },
+        conda_prefix: path_of(matches, "conda-prefix"),
+        environment_products: HashMap::new(),
+        environment_spec: path_of(matches, "environment-spec"),
+        git_auth: GitAuthConfig {
+            hosts: auth_specs(matches, "host-auth")?,
+            products: auth_specs(matches, "product-auth")?,
+        },
+        fetch_remote: matches
+            .value_of("fetch-remote")
+            .unwrap_or("origin")
+            .to_string(),
+        max_clone_age: parse_opt(matches, "max-clone-age")?.map(Duration::from_secs),
+        offline: matches.is_present("offline"),
+        no_checkout: false,
+        skip_lfs: matches.is_present("skip-lfs"),
+        clone_depth: parse_opt(matches, "depth")?,
+        mirror_root: setting(matches, "mirror-root", &settings, "mirror_root").map(PathBuf::from),
+        report,
+        verb_retries: verb_retries(matches)?,
+        restage_on_retry: matches.is_present("restage-on-retry"),
+        progress_sink,
+        strict_tables: matches.is_present("strict-tables"),
+        command_wrapper: matches
+            .value_of("wrapper")
+            .map(|w| w.split_whitespace().map(|s| s.to_string()).collect()),
+        clock_skew_threshold: Some(Duration::from_secs(2)),
+        normalize_mtimes: matches.is_present("normalize-mtimes"),
+        atomic_tag: matches.is_present("atomic-tag"),
+        collect_compile_commands: matches.is_present("compile-commands"),
+        run_name_template: config.run_name_template.clone(),
+        confirm_threshold: match setting(
+            matches,
+            "confirm-threshold",
+            &settings,
+            "confirm_threshold",
+        ) {
+            Some(n) => match n.parse() {
+                Ok(0) => None,
+                Ok(n) => Some(n),
+                Err(_) => return Err(format!("{} is not a number of products", n)),
+            },
+            None => None,
+        },
+        assume_yes: matches.is_present("yes"),
+        table_fallback,
+        abi_check,
+        missing_installs: match matches.value_of("missing-installs") {
+            Some("fail") => MissingInstall::Fail,
+            _ => MissingInstall::Rebuild,
+        },
+        host_check: match matches.value_of("host-check") {
+            Some("off") => HostCheck::Off,
+            Some("rebuild") => HostCheck::Rebuild,
+            Some("fail") => HostCheck::Fail,
+            _ => HostCheck::Warn,
+        },
+        optional_dependencies,
+        duplicate_urls: match matches.value_of("duplicate-urls") {
+            Some("fail") => DuplicateUrls::Fail,
+            _ => DuplicateUrls::Share,
+        },
+        identity,
+        extra_env,
+        implicit_dependencies,
+        pinned_shas: match path_of(matches, "exact-manifest") {
+            Some(path) => release_manifest::load_pins(&path)?,
+            None => BTreeMap::new(),
+        },
+        fail_on_file_conflicts: matches.is_present("fail-on-file-conflicts"),
+        build_stream: path_of(matches, "build-stream"),
+        run_manifest: path_of(matches, "write-manifest"),
+        strict_host_keys: matches.is_present("strict-host-keys"),
+        output_classifiers: config.classifiers.clone(),
+        build_jobs: parse_opt(matches, "jobs")?,
+        cmake_toolchain_file: path_of(matches, "cmake-toolchain"),
+        run_tests: matches.is_present("run-tests"),
+        fail_on_tests: matches.is_present("fail-on-tests"),
+        stream_output: matches.occurrences_of("verbose") > 0,
+        phases: match matches.value_of("phases") {
+            Some(text) => phase_list(text)?,
+            None => vec![],
+        },
+        product_phases,
+        build_timeout: parse_opt(matches, "build-timeout")?.map(Duration::from_secs),
+        verb_timeouts,
+        store_root: path_of(matches, "store"),
+        strict_reproducibility: matches.is_present("strict-reproducibility"),
+        reproducible: matches.is_present("reproducible"),
+        min_free_space: match matches.value_of("min-free-space") {
+            Some(size) => Some(disk_space::parse_size(size)?),
+            None => None,
+        },
+        min_free_inodes: parse_opt(matches, "min-free-inodes")?,
+        disk_space_wait: Duration::from_secs(parse_opt(matches, "disk-space-wait")?.unwrap_or(600)),
+        skip_preflight: matches.is_present("skip-preflight"),
+        build_workers: parse_opt(matches, "workers")?.unwrap_or(1),
+        log_upload: match matches.value_of("log-upload") {
+            Some(url) => Some(LogDestination::parse(url)?),
+            None => None,
+        },
+        binary_cache: match matches.value_of("binary-cache") {
+            Some(url) => Some(LogDestination::parse(url)?),
+            None => None,
+        },
+        product_filter: ProductFilter::new(
+            &config.expand_products(&values(matches, "only"))?,
+            &config.expand_products(&values(matches, "exclude"))?,
+        )?,
+        keep_going: matches.is_present("keep-going"),
+        fresh: matches.is_present("fresh"),
+    })
+}
+
+/// Parse the command line and run the requested subcommand. Aliases from the
+/// configuration are expanded before parsing.
+pub fn run(args: Vec<String>, config: &Config) -> Result<(), String> {
+    let args = config.expand_aliases(args)?;
+    let matches = match app().get_matches_from_safe(args) {
+        Ok(m) => m,
+        Err(e) => e.exit(),
+    };
+    let level = log_level(&matches);
+    let _ = log::set_boxed_logger(reups::Logger::new(level, std::io::stdout()));
+    log::set_max_level(level);
+    match matches.subcommand() {
+        ("install", Some(m)) => install(m, config),
+        ("plan", Some(m)) => plan(m, config),
+        ("demo", Some(m)) => demo(m, config),
+        ("graph", Some(m)) => graph(m, config),
+        ("graph-diff", Some(m)) => graph_diff(m),
+        ("explore", Some(m)) => explore(m, config),
+        ("closure-check", Some(m)) => closure_check(m, config),
+        ("check-overlay", Some(m)) => check_overlay(m, config),
+        ("check-reproducible", Some(m)) => check_reproducible(m, config),
+        ("clean", Some(m)) => clean(m, config),
+        ("refresh-clones", Some(m)) => refresh_clones(m, config),
+        ("store-gc", Some(m)) => store_gc(m, config),
+        ("rollback", Some(m)) => rollback(m, config),
+        ("promote", Some(m)) => promote(m, config),
+        ("restore-db", Some(m)) => restore_db(m, config),
+        ("declare-only", Some(m)) => declare_only(m, config),
+        ("hold", Some(m)) => hold(m, config),
+        ("unhold", Some(m)) => unhold(m, config),
+        ("holds", Some(m)) => list_holds(m, config),
+        ("tail", Some(m)) => tail(m, config),
+        ("validate", Some(m)) => validate_map(m),
+        ("profile", Some(m)) => profile(m, config),
+        ("replay", Some(m)) => replay(m, config),
+        ("ide-setup", Some(m)) => ide_setup(m, config),
+        ("env-diff", Some(m)) => env_diff(m, config),
+        ("bisect", Some(m)) => bisect(m, config),
+        ("auth", Some(m)) => match m.subcommand() {
+            ("login", Some(m)) => credentials::login(m.value_of("host").unwrap_or_default()),
+            ("logout", Some(m)) => {
+                credentials::remove_token(m.value_of("host").unwrap_or_default())
+            }
+            _ => Err("auth needs a subcommand".to_string()),
+        },
+        ("config", Some(m)) => workspace_settings(m, config),
+        ("completions", Some(m)) => {
+            let shell = Shell::from_name(m.value_of("shell").unwrap_or_default())?;
+            print!(
+                "{}",
+                completions::generate(&shell, &SUBCOMMANDS, &PRODUCT_COMMANDS)
+            );
+            Ok(())
+        }
+        ("__complete", Some(m)) => {
+            let kind = m.value_of("kind").unwrap_or_default();
+            let local_yaml = path_of(m, "local-yaml");
+            for candidate in completions::dynamic_candidates(
+                kind,
+                config,
+                local_yaml.as_ref().map(|p| p.as_path()),
+            ) {
+                println!("{}", candidate);
+            }
+            Ok(())
+        }
+        (name, _) => Err(format!("Unknown subcommand {}", name)),
+    }
+}
+
+fn install(matches: &ArgMatches, config: &Config) -> Result<(), String> {
+    let workspace = workspace(matches, config)?;
+    let names = products(matches, config)?;
+    let mut db = workspace.open_db()?;
+    let mut options = regen_options(matches, config, &workspace)?;
+    let jobs = options.build_jobs.unwrap_or(1);
+    let dry_run = matches.is_present("dry-run");
+    // a dry run plans from the clones and maps already on disk, without
+    // cloning, fetching, or checking out anything
+    options.offline |= dry_run;
+    options.no_checkout |= dry_run;
+    let mut app = Regenerate::new(&mut db, options)?;
+    if dry_run {
+        for product in names.iter() {
+            let plan = app.plan(product).map_err(|e| {
+                format!(
+                    "Could not plan {} from what is on disk, a dry run does not clone \
+                     missing products or fetch existing ones: {}",
+                    product, e
+                )
+            })?;
+            print!("{}", plan.render());
+            println!("{}", plan.estimate(workers).render());
+        }
+        return Ok(());
+    }
+    // every product is installed from one combined graph
+    let result = app.install_products(&names);
+    match result.as_ref() {
+        Ok(_) => info!("Installed {}", names.join(", ")),
+        Err(e) => {
+            if let RegenError::Build { product, .. } = e {
+                if let Some(path) = app.failure_log(product) {
+                    error!("The build log of {} is in {}", product, path.display());
+                }
+            }
+        }
+    }
+    if matches.is_present("keep-going") {
+        print!("{}", app.report().render_outcomes());
+    }
+    app.publish_report()?;
+    result.map_err(|e| format!("Could not install {}: {}", names.join(", "), e))
+}
+
+fn replay(matches: &ArgMatches, config: &Config) -> Result<(), String> {
+    let workspace = workspace(matches, config)?;
+    let manifest =
+        ReleaseManifest::load(Path::new(matches.value_of("manifest").unwrap_or_default()))?;
+    let mut db = workspace.open_db()?;
+    let options = regen_options(matches, config, &workspace)?;
+    let mut app = Regenerate::new(&mut db, options)?;
+    let result = app.install_manifest(&manifest);
+    if matches.is_present("keep-going") {
+        print!("{}", app.report().render_outcomes());
+    }
+    app.publish_report()?;
+    result.map_err(|e| format!("Could not install the manifest: {}", e))
+}
+
+/// Synthesize the demo stack and install it, using the run options given
+/// other than where things are cloned, built, and installed
+fn demo(matches: &ArgMatches, config: &Config) -> Result<(), String> {
+    let tmp_dir;
+    let root = match path_of(matches, "dir") {
+        Some(dir) => dir,
+        None => {
+            tmp_dir = TempDir::new("regenerate-demo").map_err(|e| format!("{}", e))?;
+            tmp_dir.path().to_path_buf()
+        }
+    };
+    let stack = demo::synthesize(&root)?;
+    info!("Created the demo stack in {}", stack.root.display());
+    let workspace = Workspace {
+        install_root: stack.install_root.clone(),
+        db_path: stack.db_path.clone(),
+    };
+    let mut db = workspace.open_db()?;
+    let mut options = regen_options(matches, config, &workspace)?;
+    options.local_yaml = vec![stack.repo_map.clone()];
+    options.remote_package_url = None;
+    options.clone_root = stack.clone_root.to_string_lossy().to_string();
+    options.build_tool = stack.build_tool.to_string_lossy().to_string();
+    options.branches = Some(vec![demo::BRANCH.to_string()]);
+    options.assume_yes = true;
+    let mut app = Regenerate::new(&mut db, options)?;
+    let result = app.install_product(demo::TOP_PRODUCT);
+    print!("{}", app.report().render_markdown());
+    app.publish_report()?;
+    result.map_err(|e| format!("The demo stack did not install: {}", e))?;
+    println!(
+        "\nInstalled the demo stack into {}",
+        stack.install_root.display()
+    );
+    Ok(())
+}
+
+fn plan(matches: &ArgMatches, config: &Config) -> Result<(), String> {
+    let workspace = workspace(matches, config)?;
+    let mut db = workspace.open_db()?;
+    let mut options = regen_options(matches, config, &workspace)?;
+    options.no_checkout = true;
+    let workers = options.build_workers;
+    let mut app = Regenerate::new(&mut db, options)?;
+    let plan = app.plan(matches.value_of("product").unwrap_or_default())?;
+    if matches.is_present("json") {
+        println!("{}", plan.to_json()?);
+    } else {
+        print!("{}", plan.render());
+        print!("{}", plan.estimate(workers).render());
+    }
+    Ok(())
+}
+
+fn graph(matches: &ArgMatches, config: &Config) -> Result<(), String> {
+    let workspace = workspace(matches, config)?;
+    let product = matches.value_of("product").unwrap_or_default();
+    let mut db = workspace.open_db()?;
+    let options = regen_options(matches, config, &workspace)?;
+    let mut app = Regenerate::new(&mut db, options)?;
+    match path_of(matches, "output") {
+        Some(path) => {
+            app.resolve_graph(product)?;
+            app.snapshot_graph(product)?.save(&path)?;
+            info!("Saved the graph of {} to {}", product, path.display());
+        }
+        None => {
+            let format = GraphFormat::from_name(matches.value_of("format").unwrap_or("dot"))?;
+            println!("{}", app.export_graph(product, format)?.trim_end());
+        }
+    }
+    Ok(())
+}
+
+fn graph_diff(matches: &ArgMatches) -> Result<(), String> {
+    let old = GraphSnapshot::load(Path::new(matches.value_of("old").unwrap_or_default()))?;
+    let new = GraphSnapshot::load(Path::new(matches.value_of("new").unwrap_or_default()))?;
+    print!("{}", graph_export::diff_to_dot(&old, &new));
+    Ok(())
+}
+
+fn explore(matches: &ArgMatches, config: &Config) -> Result<(), String> {
+    let workspace = workspace(matches, config)?;
+    let product = matches.value_of("product").unwrap_or_default();
+    let options = regen_options(matches, config, &workspace)?;
+    let version = options.version.clone();
+    let snapshot = match path_of(matches, "graph") {
+        Some(path) => GraphSnapshot::load(&path)?,
+        None => {
+            let mut db = workspace.open_db()?;
+            let mut app = Regenerate::new(&mut db, options)?;
+            app.resolve_graph(product)?;
+            app.snapshot_graph(product)?
+        }
+    };
+    if !snapshot.nodes.contains_key(product) {
+        return Err(format!("{} is not in the graph", product));
+    }
+    let mut explorer = Explorer::new(&snapshot, &workspace.install_root, &version);
+    if product != snapshot.root {
+        explorer.handle(&format!("cd {}", product));
+    }
+    let stdin = std::io::stdin();
+    explorer.run(stdin.lock(), std::io::stdout())
+}
+
+fn check_overlay(matches: &ArgMatches, config: &Config) -> Result<(), String> {
+    let workspace = workspace(matches, config)?;
+    let options = regen_options(matches, config, &workspace)?;
+    let local_paths = repo_wrapper::overlay_files(&options.local_yaml)?;
+    if local_paths.is_empty() {
+        return Err("There is no local map to check".to_string());
+    }
+    let url = options
+        .remote_package_url
+        .as_ref()
+        .ok_or("There is no remote map to compare with")?;
+    let remote = fetch_remote_mapping(
+        url,
+        &options.remote_package_mirrors,
+        options.clone_limits.max_rate,
+        Some(&MapCache::new(&workspace.install_root)),
+    )?;
+    let mut problems = 0;
+    for local_path in local_paths.iter() {
+        let local = repo_wrapper::parse_map(
+            &std::fs::read_to_string(local_path)
+                .map_err(|e| format!("Could not read {}: {}", local_path.display(), e))?,
+            &local_path.display().to_string(),
+        )?;
+        let findings = overlay::check_overlay(&local, &remote, !matches.is_present("skip-refs"));
+        for finding in findings.iter() {
+            println!("{}: {}", local_path.display(), finding);
+        }
+        problems += findings.iter().filter(|f| f.is_problem()).count();
+    }
+    match problems {
+        0 => Ok(()),
+        n => Err(format!(
+            "{} entries of the local maps have drifted from the remote map",
+            n
+        )),
+    }
+}
+
+fn check_reproducible(matches: &ArgMatches, config: &Config) -> Result<(), String> {
+    let workspace = workspace(matches, config)?;
+    let product = matches.value_of("product").unwrap_or_default();
+    let mut db = workspace.open_db()?;
+    let mut options = regen_options(matches, config, &workspace)?;
+    options.reproducible = true;
+    let mut app = Regenerate::new(&mut db, options)?;
+    let differences = app.check_reproducible(product)?;
+    for difference in differences.iter() {
+        println!("{}", difference);
+    }
+    match differences.len() {
+        0 => {
+            info!("Both builds of {} are identical", product);
+            Ok(())
+        }
+        n => Err(format!("{} files of {} differ between builds", n, product)),
+    }
+}
+
+fn closure_check(matches: &ArgMatches, config: &Config) -> Result<(), String> {
+    let workspace = workspace(matches, config)?;
+    let names = products(matches, config)?;
+    let path = path_of(matches, "baseline")
+        .unwrap_or_else(|| closure::default_path(&workspace.install_root));
+    let mut baseline = ClosureBaseline::open(&path)?;
+    let mut db = workspace.open_db()?;
+    let options = regen_options(matches, config, &workspace)?;
+    let mut app = Regenerate::new(&mut db, options)?;
+    let mut grown = vec![];
+    for product in names.iter() {
+        let current = app.dependency_closure(product)?;
+        if matches.is_present("record") {
+            info!("Recording {} dependencies of {}", current.len(), product);
+            baseline.record(product, current);
+            continue;
+        }
+        let check = match baseline.get(product) {
+            Some(recorded) => ClosureCheck::compare(product, recorded, &current),
+            None => {
+                warn!(
+                    "There is no baseline for {} in {}, record one with --record",
+                    product,
+                    path.display()
+                );
+                continue;
+            }
+        };
+        print!("{}", check.render());
+        if check.grew() {
+            grown.push(product.clone());
+        }
+    }
+    if matches.is_present("record") {
+        return baseline.save();
+    }
+    match (grown.is_empty(), matches.is_present("warn-only")) {
+        (true, _) => Ok(()),
+        (false, true) => {
+            warn!("New dependencies appeared for {}", grown.join(", "));
+            Ok(())
+        }
+        (false, false) => Err(format!(
+            "New dependencies appeared for {}",
+            grown.join(", ")
+        )),
+    }
+}
+
+fn clean(matches: &ArgMatches, config: &Config) -> Result<(), String> {
+    let clone_root = clone_root(matches, &workspace(matches, config)?)?;
+    let dry_run = matches.is_present("dry-run");
+    let names = products(matches, config)?;
+    let entries = match std::fs::read_dir(&clone_root) {
+        Ok(e) => e,
+        Err(_) => {
+            info!("There are no clones in {}", clone_root.display());
+            return Ok(());
+        }
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let path = entry.path();
+        // only remove what regenerate cloned, and only what was asked for
+        if !path.join(".git").exists() || (!names.is_empty() && !names.contains(&name)) {
+            continue;
+        }
+        safety::ensure_under(&clone_root, &path)?;
+        println!("{}", path.display());
+        if !dry_run {
+            std::fs::remove_dir_all(&path)
+                .map_err(|e| format!("Could not remove {}: {}", path.display(), e))?;
+        }
+    }
+    Ok(())
+}
+
+fn refresh_clones(matches: &ArgMatches, config: &Config) -> Result<(), String> {
+    let workspace = workspace(matches, config)?;
+    let clone_root = clone_root(matches, &workspace)?;
+    let jobs = parse_opt(matches, "jobs")?.unwrap_or(4);
+    let host_keys = HostKeyPolicy::new(
+        &workspace.install_root,
+        matches.is_present("strict-host-keys"),
+    );
+    let results = refresh::refresh_clones(
+        &clone_root,
+        matches.value_of("remote").unwrap_or("origin"),
+        jobs,
+        parse_opt(matches, "max-rate")?,
+        Some(host_keys),
+    )?;
+    let mut failed = 0;
+    for (path, result) in results.iter() {
+        if let Err(e) = result {
+            warn!("Could not refresh {}: {}", path.display(), e);
+            failed += 1;
+        }
+    }
+    match failed {
+        0 => Ok(()),
+        n => Err(format!(
+            "{} of {} clones could not be refreshed",
+            n,
+            results.len()
+        )),
+    }
+}
+
+fn workspace_settings(matches: &ArgMatches, config: &Config) -> Result<(), String> {
+    match matches.subcommand() {
+        ("set", Some(m)) => {
+            let mut settings = WorkspaceSettings::open(&workspace(m, config)?.install_root)?;
+            let values: Vec<String> = m
+                .values_of("values")
+                .into_iter()
+                .flatten()
+                .map(|v| v.to_string())
+                .collect();
+            settings.set(m.value_of("key").unwrap_or_default(), &values)?;
+            settings.save()
+        }
+        ("unset", Some(m)) => {
+            let mut settings = WorkspaceSettings::open(&workspace(m, config)?.install_root)?;
+            let key = m.value_of("key").unwrap_or_default();
+            if !settings.unset(key)? {
+                warn!("{} was not set", key);
+            }
+            settings.save()
+        }
+        ("show", Some(m)) => {
+            let settings = WorkspaceSettings::open(&workspace(m, config)?.install_root)?;
+            print!("{}", settings.render());
+            Ok(())
+        }
+        _ => Err("config needs a subcommand".to_string()),
+    }
+}
+
+fn store_gc(matches: &ArgMatches, config: &Config) -> Result<(), String> {
+    let workspace = workspace(matches, config)?;
+    let store = Store::new(Path::new(matches.value_of("store").unwrap_or_default()));
+    // every workspace may link into the store, not only this one
+    let mut roots = vec![workspace.install_root.clone()];
+    roots.extend(
+        config
+            .workspaces
+            .keys()
+            .filter_map(|name| config.workspace(name))
+            .map(|w| w.install_root),
+    );
+    roots.extend(
+        matches
+            .values_of("also-root")
+            .into_iter()
+            .flatten()
+            .map(PathBuf::from),
+    );
+    for entry in store.collect_garbage(&roots, matches.is_present("dry-run"))? {
+        println!("{}", entry.display());
+    }
+    Ok(())
+}
+
+fn rollback(matches: &ArgMatches, config: &Config) -> Result<(), String> {
+    let workspace = workspace(matches, config)?;
+    let product = matches.value_of("product").unwrap_or_default();
+    let version = matches.value_of("build-version").unwrap_or_default();
+    let store = Store::new(Path::new(matches.value_of("store").unwrap_or_default()));
+    let builds = store.builds_of(product)?;
+    let view = workspace.product_dir(product, version);
+    let target = match matches.value_of("to") {
+        Some(id) => builds
+            .iter()
+            .find(|(_, p)| p.id.starts_with(id))
+            .ok_or(format!(
+                "The store has no build of {} with id {}",
+                product, id
+            ))?,
+        None => {
+            // the build before the one currently linked
+            let current = view
+                .canonicalize()
+                .map_err(|e| format!("{} is not linked: {}", view.display(), e))?;
+            let position = builds
+                .iter()
+                .position(|(entry, _)| entry.canonicalize().ok().as_ref() == Some(&current))
+                .ok_or(format!("{} does not point into the store", view.display()))?;
+            if position == 0 {
+                return Err(format!(
+                    "There is no build of {} before {}",
+                    product,
+                    current.display()
+                ));
+            }
+            &builds[position - 1]
+        }
+    };
+    store::link_view(&view, &target.0)?;
+    // declare the version again with the id of the build it now points to,
+    // so setup and later runs see which build it is
+    let table_path = view.join("ups").join(format!("{}.table", product));
+    let table = reups::table::Table::from_file(product.to_string(), table_path, view.clone())
+        .map_err(|e| format!("Could not read the table of {}: {}", product, e))?;
+    let mut db = workspace.open_db()?;
+    ProductDatabase::declare(
+        &mut db,
+        vec![reups::DeclareInputs {
+            product,
+            prod_dir: &view,
+            version,
+            tag: None,
+            ident: Some(target.1.id.as_str()),
+            flavor: Some(reups::SYSTEM_OS),
+            table: Some(table),
+            relative: false,
+        }],
+    )?;
+    info!(
+        "{} {} now points to build {}",
+        product, version, target.1.id
+    );
+    Ok(())
+}
+
+fn promote(matches: &ArgMatches, config: &Config) -> Result<(), String> {
+    let scratch = workspace(matches, config)?;
+    let to = matches.value_of("to").unwrap_or_default();
+    let production = config
+        .workspace(to)
+        .ok_or(format!("No workspace named {} is configured", to))?;
+    let options = PromoteOptions {
+        tag: matches.value_of("tag").unwrap_or_default().to_string(),
+        production_tag: matches.value_of("production-tag").map(|t| t.to_string()),
+        link: matches.is_present("link"),
+    };
+    for product in promote::promote(&scratch, &production, &options)? {
+        println!("{}", product);
+    }
+    Ok(())
+}
+
+fn restore_db(matches: &ArgMatches, config: &Config) -> Result<(), String> {
+    let workspace = workspace(matches, config)?;
+    for restored in restore::restore_db(&workspace, matches.is_present("dry-run"))? {
+        println!(
+            "{} {} {}",
+            restored.provenance.product,
+            restored.provenance.version,
+            restored.product_dir.display()
+        );
+    }
+    Ok(())
+}
+
+fn declare_only(matches: &ArgMatches, config: &Config) -> Result<(), String> {
+    let workspace = workspace(matches, config)?;
+    let install = restore::ExternalInstall {
+        product: matches.value_of("product").unwrap_or_default().to_string(),
+        product_dir: PathBuf::from(matches.value_of("prod-dir").unwrap_or_default()),
+        version: matches
+            .value_of("build-version")
+            .unwrap_or_default()
+            .to_string(),
+        id: matches.value_of("id").map(|i| i.to_string()),
+        tag: matches.value_of("tag").map(|t| t.to_string()),
+    };
+    for issue in restore::declare_external(&workspace, &install)?.iter() {
+        warn!("{}", issue);
+    }
+    println!(
+        "Declared {} {} from {}",
+        install.product,
+        install.version,
+        install.product_dir.display()
+    );
+    Ok(())
+}
+
+fn hold(matches: &ArgMatches, config: &Config) -> Result<(), String> {
+    let workspace = workspace(matches, config)?;
+    let mut holds = Holds::open(&workspace.install_root)?;
+    for spec in matches.values_of("specs").into_iter().flatten() {
+        // a group holds each of its products at the revision
+        let (names, pin) = match spec.find('@') {
+            Some(pos) => (
+                config.expand_products(&[spec[..pos].to_string()])?,
+                &spec[pos..],
+            ),
+            None => (vec![spec.to_string()], ""),
+        };
+        for name in names.iter() {
+            let (product, pin) = holds::parse_spec(&format!("{}{}", name, pin))?;
+            holds.hold(&product, &pin);
+        }
+    }
+    holds.save()
+}
+
+fn unhold(matches: &ArgMatches, config: &Config) -> Result<(), String> {
+    let workspace = workspace(matches, config)?;
+    let mut holds = Holds::open(&workspace.install_root)?;
+    for product in products(matches, config)? {
+        if holds.unhold(&product).is_none() {
+            warn!("{} was not held", product);
+        }
+    }
+    holds.save()
+}
+
+fn list_holds(matches: &ArgMatches, config: &Config) -> Result<(), String> {
+    let workspace = workspace(matches, config)?;
+    for (product, pin) in Holds::open(&workspace.install_root)?.all().iter() {
+        println!("{}@{}", product, pin);
+    }
+    Ok(())
+}
+
+fn validate_map(matches: &ArgMatches) -> Result<(), String> {
+    let path = Path::new(matches.value_of("map").unwrap_or_default());
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    let issues = repo_wrapper::lint_map(&text, &path.display().to_string());
+    for issue in issues.iter() {
+        println!("{}", issue);
+    }
+    match issues
+        .iter()
+        .filter(|i| i.severity == Severity::Error)
+        .count()
+    {
+        0 => Ok(()),
+        n => Err(format!("{} has {} errors", path.display(), n)),
+    }
+}
+
+fn tail(matches: &ArgMatches, config: &Config) -> Result<(), String> {
+    let workspace = workspace(matches, config)?;
+    let product = matches.value_of("product").unwrap_or_default();
+    safety::validate_product_name(product)?;
+    let settings = WorkspaceSettings::open(&workspace.install_root)?;
+    let version = setting(matches, "build-version", &settings, "version").unwrap_or_default();
+    let path = workspace.build_log(product, &version);
+    let live = log_tail::is_live(&path);
+    if !live && !path.exists() {
+        return Err(format!(
+            "{} is not being built and has no build log",
+            product
+        ));
+    }
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    log_tail::tail(
+        &path,
+        parse_opt(matches, "lines")?,
+        live && !matches.is_present("no-follow"),
+        &mut out,
+    )
+}
+
+fn profile(matches: &ArgMatches, config: &Config) -> Result<(), String> {
+    let workspace = workspace(matches, config)?;
+    let run_id = matches.value_of("run-id").unwrap_or_default();
+    print!(
+        "{}",
+        Profile::load(&workspace.install_root, run_id)?.render()
+    );
+    Ok(())
+}
+
+fn ide_setup(matches: &ArgMatches, config: &Config) -> Result<(), String> {
+    let workspace = workspace(matches, config)?;
+    let product = matches.value_of("product").unwrap_or_default();
+    safety::validate_product_name(product)?;
+    let checkout = clone_root(matches, &workspace)?.join(product);
+    let link = compile_db::ide_setup(product, &workspace.install_root, &checkout)?;
+    println!("{}", link.display());
+    Ok(())
+}
+
+fn env_diff(matches: &ArgMatches, config: &Config) -> Result<(), String> {
+    let workspace = workspace(matches, config)?;
+    let first = matches.value_of("first").unwrap_or_default();
+    let second = matches.value_of("second").unwrap_or_default();
+    let mut db = workspace.open_db()?;
+    let options = regen_options(matches, config, &workspace)?;
+    let mut app = Regenerate::new(&mut db, options)?;
+    let first_env = app.build_environment(first)?;
+    let second_env = app.build_environment(second)?;
+    let diff = env_diff::diff((first, &first_env), (second, &second_env));
+    match matches.is_present("json") {
+        true => println!("{}", diff.to_json()?),
+        false => print!("{}", diff.render()),
+    }
+    Ok(())
+}
+
+fn bisect(matches: &ArgMatches, config: &Config) -> Result<(), String> {
+    let workspace = workspace(matches, config)?;
+    let product = matches.value_of("product").unwrap_or_default();
+    let test = matches.value_of("test").unwrap_or_default();
+    let good = GraphSnapshot::load(Path::new(matches.value_of("good").unwrap_or_default()))?;
+    let bad = GraphSnapshot::load(Path::new(matches.value_of("bad").unwrap_or_default()))?;
+    let order = match matches.value_of("order") {
+        Some("date") => BisectOrder::ByDate,
+        _ => BisectOrder::PerProduct,
+    };
+    let clone_root = clone_root(matches, &workspace)?;
+    let changes = bisect::changes(&clone_root, &good, &bad, order)?;
+    info!("{} commits separate the good and bad states", changes.len());
+    let mut db = workspace.open_db()?;
+    let result = bisect::bisect(&good, &changes, |n, state: &BTreeMap<String, String>| {
+        let mut options = regen_options(matches, config, &workspace)?;
+        // each state is declared under its own version so they never mix
+        options.version = format!("{}-bisect-{}", options.version, n);
+        options.tag = None;
+        options.assume_yes = true;
+        let version = options.version.clone();
+        let install_root = options.install_root.clone();
+        let mut app = Regenerate::new(&mut db, options)?;
+        for (name, sha) in state.iter() {
+            app.pin(name, sha);
+        }
+        if let Err(e) = app.install_product(product) {
+            warn!("Skipping a state which could not be installed: {}", e);
+            return Ok(Verdict::Skip);
+        }
+        let env = vec![
+            ("REGENERATE_PRODUCT".to_string(), product.to_string()),
+            ("REGENERATE_VERSION".to_string(), version),
+            ("REGENERATE_INSTALL_ROOT".to_string(), install_root),
+            (
+                "REGENERATE_DB".to_string(),
+                workspace.db_path.to_string_lossy().to_string(),
+            ),
+        ];
+        bisect::run_check(test, &env)
+    })?;
+    print!("{}", result.render());
+    Ok(())
+}
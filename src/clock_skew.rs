@@ -0,0 +1,75 @@
+use filetime::FileTime;
+use log::{debug, warn};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Measure how far the clock of the filesystem holding dir is from the local
+/// clock, by writing a probe file and looking at its modification time.
+/// Positive values mean the filesystem is ahead of the local clock.
+pub fn measure_skew(dir: &Path) -> Result<i64, String> {
+    let mut probe = PathBuf::from(dir);
+    probe.push(format!(".regenerate-clock-probe-{}", std::process::id()));
+    let before = SystemTime::now();
+    std::fs::write(&probe, b"")
+        .map_err(|e| format!("Could not write probe file in {}: {}", dir.display(), e))?;
+    let modified = std::fs::metadata(&probe)
+        .and_then(|m| m.modified())
+        .map_err(|e| format!("Could not stat probe file in {}: {}", dir.display(), e));
+    let _ = std::fs::remove_file(&probe);
+    let modified = modified?;
+    let skew = match modified.duration_since(before) {
+        Ok(ahead) => ahead.as_secs() as i64,
+        Err(behind) => -(behind.duration().as_secs() as i64),
+    };
+    debug!("Filesystem at {} is skewed by {}s", dir.display(), skew);
+    Ok(skew)
+}
+
+/// Check each root for clock skew beyond threshold, warning about any which
+/// exceed it. Returns the description of each problem found.
+pub fn check_roots(roots: &[&Path], threshold: Duration) -> Vec<String> {
+    let mut problems = vec![];
+    for root in roots.iter() {
+        if !root.is_dir() {
+            continue;
+        }
+        match measure_skew(root) {
+            Ok(skew) if skew.abs() as u64 > threshold.as_secs() => {
+                let problem = format!(
+                    "The clock of the filesystem holding {} is {}s {} the local clock, \
+                     build tools relying on timestamps may skip steps or loop",
+                    root.display(),
+                    skew.abs(),
+                    if skew > 0 { "ahead of" } else { "behind" }
+                );
+                warn!("{}", problem);
+                problems.push(problem);
+            }
+            Ok(_) => (),
+            Err(e) => warn!("Could not check clock skew: {}", e),
+        }
+    }
+    problems
+}
+
+/// Set the modification time of every file in a working tree to now, so that
+/// timestamps written by a skewed filesystem do not confuse build tools. The
+/// git directory itself is left alone.
+pub fn normalize_mtimes(dir: &Path) -> Result<(), String> {
+    let now = FileTime::from_system_time(SystemTime::now());
+    let entries =
+        std::fs::read_dir(dir).map_err(|e| format!("Could not read {}: {}", dir.display(), e))?;
+    for entry in entries.filter_map(|e| e.ok()) {
+        if entry.file_name() == ".git" {
+            continue;
+        }
+        let file_type = entry.file_type().map_err(|e| format!("{}", e))?;
+        if file_type.is_dir() {
+            normalize_mtimes(&entry.path())?;
+        } else if file_type.is_file() {
+            filetime::set_file_mtime(entry.path(), now)
+                .map_err(|e| format!("Could not set mtime of {}: {}", entry.path().display(), e))?;
+        }
+    }
+    Ok(())
+}
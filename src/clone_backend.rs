@@ -0,0 +1,584 @@
+use crate::credentials::{self, GitAuth};
+use crate::host_keys::HostKeyPolicy;
+use crate::network::{self, Throttle};
+use git2::Repository;
+use log::debug;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::io::Read;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Selects which implementation is used to clone repositories
+#[derive(Clone, Debug)]
+pub enum BackendKind {
+    /// Clone in process using libgit2, this is the default
+    Git2,
+    /// Clone by running the system git executable, optionally with a partial
+    /// clone filter spec such as blob:none
+    SystemGit { filter: Option<String> },
+}
+
+impl Default for BackendKind {
+    fn default() -> BackendKind {
+        BackendKind::Git2
+    }
+}
+
+/// Limits applied to a single clone operation, any clone exceeding one of
+/// these is aborted and reported as a failure
+#[derive(Clone, Debug, Default)]
+pub struct CloneLimits {
+    /// Maximum wall time the clone may take
+    pub timeout: Option<Duration>,
+    /// Minimum average transfer rate in bytes per second, enforced once the
+    /// clone has been running for longer than RATE_GRACE_PERIOD
+    pub min_rate: Option<u64>,
+    /// Maximum number of bytes which may be transferred
+    pub max_size: Option<u64>,
+    /// Cap on the average transfer rate in bytes per second, transfers going
+    /// faster than this are held back
+    pub max_rate: Option<u64>,
+    /// Verification applied to the host keys of ssh remotes
+    pub host_keys: Option<HostKeyPolicy>,
+    /// Credentials to authenticate with, rather than those in the keyring
+    pub auth: Option<GitAuth>,
+}
+
+/// Time given to a clone to get up to speed before the rate floor applies
+const RATE_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+impl CloneLimits {
+    /// Check the progress of a clone against the limits, returning a
+    /// description of the limit which was exceeded, if any
+    fn check(&self, elapsed: Duration, bytes: u64) -> Option<String> {
+        if let Some(timeout) = self.timeout {
+            if elapsed > timeout {
+                return Some(format!("exceeded timeout of {}s", timeout.as_secs()));
+            }
+        }
+        if let Some(max_size) = self.max_size {
+            if bytes > max_size {
+                return Some(format!("exceeded maximum size of {} bytes", max_size));
+            }
+        }
+        if let Some(min_rate) = self.min_rate {
+            if elapsed > RATE_GRACE_PERIOD {
+                let rate = bytes / elapsed.as_secs().max(1);
+                if rate < min_rate {
+                    return Some(format!(
+                        "transfer rate {} bytes/s fell below the floor of {} bytes/s",
+                        rate, min_rate
+                    ));
+                }
+            }
+        }
+        None
+    }
+}
+
+pub trait CloneBackend {
+    fn clone_repo(
+        &self,
+        url: &str,
+        dest: &Path,
+        limits: &CloneLimits,
+    ) -> Result<Repository, String>;
+}
+
+pub struct Git2Backend;
+
+impl CloneBackend for Git2Backend {
+    fn clone_repo(
+        &self,
+        url: &str,
+        dest: &Path,
+        limits: &CloneLimits,
+    ) -> Result<Repository, String> {
+        let start = Instant::now();
+        let exceeded = RefCell::new(None);
+        let throttle = Throttle::new(limits.max_rate);
+        let counted = Cell::new(0);
+        let key_failure = RefCell::new(None);
+        let mut callbacks = git2::RemoteCallbacks::new();
+        callbacks.credentials(credentials::git_credentials_with(limits.auth.as_ref(), url));
+        if let Some(policy) = limits.host_keys.as_ref() {
+            policy.attach(&mut callbacks, url, &key_failure);
+        }
+        callbacks.transfer_progress(|stats| {
+            let received = stats.received_bytes() as u64;
+            network::record_git(received - counted.get());
+            counted.set(received);
+            throttle.pace(received);
+            match limits.check(start.elapsed(), received) {
+                Some(reason) => {
+                    *exceeded.borrow_mut() = Some(reason);
+                    false
+                }
+                None => true,
+            }
+        });
+        let mut fetch_options = git2::FetchOptions::new();
+        fetch_options.remote_callbacks(callbacks);
+        let result = git2::build::RepoBuilder::new()
+            .fetch_options(fetch_options)
+            .clone(url, dest);
+        if let Some(reason) = exceeded.borrow().as_ref() {
+            return Err(format!("Aborted clone of {}: {}", url, reason));
+        }
+        if let Some(reason) = key_failure.borrow().as_ref() {
+            return Err(format!("Refused to clone {}: {}", url, reason));
+        }
+        result.map_err(|e| format!("Failed to clone {}: {}", url, e))
+    }
+}
+
+pub struct SystemGitBackend {
+    pub filter: Option<String>,
+    /// Only fetch this many commits of history for each branch
+    pub depth: Option<u32>,
+}
+
+/// Total size in bytes of all the files under a directory
+pub fn dir_size(path: &Path) -> u64 {
+    let entries = match std::fs::read_dir(path) {
+        Ok(x) => x,
+        Err(_) => return 0,
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| match entry.file_type() {
+            Ok(t) if t.is_dir() => dir_size(&entry.path()),
+            Ok(_) => entry.metadata().map(|m| m.len()).unwrap_or(0),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+/// Bytes of git objects in a git directory, which grows by what a clone or
+/// fetch downloads, unlike the checked out files beside it
+pub fn object_size(git_dir: &Path) -> u64 {
+    dir_size(&git_dir.join("objects"))
+}
+
+impl CloneBackend for SystemGitBackend {
+    fn clone_repo(
+        &self,
+        url: &str,
+        dest: &Path,
+        limits: &CloneLimits,
+    ) -> Result<Repository, String> {
+        let mut command = std::process::Command::new("git");
+        command.arg("clone").arg("--quiet");
+        if let Some(filter) = self.filter.as_ref() {
+            command.arg(format!("--filter={}", filter));
+        }
+        if let Some(depth) = self.depth {
+            // every branch is wanted, not only the default one
+            command
+                .arg(format!("--depth={}", depth))
+                .arg("--no-single-branch");
+        }
+        // LFS content is fetched after checkout, where a quota or login
+        // failure can be explained rather than failing the clone
+        command
+            .arg("--")
+            .arg(url)
+            .arg(dest)
+            .env("GIT_LFS_SKIP_SMUDGE", "1")
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::piped());
+        credentials::configure_command(
+            &mut command,
+            url,
+            limits.auth.as_ref(),
+            limits.host_keys.as_ref(),
+        )?;
+        // the transfer is done by helpers git starts, git-remote-https and
+        // index-pack, so the clone gets a process group they share which can
+        // be stopped and killed as a whole
+        unsafe {
+            command.pre_exec(|| {
+                libc::setpgid(0, 0);
+                Ok(())
+            });
+        }
+        debug!("Running {:?}", command);
+        let start = Instant::now();
+        let mut child = command
+            .spawn()
+            .map_err(|e| format!("Could not run system git to clone {}: {}", url, e))?;
+        let group = child.id() as libc::pid_t;
+        let signal_group = |signal| unsafe {
+            libc::killpg(group, signal);
+        };
+        // poll the clone so the limits can be enforced, using the size of the
+        // objects received so far, packs being written as they arrive, as a
+        // measure of how much has been transferred
+        let measure_size =
+            limits.max_size.is_some() || limits.min_rate.is_some() || limits.max_rate.is_some();
+        let throttle = Throttle::new(limits.max_rate);
+        let mut stopped = false;
+        let status = loop {
+            if let Some(status) = child.try_wait().map_err(|e| format!("{}", e))? {
+                break status;
+            }
+            let size = match measure_size {
+                true => object_size(&dest.join(".git")),
+                false => 0,
+            };
+            // git has no rate limit of its own, so hold it back by stopping
+            // the processes of the clone until the average rate is under the
+            // cap again
+            let over_cap = throttle.delay(size).is_some();
+            if over_cap != stopped {
+                signal_group(match over_cap {
+                    true => libc::SIGSTOP,
+                    false => libc::SIGCONT,
+                });
+                stopped = over_cap;
+            }
+            if let Some(reason) = limits.check(start.elapsed(), size) {
+                signal_group(libc::SIGKILL);
+                let _ = child.wait();
+                return Err(format!("Aborted clone of {}: {}", url, reason));
+            }
+            std::thread::sleep(Duration::from_millis(500));
+        };
+        if !status.success() {
+            let mut stderr = String::new();
+            if let Some(mut pipe) = child.stderr.take() {
+                let _ = pipe.read_to_string(&mut stderr);
+            }
+            return Err(format!("Failed to clone {}: {}", url, stderr));
+        }
+        network::record_git(object_size(&dest.join(".git")));
+        Repository::open(dest).map_err(|e| format!("Could not open clone of {}: {}", url, e))
+    }
+}
+
+/// Determine if a repository was created as a partial clone, in which case
+/// missing objects must be fetched on demand by the system git
+pub fn is_partial_clone(repo: &Repository) -> bool {
+    match repo.config() {
+        Ok(config) => config.get_string("extensions.partialclone").is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// The url of a remote of a repository, empty if it has none
+pub fn remote_url(repo: &Repository, remote_name: &str) -> String {
+    repo.find_remote(remote_name)
+        .ok()
+        .and_then(|r| r.url().map(|u| u.to_string()))
+        .unwrap_or_default()
+}
+
+/// Determine if a repository was cloned with only part of its history
+pub fn is_shallow(repo: &Repository) -> bool {
+    repo.path().join("shallow").exists()
+}
+
+/// Fetch the rest of the history of a shallow clone, along with all tags,
+/// turning it into a full clone
+pub fn unshallow(repo: &Repository, remote_name: &str, limits: &CloneLimits) -> Result<(), String> {
+    let workdir = repo.workdir().unwrap_or_else(|| repo.path());
+    let mut command = std::process::Command::new("git");
+    command
+        .args(&["fetch", "--quiet", "--unshallow", "--tags", remote_name])
+        .current_dir(workdir);
+    credentials::configure_command(
+        &mut command,
+        &remote_url(repo, remote_name),
+        limits.auth.as_ref(),
+        limits.host_keys.as_ref(),
+    )?;
+    let size_before = object_size(repo.path());
+    let output = command
+        .output()
+        .map_err(|e| format!("Could not run system git to unshallow: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "Failed to fetch the full history in {}: {}",
+            workdir.display(),
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    network::record_git(object_size(repo.path()).saturating_sub(size_before));
+    Ok(())
+}
+
+/// Determine if a repository only materializes part of its working tree
+pub fn is_sparse_checkout(repo: &Repository) -> bool {
+    match repo.config() {
+        Ok(config) => config.get_bool("core.sparseCheckout").unwrap_or(false),
+        Err(_) => false,
+    }
+}
+
+/// Run a sparse-checkout subcommand of the system git, as libgit2 does not
+/// understand sparse checkouts
+fn sparse_checkout_command(repo: &Repository, args: &[&str]) -> Result<(), String> {
+    let workdir = repo.workdir().ok_or("Clone has no working directory")?;
+    let output = std::process::Command::new("git")
+        .arg("sparse-checkout")
+        .args(args)
+        .current_dir(workdir)
+        .output()
+        .map_err(|e| format!("Could not run system git sparse-checkout: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "git sparse-checkout failed in {}: {}",
+            workdir.display(),
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    Ok(())
+}
+
+/// Return the working tree of a clone to its checked out commit in place,
+/// discarding modified files along with any untracked or ignored ones a
+/// previous build left behind
+pub fn clean_tree(workdir: &Path) -> Result<(), String> {
+    for args in [&["reset", "--hard", "--quiet"][..], &["clean", "-xfdq"][..]].iter() {
+        let output = std::process::Command::new("git")
+            .args(*args)
+            .current_dir(workdir)
+            .output()
+            .map_err(|e| format!("Could not run system git {}: {}", args[0], e))?;
+        if !output.status.success() {
+            return Err(format!(
+                "git {} failed in {}: {}",
+                args[0],
+                workdir.display(),
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+    }
+    debug!("Cleaned {}", workdir.display());
+    Ok(())
+}
+
+/// Restrict the working tree of a clone to the given directories, using cone
+/// mode. The ups directory is always included, as the table is needed to
+/// resolve and build the product.
+pub fn set_sparse_checkout(repo: &Repository, directories: &[String]) -> Result<(), String> {
+    for dir in directories.iter() {
+        let path = Path::new(dir);
+        if dir.is_empty()
+            || dir.starts_with('-')
+            || path.is_absolute()
+            || path
+                .components()
+                .any(|c| c == std::path::Component::ParentDir)
+        {
+            return Err(format!("{} is not a valid sparse checkout directory", dir));
+        }
+    }
+    let mut args = vec!["set", "--cone", "--", "ups"];
+    args.extend(directories.iter().map(|d| d.as_str()));
+    debug!("Setting sparse checkout of {:?}", directories);
+    sparse_checkout_command(repo, &args)
+}
+
+/// Materialize the whole working tree of a clone again
+pub fn disable_sparse_checkout(repo: &Repository) -> Result<(), String> {
+    sparse_checkout_command(repo, &["disable"])
+}
+
+/// Update the index and working tree of a partial clone to match the given
+/// object. This goes through the system git so that any blobs not yet present
+/// locally are fetched from the promisor remote, and so that sparse checkout
+/// patterns are honored.
+pub fn checkout_partial(
+    repo: &Repository,
+    oid: &str,
+    url: &str,
+    limits: &CloneLimits,
+) -> Result<(), String> {
+    let workdir = repo
+        .workdir()
+        .ok_or("Partial clone has no working directory")?;
+    debug!(
+        "Checking out {} in partial clone {}",
+        oid,
+        workdir.display()
+    );
+    // objects missing from a partial clone are fetched from its promisor
+    // remote during the checkout
+    let mut command = std::process::Command::new("git");
+    command
+        .args(&["read-tree", "--reset", "-u", oid])
+        .current_dir(workdir)
+        .env("GIT_LFS_SKIP_SMUDGE", "1");
+    credentials::configure_command(
+        &mut command,
+        url,
+        limits.auth.as_ref(),
+        limits.host_keys.as_ref(),
+    )?;
+    let output = command
+        .output()
+        .map_err(|e| format!("Could not run system git to checkout {}: {}", oid, e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "Failed to checkout {}: {}",
+            oid,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    Ok(())
+}
+
+/// The contents of the file at path in a commit, None when the commit has
+/// no such file, read without touching the working tree
+pub fn read_blob(
+    repo: &Repository,
+    commit: git2::Oid,
+    path: &Path,
+) -> Result<Option<Vec<u8>>, String> {
+    let tree = repo
+        .find_commit(commit)
+        .and_then(|c| c.tree())
+        .map_err(|e| format!("Could not read commit {}: {}", commit, e))?;
+    let entry = match tree.get_path(path) {
+        Ok(entry) => entry,
+        Err(_) => return Ok(None),
+    };
+    // a partial clone only has the blobs it has checked out before
+    let blob = repo.find_blob(entry.id()).map_err(|e| {
+        format!(
+            "Could not read {} at {}, it may not have been fetched: {}",
+            path.display(),
+            commit,
+            e
+        )
+    })?;
+    Ok(Some(blob.content().to_vec()))
+}
+
+/// A url with the differences which do not change the repository it names
+/// removed, so two spellings of the same remote compare equal. Only the
+/// scheme and host are case insensitive, paths on most servers are not.
+pub fn normalize_url(url: &str) -> String {
+    let url = url.trim().trim_end_matches('/');
+    let url = url.trim_end_matches(".git");
+    let (scheme, rest) = match url.find("://") {
+        Some(pos) => (url[..pos + 3].to_lowercase(), &url[pos + 3..]),
+        None => (String::new(), url),
+    };
+    // the host runs to the path, which scp style urls (host:path) start
+    // with a colon, and a local path has none
+    let end = match scheme.is_empty() {
+        false => rest.find('/').unwrap_or_else(|| rest.len()),
+        true => match (rest.find(':'), rest.find('/')) {
+            (Some(colon), Some(slash)) if colon < slash => colon,
+            (Some(colon), None) => colon,
+            _ => 0,
+        },
+    };
+    let (authority, path) = rest.split_at(end);
+    // a user name before the host keeps its case
+    let (user, host) = match authority.rfind('@') {
+        Some(at) => authority.split_at(at),
+        None => ("", authority),
+    };
+    format!("{}{}{}{}", scheme, user, host.to_lowercase(), path)
+}
+
+/// Check out the repository of an existing clone as a new working tree at
+/// dest, detached so it never holds a branch the clone needs
+pub fn add_worktree(source: &Path, dest: &Path) -> Result<Repository, String> {
+    let output = std::process::Command::new("git")
+        .args(&["worktree", "add", "--detach", "--quiet"])
+        .arg(dest)
+        .current_dir(source)
+        .env("GIT_LFS_SKIP_SMUDGE", "1")
+        .output()
+        .map_err(|e| format!("Could not run system git worktree: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "Could not add a worktree of {} at {}: {}",
+            source.display(),
+            dest.display(),
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    debug!(
+        "Added a worktree of {} at {}",
+        source.display(),
+        dest.display()
+    );
+    Repository::open(dest).map_err(|e| format!("Could not open {}: {}", dest.display(), e))
+}
+
+/// Extract the host name from a git url, handling both url style and scp
+/// style (user@host:path) remotes
+pub fn url_host(url: &str) -> Option<&str> {
+    let rest = match url.find("://") {
+        Some(pos) => &url[pos + 3..],
+        None => url,
+    };
+    let rest = match rest.find('@') {
+        Some(pos) => &rest[pos + 1..],
+        None => rest,
+    };
+    let end = rest
+        .find(|c: char| c == '/' || c == ':')
+        .unwrap_or(rest.len());
+    match end {
+        0 => None,
+        _ => Some(&rest[..end]),
+    }
+}
+
+/// Choose the backend to use for a url, preferring a per host setting over the
+/// global default. A product requesting a partial or shallow clone always uses
+/// the system git, as libgit2 can not create them.
+pub fn backend_for_url(
+    url: &str,
+    default: &BackendKind,
+    per_host: &HashMap<String, BackendKind>,
+    partial_filter: Option<String>,
+    depth: Option<u32>,
+) -> Box<dyn CloneBackend> {
+    if partial_filter.is_some() || depth.is_some() {
+        return Box::new(SystemGitBackend {
+            filter: partial_filter,
+            depth,
+        });
+    }
+    let kind = url_host(url)
+        .and_then(|host| per_host.get(host))
+        .unwrap_or(default);
+    match kind {
+        BackendKind::Git2 => Box::new(Git2Backend),
+        BackendKind::SystemGit { filter } => Box::new(SystemGitBackend {
+            filter: filter.clone(),
+            depth: None,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_scheme_and_host_of_urls_are_case_insensitive() {
+        assert_eq!(
+            normalize_url("HTTPS://GitHub.com/LSST/Base.git/"),
+            "https://github.com/LSST/Base"
+        );
+        assert_eq!(
+            normalize_url("Git@GitHub.com:LSST/Base.git"),
+            "Git@github.com:LSST/Base"
+        );
+        assert_eq!(normalize_url("/Repos/Base.git"), "/Repos/Base");
+        assert_ne!(
+            normalize_url("https://example.com/Base"),
+            normalize_url("https://example.com/base")
+        );
+    }
+}
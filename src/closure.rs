@@ -0,0 +1,123 @@
+use log::debug;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use yaml_rust::yaml::{Hash, Yaml};
+
+/// The transitive dependencies each product was last accepted with, so a
+/// table change pulling in far more than intended stands out in review
+pub struct ClosureBaseline {
+    path: PathBuf,
+    closures: BTreeMap<String, BTreeSet<String>>,
+}
+
+/// Where a workspace keeps its baseline when no other file is given
+pub fn default_path(install_root: &Path) -> PathBuf {
+    let mut path = PathBuf::from(install_root);
+    path.push(".regenerate");
+    path.push("closures.yaml");
+    path
+}
+
+impl ClosureBaseline {
+    /// Open the baseline at path, which is empty if nothing was recorded
+    pub fn open(path: &Path) -> Result<ClosureBaseline, String> {
+        let mut closures = BTreeMap::new();
+        if path.exists() {
+            debug!("Loading dependency baseline from {}", path.display());
+            let text = std::fs::read_to_string(path)
+                .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+            let docs = yaml_rust::YamlLoader::load_from_str(&text)
+                .map_err(|e| format!("Could not parse {}: {}", path.display(), e))?;
+            if let Some(Yaml::Hash(hash)) = docs.get(0) {
+                for (name, deps) in hash.iter() {
+                    if let (Some(name), Some(deps)) = (name.as_str(), deps.as_vec()) {
+                        let deps = deps
+                            .iter()
+                            .filter_map(|d| d.as_str())
+                            .map(|d| d.to_string())
+                            .collect();
+                        closures.insert(name.to_string(), deps);
+                    }
+                }
+            }
+        }
+        Ok(ClosureBaseline {
+            path: path.to_path_buf(),
+            closures,
+        })
+    }
+
+    pub fn get(&self, product: &str) -> Option<&BTreeSet<String>> {
+        self.closures.get(product)
+    }
+
+    /// Accept closure as the dependencies of product from now on
+    pub fn record(&mut self, product: &str, closure: BTreeSet<String>) {
+        self.closures.insert(product.to_string(), closure);
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| format!("{}", e))?;
+        }
+        let mut hash = Hash::new();
+        for (name, deps) in self.closures.iter() {
+            hash.insert(
+                Yaml::String(name.clone()),
+                Yaml::Array(deps.iter().map(|d| Yaml::String(d.clone())).collect()),
+            );
+        }
+        let mut out = String::new();
+        yaml_rust::YamlEmitter::new(&mut out)
+            .dump(&Yaml::Hash(hash))
+            .map_err(|e| format!("Could not serialize dependency baseline: {:?}", e))?;
+        std::fs::write(&self.path, out)
+            .map_err(|e| format!("Could not write {}: {}", self.path.display(), e))
+    }
+}
+
+/// How the dependency closure of a product differs from its baseline
+pub struct ClosureCheck {
+    pub product: String,
+    pub baseline: usize,
+    pub current: usize,
+    /// Dependencies which were not in the baseline
+    pub added: BTreeSet<String>,
+    /// Dependencies of the baseline which are no longer needed
+    pub removed: BTreeSet<String>,
+}
+
+impl ClosureCheck {
+    pub fn compare(
+        product: &str,
+        baseline: &BTreeSet<String>,
+        current: &BTreeSet<String>,
+    ) -> ClosureCheck {
+        ClosureCheck {
+            product: product.to_string(),
+            baseline: baseline.len(),
+            current: current.len(),
+            added: current.difference(baseline).cloned().collect(),
+            removed: baseline.difference(current).cloned().collect(),
+        }
+    }
+
+    /// True when dependencies appeared which the baseline does not have
+    pub fn grew(&self) -> bool {
+        !self.added.is_empty()
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = format!(
+            "{}: {} dependencies, {} in the baseline\n",
+            self.product, self.current, self.baseline
+        );
+        for name in self.added.iter() {
+            out.push_str(&format!("  + {}\n", name));
+        }
+        for name in self.removed.iter() {
+            out.push_str(&format!("  - {}\n", name));
+        }
+        out
+    }
+}
@@ -0,0 +1,85 @@
+use log::{debug, info};
+use std::path::{Path, PathBuf};
+
+const COMPILE_COMMANDS: &str = "compile_commands.json";
+
+/// Directory under the install root where the compilation database of a
+/// product is collected
+pub fn collection_dir(install_root: &Path, product: &str) -> PathBuf {
+    let mut dir = PathBuf::from(install_root);
+    dir.push(".regenerate");
+    dir.push("compile_commands");
+    dir.push(product);
+    dir
+}
+
+/// Look for a compilation database written by the build, either at the top
+/// of the build directory as scons does, or in a build subdirectory as is
+/// usual for cmake
+fn find_compile_commands(build_dir: &Path) -> Option<PathBuf> {
+    let mut candidates = vec![build_dir.join(COMPILE_COMMANDS)];
+    if let Ok(entries) = std::fs::read_dir(build_dir) {
+        for entry in entries.filter_map(|e| e.ok()) {
+            let name = entry.file_name();
+            let is_build_dir = name
+                .to_str()
+                .map_or(false, |n| n.starts_with("build") || n.starts_with("_build"));
+            if is_build_dir && entry.path().is_dir() {
+                candidates.push(entry.path().join(COMPILE_COMMANDS));
+            }
+        }
+    }
+    candidates.into_iter().find(|p| p.is_file())
+}
+
+/// Copy the compilation database produced by building a product, if there is
+/// one, into the collection directory. Returns where it was collected to.
+pub fn collect(
+    product: &str,
+    build_dir: &Path,
+    install_root: &Path,
+) -> Result<Option<PathBuf>, String> {
+    let found = match find_compile_commands(build_dir) {
+        Some(f) => f,
+        None => {
+            debug!("{} produced no {}", product, COMPILE_COMMANDS);
+            return Ok(None);
+        }
+    };
+    let dir = collection_dir(install_root, product);
+    std::fs::create_dir_all(&dir)
+        .map_err(|e| format!("Could not create {}: {}", dir.display(), e))?;
+    let dest = dir.join(COMPILE_COMMANDS);
+    std::fs::copy(&found, &dest)
+        .map_err(|e| format!("Could not collect {}: {}", found.display(), e))?;
+    info!("Collected {} for {}", COMPILE_COMMANDS, product);
+    Ok(Some(dest))
+}
+
+/// Link the collected compilation database of a product into a checkout, so
+/// tools like clangd pick it up. An existing link is replaced, but a regular
+/// file is left alone.
+pub fn ide_setup(product: &str, install_root: &Path, checkout: &Path) -> Result<PathBuf, String> {
+    let source = collection_dir(install_root, product).join(COMPILE_COMMANDS);
+    if !source.is_file() {
+        return Err(format!(
+            "No {} has been collected for {}, was it built with collection enabled?",
+            COMPILE_COMMANDS, product
+        ));
+    }
+    let link = checkout.join(COMPILE_COMMANDS);
+    match std::fs::symlink_metadata(&link) {
+        Ok(meta) if meta.file_type().is_symlink() => std::fs::remove_file(&link)
+            .map_err(|e| format!("Could not replace {}: {}", link.display(), e))?,
+        Ok(_) => {
+            return Err(format!(
+                "{} already exists and is not a link, not replacing it",
+                link.display()
+            ))
+        }
+        Err(_) => (),
+    }
+    std::os::unix::fs::symlink(&source, &link)
+        .map_err(|e| format!("Could not link {}: {}", link.display(), e))?;
+    Ok(link)
+}
@@ -0,0 +1,116 @@
+use crate::config::Config;
+use std::path::Path;
+
+#[derive(Clone, Debug)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl Shell {
+    pub fn from_name(name: &str) -> Result<Shell, String> {
+        match name {
+            "bash" => Ok(Shell::Bash),
+            "zsh" => Ok(Shell::Zsh),
+            "fish" => Ok(Shell::Fish),
+            _ => Err(format!("Unsupported shell {}", name)),
+        }
+    }
+}
+
+/// Names to offer when completing dynamic arguments. These are produced by the
+/// hidden `__complete <kind>` subcommand, which the generated scripts call.
+pub fn dynamic_candidates(kind: &str, config: &Config, local_yaml: Option<&Path>) -> Vec<String> {
+    match kind {
+        "products" => {
+            let mut names = vec![];
+            if let Some(path) = local_yaml {
+                if let Ok(text) = std::fs::read_to_string(path) {
+                    if let Ok(docs) = yaml_rust::YamlLoader::load_from_str(&text) {
+                        if let Some(hash) = docs.get(0).and_then(|d| d.as_hash()) {
+                            names.extend(
+                                hash.keys()
+                                    .filter_map(|k| k.as_str().map(|s| s.to_string())),
+                            );
+                        }
+                    }
+                }
+            }
+            names.sort();
+            names
+        }
+        "workspaces" => config.workspaces.keys().cloned().collect(),
+        "aliases" => config.aliases.keys().cloned().collect(),
+        _ => vec![],
+    }
+}
+
+/// Generate a completion script for a shell. Subcommands are completed
+/// statically along with any aliases, and arguments of the subcommands listed
+/// in product_commands complete to product names from the repository maps.
+pub fn generate(shell: &Shell, subcommands: &[&str], product_commands: &[&str]) -> String {
+    let words = subcommands.join(" ");
+    let product_words = product_commands.join(" ");
+    match shell {
+        Shell::Bash => format!(
+            r#"_regenerate() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}"
+    if [ "$COMP_CWORD" -eq 1 ]; then
+        local aliases="$(regenerate __complete aliases 2>/dev/null)"
+        COMPREPLY=($(compgen -W "{words} $aliases" -- "$cur"))
+        return
+    fi
+    case "${{COMP_WORDS[COMP_CWORD-1]}}" in
+        --to|--workspace)
+            COMPREPLY=($(compgen -W "$(regenerate __complete workspaces 2>/dev/null)" -- "$cur"))
+            return;;
+    esac
+    case " {product_words} " in
+        *" ${{COMP_WORDS[1]}} "*)
+            COMPREPLY=($(compgen -W "$(regenerate __complete products 2>/dev/null)" -- "$cur"));;
+    esac
+}}
+complete -F _regenerate regenerate
+"#,
+            words = words,
+            product_words = product_words
+        ),
+        Shell::Zsh => format!(
+            r#"#compdef regenerate
+_regenerate() {{
+    if (( CURRENT == 2 )); then
+        compadd -- {words} $(regenerate __complete aliases 2>/dev/null)
+        return
+    fi
+    case "${{words[CURRENT-1]}}" in
+        --to|--workspace)
+            compadd -- $(regenerate __complete workspaces 2>/dev/null)
+            return;;
+    esac
+    case " {product_words} " in
+        *" ${{words[2]}} "*)
+            compadd -- $(regenerate __complete products 2>/dev/null);;
+    esac
+}}
+compdef _regenerate regenerate
+"#,
+            words = words,
+            product_words = product_words
+        ),
+        Shell::Fish => {
+            let mut out = String::new();
+            out.push_str("complete -c regenerate -f\n");
+            out.push_str(&format!(
+                "complete -c regenerate -n '__fish_use_subcommand' -a '{} (regenerate __complete aliases 2>/dev/null)'\n",
+                words
+            ));
+            out.push_str(&format!(
+                "complete -c regenerate -n '__fish_seen_subcommand_from {}' -a '(regenerate __complete products 2>/dev/null)'\n",
+                product_words
+            ));
+            out.push_str("complete -c regenerate -l to -l workspace -x -a '(regenerate __complete workspaces 2>/dev/null)'\n");
+            out
+        }
+    }
+}
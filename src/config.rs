@@ -0,0 +1,182 @@
+use crate::workspace::Workspace;
+use log::debug;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use yaml_rust::Yaml;
+
+/// Settings read from the user configuration file
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    /// Command aliases, mapping a name to the arguments it expands to
+    pub aliases: BTreeMap<String, String>,
+    /// Named workspaces which may be referred to instead of spelling out
+    /// their paths
+    pub workspaces: BTreeMap<String, (String, String)>,
+    /// Template used to name run artifacts such as logs and reports
+    pub run_name_template: Option<String>,
+    /// Named regular expressions to count in build output
+    pub classifiers: BTreeMap<String, String>,
+    /// Named groups of products, which may be given anywhere a product name
+    /// is accepted
+    pub groups: BTreeMap<String, Vec<String>>,
+}
+
+/// Location of the user configuration file
+pub fn config_path() -> Option<PathBuf> {
+    let mut path = match std::env::var("XDG_CONFIG_HOME") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => {
+            let mut home = PathBuf::from(std::env::var("HOME").ok()?);
+            home.push(".config");
+            home
+        }
+    };
+    path.push("regenerate");
+    path.push("config.yaml");
+    Some(path)
+}
+
+fn string_map(yaml: &Yaml) -> BTreeMap<String, String> {
+    let mut map = BTreeMap::new();
+    if let Some(hash) = yaml.as_hash() {
+        for (k, v) in hash.iter() {
+            if let (Some(k), Some(v)) = (k.as_str(), v.as_str()) {
+                map.insert(k.to_string(), v.to_string());
+            }
+        }
+    }
+    map
+}
+
+/// Read the groups section, where members are given either as a list or as a
+/// string separated by commas or whitespace
+fn groups(yaml: &Yaml) -> Result<BTreeMap<String, Vec<String>>, String> {
+    let mut groups = BTreeMap::new();
+    if let Some(hash) = yaml.as_hash() {
+        for (name, members) in hash.iter() {
+            let name = name.as_str().ok_or("Group names must be strings")?;
+            let members: Vec<String> = match members {
+                Yaml::String(s) => s
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|m| !m.is_empty())
+                    .map(|m| m.to_string())
+                    .collect(),
+                Yaml::Array(a) => a
+                    .iter()
+                    .map(|m| {
+                        m.as_str()
+                            .map(|m| m.to_string())
+                            .ok_or(format!("Members of group {} must be strings", name))
+                    })
+                    .collect::<Result<_, _>>()?,
+                _ => return Err(format!("Group {} must be a list of products", name)),
+            };
+            groups.insert(name.to_string(), members);
+        }
+    }
+    Ok(groups)
+}
+
+impl Config {
+    /// Load the user configuration, an absent file yields the defaults
+    pub fn load() -> Result<Config, String> {
+        match config_path() {
+            Some(path) if path.exists() => Config::load_from(&path),
+            _ => Ok(Config::default()),
+        }
+    }
+
+    pub fn load_from(path: &PathBuf) -> Result<Config, String> {
+        debug!("Loading configuration from {}", path.display());
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        let docs = yaml_rust::YamlLoader::load_from_str(&text)
+            .map_err(|e| format!("Could not parse {}: {}", path.display(), e))?;
+        let yaml = match docs.get(0) {
+            Some(y) => y,
+            None => return Ok(Config::default()),
+        };
+        let mut workspaces = BTreeMap::new();
+        if let Some(hash) = yaml["workspaces"].as_hash() {
+            for (name, entry) in hash.iter() {
+                let name = name
+                    .as_str()
+                    .ok_or("Workspace names must be strings")?
+                    .to_string();
+                let install_root = entry["install_root"]
+                    .as_str()
+                    .ok_or(format!("Workspace {} has no install_root", name))?;
+                let db_path = entry["db_path"]
+                    .as_str()
+                    .ok_or(format!("Workspace {} has no db_path", name))?;
+                workspaces.insert(name, (install_root.to_string(), db_path.to_string()));
+            }
+        }
+        Ok(Config {
+            aliases: string_map(&yaml["aliases"]),
+            workspaces,
+            run_name_template: yaml["run_name_template"].as_str().map(|s| s.to_string()),
+            classifiers: string_map(&yaml["classifiers"]),
+            groups: groups(&yaml["groups"])?,
+        })
+    }
+
+    /// Look up a named workspace
+    pub fn workspace(&self, name: &str) -> Option<Workspace> {
+        self.workspaces
+            .get(name)
+            .map(|(install_root, db_path)| Workspace::new(install_root, db_path))
+    }
+
+    /// Expand any group names in a list of products to their members, which
+    /// may themselves be groups. Each product appears once, at its first
+    /// position.
+    pub fn expand_products(&self, names: &[String]) -> Result<Vec<String>, String> {
+        let mut expanded = vec![];
+        for name in names.iter() {
+            self.expand_into(name, &mut expanded, 0)?;
+        }
+        Ok(expanded)
+    }
+
+    fn expand_into(&self, name: &str, out: &mut Vec<String>, depth: usize) -> Result<(), String> {
+        if depth > 8 {
+            return Err(format!(
+                "Group {} is nested too deeply, is there a loop?",
+                name
+            ));
+        }
+        match self.groups.get(name) {
+            Some(members) => {
+                debug!("Expanding group {} to {:?}", name, members);
+                for member in members.iter() {
+                    self.expand_into(member, out, depth + 1)?;
+                }
+            }
+            None => {
+                if !out.iter().any(|n| n == name) {
+                    out.push(name.to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace an alias used as the subcommand with its expansion. Aliases
+    /// may refer to other aliases, up to a fixed depth to prevent loops.
+    pub fn expand_aliases(&self, args: Vec<String>) -> Result<Vec<String>, String> {
+        let mut args = args;
+        for _ in 0..8 {
+            let expansion = match args.get(1).and_then(|a| self.aliases.get(a)) {
+                Some(e) => e,
+                None => return Ok(args),
+            };
+            debug!("Expanding alias {} to {}", args[1], expansion);
+            let mut expanded = vec![args[0].clone()];
+            expanded.extend(expansion.split_whitespace().map(|s| s.to_string()));
+            expanded.extend(args.into_iter().skip(2));
+            args = expanded;
+        }
+        Err("Aliases are nested too deeply, is there a loop?".to_string())
+    }
+}
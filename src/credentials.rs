@@ -0,0 +1,310 @@
+use crate::clone_backend::url_host;
+use crate::host_keys::HostKeyPolicy;
+use log::debug;
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+
+/// Service name tokens are filed under in the system keyring
+const KEYRING_SERVICE: &str = "regenerate";
+
+/// Times libgit2 may ask for credentials during one operation, it asks again
+/// each time what was given is rejected
+const MAX_CREDENTIAL_ATTEMPTS: u32 = 3;
+
+/// The first version of git reading configuration from GIT_CONFIG_COUNT,
+/// which tokens are handed to the system git through
+const GIT_CONFIG_ENV_VERSION: (u32, u32) = (2, 31);
+
+/// How to authenticate to a git remote
+#[derive(Clone, Debug)]
+pub enum GitAuth {
+    /// Keys held by the running ssh-agent
+    SshAgent,
+    /// A private key file, which the system git can only use without a
+    /// passphrase
+    SshKey {
+        private_key: PathBuf,
+        passphrase: Option<String>,
+    },
+    /// A token sent as the password of https requests
+    Token(String),
+}
+
+impl GitAuth {
+    /// Parse agent, key:PATH, or token:VAR where the token is read from the
+    /// environment variable VAR so it never appears on a command line
+    pub fn parse(method: &str) -> Result<GitAuth, String> {
+        if method == "agent" {
+            Ok(GitAuth::SshAgent)
+        } else if method.starts_with("key:") {
+            Ok(GitAuth::SshKey {
+                private_key: PathBuf::from(&method[4..]),
+                passphrase: std::env::var("REGENERATE_SSH_PASSPHRASE").ok(),
+            })
+        } else if method.starts_with("token:") {
+            std::env::var(&method[6..])
+                .map(GitAuth::Token)
+                .map_err(|_| format!("The token variable {} is not set", &method[6..]))
+        } else {
+            Err(format!(
+                "{} is not one of agent, key:PATH, or token:VAR",
+                method
+            ))
+        }
+    }
+}
+
+/// Credentials to use for particular hosts and products, where those of a
+/// product win over those of its host. Remotes with neither use the keyring.
+#[derive(Clone, Debug, Default)]
+pub struct GitAuthConfig {
+    pub hosts: HashMap<String, GitAuth>,
+    pub products: HashMap<String, GitAuth>,
+}
+
+impl GitAuthConfig {
+    pub fn lookup(&self, product: &str, url: &str) -> Option<&GitAuth> {
+        self.products
+            .get(product)
+            .or_else(|| url_host(url).and_then(|host| self.hosts.get(host)))
+    }
+}
+
+/// Store the token used to access a host in the system keyring
+#[cfg(feature = "keyring")]
+pub fn store_token(host: &str, token: &str) -> Result<(), String> {
+    keyring::Keyring::new(KEYRING_SERVICE, host)
+        .set_password(token)
+        .map_err(|e| format!("Could not store token for {} in the keyring: {}", host, e))
+}
+
+#[cfg(not(feature = "keyring"))]
+pub fn store_token(host: &str, _token: &str) -> Result<(), String> {
+    Err(format!(
+        "Can not store a token for {}, regenerate was built without keyring support",
+        host
+    ))
+}
+
+/// Look up the token used to access a host in the system keyring, if one has
+/// been stored
+#[cfg(feature = "keyring")]
+pub fn lookup_token(host: &str) -> Option<String> {
+    match keyring::Keyring::new(KEYRING_SERVICE, host).get_password() {
+        Ok(token) => Some(token),
+        Err(e) => {
+            debug!("No token for {} in the keyring: {}", host, e);
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "keyring"))]
+pub fn lookup_token(host: &str) -> Option<String> {
+    debug!("Built without keyring support, no token for {}", host);
+    None
+}
+
+/// Remove the token for a host from the system keyring
+#[cfg(feature = "keyring")]
+pub fn remove_token(host: &str) -> Result<(), String> {
+    keyring::Keyring::new(KEYRING_SERVICE, host)
+        .delete_password()
+        .map_err(|e| format!("Could not remove token for {}: {}", host, e))
+}
+
+#[cfg(not(feature = "keyring"))]
+pub fn remove_token(host: &str) -> Result<(), String> {
+    Err(format!(
+        "Can not remove the token for {}, regenerate was built without keyring support",
+        host
+    ))
+}
+
+/// Interactively ask for the token of a host and store it in the keyring
+pub fn login(host: &str) -> Result<(), String> {
+    eprint!("Token for {}: ", host);
+    std::io::stderr().flush().map_err(|e| format!("{}", e))?;
+    let mut token = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut token)
+        .map_err(|e| format!("Could not read token: {}", e))?;
+    let token = token.trim();
+    if token.is_empty() {
+        return Err("No token given".to_string());
+    }
+    store_token(host, token)
+}
+
+/// Credential callback for git operations on url, answering requests for a
+/// username and password with the token stored for the host, and requests
+/// for ssh keys with the ssh-agent
+fn git_credentials(
+    url: &str,
+    username: Option<&str>,
+    allowed: git2::CredentialType,
+) -> Result<git2::Cred, git2::Error> {
+    if allowed.contains(git2::CredentialType::USER_PASS_PLAINTEXT) {
+        if let Some(token) = url_host(url).and_then(lookup_token) {
+            return git2::Cred::userpass_plaintext(username.unwrap_or("git"), &token);
+        }
+    }
+    if allowed.contains(git2::CredentialType::SSH_KEY) {
+        return git2::Cred::ssh_key_from_agent(username.unwrap_or("git"));
+    }
+    if allowed.contains(git2::CredentialType::DEFAULT) {
+        return git2::Cred::default();
+    }
+    Err(git2::Error::from_str(&format!(
+        "No stored credentials for {}",
+        url
+    )))
+}
+
+/// Credential callback for a connection to url, answering with auth where
+/// it applies to what is asked for, and otherwise as git_credentials does.
+/// auth is only given to the host of url, not to one a request is redirected
+/// to. Gives up once libgit2 has rejected a few answers rather than asking
+/// forever.
+pub fn git_credentials_with<'a>(
+    auth: Option<&'a GitAuth>,
+    url: &str,
+) -> impl FnMut(&str, Option<&str>, git2::CredentialType) -> Result<git2::Cred, git2::Error> + 'a {
+    let mut attempts = 0;
+    let host = url_host(url).map(|h| h.to_string());
+    move |url, username, allowed| {
+        attempts += 1;
+        if attempts > MAX_CREDENTIAL_ATTEMPTS {
+            return Err(git2::Error::from_str(&format!(
+                "The credentials for {} were rejected",
+                url
+            )));
+        }
+        let user = username.unwrap_or("git");
+        if allowed.contains(git2::CredentialType::USERNAME) {
+            return git2::Cred::username(user);
+        }
+        let ssh = allowed.contains(git2::CredentialType::SSH_KEY);
+        let auth = auth.filter(|_| url_host(url) == host.as_ref().map(|h| h.as_str()));
+        match auth {
+            Some(GitAuth::SshAgent) if ssh => git2::Cred::ssh_key_from_agent(user),
+            Some(GitAuth::SshKey {
+                private_key,
+                passphrase,
+            }) if ssh => git2::Cred::ssh_key(
+                user,
+                None,
+                private_key,
+                passphrase.as_ref().map(|p| p.as_str()),
+            ),
+            Some(GitAuth::Token(token))
+                if allowed.contains(git2::CredentialType::USER_PASS_PLAINTEXT) =>
+            {
+                git2::Cred::userpass_plaintext(user, token)
+            }
+            _ => git_credentials(url, username, allowed),
+        }
+    }
+}
+
+/// The major and minor version of the system git
+fn git_version() -> Option<(u32, u32)> {
+    let output = std::process::Command::new("git")
+        .arg("--version")
+        .output()
+        .ok()?;
+    // git version 2.39.5, with a platform suffix on some systems
+    let text = String::from_utf8_lossy(&output.stdout);
+    let version = text.split_whitespace().nth(2)?;
+    let mut parts = version.split('.').map(|p| p.parse::<u32>().ok());
+    Some((parts.next()??, parts.next()??))
+}
+
+/// The scheme and host of an http url, which git matches credential
+/// settings against
+fn credential_scope(url: &str) -> Option<String> {
+    let scheme = url.find("://").map(|pos| &url[..pos])?;
+    if scheme != "https" && scheme != "http" {
+        return None;
+    }
+    url_host(url).map(|host| format!("{}://{}", scheme, host))
+}
+
+/// Set up a system git command talking to url to authenticate with auth,
+/// and to verify ssh host keys with host_keys if given. Every system git
+/// which may reach a remote is set up here, so all of them apply the same
+/// policy. A token is only offered to the host of url.
+pub fn configure_command(
+    command: &mut std::process::Command,
+    url: &str,
+    auth: Option<&GitAuth>,
+    host_keys: Option<&HostKeyPolicy>,
+) -> Result<(), String> {
+    let ssh = match host_keys {
+        Some(policy) => {
+            policy.prepare_ssh()?;
+            Some(policy.ssh_command())
+        }
+        None => None,
+    };
+    let ssh = match auth {
+        Some(GitAuth::SshKey { private_key, .. }) => Some(format!(
+            "{} -i '{}' -o IdentitiesOnly=yes",
+            ssh.unwrap_or_else(|| "ssh".to_string()),
+            private_key.display()
+        )),
+        Some(GitAuth::Token(token)) => {
+            let scope = match credential_scope(url) {
+                Some(scope) => scope,
+                None => {
+                    debug!("Not offering a token to {}, which is not http", url);
+                    return set_ssh_command(command, ssh);
+                }
+            };
+            match git_version() {
+                Some(version) if version >= GIT_CONFIG_ENV_VERSION => (),
+                found => {
+                    return Err(format!(
+                        "A token can only be given to git {}.{} or newer, the system git is {}",
+                        GIT_CONFIG_ENV_VERSION.0,
+                        GIT_CONFIG_ENV_VERSION.1,
+                        found.map_or("of an unknown version".to_string(), |(major, minor)| {
+                            format!("{}.{}", major, minor)
+                        })
+                    ))
+                }
+            }
+            // a credential helper reading the token from the environment
+            // keeps it out of the arguments of the process, added after any
+            // configuration the environment already gives
+            let index: usize = std::env::var("GIT_CONFIG_COUNT")
+                .ok()
+                .and_then(|c| c.parse().ok())
+                .unwrap_or(0);
+            command
+                .env("REGENERATE_GIT_TOKEN", token)
+                .env("GIT_CONFIG_COUNT", format!("{}", index + 1))
+                .env(
+                    format!("GIT_CONFIG_KEY_{}", index),
+                    format!("credential.{}.helper", scope),
+                )
+                .env(
+                    format!("GIT_CONFIG_VALUE_{}", index),
+                    "!f() { echo username=git; echo \"password=$REGENERATE_GIT_TOKEN\"; }; f",
+                );
+            ssh
+        }
+        // the system ssh already uses the agent
+        _ => ssh,
+    };
+    set_ssh_command(command, ssh)
+}
+
+fn set_ssh_command(command: &mut std::process::Command, ssh: Option<String>) -> Result<(), String> {
+    if let Some(ssh) = ssh {
+        command.env("GIT_SSH_COMMAND", ssh);
+    }
+    Ok(())
+}
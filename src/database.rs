@@ -0,0 +1,120 @@
+use crate::regenerate::reups;
+use std::path::PathBuf;
+
+/// The operations regenerate needs from a product registry. This is
+/// implemented for the reups database, but allows other registries to be
+/// substituted, or mocked out.
+pub trait ProductDatabase {
+    /// Determine if a product with the given id has been declared
+    fn has_identity(&self, product: &str, id: &str) -> bool;
+
+    /// Look up the table of the product declared with the given id
+    fn get_table_from_identity(&self, product: &str, id: &str) -> Option<reups::table::Table>;
+
+    /// Look up the table of the product declared with the given version
+    fn get_table_from_version(&self, product: &str, version: &str) -> Option<reups::table::Table>;
+
+    /// The id a product version was declared with, if any
+    fn get_identity_from_version(&self, product: &str, version: &str) -> Option<String>;
+
+    /// Path to the database a product version was declared in
+    fn get_database_path_from_version(&self, product: &str, version: &str) -> PathBuf;
+
+    /// Names of all the products in the database
+    fn get_all_products(&self) -> Vec<String>;
+
+    /// Versions of a product carrying any of the given tags
+    fn get_versions_from_tag(&self, product: &str, tags: Vec<&str>) -> Vec<String>;
+
+    /// Declare products into the database
+    fn declare(&mut self, inputs: Vec<reups::DeclareInputs>) -> Result<(), String>;
+
+    /// Assign a tag to a declared product version, replacing whichever
+    /// version the tag pointed at before
+    fn set_tag(&mut self, product: &str, version: &str, tag: &str) -> Result<(), String>;
+
+    /// Take a tag off a product, where version is one the product is declared
+    /// with
+    fn remove_tag(&mut self, product: &str, version: &str, tag: &str) -> Result<(), String>;
+}
+
+/// The chain file a tag of a product is kept in, beside its declarations
+fn chain_path(db: &reups::DB, product: &str, version: &str, tag: &str) -> PathBuf {
+    let mut path = reups::DB::get_database_path_from_version(db, product, version);
+    path.push(product);
+    path.push(format!("{}.chain", tag));
+    path
+}
+
+fn render_chain(product: &str, version: &str, tag: &str) -> String {
+    format!(
+        "FILE = version\nPRODUCT = {}\nCHAIN = {}\n#***************************************\n\n\
+         #Group:\n   FLAVOR = {}\n   VERSION = {}\n   QUALIFIERS = \"\"\n   DECLARER = {}\n   DECLARED = {}\nEnd:\n",
+        product,
+        tag,
+        reups::SYSTEM_OS,
+        version,
+        std::env::var("USER").unwrap_or_else(|_| "regenerate".to_string()),
+        time::now().rfc3339()
+    )
+}
+
+impl ProductDatabase for reups::DB {
+    fn has_identity(&self, product: &str, id: &str) -> bool {
+        reups::DB::has_identity(self, product, id)
+    }
+
+    fn get_table_from_identity(&self, product: &str, id: &str) -> Option<reups::table::Table> {
+        reups::DB::get_table_from_identity(self, product, id)
+    }
+
+    fn get_table_from_version(&self, product: &str, version: &str) -> Option<reups::table::Table> {
+        reups::DB::get_table_from_version(self, product, version)
+    }
+
+    fn get_identity_from_version(&self, product: &str, version: &str) -> Option<String> {
+        reups::DB::get_identity_from_version(self, product, version)
+    }
+
+    fn get_database_path_from_version(&self, product: &str, version: &str) -> PathBuf {
+        reups::DB::get_database_path_from_version(self, product, version)
+    }
+
+    fn get_all_products(&self) -> Vec<String> {
+        reups::DB::get_all_products(self)
+    }
+
+    fn get_versions_from_tag(&self, product: &str, tags: Vec<&str>) -> Vec<String> {
+        reups::DB::get_versions_from_tag(self, product, tags)
+    }
+
+    fn declare(&mut self, inputs: Vec<reups::DeclareInputs>) -> Result<(), String> {
+        reups::DB::declare(self, inputs, None)
+            .map(|_| ())
+            .map_err(|e| format!("{}", e))
+    }
+
+    fn set_tag(&mut self, product: &str, version: &str, tag: &str) -> Result<(), String> {
+        // the chain is written beside the old one and renamed over it, so it
+        // is never seen half written
+        let path = chain_path(self, product, version, tag);
+        let mut staged = path.clone();
+        staged.set_extension("chain.regenerate-staged");
+        std::fs::write(&staged, render_chain(product, version, tag))
+            .and_then(|_| std::fs::rename(&staged, &path))
+            .map_err(|e| {
+                let _ = std::fs::remove_file(&staged);
+                format!("Could not write {}: {}", path.display(), e)
+            })
+    }
+
+    fn remove_tag(&mut self, product: &str, version: &str, tag: &str) -> Result<(), String> {
+        let path = chain_path(self, product, version, tag);
+        match std::fs::remove_file(&path) {
+            Err(ref e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(format!("Could not remove {}: {}", path.display(), e))
+            }
+            _ => Ok(()),
+        }
+    }
+}
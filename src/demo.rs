@@ -0,0 +1,248 @@
+use git2::{IndexAddOption, Repository, Signature};
+use log::debug;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Products of the demo stack along with the products each requires. The
+/// python environment is part of the stack as every build currently depends
+/// on it.
+const PRODUCTS: [(&str, &[&str]); 5] = [
+    ("scipipe_conda", &[]),
+    ("demo_base", &["scipipe_conda"]),
+    ("demo_utils", &["demo_base"]),
+    ("demo_geom", &["demo_base"]),
+    ("demo_app", &["demo_utils", "demo_geom"]),
+];
+
+/// The product at the top of the demo stack, installing it builds them all
+pub const TOP_PRODUCT: &str = "demo_app";
+
+/// The branch demo products are checked out from
+pub const BRANCH: &str = "origin/master";
+
+/// A stand in for eupspkg which understands the same arguments, building
+/// each product by writing a file and installing it along with the table
+const BUILD_TOOL: &str = r#"#!/bin/sh
+# Toy eupspkg for the regenerate demo stack
+for arg in "$@"; do
+    case "$arg" in
+        *=*) export "$arg" ;;
+        *) verb="$arg" ;;
+    esac
+done
+case "$verb" in
+    fetch|prep|config)
+        echo "$verb $PRODUCT $VERSION" ;;
+    build)
+        mkdir -p lib && echo "$PRODUCT $VERSION built $(date)" > "lib/$PRODUCT.txt" ;;
+    install)
+        mkdir -p "$PREFIX" && cp -r ups lib "$PREFIX"/ ;;
+    *)
+        echo "Unknown verb $verb" >&2
+        exit 1 ;;
+esac
+"#;
+
+/// A miniature stack of products in git repositories, with everything a run
+/// needs to build it
+pub struct DemoStack {
+    pub root: PathBuf,
+    /// Repository map naming the local repository of each product
+    pub repo_map: PathBuf,
+    pub build_tool: PathBuf,
+    pub clone_root: PathBuf,
+    pub install_root: PathBuf,
+    pub db_path: PathBuf,
+}
+
+fn write(path: &Path, contents: &str) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| format!("Could not create {}: {}", parent.display(), e))?;
+    }
+    std::fs::write(path, contents).map_err(|e| format!("Could not write {}: {}", path.display(), e))
+}
+
+/// Create a repository for a product holding its table and a single commit
+fn create_product(source: &Path, product: &str, requires: &[&str]) -> Result<(), String> {
+    let mut table = String::new();
+    for dep in requires.iter() {
+        table.push_str(&format!("setupRequired({})\n", dep));
+    }
+    table.push_str("envPrepend(DEMO_PATH, ${PRODUCT_DIR}/lib)\n");
+    write(
+        &source.join("ups").join(format!("{}.table", product)),
+        &table,
+    )?;
+    write(
+        &source.join("README.md"),
+        &format!("# {}\n\nPart of the regenerate demo stack.\n", product),
+    )?;
+    let git_error =
+        |e: git2::Error| format!("Could not commit the demo product {}: {}", product, e);
+    let repo = Repository::init(source).map_err(git_error)?;
+    let mut index = repo.index().map_err(git_error)?;
+    index
+        .add_all(["*"].iter(), IndexAddOption::DEFAULT, None)
+        .map_err(git_error)?;
+    index.write().map_err(git_error)?;
+    let tree = repo
+        .find_tree(index.write_tree().map_err(git_error)?)
+        .map_err(git_error)?;
+    let signature = Signature::now("regenerate demo", "demo@localhost").map_err(git_error)?;
+    repo.commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        &format!("Add {}", product),
+        &tree,
+        &[],
+    )
+    .map_err(git_error)?;
+    debug!("Created demo product {} in {}", product, source.display());
+    Ok(())
+}
+
+/// Synthesize the demo stack below root, which is created if needed
+pub fn synthesize(root: &Path) -> Result<DemoStack, String> {
+    let stack = DemoStack {
+        root: root.to_path_buf(),
+        repo_map: root.join("repos.yaml"),
+        build_tool: root.join("eupspkg"),
+        clone_root: root.join("clones"),
+        install_root: root.join("install"),
+        db_path: root.join("install").join("demo.json"),
+    };
+    let mut map = String::new();
+    for (product, requires) in PRODUCTS.iter() {
+        let source = root.join("sources").join(product);
+        if source.exists() {
+            return Err(format!(
+                "{} already exists, use an empty directory for the demo",
+                source.display()
+            ));
+        }
+        create_product(&source, product, requires)?;
+        map.push_str(&format!("{}: {}\n", product, source.display()));
+    }
+    write(&stack.repo_map, &map)?;
+    write(&stack.build_tool, BUILD_TOOL)?;
+    std::fs::set_permissions(&stack.build_tool, std::fs::Permissions::from_mode(0o755))
+        .map_err(|e| format!("Could not make the demo build tool executable: {}", e))?;
+    for dir in [&stack.clone_root, &stack.install_root].iter() {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("Could not create {}: {}", dir.display(), e))?;
+    }
+    Ok(stack)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::regenerate::{PlanAction, RegenOptions, Regenerate};
+    use crate::toolchain;
+    use crate::workspace::Workspace;
+    use fnv::FnvHashMap;
+    use tempdir::TempDir;
+
+    fn demo_options(stack: &DemoStack) -> RegenOptions {
+        let mut options = RegenOptions::new(
+            &stack.install_root.to_string_lossy(),
+            &stack.clone_root.to_string_lossy(),
+            "demo",
+            &stack.build_tool.to_string_lossy(),
+        );
+        options.local_yaml = vec![stack.repo_map.clone()];
+        options.remote_package_url = None;
+        options.branches = Some(vec![BRANCH.to_string()]);
+        options.assume_yes = true;
+        options
+    }
+
+    /// The first program the toy build tool runs which can not be found, a
+    /// machine without one can not build the demo stack
+    fn missing_tool() -> Option<&'static str> {
+        ["sh", "mkdir", "cp", "date"]
+            .iter()
+            .find(|tool| toolchain::find_program(tool, &FnvHashMap::default()).is_none())
+            .cloned()
+    }
+
+    fn workspace(stack: &DemoStack) -> Workspace {
+        Workspace {
+            install_root: stack.install_root.clone(),
+            db_path: stack.db_path.clone(),
+        }
+    }
+
+    #[test]
+    fn synthesized_stack_has_a_source_per_product() {
+        let dir = TempDir::new("regenerate-demo").unwrap();
+        let stack = synthesize(dir.path()).unwrap();
+        let map = std::fs::read_to_string(&stack.repo_map).unwrap();
+        for (product, _) in PRODUCTS.iter() {
+            assert!(
+                map.contains(&format!("{}:", product)),
+                "{} is not mapped",
+                product
+            );
+            let table = dir
+                .path()
+                .join("sources")
+                .join(product)
+                .join("ups")
+                .join(format!("{}.table", product));
+            assert!(table.is_file(), "{} has no table", product);
+        }
+        assert!(
+            synthesize(dir.path()).is_err(),
+            "synthesized over an existing stack"
+        );
+    }
+
+    #[test]
+    fn demo_stack_plans_every_product_after_its_dependencies() {
+        let dir = TempDir::new("regenerate-demo").unwrap();
+        let stack = synthesize(dir.path()).unwrap();
+        let mut db = workspace(&stack).open_db().unwrap();
+        let mut app = Regenerate::new(&mut db, demo_options(&stack)).unwrap();
+        let plan = app.plan(TOP_PRODUCT).unwrap();
+        let order: Vec<&str> = plan.steps.iter().map(|s| s.product.as_str()).collect();
+        for (product, requires) in PRODUCTS.iter() {
+            let position = order
+                .iter()
+                .position(|p| p == product)
+                .unwrap_or_else(|| panic!("{} is not in the plan", product));
+            for dep in requires.iter() {
+                let dep_position = order.iter().position(|p| p == dep).unwrap();
+                assert!(
+                    dep_position < position,
+                    "{} is planned before {}",
+                    product,
+                    dep
+                );
+            }
+        }
+        assert!(plan.steps.iter().all(|s| s.action == PlanAction::Build));
+        assert_eq!(order.last(), Some(&TOP_PRODUCT));
+    }
+
+    #[test]
+    fn demo_stack_builds_and_is_reused() {
+        if let Some(tool) = missing_tool() {
+            eprintln!("Skipping the demo build, {} is not installed", tool);
+            return;
+        }
+        let dir = TempDir::new("regenerate-demo").unwrap();
+        let stack = synthesize(dir.path()).unwrap();
+        let mut db = workspace(&stack).open_db().unwrap();
+        {
+            let mut app = Regenerate::new(&mut db, demo_options(&stack)).unwrap();
+            app.install_product(TOP_PRODUCT).unwrap();
+        }
+        let mut db = workspace(&stack).open_db().unwrap();
+        let mut app = Regenerate::new(&mut db, demo_options(&stack)).unwrap();
+        let plan = app.plan(TOP_PRODUCT).unwrap();
+        assert!(plan.steps.iter().all(|s| s.action == PlanAction::Reuse));
+    }
+}
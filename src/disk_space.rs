@@ -0,0 +1,154 @@
+use crate::network;
+use log::debug;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Prefix of the temporary directories builds are staged in, which marks
+/// leftovers of interrupted runs
+pub const TEMP_PREFIX: &str = "regenerate-";
+
+/// What a filesystem is short of, with how much of it is left
+#[derive(Clone, Copy, Debug)]
+pub enum Shortage {
+    Bytes(u64),
+    Inodes(u64),
+}
+
+/// The statistics of the filesystem holding path. A path which does not
+/// exist yet is measured at its nearest existing parent.
+fn statvfs(path: &Path) -> Result<libc::statvfs, String> {
+    let existing = path
+        .ancestors()
+        .find(|p| !p.as_os_str().is_empty() && p.exists())
+        .unwrap_or_else(|| Path::new("."));
+    let c_path = CString::new(existing.as_os_str().as_bytes())
+        .map_err(|e| format!("Invalid path {}: {}", existing.display(), e))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(format!(
+            "Could not determine the free space of {}: {}",
+            existing.display(),
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(stat)
+}
+
+/// Bytes available to an unprivileged user on the filesystem holding path
+pub fn free_bytes(path: &Path) -> Result<u64, String> {
+    let stat = statvfs(path)?;
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Inodes available to an unprivileged user on the filesystem holding path,
+/// or None for filesystems such as btrfs which allocate them as needed and
+/// report no fixed number
+pub fn free_inodes(path: &Path) -> Result<Option<u64>, String> {
+    let stat = statvfs(path)?;
+    match stat.f_files {
+        0 => Ok(None),
+        _ => Ok(Some(stat.f_favail as u64)),
+    }
+}
+
+/// Parse a size given in bytes, optionally with a K, M, G, or T suffix
+/// counting in powers of 1024
+pub fn parse_size(text: &str) -> Result<u64, String> {
+    let text = text.trim();
+    let (number, multiplier) = match text.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&text[..text.len() - 1], 1u64 << 10),
+        Some('M') => (&text[..text.len() - 1], 1 << 20),
+        Some('G') => (&text[..text.len() - 1], 1 << 30),
+        Some('T') => (&text[..text.len() - 1], 1 << 40),
+        _ => (text, 1),
+    };
+    number
+        .trim()
+        .parse::<u64>()
+        .map(|n| n * multiplier)
+        .map_err(|e| format!("{} is not a size: {}", text, e))
+}
+
+/// The paths among those given whose filesystems have less than min_free
+/// bytes or min_inodes inodes available, with what they are short of. A
+/// filesystem short of both is listed once for each.
+pub fn low_space(
+    paths: &[PathBuf],
+    min_free: Option<u64>,
+    min_inodes: Option<u64>,
+) -> Vec<(PathBuf, Shortage)> {
+    let mut low = vec![];
+    for path in paths.iter() {
+        if let Some(min_free) = min_free {
+            match free_bytes(path) {
+                Ok(free) if free < min_free => low.push((path.clone(), Shortage::Bytes(free))),
+                Ok(_) => (),
+                Err(e) => debug!("{}", e),
+            }
+        }
+        if let Some(min_inodes) = min_inodes {
+            match free_inodes(path) {
+                Ok(Some(free)) if free < min_inodes => {
+                    low.push((path.clone(), Shortage::Inodes(free)))
+                }
+                Ok(_) => (),
+                Err(e) => debug!("{}", e),
+            }
+        }
+    }
+    low
+}
+
+/// Describe the paths found by low_space for messages
+pub fn describe(low: &[(PathBuf, Shortage)]) -> String {
+    let mut text = low
+        .iter()
+        .map(|(path, shortage)| match shortage {
+            Shortage::Bytes(free) => format!(
+                "{} has {} free",
+                path.display(),
+                network::format_bytes(*free)
+            ),
+            Shortage::Inodes(free) => format!("{} has {} inodes free", path.display(), free),
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    // running out of inodes with bytes to spare is puzzling without a hint
+    if low.iter().any(|(_, s)| match s {
+        Shortage::Inodes(_) => true,
+        Shortage::Bytes(_) => false,
+    }) {
+        text.push_str(
+            ", the filesystem has run out of room for new files, typically taken by \
+             many small files such as those of Python packages, even though bytes may be free",
+        );
+    }
+    text
+}
+
+/// Directories in parent whose names start with prefix and which have not
+/// been modified for at least age, so no running build can still be using
+/// them
+pub fn stale_dirs(parent: &Path, prefix: &str, age: Duration) -> Vec<PathBuf> {
+    let entries = match std::fs::read_dir(parent) {
+        Ok(e) => e,
+        Err(_) => return vec![],
+    };
+    let now = SystemTime::now();
+    entries
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_name().to_string_lossy().starts_with(prefix))
+        .filter(|e| e.file_type().map(|t| t.is_dir()).unwrap_or(false))
+        .filter(|e| {
+            e.metadata()
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|modified| now.duration_since(modified).ok())
+                .map(|elapsed| elapsed >= age)
+                .unwrap_or(false)
+        })
+        .map(|e| e.path())
+        .collect()
+}
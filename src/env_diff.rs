@@ -0,0 +1,162 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// How one environment variable differs between two environments
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum VarChange {
+    /// Only set in the second environment
+    Added {
+        value: String,
+    },
+    /// Only set in the first environment
+    Removed {
+        value: String,
+    },
+    Changed {
+        from: String,
+        to: String,
+    },
+    /// A search path whose entries differ
+    PathChanged {
+        added: Vec<String>,
+        removed: Vec<String>,
+        /// Entries common to both appear in a different order
+        reordered: bool,
+    },
+}
+
+/// Differences between the environments of two products
+#[derive(Clone, Debug, Serialize)]
+pub struct EnvDiff {
+    pub first: String,
+    pub second: String,
+    pub changes: BTreeMap<String, VarChange>,
+}
+
+/// Determine if a variable holds a colon separated list of paths, which is
+/// compared entry by entry
+pub fn is_path_like(name: &str, value: &str) -> bool {
+    name.ends_with("PATH")
+        || name.ends_with("_DIRS")
+        || (value.contains(':') && value.split(':').all(|p| p.is_empty() || p.starts_with('/')))
+}
+
+fn path_change(from: &str, to: &str) -> VarChange {
+    let from_entries: Vec<&str> = from.split(':').filter(|e| !e.is_empty()).collect();
+    let to_entries: Vec<&str> = to.split(':').filter(|e| !e.is_empty()).collect();
+    let added = to_entries
+        .iter()
+        .filter(|e| !from_entries.contains(e))
+        .map(|e| e.to_string())
+        .collect();
+    let removed = from_entries
+        .iter()
+        .filter(|e| !to_entries.contains(e))
+        .map(|e| e.to_string())
+        .collect();
+    let common_from: Vec<&&str> = from_entries
+        .iter()
+        .filter(|e| to_entries.contains(e))
+        .collect();
+    let common_to: Vec<&&str> = to_entries
+        .iter()
+        .filter(|e| from_entries.contains(e))
+        .collect();
+    VarChange::PathChanged {
+        added,
+        removed,
+        reordered: common_from != common_to,
+    }
+}
+
+/// Compare two environments, where names label them in the output
+pub fn diff(
+    first: (&str, &BTreeMap<String, String>),
+    second: (&str, &BTreeMap<String, String>),
+) -> EnvDiff {
+    let (first_name, a) = first;
+    let (second_name, b) = second;
+    let mut changes = BTreeMap::new();
+    for (name, value) in a.iter() {
+        match b.get(name) {
+            None => {
+                changes.insert(
+                    name.clone(),
+                    VarChange::Removed {
+                        value: value.clone(),
+                    },
+                );
+            }
+            Some(other) if other == value => (),
+            Some(other) => {
+                let change = if is_path_like(name, value) || is_path_like(name, other) {
+                    path_change(value, other)
+                } else {
+                    VarChange::Changed {
+                        from: value.clone(),
+                        to: other.clone(),
+                    }
+                };
+                changes.insert(name.clone(), change);
+            }
+        }
+    }
+    for (name, value) in b.iter() {
+        if !a.contains_key(name) {
+            changes.insert(
+                name.clone(),
+                VarChange::Added {
+                    value: value.clone(),
+                },
+            );
+        }
+    }
+    EnvDiff {
+        first: first_name.to_string(),
+        second: second_name.to_string(),
+        changes,
+    }
+}
+
+impl EnvDiff {
+    pub fn to_json(&self) -> Result<String, String> {
+        serde_json::to_string_pretty(self)
+            .map_err(|e| format!("Could not serialize environment diff: {}", e))
+    }
+
+    /// Render the diff for reading, lines starting with - belong to the first
+    /// environment and + to the second
+    pub fn render(&self) -> String {
+        let mut out = format!("--- {}\n+++ {}\n", self.first, self.second);
+        if self.changes.is_empty() {
+            out.push_str("The environments are the same\n");
+        }
+        for (name, change) in self.changes.iter() {
+            match change {
+                VarChange::Added { value } => out.push_str(&format!("+ {}={}\n", name, value)),
+                VarChange::Removed { value } => out.push_str(&format!("- {}={}\n", name, value)),
+                VarChange::Changed { from, to } => {
+                    out.push_str(&format!("~ {}\n  - {}\n  + {}\n", name, from, to))
+                }
+                VarChange::PathChanged {
+                    added,
+                    removed,
+                    reordered,
+                } => {
+                    out.push_str(&format!("~ {}\n", name));
+                    for entry in removed.iter() {
+                        out.push_str(&format!("  - {}\n", entry));
+                    }
+                    for entry in added.iter() {
+                        out.push_str(&format!("  + {}\n", entry));
+                    }
+                    if *reordered {
+                        out.push_str("  (shared entries are in a different order)\n");
+                    }
+                }
+            }
+        }
+        out
+    }
+}
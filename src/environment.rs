@@ -0,0 +1,102 @@
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use log::debug;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// The packages installed into a provisioned conda environment, used to
+/// determine which table dependencies are satisfied without eups
+pub struct ProvisionedEnvironment {
+    prefix: PathBuf,
+    /// package name -> name-version-build string recorded by conda
+    packages: BTreeMap<String, String>,
+}
+
+impl ProvisionedEnvironment {
+    /// Read the package metadata of the conda environment at prefix
+    pub fn from_prefix(prefix: &Path) -> Result<ProvisionedEnvironment, String> {
+        let mut meta_dir = PathBuf::from(prefix);
+        meta_dir.push("conda-meta");
+        debug!("Reading conda environment from {}", meta_dir.display());
+        let entries = std::fs::read_dir(&meta_dir).map_err(|e| {
+            format!(
+                "Could not read conda environment metadata in {}: {}",
+                meta_dir.display(),
+                e
+            )
+        })?;
+        let mut packages = BTreeMap::new();
+        for entry in entries.filter_map(|e| e.ok()) {
+            let file_name = entry.file_name();
+            let file_name = match file_name.to_str() {
+                Some(x) => x,
+                None => continue,
+            };
+            if !file_name.ends_with(".json") {
+                continue;
+            }
+            let spec = &file_name[..file_name.len() - ".json".len()];
+            // the spec is name-version-build, and names may themselves contain
+            // dashes so split from the right
+            let mut parts = spec.rsplitn(3, '-');
+            let (_, _, name) = (parts.next(), parts.next(), parts.next());
+            if let Some(name) = name {
+                packages.insert(name.to_string(), spec.to_string());
+            }
+        }
+        Ok(ProvisionedEnvironment {
+            prefix: PathBuf::from(prefix),
+            packages,
+        })
+    }
+
+    pub fn prefix(&self) -> &Path {
+        &self.prefix
+    }
+
+    /// Determine if the environment has the named conda package installed
+    pub fn provides(&self, package: &str) -> bool {
+        self.packages.contains_key(package)
+    }
+
+    /// Hash of the installed versions and builds of the given packages, so that
+    /// changing any of them changes the identity of products depending on them
+    pub fn spec_hash<'a, I: Iterator<Item = &'a String>>(&self, packages: I) -> String {
+        let mut specs: Vec<&String> = packages.filter_map(|p| self.packages.get(p)).collect();
+        specs.sort();
+        let mut hasher = Sha1::new();
+        for spec in specs {
+            hasher.input(spec.as_bytes());
+        }
+        hasher.result_str()
+    }
+
+    /// Hash of every package installed in the environment
+    pub fn resolved_hash(&self) -> String {
+        self.spec_hash(self.packages.keys())
+    }
+}
+
+/// Hash an environment specification file, such as the output of conda list
+/// --explicit or a conda-lock file. Comments, blank lines, and line ordering
+/// are ignored so only material changes to the environment alter the hash.
+pub fn hash_spec_file(path: &Path) -> Result<String, String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| {
+        format!(
+            "Could not read environment specification {}: {}",
+            path.display(),
+            e
+        )
+    })?;
+    let mut lines: Vec<&str> = contents
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .collect();
+    lines.sort();
+    let mut hasher = Sha1::new();
+    for line in lines {
+        hasher.input(line.as_bytes());
+    }
+    Ok(hasher.result_str())
+}
@@ -0,0 +1,35 @@
+use thiserror::Error;
+
+/// Why installing a product failed. Clone and build failures name the
+/// product responsible, so a caller may report it and carry on with other
+/// products rather than abandoning the whole run.
+#[derive(Debug, Clone, Error)]
+pub enum RegenError {
+    /// A product could not be cloned, or an existing clone opened
+    #[error("Could not clone {product}: {message}")]
+    Clone { product: String, message: String },
+    /// A product could not be built or its build completed
+    #[error("Could not build {product}: {message}")]
+    Build { product: String, message: String },
+    /// Any other failure, such as a bad table or database problem
+    #[error("{0}")]
+    Other(String),
+}
+
+impl From<String> for RegenError {
+    fn from(message: String) -> RegenError {
+        RegenError::Other(message)
+    }
+}
+
+impl<'a> From<&'a str> for RegenError {
+    fn from(message: &'a str) -> RegenError {
+        RegenError::Other(message.to_string())
+    }
+}
+
+impl From<RegenError> for String {
+    fn from(error: RegenError) -> String {
+        format!("{}", error)
+    }
+}
@@ -0,0 +1,238 @@
+use crate::graph_export::GraphSnapshot;
+use crate::log_tail;
+use crate::provenance::Provenance;
+use std::collections::BTreeSet;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+const HELP: &str = "\
+Commands:
+  tree                 show the graph below the current product
+  expand NAME|all      show the dependencies of a product in the tree
+  collapse NAME|all    hide the dependencies of a product in the tree
+  show NAME            show the sha, id, dependencies, and dependents
+  cd NAME              make a product the root of the tree
+  top                  return to the product being explored
+  dependents NAME      list the products which depend on a product
+  log NAME             open the build log of a product
+  provenance NAME      show how the install of a product was produced
+  help                 show this help
+  quit                 leave the explorer
+";
+
+/// An interactive session walking a resolved graph, one command per line
+pub struct Explorer<'a> {
+    graph: &'a GraphSnapshot,
+    install_root: PathBuf,
+    version: String,
+    focus: String,
+    expanded: BTreeSet<String>,
+}
+
+fn short_sha(sha: &str) -> &str {
+    &sha[..sha.len().min(8)]
+}
+
+impl<'a> Explorer<'a> {
+    /// Explore graph, finding build logs and provenance of installs of
+    /// version under install_root. Only the root starts out expanded.
+    pub fn new(graph: &'a GraphSnapshot, install_root: &Path, version: &str) -> Explorer<'a> {
+        let mut expanded = BTreeSet::new();
+        expanded.insert(graph.root.clone());
+        Explorer {
+            graph,
+            install_root: install_root.to_path_buf(),
+            version: version.to_string(),
+            focus: graph.root.clone(),
+            expanded,
+        }
+    }
+
+    fn dependencies(&self, product: &str) -> Vec<&'a String> {
+        self.graph
+            .edges
+            .iter()
+            .filter(|(from, _)| from == product)
+            .map(|(_, to)| to)
+            .collect()
+    }
+
+    fn dependents(&self, product: &str) -> Vec<&'a String> {
+        self.graph
+            .edges
+            .iter()
+            .filter(|(_, to)| to == product)
+            .map(|(from, _)| from)
+            .collect()
+    }
+
+    /// The name of a product in the graph, or an error for anything else
+    fn node<'b>(&self, name: Option<&'b str>) -> Result<&'b str, String> {
+        let name = name.ok_or("Give the name of a product")?;
+        match self.graph.nodes.contains_key(name) {
+            true => Ok(name),
+            false => Err(format!("{} is not in the graph", name)),
+        }
+    }
+
+    fn tree_lines(&self, product: &str, depth: usize, path: &mut Vec<String>, out: &mut String) {
+        let deps = self.dependencies(product);
+        let open = self.expanded.contains(product);
+        let marker = match (deps.is_empty(), open) {
+            (true, _) => ' ',
+            (false, true) => '-',
+            (false, false) => '+',
+        };
+        let sha = self
+            .graph
+            .nodes
+            .get(product)
+            .map(|n| short_sha(&n.sha))
+            .unwrap_or_default();
+        out.push_str(&format!(
+            "{}{} {} {}\n",
+            "  ".repeat(depth),
+            marker,
+            product,
+            sha
+        ));
+        // a cycle is shown once rather than followed forever
+        if !open || path.iter().any(|p| p == product) {
+            return;
+        }
+        path.push(product.to_string());
+        for dep in deps {
+            self.tree_lines(dep, depth + 1, path, out);
+        }
+        path.pop();
+    }
+
+    pub fn tree(&self) -> String {
+        let mut out = String::new();
+        self.tree_lines(&self.focus, 0, &mut vec![], &mut out);
+        out
+    }
+
+    fn show(&self, product: &str) -> String {
+        let node = &self.graph.nodes[product];
+        let names = |list: Vec<&String>| match list.is_empty() {
+            true => "none".to_string(),
+            false => list
+                .iter()
+                .map(|s| s.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+        };
+        format!(
+            "{}\n  sha: {}\n  id: {}\n  dependencies: {}\n  dependents: {}\n",
+            product,
+            node.sha,
+            node.id,
+            names(self.dependencies(product)),
+            names(self.dependents(product))
+        )
+    }
+
+    fn log(&self, product: &str) -> Result<String, String> {
+        let path = log_tail::live_log_path(&self.install_root, product, &self.version);
+        if !path.exists() {
+            return Err(format!(
+                "{} has no build log at {}",
+                product,
+                path.display()
+            ));
+        }
+        // hand the log to a pager when there is one, as logs run long
+        if let Ok(pager) = std::env::var("PAGER") {
+            std::process::Command::new(&pager)
+                .arg(&path)
+                .status()
+                .map_err(|e| format!("Could not run {}: {}", pager, e))?;
+            return Ok(String::new());
+        }
+        std::fs::read(&path)
+            .map(|log| String::from_utf8_lossy(&log).to_string())
+            .map_err(|e| format!("Could not read {}: {}", path.display(), e))
+    }
+
+    fn provenance(&self, product: &str) -> Result<String, String> {
+        let id = &self.graph.nodes[product].id;
+        let dir = Provenance::find_install(&self.install_root, product, id)
+            .ok_or(format!("There is no install of {} with id {}", product, id))?;
+        let mut out = String::new();
+        yaml_rust::YamlEmitter::new(&mut out)
+            .dump(&Provenance::read(&dir)?.to_yaml())
+            .map_err(|e| format!("Could not show provenance: {:?}", e))?;
+        out.push('\n');
+        Ok(out)
+    }
+
+    fn set_expanded(&mut self, name: Option<&str>, open: bool) -> Result<String, String> {
+        match name {
+            Some("all") if open => self.expanded = self.graph.nodes.keys().cloned().collect(),
+            Some("all") => self.expanded.clear(),
+            _ => {
+                let product = self.node(name)?.to_string();
+                match open {
+                    true => self.expanded.insert(product),
+                    false => self.expanded.remove(&product),
+                };
+            }
+        }
+        Ok(self.tree())
+    }
+
+    /// Carry out one command, returning what to print, or None once the
+    /// session should end
+    pub fn handle(&mut self, line: &str) -> Option<Result<String, String>> {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        let name = words.next();
+        let result = match command {
+            "" => Ok(String::new()),
+            "quit" | "exit" | "q" => return None,
+            "help" | "?" => Ok(HELP.to_string()),
+            "tree" => Ok(self.tree()),
+            "expand" => self.set_expanded(name, true),
+            "collapse" => self.set_expanded(name, false),
+            "show" => self.node(name).map(|n| self.show(n)),
+            "cd" => self.node(name).map(|n| {
+                self.focus = n.to_string();
+                self.expanded.insert(n.to_string());
+                self.tree()
+            }),
+            "top" => {
+                self.focus = self.graph.root.clone();
+                Ok(self.tree())
+            }
+            "dependents" => self.node(name).map(|n| {
+                self.dependents(n)
+                    .iter()
+                    .map(|d| format!("{}\n", d))
+                    .collect()
+            }),
+            "log" => self.node(name).and_then(|n| self.log(n)),
+            "provenance" => self.node(name).and_then(|n| self.provenance(n)),
+            _ => Err(format!("Unknown command {}, try help", command)),
+        };
+        Some(result)
+    }
+
+    /// Read commands from input until it ends or the session is quit
+    pub fn run<R: BufRead, W: Write>(&mut self, input: R, mut output: W) -> Result<(), String> {
+        let io_error = |e: std::io::Error| format!("{}", e);
+        write!(output, "{}explore> ", self.tree()).map_err(io_error)?;
+        output.flush().map_err(io_error)?;
+        for line in input.lines() {
+            let line = line.map_err(io_error)?;
+            match self.handle(&line) {
+                None => break,
+                Some(Ok(text)) => write!(output, "{}", text).map_err(io_error)?,
+                Some(Err(e)) => writeln!(output, "{}", e).map_err(io_error)?,
+            }
+            write!(output, "explore> ").map_err(io_error)?;
+            output.flush().map_err(io_error)?;
+        }
+        Ok(())
+    }
+}
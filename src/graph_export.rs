@@ -0,0 +1,117 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+/// How a graph is written out
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GraphFormat {
+    /// The graphviz dot language, for visualizing
+    Dot,
+    /// The json form of a snapshot, for graph-diff, bisect, and other tools
+    Json,
+}
+
+impl GraphFormat {
+    pub fn from_name(name: &str) -> Result<GraphFormat, String> {
+        match name {
+            "dot" => Ok(GraphFormat::Dot),
+            "json" => Ok(GraphFormat::Json),
+            _ => Err(format!("Unknown graph format {}, use dot or json", name)),
+        }
+    }
+}
+
+/// A resolved product in a graph snapshot
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotNode {
+    pub sha: String,
+    pub id: String,
+}
+
+/// A serializable copy of a resolved dependency graph, which can be saved and
+/// compared against the graph of a later run
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct GraphSnapshot {
+    pub root: String,
+    pub nodes: BTreeMap<String, SnapshotNode>,
+    /// (product, dependency) pairs
+    pub edges: BTreeSet<(String, String)>,
+}
+
+impl GraphSnapshot {
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let text = self.to_json()?;
+        std::fs::write(path, text).map_err(|e| format!("Could not write {}: {}", path.display(), e))
+    }
+
+    pub fn to_json(&self) -> Result<String, String> {
+        serde_json::to_string_pretty(self).map_err(|e| format!("Could not serialize graph: {}", e))
+    }
+
+    pub fn render(&self, format: GraphFormat) -> Result<String, String> {
+        match format {
+            GraphFormat::Dot => Ok(self.to_dot()),
+            GraphFormat::Json => self.to_json(),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<GraphSnapshot, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        serde_json::from_str(&text)
+            .map_err(|e| format!("Could not parse graph {}: {}", path.display(), e))
+    }
+
+    /// Render the graph in the graphviz dot language
+    pub fn to_dot(&self) -> String {
+        // a graph differs from itself nowhere, so nothing is highlighted
+        diff_to_dot(self, self)
+    }
+}
+
+fn short_sha(sha: &str) -> &str {
+    &sha[..sha.len().min(8)]
+}
+
+/// Render the difference between two graphs in the graphviz dot language.
+/// Added products and dependencies are green, removed ones red and dashed,
+/// and products whose sha changed are orange and labeled with both shas.
+pub fn diff_to_dot(old: &GraphSnapshot, new: &GraphSnapshot) -> String {
+    let mut out = String::new();
+    out.push_str("digraph regenerate_diff {\n");
+    out.push_str("    node [shape=box];\n");
+    let names: BTreeSet<&String> = old.nodes.keys().chain(new.nodes.keys()).collect();
+    for name in names {
+        let attributes = match (old.nodes.get(name), new.nodes.get(name)) {
+            (None, Some(n)) => format!(
+                "label=\"{}\\n{}\", color=green, fontcolor=green",
+                name,
+                short_sha(&n.sha)
+            ),
+            (Some(o), None) => format!(
+                "label=\"{}\\n{}\", color=red, fontcolor=red, style=dashed",
+                name,
+                short_sha(&o.sha)
+            ),
+            (Some(o), Some(n)) if o.sha != n.sha => format!(
+                "label=\"{}\\n{} -> {}\", color=orange, fontcolor=orange",
+                name,
+                short_sha(&o.sha),
+                short_sha(&n.sha)
+            ),
+            (_, Some(n)) => format!("label=\"{}\\n{}\"", name, short_sha(&n.sha)),
+            (None, None) => continue,
+        };
+        out.push_str(&format!("    \"{}\" [{}];\n", name, attributes));
+    }
+    for edge in old.edges.union(&new.edges) {
+        let style = match (old.edges.contains(edge), new.edges.contains(edge)) {
+            (false, true) => " [color=green]",
+            (true, false) => " [color=red, style=dashed]",
+            _ => "",
+        };
+        out.push_str(&format!("    \"{}\" -> \"{}\"{};\n", edge.0, edge.1, style));
+    }
+    out.push_str("}\n");
+    out
+}
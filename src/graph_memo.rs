@@ -0,0 +1,110 @@
+use log::debug;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+#[derive(Default)]
+struct MemoTables {
+    // post order dependency lists, ending with the product itself
+    subtrees: HashMap<String, Rc<Vec<String>>>,
+    // the contribution of a single node to the ids of products above it
+    node_hashes: HashMap<String, String>,
+    ids: HashMap<String, String>,
+    hits: u64,
+    misses: u64,
+}
+
+/// Results of walks over the dependency graph, kept for the duration of a run
+/// so that computing ids and dependency lists for every product of a deep
+/// stack does not walk the same subtrees over and over. Everything is dropped
+/// whenever the graph, or a checkout it was built from, changes.
+#[derive(Default)]
+pub struct GraphMemo {
+    tables: RefCell<MemoTables>,
+}
+
+impl GraphMemo {
+    pub fn new() -> GraphMemo {
+        GraphMemo::default()
+    }
+
+    pub fn invalidate(&self) {
+        let mut tables = self.tables.borrow_mut();
+        if !tables.subtrees.is_empty() || !tables.ids.is_empty() {
+            debug!("Graph changed, dropping memoized traversals");
+        }
+        tables.subtrees.clear();
+        tables.node_hashes.clear();
+        tables.ids.clear();
+    }
+
+    pub fn subtree<F>(&self, product: &str, compute: F) -> Result<Rc<Vec<String>>, String>
+    where
+        F: FnOnce() -> Result<Vec<String>, String>,
+    {
+        let found = self.tables.borrow_mut().hit_subtree(product);
+        if let Some(found) = found {
+            return Ok(found);
+        }
+        let subtree = Rc::new(compute()?);
+        let mut tables = self.tables.borrow_mut();
+        tables.misses += 1;
+        tables
+            .subtrees
+            .insert(product.to_string(), Rc::clone(&subtree));
+        Ok(subtree)
+    }
+
+    pub fn node_hash<F>(&self, product: &str, compute: F) -> Result<String, String>
+    where
+        F: FnOnce() -> Result<String, String>,
+    {
+        self.memoize(product, |t| &mut t.node_hashes, compute)
+    }
+
+    pub fn id<F>(&self, product: &str, compute: F) -> Result<String, String>
+    where
+        F: FnOnce() -> Result<String, String>,
+    {
+        self.memoize(product, |t| &mut t.ids, compute)
+    }
+
+    /// How many lookups were answered from the memo, and how many had to be
+    /// computed
+    pub fn stats(&self) -> (u64, u64) {
+        let tables = self.tables.borrow();
+        (tables.hits, tables.misses)
+    }
+
+    fn memoize<S, F>(&self, product: &str, select: S, compute: F) -> Result<String, String>
+    where
+        S: Fn(&mut MemoTables) -> &mut HashMap<String, String>,
+        F: FnOnce() -> Result<String, String>,
+    {
+        {
+            let mut tables = self.tables.borrow_mut();
+            let found = select(&mut tables).get(product).cloned();
+            if let Some(found) = found {
+                tables.hits += 1;
+                return Ok(found);
+            }
+        }
+        // the borrow is released while computing, as computing a value may
+        // itself consult the memo
+        let value = compute()?;
+        let mut tables = self.tables.borrow_mut();
+        tables.misses += 1;
+        select(&mut tables).insert(product.to_string(), value.clone());
+        Ok(value)
+    }
+}
+
+impl MemoTables {
+    fn hit_subtree(&mut self, product: &str) -> Option<Rc<Vec<String>>> {
+        let found = self.subtrees.get(product).cloned();
+        if found.is_some() {
+            self.hits += 1;
+        }
+        found
+    }
+}
@@ -0,0 +1,136 @@
+use log::debug;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use yaml_rust::yaml::{Hash, Yaml};
+
+/// What is remembered about a product across runs
+#[derive(Clone, Debug, Default)]
+pub struct ProductHistory {
+    /// Build verbs which have needed retries to succeed
+    pub flaky_verbs: Vec<String>,
+    /// How long the last source build took, in seconds
+    pub build_seconds: Option<u64>,
+    /// Size of the last installation, in bytes
+    pub install_bytes: Option<u64>,
+}
+
+/// Per workspace record of past builds, stored as yaml in the install root
+pub struct History {
+    path: PathBuf,
+    products: BTreeMap<String, ProductHistory>,
+}
+
+impl History {
+    /// Open the history of the workspace rooted at install_root, starting a
+    /// new one if none exists yet
+    pub fn open(install_root: &Path) -> Result<History, String> {
+        let mut path = PathBuf::from(install_root);
+        path.push(".regenerate");
+        path.push("history.yaml");
+        let mut products = BTreeMap::new();
+        if path.exists() {
+            debug!("Loading build history from {}", path.display());
+            let text = std::fs::read_to_string(&path)
+                .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+            let docs = yaml_rust::YamlLoader::load_from_str(&text)
+                .map_err(|e| format!("Could not parse {}: {}", path.display(), e))?;
+            if let Some(Yaml::Hash(hash)) = docs.get(0) {
+                for (name, entry) in hash.iter() {
+                    let name = match name.as_str() {
+                        Some(n) => n.to_string(),
+                        None => continue,
+                    };
+                    let flaky_verbs = entry["flaky_verbs"]
+                        .as_vec()
+                        .map(|v| {
+                            v.iter()
+                                .filter_map(|x| x.as_str().map(|s| s.to_string()))
+                                .collect()
+                        })
+                        .unwrap_or_default();
+                    let build_seconds = entry["build_seconds"].as_i64().map(|v| v as u64);
+                    let install_bytes = entry["install_bytes"].as_i64().map(|v| v as u64);
+                    products.insert(
+                        name,
+                        ProductHistory {
+                            flaky_verbs,
+                            build_seconds,
+                            install_bytes,
+                        },
+                    );
+                }
+            }
+        }
+        Ok(History { path, products })
+    }
+
+    pub fn get(&self, product: &str) -> Option<&ProductHistory> {
+        self.products.get(product)
+    }
+
+    /// Record that a build verb of a product needed to be retried
+    pub fn mark_flaky(&mut self, product: &str, verb: &str) {
+        let entry = self
+            .products
+            .entry(product.to_string())
+            .or_insert_with(ProductHistory::default);
+        if !entry.flaky_verbs.iter().any(|v| v == verb) {
+            entry.flaky_verbs.push(verb.to_string());
+        }
+    }
+
+    /// Record how long a source build of a product took and how large the
+    /// result was, for estimating future builds
+    pub fn record_build(&mut self, product: &str, duration: Duration, install_bytes: u64) {
+        let entry = self
+            .products
+            .entry(product.to_string())
+            .or_insert_with(ProductHistory::default);
+        entry.build_seconds = Some(duration.as_secs());
+        entry.install_bytes = Some(install_bytes);
+    }
+
+    fn to_yaml(&self) -> Yaml {
+        let mut hash = Hash::new();
+        for (name, entry) in self.products.iter() {
+            let mut product = Hash::new();
+            product.insert(
+                Yaml::String("flaky_verbs".to_string()),
+                Yaml::Array(
+                    entry
+                        .flaky_verbs
+                        .iter()
+                        .map(|v| Yaml::String(v.clone()))
+                        .collect(),
+                ),
+            );
+            if let Some(seconds) = entry.build_seconds {
+                product.insert(
+                    Yaml::String("build_seconds".to_string()),
+                    Yaml::Integer(seconds as i64),
+                );
+            }
+            if let Some(bytes) = entry.install_bytes {
+                product.insert(
+                    Yaml::String("install_bytes".to_string()),
+                    Yaml::Integer(bytes as i64),
+                );
+            }
+            hash.insert(Yaml::String(name.clone()), Yaml::Hash(product));
+        }
+        Yaml::Hash(hash)
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| format!("{}", e))?;
+        }
+        let mut out = String::new();
+        yaml_rust::YamlEmitter::new(&mut out)
+            .dump(&self.to_yaml())
+            .map_err(|e| format!("Could not serialize history: {:?}", e))?;
+        std::fs::write(&self.path, out)
+            .map_err(|e| format!("Could not write {}: {}", self.path.display(), e))
+    }
+}
@@ -0,0 +1,85 @@
+use crate::safety;
+use log::debug;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use yaml_rust::yaml::{Hash, Yaml};
+
+/// Products pinned to a particular revision in a workspace. A held product is
+/// always resolved to its pin, whatever branches a run asks for, until it is
+/// released again.
+pub struct Holds {
+    path: PathBuf,
+    pins: BTreeMap<String, String>,
+}
+
+/// Split a product@revision hold specification, where the revision may be a
+/// sha or any other name git can resolve, such as a version tag
+pub fn parse_spec(spec: &str) -> Result<(String, String), String> {
+    let pos = spec
+        .find('@')
+        .ok_or(format!("{} is not of the form product@revision", spec))?;
+    let (product, pin) = (&spec[..pos], &spec[pos + 1..]);
+    safety::validate_product_name(product)?;
+    if pin.is_empty() || pin.starts_with('-') {
+        return Err(format!("{} is not a valid revision to hold at", pin));
+    }
+    Ok((product.to_string(), pin.to_string()))
+}
+
+impl Holds {
+    /// Open the holds of the workspace rooted at install_root
+    pub fn open(install_root: &Path) -> Result<Holds, String> {
+        let mut path = PathBuf::from(install_root);
+        path.push(".regenerate");
+        path.push("holds.yaml");
+        let mut pins = BTreeMap::new();
+        if path.exists() {
+            debug!("Loading holds from {}", path.display());
+            let text = std::fs::read_to_string(&path)
+                .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+            let docs = yaml_rust::YamlLoader::load_from_str(&text)
+                .map_err(|e| format!("Could not parse {}: {}", path.display(), e))?;
+            if let Some(Yaml::Hash(hash)) = docs.get(0) {
+                for (name, pin) in hash.iter() {
+                    if let (Some(name), Some(pin)) = (name.as_str(), pin.as_str()) {
+                        pins.insert(name.to_string(), pin.to_string());
+                    }
+                }
+            }
+        }
+        Ok(Holds { path, pins })
+    }
+
+    pub fn get(&self, product: &str) -> Option<&String> {
+        self.pins.get(product)
+    }
+
+    pub fn all(&self) -> &BTreeMap<String, String> {
+        &self.pins
+    }
+
+    pub fn hold(&mut self, product: &str, pin: &str) {
+        self.pins.insert(product.to_string(), pin.to_string());
+    }
+
+    /// Release a held product, returning the pin it was held at
+    pub fn unhold(&mut self, product: &str) -> Option<String> {
+        self.pins.remove(product)
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| format!("{}", e))?;
+        }
+        let mut hash = Hash::new();
+        for (name, pin) in self.pins.iter() {
+            hash.insert(Yaml::String(name.clone()), Yaml::String(pin.clone()));
+        }
+        let mut out = String::new();
+        yaml_rust::YamlEmitter::new(&mut out)
+            .dump(&Yaml::Hash(hash))
+            .map_err(|e| format!("Could not serialize holds: {:?}", e))?;
+        std::fs::write(&self.path, out)
+            .map_err(|e| format!("Could not write {}: {}", self.path.display(), e))
+    }
+}
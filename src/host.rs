@@ -0,0 +1,188 @@
+use crate::toolchain;
+use fnv::FnvHashMap;
+use log::debug;
+use std::process::Command;
+use yaml_rust::yaml::{Hash, Yaml};
+
+/// Instruction set extensions of each x86-64 microarchitecture level above
+/// the baseline, as named in /proc/cpuinfo
+const X86_64_LEVELS: [(&str, &[&str]); 3] = [
+    (
+        "x86-64-v2",
+        &["cx16", "lahf_lm", "popcnt", "sse4_1", "sse4_2", "ssse3"],
+    ),
+    (
+        "x86-64-v3",
+        &[
+            "avx", "avx2", "bmi1", "bmi2", "f16c", "fma", "abm", "movbe", "xsave",
+        ],
+    ),
+    (
+        "x86-64-v4",
+        &["avx512f", "avx512bw", "avx512cd", "avx512dq", "avx512vl"],
+    ),
+];
+
+/// What about the machine a product was built on decides whether its install
+/// runs elsewhere. Anything which could not be found out is None.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HostFingerprint {
+    /// Operating system and architecture, such as linux-x86_64
+    pub os: String,
+    /// Version of the GNU C library, on systems which use it
+    pub glibc: Option<String>,
+    /// Microarchitecture level the CPU supports, such as x86-64-v3
+    pub cpu: Option<String>,
+    /// Target triple of the C compiler, such as x86_64-pc-linux-gnu
+    pub triple: Option<String>,
+}
+
+fn output_of(program: &str, args: &[&str], env: &FnvHashMap<String, String>) -> Option<String> {
+    let program = toolchain::find_program(program, env)?;
+    let output = Command::new(program).args(args).envs(env).output().ok()?;
+    match output.status.success() {
+        true => Some(String::from_utf8_lossy(&output.stdout).trim().to_string()),
+        false => None,
+    }
+}
+
+/// The glibc version as getconf reports it, "glibc 2.31"
+fn glibc_version() -> Option<String> {
+    output_of("getconf", &["GNU_LIBC_VERSION"], &FnvHashMap::default())?
+        .split_whitespace()
+        .nth(1)
+        .map(|v| v.to_string())
+}
+
+/// The highest x86-64 level all of whose extensions the CPU has
+fn cpu_level() -> Option<String> {
+    if std::env::consts::ARCH != "x86_64" {
+        return None;
+    }
+    let cpuinfo = std::fs::read_to_string("/proc/cpuinfo").ok()?;
+    let flags: Vec<&str> = cpuinfo
+        .lines()
+        .find(|l| l.starts_with("flags"))?
+        .splitn(2, ':')
+        .nth(1)?
+        .split_whitespace()
+        .collect();
+    let mut level = "x86-64-v1";
+    for (name, required) in X86_64_LEVELS.iter() {
+        if !required.iter().all(|r| flags.contains(r)) {
+            break;
+        }
+        level = *name;
+    }
+    Some(level.to_string())
+}
+
+/// Dotted version numbers as lists of numbers, so 2.9 sorts before 2.31
+fn version_key(version: &str) -> Vec<u64> {
+    version
+        .split('.')
+        .map(|part| {
+            part.chars()
+                .take_while(|c| c.is_ascii_digit())
+                .collect::<String>()
+                .parse()
+                .unwrap_or(0)
+        })
+        .collect()
+}
+
+fn level_key(level: &str) -> u64 {
+    level
+        .rsplit('v')
+        .next()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(0)
+}
+
+impl HostFingerprint {
+    /// Fingerprint the machine this runs on, with the compiler CC names or cc
+    /// in the build environment env, over the environment of this process
+    pub fn probe(env: &FnvHashMap<String, String>) -> HostFingerprint {
+        let compiler = env
+            .get("CC")
+            .cloned()
+            .or_else(|| std::env::var("CC").ok())
+            .and_then(|c| c.split_whitespace().next().map(|p| p.to_string()))
+            .unwrap_or_else(|| "cc".to_string());
+        let fingerprint = HostFingerprint {
+            os: format!("{}-{}", std::env::consts::OS, std::env::consts::ARCH),
+            glibc: glibc_version(),
+            cpu: cpu_level(),
+            triple: output_of(&compiler, &["-dumpmachine"], env),
+        };
+        debug!("This host is {:?}", fingerprint);
+        fingerprint
+    }
+
+    /// Reasons an install built on the host of self may not run on current,
+    /// none when it can be reused there
+    pub fn incompatibilities(&self, current: &HostFingerprint) -> Vec<String> {
+        let mut problems = vec![];
+        if self.os != current.os {
+            problems.push(format!(
+                "it was built on {}, this host is {}",
+                self.os, current.os
+            ));
+        }
+        if let (Some(built), Some(here)) = (self.triple.as_ref(), current.triple.as_ref()) {
+            if built != here {
+                problems.push(format!(
+                    "it was compiled for {}, the compiler here targets {}",
+                    built, here
+                ));
+            }
+        }
+        if let (Some(built), Some(here)) = (self.glibc.as_ref(), current.glibc.as_ref()) {
+            if version_key(built) > version_key(here) {
+                problems.push(format!(
+                    "it was built against glibc {}, newer than glibc {} here",
+                    built, here
+                ));
+            }
+        }
+        if let (Some(built), Some(here)) = (self.cpu.as_ref(), current.cpu.as_ref()) {
+            if level_key(built) > level_key(here) {
+                problems.push(format!(
+                    "it was built on a {} CPU, this one is only {}",
+                    built, here
+                ));
+            }
+        }
+        problems
+    }
+
+    pub fn to_yaml(&self) -> Yaml {
+        let mut hash = Hash::new();
+        let fields = [
+            ("os", Some(&self.os)),
+            ("glibc", self.glibc.as_ref()),
+            ("cpu", self.cpu.as_ref()),
+            ("triple", self.triple.as_ref()),
+        ];
+        for (key, value) in fields.iter() {
+            if let Some(value) = value {
+                hash.insert(
+                    Yaml::String(key.to_string()),
+                    Yaml::String(value.to_string()),
+                );
+            }
+        }
+        Yaml::Hash(hash)
+    }
+
+    /// Read a fingerprint written by to_yaml, None if it has no os
+    pub fn from_yaml(yaml: &Yaml) -> Option<HostFingerprint> {
+        let get = |key: &str| yaml[key].as_str().map(|s| s.to_string());
+        Some(HostFingerprint {
+            os: get("os")?,
+            glibc: get("glibc"),
+            cpu: get("cpu"),
+            triple: get("triple"),
+        })
+    }
+}
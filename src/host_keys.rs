@@ -0,0 +1,265 @@
+use crypto::digest::Digest;
+use crypto::md5::Md5;
+use crypto::sha1::Sha1;
+use log::{debug, info};
+use std::cell::RefCell;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// How the ssh host keys of git servers are verified. The key of a host is
+/// recorded in the workspace the first time it is seen, and every later
+/// connection must present the same key. In strict mode hosts must already be
+/// recorded. Connections made in process (libgit2) and through the system
+/// ssh share one known_hosts file, so a host trusted by either is trusted by
+/// both.
+#[derive(Clone, Debug)]
+pub struct HostKeyPolicy {
+    /// known_hosts file of the workspace, in the format of OpenSSH
+    pub known_hosts: PathBuf,
+    pub strict: bool,
+}
+
+/// Determine if a git url is reached over ssh, either url style or scp style
+/// (user@host:path)
+pub fn is_ssh_url(url: &str) -> bool {
+    if url.starts_with("ssh://") || url.starts_with("git+ssh://") || url.starts_with("ssh+git://") {
+        return true;
+    }
+    if url.contains("://") {
+        return false;
+    }
+    // scp style has a colon before any slash
+    match (url.find(':'), url.find('/')) {
+        (Some(colon), Some(slash)) => colon < slash,
+        (Some(_), None) => true,
+        _ => false,
+    }
+}
+
+/// The port an ssh url names, when it is not the default one
+fn ssh_port(url: &str) -> Option<u16> {
+    let rest = &url[url.find("://")? + 3..];
+    let authority = rest.split('/').next()?;
+    let host = authority.rsplit('@').next()?;
+    let port = host.rsplit(':').next()?;
+    if port == host {
+        return None;
+    }
+    port.parse().ok().filter(|p| *p != 22)
+}
+
+/// How a host is named in known_hosts, with its port if it is not 22
+fn known_hosts_name(host: &str, port: Option<u16>) -> String {
+    match port {
+        Some(port) => format!("[{}]:{}", host, port),
+        None => host.to_string(),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Fingerprint of the host key presented in a certificate check, None when
+/// the certificate is not an ssh host key
+pub fn fingerprint(cert: &git2::Cert) -> Option<String> {
+    let hostkey = cert.as_hostkey()?;
+    if let Some(hash) = hostkey.hash_sha1() {
+        return Some(format!("sha1:{}", hex(hash)));
+    }
+    hostkey.hash_md5().map(|hash| format!("md5:{}", hex(hash)))
+}
+
+/// Whether a key from known_hosts, base64 encoded as it is there, is the
+/// key with the given fingerprint
+fn key_matches(encoded: &str, fingerprint: &str) -> bool {
+    let blob = match base64::decode(encoded) {
+        Ok(b) => b,
+        Err(_) => return false,
+    };
+    let mut out = [0u8; 20];
+    if fingerprint.starts_with("sha1:") {
+        let mut hasher = Sha1::new();
+        hasher.input(&blob);
+        hasher.result(&mut out);
+        return fingerprint[5..] == hex(&out[..20]);
+    }
+    if fingerprint.starts_with("md5:") {
+        let mut hasher = Md5::new();
+        hasher.input(&blob);
+        hasher.result(&mut out);
+        return fingerprint[4..] == hex(&out[..16]);
+    }
+    false
+}
+
+/// A host key line of a known_hosts file, as (host names, key type, key)
+fn parse_line(line: &str) -> Option<(Vec<&str>, &str, &str)> {
+    let line = line.trim();
+    // markers and hashed names are not written by regenerate or by the
+    // system ssh with the options it is run with
+    if line.is_empty() || line.starts_with('#') || line.starts_with('@') || line.starts_with('|') {
+        return None;
+    }
+    let mut fields = line.split_whitespace();
+    let names = fields.next()?.split(',').collect();
+    let key_type = fields.next()?;
+    let key = fields.next()?;
+    Some((names, key_type, key))
+}
+
+impl HostKeyPolicy {
+    /// The policy of the workspace rooted at install_root
+    pub fn new(install_root: &Path, strict: bool) -> HostKeyPolicy {
+        let mut dir = PathBuf::from(install_root);
+        dir.push(".regenerate");
+        HostKeyPolicy {
+            known_hosts: dir.join("ssh_known_hosts"),
+            strict,
+        }
+    }
+
+    /// The keys recorded for a host, base64 encoded
+    fn load(&self, name: &str) -> Result<Vec<String>, String> {
+        if !self.known_hosts.exists() {
+            return Ok(vec![]);
+        }
+        let text = std::fs::read_to_string(&self.known_hosts)
+            .map_err(|e| format!("Could not read {}: {}", self.known_hosts.display(), e))?;
+        Ok(text
+            .lines()
+            .filter_map(parse_line)
+            .filter(|(names, _, _)| names.contains(&name))
+            .map(|(_, _, key)| key.to_string())
+            .collect())
+    }
+
+    /// Find the key a host presented with ssh-keyscan, as ssh writes it to
+    /// known_hosts, since libgit2 only gives its fingerprint
+    fn scan(&self, host: &str, port: Option<u16>, fingerprint: &str) -> Result<String, String> {
+        let mut command = std::process::Command::new("ssh-keyscan");
+        if let Some(port) = port {
+            command.arg("-p").arg(port.to_string());
+        }
+        let output = command
+            .arg("--")
+            .arg(host)
+            .stderr(std::process::Stdio::null())
+            .output()
+            .map_err(|e| {
+                format!(
+                    "Could not run ssh-keyscan to record the key of {}: {}",
+                    host, e
+                )
+            })?;
+        let text = String::from_utf8_lossy(&output.stdout);
+        text.lines()
+            .filter_map(parse_line)
+            .find(|(_, _, key)| key_matches(key, fingerprint))
+            .map(|(_, key_type, key)| format!("{} {}", key_type, key))
+            .ok_or_else(|| {
+                format!(
+                    "ssh-keyscan did not find the key {} {} presented, so it can not be \
+                     recorded in {}",
+                    fingerprint,
+                    host,
+                    self.known_hosts.display()
+                )
+            })
+    }
+
+    fn record(&self, name: &str, key: &str) -> Result<(), String> {
+        self.prepare_ssh()?;
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.known_hosts)
+            .map_err(|e| format!("Could not open {}: {}", self.known_hosts.display(), e))?;
+        file.write_all(format!("{} {}\n", name, key).as_bytes())
+            .map_err(|e| format!("Could not write {}: {}", self.known_hosts.display(), e))
+    }
+
+    /// Verify the key a host presented, recording it if the host is new and
+    /// the policy is not strict
+    pub fn check(&self, host: &str, port: Option<u16>, fingerprint: &str) -> Result<(), String> {
+        let name = known_hosts_name(host, port);
+        let known = self.load(&name)?;
+        if known.iter().any(|key| key_matches(key, fingerprint)) {
+            debug!("Host key of {} matches {}", name, fingerprint);
+            return Ok(());
+        }
+        if !known.is_empty() {
+            return Err(format!(
+                "The host key of {} has changed, it is now {}. This may mean someone \
+                 is intercepting the connection; if the change is expected run \
+                 ssh-keygen -R '{}' -f '{}'",
+                name,
+                fingerprint,
+                name,
+                self.known_hosts.display()
+            ));
+        }
+        if self.strict {
+            return Err(format!(
+                "{} is not a known host and strict host key checking is on, its key \
+                 is {}; add it to {} to trust it",
+                name,
+                fingerprint,
+                self.known_hosts.display()
+            ));
+        }
+        let key = self.scan(host, port, fingerprint)?;
+        info!("Trusting new host {} with key {}", name, fingerprint);
+        self.record(&name, &key)
+    }
+
+    /// Add host key verification to the callbacks of a connection to url,
+    /// recording why a connection was refused in failure. Only ssh urls are
+    /// checked, tls certificates are left to the usual verification.
+    pub fn attach<'a>(
+        &'a self,
+        callbacks: &mut git2::RemoteCallbacks<'a>,
+        url: &str,
+        failure: &'a RefCell<Option<String>>,
+    ) {
+        if !is_ssh_url(url) {
+            return;
+        }
+        let port = ssh_port(url);
+        callbacks.certificate_check(move |cert, host| {
+            let result = match fingerprint(cert) {
+                Some(key) => self.check(host, port, &key),
+                None => Err(format!("{} did not present an ssh host key", host)),
+            };
+            match result {
+                Ok(()) => true,
+                Err(e) => {
+                    *failure.borrow_mut() = Some(e);
+                    false
+                }
+            }
+        });
+    }
+
+    /// Make sure the system ssh can record new hosts in the known_hosts file
+    pub fn prepare_ssh(&self) -> Result<(), String> {
+        match self.known_hosts.parent() {
+            Some(parent) => std::fs::create_dir_all(parent)
+                .map_err(|e| format!("Could not create {}: {}", parent.display(), e)),
+            None => Ok(()),
+        }
+    }
+
+    /// Value of GIT_SSH_COMMAND making the system ssh apply the same policy,
+    /// writing names unhashed so in process connections can find them
+    pub fn ssh_command(&self) -> String {
+        format!(
+            "ssh -o UserKnownHostsFile='{}' -o HashKnownHosts=no -o StrictHostKeyChecking={}",
+            self.known_hosts.display(),
+            match self.strict {
+                true => "yes",
+                false => "accept-new",
+            }
+        )
+    }
+}
@@ -0,0 +1,121 @@
+use regex::Regex;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Identities an install may be known by beyond the content id regenerate
+/// works out from the graph, such as the build ids of another build system
+pub trait IdentityBackend {
+    /// Other identities an install of product is accepted under when looking
+    /// for one to reuse, tried in order once its content id is not found
+    fn accepted_ids(&self, product: &str, content_id: &str) -> Vec<String>;
+    /// An identity to record alongside the content id of a product built by
+    /// this run
+    fn build_id(&self, product: &str) -> Option<String>;
+}
+
+/// Identify installs by their content id alone, the default
+pub struct ContentIds;
+
+impl IdentityBackend for ContentIds {
+    fn accepted_ids(&self, _product: &str, _content_id: &str) -> Vec<String> {
+        vec![]
+    }
+
+    fn build_id(&self, _product: &str) -> Option<String> {
+        None
+    }
+}
+
+/// lsst_build style bNNNN build ids given by whatever drives the run, either
+/// one for the whole run or per product, recorded with what the run builds.
+/// An install declared with a build id is only accepted in place of a
+/// content id the driver has said that build id has, as a build id alone
+/// says nothing of what was built.
+#[derive(Default)]
+pub struct BuildIds {
+    run: Option<String>,
+    products: HashMap<String, String>,
+    // the build id accepted for each content id of each product
+    accepted: HashMap<String, HashMap<String, String>>,
+}
+
+fn validate(id: &str) -> Result<(), String> {
+    let pattern = Regex::new(r"^b[0-9]+$").map_err(|e| format!("{}", e))?;
+    match pattern.is_match(id) {
+        true => Ok(()),
+        false => Err(format!("{} is not a build id of the form bNNNN", id)),
+    }
+}
+
+impl BuildIds {
+    pub fn new(run: Option<&str>, products: HashMap<String, String>) -> Result<BuildIds, String> {
+        if let Some(id) = run {
+            validate(id)?;
+        }
+        for id in products.values() {
+            validate(id)?;
+        }
+        Ok(BuildIds {
+            run: run.map(|id| id.to_string()),
+            products,
+            accepted: HashMap::new(),
+        })
+    }
+
+    /// Accept the build ids of a map file, whose lines are
+    /// `PRODUCT CONTENT_ID bNNNN`, for installs of those products with those
+    /// content ids. Blank lines and # comments are skipped.
+    pub fn accept_from(&mut self, path: &Path) -> Result<(), String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [product, content_id, build_id] => {
+                    validate(build_id)?;
+                    self.accepted
+                        .entry(product.to_string())
+                        .or_insert_with(HashMap::new)
+                        .insert(content_id.to_string(), build_id.to_string());
+                }
+                _ => {
+                    return Err(format!(
+                        "Line {} of {} is not PRODUCT CONTENT_ID bNNNN",
+                        number + 1,
+                        path.display()
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Split a product=bNNNN specification
+    pub fn parse_spec(spec: &str) -> Result<(String, String), String> {
+        let pos = spec
+            .find('=')
+            .ok_or(format!("{} is not of the form product=bNNNN", spec))?;
+        let (product, id) = (&spec[..pos], &spec[pos + 1..]);
+        validate(id)?;
+        Ok((product.to_string(), id.to_string()))
+    }
+}
+
+impl IdentityBackend for BuildIds {
+    fn accepted_ids(&self, product: &str, content_id: &str) -> Vec<String> {
+        self.accepted
+            .get(product)
+            .and_then(|ids| ids.get(content_id))
+            .cloned()
+            .into_iter()
+            .collect()
+    }
+
+    fn build_id(&self, product: &str) -> Option<String> {
+        self.products.get(product).or(self.run.as_ref()).cloned()
+    }
+}
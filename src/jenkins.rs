@@ -0,0 +1,204 @@
+use crate::repo_wrapper::RefPin;
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// A product entry of the build manifest
+pub struct ManifestEntry {
+    pub product: String,
+    pub sha: String,
+    pub version: String,
+    pub dependencies: Vec<String>,
+    /// The product id the product was declared with
+    pub id: String,
+    /// What the repository map pinned the product to, if anything
+    pub pin: Option<RefPin>,
+}
+
+/// The manifest of a run exactly as lsst_build writes it, a BUILD= line, a
+/// header comment, and a line per product of its sha, version, and comma
+/// separated dependencies
+fn manifest_text(manifest_id: Option<&str>, entries: &[ManifestEntry]) -> String {
+    let mut out = String::new();
+    if let Some(id) = manifest_id {
+        out.push_str(&format!("BUILD={}\n", id));
+    }
+    out.push_str(&format!(
+        "# {:<28} {:<40} {:<24} {}\n",
+        "product", "SHA1", "Version", "Deps"
+    ));
+    for entry in entries.iter() {
+        let line = format!(
+            "{:<30} {:<40} {:<24} {}",
+            entry.product,
+            entry.sha,
+            entry.version,
+            entry.dependencies.join(",")
+        );
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+/// What lsst_build has no column for, the product id of each product and
+/// what the repository map pinned it to, as ref:, tag:, or commit: and the
+/// name, or - when it was not pinned
+fn ids_text(entries: &[ManifestEntry]) -> String {
+    let mut out = format!("# {:<28} {:<40} {}\n", "product", "Id", "Pin");
+    for entry in entries.iter() {
+        let pin = match entry.pin.as_ref() {
+            Some(pin) => format!("{}:{}", pin.kind(), pin.name()),
+            None => "-".to_string(),
+        };
+        out.push_str(&format!("{:<30} {:<40} {}\n", entry.product, entry.id, pin));
+    }
+    out
+}
+
+/// The file the ids of a run manifest written to path are kept in, beside it
+fn ids_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".ids");
+    path.with_file_name(name)
+}
+
+/// Write the manifest of a finished run to path in the lsst_build format, so
+/// eups distrib and lsst_build can read it, with the product id and pin of
+/// each product written beside it to the file given by ids_path
+pub fn write_run_manifest(
+    path: &Path,
+    manifest_id: Option<&str>,
+    entries: &[ManifestEntry],
+) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        if !parent.as_os_str().is_empty() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| format!("Could not create {}: {}", parent.display(), e))?;
+        }
+    }
+    std::fs::write(path, manifest_text(manifest_id, entries))
+        .map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+    let ids = ids_path(path);
+    std::fs::write(&ids, ids_text(entries))
+        .map_err(|e| format!("Could not write {}: {}", ids.display(), e))
+}
+
+/// Writes the status lines and manifest which lsst_build produces, so runs can
+/// be followed by CI jobs and dashboards written for it. Everything goes into
+/// one directory, events.log holding a line per BUILD, OK, or FAIL event and
+/// manifest.txt listing what the run was made of.
+pub struct BuildStream {
+    dir: PathBuf,
+    manifest_id: String,
+    log: Option<std::fs::File>,
+    started: HashMap<String, Instant>,
+}
+
+/// Take the next build number of a workspace, stored under its install root,
+/// as the manifest id bNNNN
+pub fn next_manifest_id(install_root: &Path) -> Result<String, String> {
+    let mut path = PathBuf::from(install_root);
+    path.push(".regenerate");
+    path.push("build_number");
+    let last: u64 = match std::fs::read_to_string(&path) {
+        Ok(text) => text
+            .trim()
+            .parse()
+            .map_err(|e| format!("Invalid build number in {}: {}", path.display(), e))?,
+        Err(_) => 0,
+    };
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| format!("{}", e))?;
+    }
+    std::fs::write(&path, format!("{}\n", last + 1))
+        .map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+    Ok(format!("b{}", last + 1))
+}
+
+impl BuildStream {
+    pub fn new(dir: &Path, manifest_id: &str) -> Result<BuildStream, String> {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("Could not create {}: {}", dir.display(), e))?;
+        let path = dir.join("events.log");
+        let log = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| format!("Could not open {}: {}", path.display(), e))?;
+        let mut stream = BuildStream {
+            dir: PathBuf::from(dir),
+            manifest_id: manifest_id.to_string(),
+            log: Some(log),
+            started: HashMap::new(),
+        };
+        stream.write_line(&format!("# BUILD ID: {}", manifest_id));
+        Ok(stream)
+    }
+
+    fn write_line(&mut self, line: &str) {
+        if let Some(log) = self.log.as_mut() {
+            if let Err(e) = writeln!(log, "{}", line).and_then(|_| log.flush()) {
+                // a broken log must not fail the build, stop writing to it
+                warn!(
+                    "Could not write build event, disabling the event log: {}",
+                    e
+                );
+                self.log = None;
+            }
+        }
+    }
+
+    fn event(&mut self, status: &str, product: &str, detail: &str) {
+        let line = format!(
+            "{} {} {:<5} {:>20}: {}",
+            time::now_utc().rfc3339(),
+            self.manifest_id,
+            status,
+            product,
+            detail
+        );
+        debug!("Build event {}", line);
+        self.write_line(&line);
+    }
+
+    pub fn started(&mut self, product: &str, version: &str) {
+        self.started.insert(product.to_string(), Instant::now());
+        self.event("BUILD", product, version);
+    }
+
+    fn elapsed(&self, product: &str) -> f64 {
+        self.started
+            .get(product)
+            .map(|s| {
+                let e = s.elapsed();
+                e.as_secs() as f64 + f64::from(e.subsec_millis()) / 1000.0
+            })
+            .unwrap_or(0.0)
+    }
+
+    pub fn succeeded(&mut self, product: &str) {
+        let detail = format!("ok ({:.1} sec).", self.elapsed(product));
+        self.event("OK", product, &detail);
+    }
+
+    pub fn failed(&mut self, product: &str) {
+        let detail = format!("ERROR ({:.1} sec).", self.elapsed(product));
+        self.event("FAIL", product, &detail);
+    }
+
+    /// Write manifest.txt, one line per product with its sha, version, and
+    /// comma separated dependencies
+    pub fn write_manifest(&self, entries: &[ManifestEntry]) -> Result<(), String> {
+        let out = manifest_text(Some(&self.manifest_id), entries);
+        let path = self.dir.join("manifest.txt");
+        std::fs::write(&path, out).map_err(|e| format!("Could not write {}: {}", path.display(), e))
+    }
+
+    /// The bNNNN id of the run
+    pub fn manifest_id(&self) -> &str {
+        &self.manifest_id
+    }
+}
@@ -0,0 +1,152 @@
+use crate::clone_backend::CloneLimits;
+use crate::credentials;
+use log::debug;
+use std::path::{Path, PathBuf};
+
+/// How every git LFS pointer file starts
+const POINTER_PREFIX: &[u8] = b"version https://git-lfs.github.com/spec/v1";
+
+/// Pointer files are small, anything larger is real content
+const MAX_POINTER_SIZE: u64 = 1024;
+
+/// Why fetching the LFS content of a repository failed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LfsFailure {
+    /// The bandwidth or storage quota of the repository owner is used up
+    Quota,
+    /// The LFS server refused the credentials, or there were none
+    Auth,
+    /// git lfs is not installed
+    NotInstalled,
+    Other,
+}
+
+impl LfsFailure {
+    /// Work out why git lfs failed from what it printed
+    pub fn classify(stderr: &str) -> LfsFailure {
+        let text = stderr.to_lowercase();
+        let any = |needles: &[&str]| needles.iter().any(|n| text.contains(n));
+        if any(&["over its data quota", "bandwidth", "quota"]) {
+            LfsFailure::Quota
+        } else if any(&[
+            "authentication required",
+            "authorization error",
+            "access denied",
+            "forbidden",
+            "bad credentials",
+        ]) {
+            LfsFailure::Auth
+        } else if any(&["'lfs' is not a git command", "git-lfs: not found"]) {
+            LfsFailure::NotInstalled
+        } else {
+            LfsFailure::Other
+        }
+    }
+
+    /// What can be done about the failure
+    fn remediation(self) -> &'static str {
+        match self {
+            LfsFailure::Quota => {
+                "the LFS bandwidth or storage quota of the repository is used up. Clone it \
+                 from a mirror which serves its LFS objects by giving it a url in the local \
+                 map, run with --skip-lfs to build with the pointer files, or retry once the \
+                 quota resets"
+            }
+            LfsFailure::Auth => {
+                "the LFS server refused the credentials. Log in with regenerate auth login or \
+                 set up a git credential helper for the host, clone it from a mirror which \
+                 serves its LFS objects, or run with --skip-lfs to build with the pointer files"
+            }
+            LfsFailure::NotInstalled => {
+                "git lfs is not installed. Install it, or run with --skip-lfs to build with \
+                 the pointer files"
+            }
+            LfsFailure::Other => {
+                "clone it from a mirror which serves its LFS objects, run with --skip-lfs to \
+                 build with the pointer files, or retry later"
+            }
+        }
+    }
+}
+
+/// True when the attributes of a checkout send files through LFS
+pub fn uses_lfs(workdir: &Path) -> bool {
+    std::fs::read_to_string(workdir.join(".gitattributes"))
+        .map(|text| text.contains("filter=lfs"))
+        .unwrap_or(false)
+}
+
+fn is_pointer(path: &Path) -> bool {
+    std::fs::read(path)
+        .map(|contents| contents.starts_with(POINTER_PREFIX))
+        .unwrap_or(false)
+}
+
+fn find_pointers(dir: &Path, found: &mut Vec<PathBuf>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        if entry.file_name() == ".git" {
+            continue;
+        }
+        let file_type = match entry.file_type() {
+            Ok(t) => t,
+            Err(_) => continue,
+        };
+        let path = entry.path();
+        if file_type.is_dir() {
+            find_pointers(&path, found);
+        } else if file_type.is_file()
+            && entry
+                .metadata()
+                .map(|m| m.len())
+                .unwrap_or(u64::max_value())
+                <= MAX_POINTER_SIZE
+            && is_pointer(&path)
+        {
+            found.push(path);
+        }
+    }
+}
+
+/// Files of a checkout which are still LFS pointers rather than their content
+pub fn pointer_files(workdir: &Path) -> Vec<PathBuf> {
+    let mut found = vec![];
+    find_pointers(workdir, &mut found);
+    found
+}
+
+/// Replace the pointer files of a checkout with their content from remote,
+/// explaining what to do when that fails. The caller names the product in
+/// the error. url is where remote fetches from, authentication and the ssh
+/// host key policy come from limits.
+pub fn pull(workdir: &Path, remote: &str, url: &str, limits: &CloneLimits) -> Result<(), String> {
+    let mut command = std::process::Command::new("git");
+    command
+        .args(&["lfs", "pull", remote])
+        .current_dir(workdir)
+        .env("GIT_TERMINAL_PROMPT", "0");
+    credentials::configure_command(
+        &mut command,
+        url,
+        limits.auth.as_ref(),
+        limits.host_keys.as_ref(),
+    )?;
+    debug!("Running {:?}", command);
+    let output = command
+        .output()
+        .map_err(|e| format!("Could not run system git lfs: {}", e))?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+    let pointers = pointer_files(workdir).len();
+    Err(format!(
+        "{} files are still LFS pointers as {} (git lfs said: {})",
+        pointers,
+        LfsFailure::classify(&stderr).remediation(),
+        stderr
+    ))
+}
@@ -0,0 +1,89 @@
+//! Regenerate clones a stack of eups products, works out the graph of their
+//! dependencies from their tables, and builds and declares any product which
+//! does not already have an install matching its sources.
+//!
+//! The regenerate command line tool is built on this library, which other
+//! tools such as CI drivers may use directly. A run is described by a
+//! RegenOptions and carried out by a Regenerate declaring into a
+//! ProductDatabase:
+//!
+//! ```no_run
+//! use regenerate::{RegenOptions, Regenerate, RepoEntry};
+//! use regenerate::workspace::Workspace;
+//!
+//! let workspace = Workspace::new("install/", "install/db.json");
+//! let mut db = workspace.open_db().unwrap();
+//! let options = RegenOptions::new("install/", "clones/", "w_2019_30", "eupspkg.sh");
+//! let mut app = Regenerate::new(&mut db, options).unwrap();
+//! app.override_source("base", RepoEntry::new("https://github.com/lsst/base"));
+//! if let Err(e) = app.install_product("base") {
+//!     eprintln!("{}", e);
+//! }
+//! ```
+
+mod abi;
+mod binary_cache;
+mod bisect;
+mod build_backend;
+mod classify;
+pub mod cli;
+mod clock_skew;
+mod clone_backend;
+mod closure;
+mod compile_db;
+mod completions;
+pub mod config;
+mod credentials;
+mod database;
+mod demo;
+mod disk_space;
+mod env_diff;
+mod environment;
+mod error;
+mod explore;
+mod graph_export;
+mod graph_memo;
+mod history;
+mod holds;
+mod host;
+mod host_keys;
+mod identity;
+mod jenkins;
+mod lfs;
+mod log_shipping;
+mod log_tail;
+mod manifest;
+mod map_cache;
+mod metadata;
+mod mirror;
+mod naming;
+mod network;
+mod overlay;
+mod plan;
+mod preflight;
+mod product_filter;
+mod profile;
+mod progress;
+mod promote;
+mod provenance;
+mod refresh;
+pub mod regenerate;
+mod release_manifest;
+pub mod repo_wrapper;
+mod report;
+mod reproducible;
+mod restore;
+mod run_state;
+mod safety;
+mod scheduler;
+mod settings;
+mod store;
+mod table_gen;
+mod table_lint;
+mod tags;
+mod toolchain;
+mod verify;
+pub mod workspace;
+
+pub use crate::regenerate::{ProductDatabase, RegenError, RegenOptions, Regenerate};
+pub use crate::repo_wrapper::{RefPin, RepoEntry, RepoSourceWrapper};
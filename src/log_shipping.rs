@@ -0,0 +1,256 @@
+use crate::clone_backend::url_host;
+use crate::credentials;
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use log::debug;
+
+/// Host of the Google Cloud Storage XML api
+const GCS_HOST: &str = "storage.googleapis.com";
+
+/// Somewhere build logs are uploaded to as products finish, so they outlive
+/// the machine which ran the build. A binary cache is kept in one as well.
+#[derive(Clone, Debug)]
+pub enum LogDestination {
+    /// Objects in an S3 bucket, signed with the AWS_ACCESS_KEY_ID and
+    /// AWS_SECRET_ACCESS_KEY of the environment
+    S3 { bucket: String, prefix: String },
+    /// Objects in a Google Cloud Storage bucket, authorized with the token in
+    /// GOOGLE_OAUTH_ACCESS_TOKEN or the keyring
+    Gcs { bucket: String, prefix: String },
+    /// An http endpoint accepting PUT requests below a base url, authorized
+    /// with the keyring token of its host if there is one
+    Http { base: String },
+}
+
+/// Split bucket/some/prefix into the bucket and the prefix
+fn bucket_and_prefix(rest: &str, url: &str) -> Result<(String, String), String> {
+    let mut parts = rest.splitn(2, '/');
+    let bucket = parts.next().unwrap_or_default();
+    if bucket.is_empty() {
+        return Err(format!("No bucket given in log destination {}", url));
+    }
+    let prefix = parts.next().unwrap_or_default().trim_matches('/');
+    Ok((bucket.to_string(), prefix.to_string()))
+}
+
+/// Percent encode everything but unreserved characters and slashes, as S3
+/// expects of paths being signed
+fn uri_encode(text: &str) -> String {
+    let mut out = String::new();
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                out.push(byte as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::new(Sha256::new(), key);
+    mac.input(data.as_bytes());
+    mac.result().code().to_vec()
+}
+
+fn env(name: &str) -> Result<String, String> {
+    std::env::var(name).map_err(|_| format!("{} must be set to upload logs to S3", name))
+}
+
+impl LogDestination {
+    /// Parse s3://bucket/prefix, gs://bucket/prefix, or an http(s) url
+    pub fn parse(url: &str) -> Result<LogDestination, String> {
+        if url.starts_with("s3://") {
+            let (bucket, prefix) = bucket_and_prefix(&url[5..], url)?;
+            Ok(LogDestination::S3 { bucket, prefix })
+        } else if url.starts_with("gs://") {
+            let (bucket, prefix) = bucket_and_prefix(&url[5..], url)?;
+            Ok(LogDestination::Gcs { bucket, prefix })
+        } else if url.starts_with("http://") || url.starts_with("https://") {
+            Ok(LogDestination::Http {
+                base: url.trim_end_matches('/').to_string(),
+            })
+        } else {
+            Err(format!(
+                "Log destination {} is not an s3://, gs://, or http(s) url",
+                url
+            ))
+        }
+    }
+
+    /// Upload contents under name, returning the url it may be read from
+    pub fn upload(&self, name: &str, contents: &[u8]) -> Result<String, String> {
+        let (url, request) = self.request(reqwest::Method::PUT, name)?;
+        debug!("Uploading {} to {}", name, url);
+        let response = request
+            .body(contents.to_vec())
+            .send()
+            .map_err(|e| format!("Could not upload {} to {}: {}", name, url, e))?;
+        if !response.status().is_success() {
+            return Err(format!(
+                "Uploading {} to {} failed with status {}",
+                name,
+                url,
+                response.status()
+            ));
+        }
+        Ok(url)
+    }
+
+    /// Download what is stored under name, None if nothing is
+    pub fn fetch(&self, name: &str) -> Result<Option<Vec<u8>>, String> {
+        let (url, request) = self.request(reqwest::Method::GET, name)?;
+        debug!("Downloading {} from {}", name, url);
+        let mut response = request
+            .send()
+            .map_err(|e| format!("Could not download {} from {}: {}", name, url, e))?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            return Err(format!(
+                "Downloading {} from {} failed with status {}",
+                name,
+                url,
+                response.status()
+            ));
+        }
+        let mut contents = vec![];
+        response
+            .copy_to(&mut contents)
+            .map_err(|e| format!("Could not download {} from {}: {}", name, url, e))?;
+        Ok(Some(contents))
+    }
+
+    /// The url of name and an authorized request of it with method
+    fn request(
+        &self,
+        method: reqwest::Method,
+        name: &str,
+    ) -> Result<(String, reqwest::RequestBuilder), String> {
+        let client = reqwest::Client::new();
+        Ok(match self {
+            LogDestination::S3 { bucket, prefix } => {
+                let key = join(prefix, name);
+                let region = std::env::var("AWS_REGION")
+                    .or_else(|_| std::env::var("AWS_DEFAULT_REGION"))
+                    .unwrap_or_else(|_| "us-east-1".to_string());
+                let host = format!("{}.s3.{}.amazonaws.com", bucket, region);
+                let path = format!("/{}", uri_encode(&key));
+                let url = format!("https://{}{}", host, path);
+                let mut request = client.request(method.clone(), url.as_str());
+                for (header, value) in s3_headers(method.as_str(), &host, &path, &region)? {
+                    request = request.header(header.as_str(), value);
+                }
+                (url, request)
+            }
+            LogDestination::Gcs { bucket, prefix } => {
+                let url = format!(
+                    "https://{}/{}/{}",
+                    GCS_HOST,
+                    bucket,
+                    uri_encode(&join(prefix, name))
+                );
+                let token = std::env::var("GOOGLE_OAUTH_ACCESS_TOKEN")
+                    .ok()
+                    .or_else(|| credentials::lookup_token(GCS_HOST))
+                    .ok_or("No GOOGLE_OAUTH_ACCESS_TOKEN or keyring token to upload logs with")?;
+                let request = client
+                    .request(method, url.as_str())
+                    .header("Authorization", format!("Bearer {}", token));
+                (url, request)
+            }
+            LogDestination::Http { base } => {
+                let url = format!("{}/{}", base, uri_encode(name));
+                let mut request = client.request(method, url.as_str());
+                if let Some(token) = url_host(base).and_then(credentials::lookup_token) {
+                    request = request.header("Authorization", format!("Bearer {}", token));
+                }
+                (url, request)
+            }
+        })
+    }
+}
+
+fn join(prefix: &str, name: &str) -> String {
+    match prefix.is_empty() {
+        true => name.to_string(),
+        false => format!("{}/{}", prefix, name),
+    }
+}
+
+/// Headers signing an S3 request of path with AWS signature version 4. The
+/// payload is left unsigned so logs need not be hashed before sending.
+fn s3_headers(
+    method: &str,
+    host: &str,
+    path: &str,
+    region: &str,
+) -> Result<Vec<(String, String)>, String> {
+    let access_key = env("AWS_ACCESS_KEY_ID")?;
+    let secret_key = env("AWS_SECRET_ACCESS_KEY")?;
+    let session_token = std::env::var("AWS_SESSION_TOKEN").ok();
+    let now = time::now_utc();
+    let amz_date = format!(
+        "{}",
+        now.strftime("%Y%m%dT%H%M%SZ")
+            .map_err(|e| format!("{}", e))?
+    );
+    let date = amz_date[..8].to_string();
+
+    // headers must be signed in sorted order
+    let mut headers = vec![
+        ("host".to_string(), host.to_string()),
+        (
+            "x-amz-content-sha256".to_string(),
+            "UNSIGNED-PAYLOAD".to_string(),
+        ),
+        ("x-amz-date".to_string(), amz_date.clone()),
+    ];
+    if let Some(token) = session_token {
+        headers.push(("x-amz-security-token".to_string(), token));
+    }
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(k, v)| format!("{}:{}\n", k, v))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(k, _)| k.as_str())
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "{}\n{}\n\n{}\n{}\nUNSIGNED-PAYLOAD",
+        method, path, canonical_headers, signed_headers
+    );
+    let mut hasher = Sha256::new();
+    hasher.input_str(&canonical_request);
+    let scope = format!("{}/{}/s3/aws4_request", date, region);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hasher.result_str()
+    );
+    let mut key = hmac(format!("AWS4{}", secret_key).as_bytes(), &date);
+    for part in [region, "s3", "aws4_request"].iter() {
+        key = hmac(&key, part);
+    }
+    let signature: String = hmac(&key, &string_to_sign)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    // reqwest sets the host header itself
+    headers.retain(|(k, _)| k != "host");
+    headers.push((
+        "Authorization".to_string(),
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            access_key, scope, signed_headers, signature
+        ),
+    ));
+    Ok(headers)
+}
@@ -0,0 +1,202 @@
+use log::debug;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How often a followed log is checked for new output
+pub const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Where the build log of a product version is written as it builds and kept
+/// after, out of the versioned install directory, which may be a link into
+/// the store shared with other runs
+pub fn live_log_path(install_root: &Path, product: &str, version: &str) -> PathBuf {
+    install_root
+        .join(".regenerate")
+        .join("logs")
+        .join(product)
+        .join(format!("{}.log", version))
+}
+
+/// The file beside a build log which marks it as still being written, holding
+/// the id of the process building the product
+fn marker_path(log: &Path) -> PathBuf {
+    let mut name = log.file_name().unwrap_or_default().to_os_string();
+    name.push(".live");
+    log.with_file_name(name)
+}
+
+/// Mark a build log as being written by this process
+pub fn mark_live(log: &Path) {
+    let marker = marker_path(log);
+    let written = marker
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| std::fs::write(&marker, std::process::id().to_string()));
+    if let Err(e) = written {
+        debug!("Could not mark {} as live: {}", log.display(), e);
+    }
+}
+
+/// Mark a build log as finished
+pub fn clear_live(log: &Path) {
+    let _ = std::fs::remove_file(marker_path(log));
+}
+
+/// True while the build writing a log is still running. A marker left behind
+/// by a run which was killed does not count.
+pub fn is_live(log: &Path) -> bool {
+    let pid = match std::fs::read_to_string(marker_path(log)) {
+        Ok(text) => match text.trim().parse::<libc::pid_t>() {
+            Ok(pid) => pid,
+            Err(_) => return false,
+        },
+        Err(_) => return false,
+    };
+    unsafe { libc::kill(pid, 0) == 0 }
+}
+
+/// What a poll of a followed log found
+#[derive(Debug)]
+pub enum TailEvent {
+    /// Output written since the last poll
+    Output(Vec<u8>),
+    /// Nothing new yet, but the build is still running
+    Idle,
+    /// The build finished and everything it wrote has been returned
+    Finished,
+}
+
+/// Follows the build log of a product from another process, replaying what
+/// was already written and then returning output as it is appended. A log
+/// which does not exist yet is waited for, and one which is started afresh
+/// by a new build is followed from its beginning.
+pub struct LogTail {
+    path: PathBuf,
+    file: Option<File>,
+    inode: u64,
+}
+
+impl LogTail {
+    pub fn new(path: &Path) -> LogTail {
+        LogTail {
+            path: path.to_path_buf(),
+            file: None,
+            inode: 0,
+        }
+    }
+
+    fn open(&mut self) -> Result<bool, String> {
+        match File::open(&self.path) {
+            Ok(file) => {
+                self.inode = file
+                    .metadata()
+                    .map_err(|e| format!("Could not read {}: {}", self.path.display(), e))?
+                    .ino();
+                self.file = Some(file);
+                Ok(true)
+            }
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(format!("Could not open {}: {}", self.path.display(), e)),
+        }
+    }
+
+    /// Everything appended since the last read
+    fn read_new(&mut self) -> Result<Vec<u8>, String> {
+        let mut bytes = vec![];
+        if let Some(file) = self.file.as_mut() {
+            file.read_to_end(&mut bytes)
+                .map_err(|e| format!("Could not read {}: {}", self.path.display(), e))?;
+        }
+        Ok(bytes)
+    }
+
+    /// The output already in the log, limited to its last lines when given,
+    /// after which polling returns only what is written later
+    pub fn backlog(&mut self, lines: Option<usize>) -> Result<Vec<u8>, String> {
+        if self.file.is_none() && !self.open()? {
+            return Ok(vec![]);
+        }
+        let bytes = self.read_new()?;
+        let lines = match lines {
+            Some(n) => n,
+            None => return Ok(bytes),
+        };
+        // a trailing newline ends the last line rather than starting another
+        let body = match bytes.last() {
+            Some(b'\n') => &bytes[..bytes.len() - 1],
+            _ => &bytes[..],
+        };
+        let start = match lines {
+            0 => bytes.len(),
+            n => body
+                .iter()
+                .enumerate()
+                .rev()
+                .filter(|(_, b)| **b == b'\n')
+                .nth(n - 1)
+                .map(|(i, _)| i + 1)
+                .unwrap_or(0),
+        };
+        Ok(bytes[start..].to_vec())
+    }
+
+    pub fn poll(&mut self) -> Result<TailEvent, String> {
+        if self.file.is_none() {
+            if !self.open()? {
+                return match is_live(&self.path) {
+                    true => Ok(TailEvent::Idle),
+                    false => Ok(TailEvent::Finished),
+                };
+            }
+        }
+        // read whether or not the build is live first, so output written
+        // just before it finished is not missed
+        let live = is_live(&self.path);
+        let bytes = self.read_new()?;
+        if !bytes.is_empty() {
+            return Ok(TailEvent::Output(bytes));
+        }
+        let replaced = std::fs::metadata(&self.path)
+            .map(|m| m.ino() != self.inode)
+            .unwrap_or(false);
+        match (live, replaced) {
+            // a new build started the log again
+            (true, true) => {
+                debug!("{} was started again, following it", self.path.display());
+                self.file = None;
+                Ok(TailEvent::Idle)
+            }
+            (true, false) => Ok(TailEvent::Idle),
+            (false, _) => Ok(TailEvent::Finished),
+        }
+    }
+}
+
+/// Write the last lines of a build log to out, or all of it without a limit,
+/// then keep writing what the build adds until it finishes when following
+pub fn tail(
+    path: &Path,
+    lines: Option<usize>,
+    follow: bool,
+    out: &mut dyn Write,
+) -> Result<(), String> {
+    let mut log = LogTail::new(path);
+    let write = |out: &mut dyn Write, bytes: &[u8]| {
+        out.write_all(bytes)
+            .and_then(|_| out.flush())
+            .map_err(|e| format!("Could not write the log: {}", e))
+    };
+    write(out, &log.backlog(lines)?)?;
+    if !follow {
+        return Ok(());
+    }
+    loop {
+        match log.poll()? {
+            TailEvent::Output(bytes) => write(out, &bytes)?,
+            TailEvent::Idle => std::thread::sleep(POLL_INTERVAL),
+            TailEvent::Finished => return Ok(()),
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use log::debug;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Top level directories of an install which are private to the product, and
+/// so can not conflict with other products however they are named
+const PRIVATE_DIRS: [&str; 5] = ["ups", "doc", "tests", ".regenerate", ".git"];
+
+/// Location of the file manifest within a product directory
+pub fn path(product_dir: &Path) -> PathBuf {
+    let mut path = PathBuf::from(product_dir);
+    path.push(".regenerate");
+    path.push("manifest.txt");
+    path
+}
+
+fn collect(root: &Path, dir: &Path, out: &mut Vec<String>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+        if is_dir {
+            collect(root, &path, out);
+        } else if let Ok(relative) = path.strip_prefix(root) {
+            out.push(relative.to_string_lossy().to_string());
+        }
+    }
+}
+
+/// List the files an install provides, relative to the product directory.
+/// Files directly in the product directory and those in private directories
+/// are left out, as they are never found through a search path.
+pub fn list_files(product_dir: &Path) -> Vec<String> {
+    let mut files = vec![];
+    let entries = match std::fs::read_dir(product_dir) {
+        Ok(e) => e,
+        Err(_) => return files,
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+        if is_dir && !PRIVATE_DIRS.contains(&name.as_str()) {
+            collect(product_dir, &entry.path(), &mut files);
+        }
+    }
+    files.sort();
+    files
+}
+
+/// Record the files of an install in its manifest
+pub fn write(product_dir: &Path) -> Result<Vec<String>, String> {
+    let files = list_files(product_dir);
+    let path = path(product_dir);
+    debug!(
+        "Writing manifest of {} files to {}",
+        files.len(),
+        path.display()
+    );
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| format!("{}", e))?;
+    }
+    let mut text = files.join("\n");
+    text.push('\n');
+    std::fs::write(&path, text)
+        .map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+    Ok(files)
+}
+
+/// The files of an install, from its manifest if it has one and otherwise by
+/// listing the directory
+pub fn read(product_dir: &Path) -> Vec<String> {
+    match std::fs::read_to_string(path(product_dir)) {
+        Ok(text) => text
+            .lines()
+            .filter(|l| !l.is_empty())
+            .map(|l| l.to_string())
+            .collect(),
+        Err(_) => list_files(product_dir),
+    }
+}
+
+/// A relative path installed by more than one product
+#[derive(Clone, Debug)]
+pub struct Conflict {
+    pub path: String,
+    pub owners: Vec<String>,
+}
+
+impl std::fmt::Display for Conflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} is installed by {}",
+            self.path,
+            self.owners.join(", ")
+        )
+    }
+}
+
+/// Whether an `__init__.py` only marks a python namespace package, which
+/// several products are expected to install into the same package
+fn is_namespace_init(contents: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(contents);
+    let code: Vec<&str> = text
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .collect();
+    code.is_empty()
+        || code
+            .iter()
+            .any(|l| l.contains("extend_path") || l.contains("declare_namespace"))
+}
+
+/// Whether a path installed by several products is harmless, because every
+/// copy is the same or it is a namespace package marker, so setup order
+/// can not change what is found
+fn is_shared(path: &str, product_dirs: &[&Path]) -> bool {
+    let contents: Option<Vec<Vec<u8>>> = product_dirs
+        .iter()
+        .map(|dir| std::fs::read(dir.join(path)).ok())
+        .collect();
+    let contents = match contents {
+        Some(c) => c,
+        None => return false,
+    };
+    if contents.windows(2).all(|pair| pair[0] == pair[1]) {
+        return true;
+    }
+    Path::new(path).file_name() == Some("__init__.py".as_ref())
+        && contents.iter().all(|c| is_namespace_init(c))
+}
+
+/// Find the paths installed by more than one of the given products, as
+/// (product, product directory, files). Which copy is used depends on the
+/// order the products are set up in, unless the copies are shared.
+pub fn conflicts(manifests: &[(String, PathBuf, Vec<String>)]) -> Vec<Conflict> {
+    let mut owners: BTreeMap<&str, Vec<(&String, &Path)>> = BTreeMap::new();
+    for (product, dir, files) in manifests.iter() {
+        for file in files.iter() {
+            owners
+                .entry(file.as_str())
+                .or_insert_with(Vec::new)
+                .push((product, dir.as_path()));
+        }
+    }
+    owners
+        .into_iter()
+        .filter(|(_, owners)| owners.len() > 1)
+        .filter(|(path, owners)| {
+            let dirs: Vec<&Path> = owners.iter().map(|(_, dir)| *dir).collect();
+            !is_shared(path, &dirs)
+        })
+        .map(|(path, owners)| Conflict {
+            path: path.to_string(),
+            owners: owners.into_iter().map(|(p, _)| p.clone()).collect(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn install(
+        root: &Path,
+        product: &str,
+        files: &[(&str, &str)],
+    ) -> (String, PathBuf, Vec<String>) {
+        let dir = root.join(product);
+        for (path, contents) in files.iter() {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, contents).unwrap();
+        }
+        (product.to_string(), dir.clone(), list_files(&dir))
+    }
+
+    #[test]
+    fn differing_copies_conflict_across_every_product() {
+        let root = TempDir::new("manifest").unwrap();
+        let manifests = vec![
+            install(root.path(), "a", &[("include/x.h", "a")]),
+            install(root.path(), "b", &[("include/y.h", "b")]),
+            install(root.path(), "c", &[("include/x.h", "c")]),
+        ];
+        let found = conflicts(&manifests);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].path, "include/x.h");
+        assert_eq!(found[0].owners, vec!["a".to_string(), "c".to_string()]);
+    }
+
+    #[test]
+    fn identical_copies_and_namespace_packages_are_shared() {
+        let root = TempDir::new("manifest").unwrap();
+        let namespace = "import pkgutil\n__path__ = pkgutil.extend_path(__path__, __name__)\n";
+        let manifests = vec![
+            install(
+                root.path(),
+                "a",
+                &[
+                    ("share/licence.txt", "same"),
+                    ("python/lsst/__init__.py", namespace),
+                ],
+            ),
+            install(
+                root.path(),
+                "b",
+                &[
+                    ("share/licence.txt", "same"),
+                    ("python/lsst/__init__.py", "# namespace\n"),
+                ],
+            ),
+        ];
+        assert!(conflicts(&manifests).is_empty());
+    }
+}
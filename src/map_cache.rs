@@ -0,0 +1,68 @@
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// A copy of the remote map as last fetched, with what the server sent to
+/// ask it later whether the map changed
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CachedMap {
+    /// Where the copy was fetched from, which may be a mirror of the url it
+    /// is cached under
+    pub source: String,
+    pub text: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// When the copy was fetched, in rfc3339
+    pub fetched: String,
+}
+
+/// Copies of remote maps kept in the workspace, one per url, so unchanged
+/// maps are not downloaded again and runs can go on without the network
+pub struct MapCache {
+    dir: PathBuf,
+}
+
+impl MapCache {
+    pub fn new(install_root: &Path) -> MapCache {
+        MapCache {
+            dir: install_root.join(".regenerate").join("remote-maps"),
+        }
+    }
+
+    fn path(&self, url: &str) -> PathBuf {
+        let mut hasher = Sha1::new();
+        hasher.input_str(url);
+        self.dir.join(format!("{}.json", hasher.result_str()))
+    }
+
+    /// The copy cached for url, if there is a readable one
+    pub fn load(&self, url: &str) -> Option<CachedMap> {
+        let path = self.path(url);
+        let text = std::fs::read_to_string(&path).ok()?;
+        match serde_json::from_str(&text) {
+            Ok(cached) => Some(cached),
+            Err(e) => {
+                debug!("Ignoring unreadable cached map {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Cache a copy of the map for url, replacing any earlier one
+    pub fn store(&self, url: &str, cached: &CachedMap) -> Result<(), String> {
+        std::fs::create_dir_all(&self.dir)
+            .map_err(|e| format!("Could not create {}: {}", self.dir.display(), e))?;
+        let path = self.path(url);
+        let text = serde_json::to_string_pretty(cached)
+            .map_err(|e| format!("Could not serialize the cached map: {}", e))?;
+        // written beside the copy and moved over it, so a concurrent run
+        // never reads half a file
+        let staging = path.with_extension("json.tmp");
+        std::fs::write(&staging, text)
+            .map_err(|e| format!("Could not write {}: {}", staging.display(), e))?;
+        std::fs::rename(&staging, &path)
+            .map_err(|e| format!("Could not write {}: {}", path.display(), e))
+    }
+}
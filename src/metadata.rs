@@ -0,0 +1,98 @@
+use std::path::{Path, PathBuf};
+
+/// Descriptive information about a product harvested from its source tree
+#[derive(Clone, Debug, Default)]
+pub struct ProductMetadata {
+    /// Best guess at the license the product is distributed under
+    pub license: Option<String>,
+    /// License files found at the top of the source tree
+    pub license_files: Vec<String>,
+    /// Short description taken from the readme
+    pub description: Option<String>,
+}
+
+const LICENSE_NAMES: [&str; 6] = [
+    "LICENSE",
+    "LICENSE.txt",
+    "LICENSE.md",
+    "COPYING",
+    "COPYRIGHT",
+    "gpl-v3.0.txt",
+];
+
+const README_NAMES: [&str; 4] = ["README.md", "README.rst", "README.txt", "README"];
+
+/// Guess the license from the text of a license file
+fn identify_license(text: &str) -> Option<String> {
+    let text = text.to_lowercase();
+    let license = if text.contains("gnu general public license") {
+        if text.contains("version 3") {
+            "GPL-3.0"
+        } else {
+            "GPL"
+        }
+    } else if text.contains("apache license") {
+        "Apache-2.0"
+    } else if text.contains("permission is hereby granted, free of charge") {
+        "MIT"
+    } else if text.contains("redistribution and use in source and binary forms") {
+        if text.contains("neither the name") {
+            "BSD-3-Clause"
+        } else {
+            "BSD"
+        }
+    } else if text.contains("mozilla public license") {
+        "MPL-2.0"
+    } else {
+        return None;
+    };
+    Some(license.to_string())
+}
+
+/// Take the first paragraph of prose from a readme, skipping headings and
+/// badges
+fn first_paragraph(text: &str) -> Option<String> {
+    let mut paragraph = vec![];
+    for line in text.lines() {
+        let line = line.trim();
+        let is_markup = line.starts_with('#')
+            || line.starts_with("[!")
+            || line.starts_with(".. ")
+            || line.chars().all(|c| c == '=' || c == '-' || c == '*');
+        if line.is_empty() || is_markup {
+            if !paragraph.is_empty() {
+                break;
+            }
+            continue;
+        }
+        paragraph.push(line);
+    }
+    match paragraph.is_empty() {
+        true => None,
+        false => Some(paragraph.join(" ")),
+    }
+}
+
+/// Collect the metadata of a product from its checked out source tree
+pub fn harvest(repo_path: &Path) -> ProductMetadata {
+    let mut metadata = ProductMetadata::default();
+    for name in LICENSE_NAMES.iter() {
+        let mut path = PathBuf::from(repo_path);
+        path.push(name);
+        if let Ok(text) = std::fs::read_to_string(&path) {
+            metadata.license_files.push(name.to_string());
+            if metadata.license.is_none() {
+                metadata.license = identify_license(&text);
+            }
+        }
+    }
+    for name in README_NAMES.iter() {
+        let mut path = PathBuf::from(repo_path);
+        path.push(name);
+        if let Ok(text) = std::fs::read_to_string(&path) {
+            metadata.description = first_paragraph(&text);
+            break;
+        }
+    }
+    metadata
+}
@@ -0,0 +1,170 @@
+use crate::clone_backend::{self, CloneLimits};
+use crate::credentials;
+use crate::network;
+use crate::refresh;
+use git2::Repository;
+use log::{debug, info, warn};
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// An exclusive lock on the mirror of one product, held across processes and
+/// users until dropped
+struct MirrorLock {
+    _file: File,
+}
+
+impl MirrorLock {
+    /// Wait until no other process holds the lock at path, then take it
+    fn acquire(path: &Path) -> Result<MirrorLock, String> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| format!("Could not create {}: {}", parent.display(), e))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(path)
+            .map_err(|e| format!("Could not open lock {}: {}", path.display(), e))?;
+        debug!("Waiting for lock {}", path.display());
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(format!(
+                "Could not lock {}: {}",
+                path.display(),
+                std::io::Error::last_os_error()
+            ));
+        }
+        Ok(MirrorLock { _file: file })
+    }
+}
+
+/// Location of the bare mirror of a product within the mirror root
+pub fn mirror_path(root: &Path, product: &str) -> PathBuf {
+    root.join(format!("{}.git", product))
+}
+
+fn run_git(command: &mut std::process::Command, what: &str) -> Result<(), String> {
+    debug!("Running {:?}", command);
+    let output = command
+        .output()
+        .map_err(|e| format!("Could not run system git to {}: {}", what, e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "Failed to {}: {}",
+            what,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    Ok(())
+}
+
+/// Make sure the mirror root holds an up to date mirror of a product, which
+/// is created if missing and otherwise fetched if older than max_age, or
+/// always when no age is given. Only one process updates a mirror at a time,
+/// others wait for it and then use the result. A mirror root which cannot be
+/// written to is used as it is.
+pub fn update(
+    root: &Path,
+    product: &str,
+    url: &str,
+    limits: &CloneLimits,
+    max_age: Option<Duration>,
+    offline: bool,
+) -> Result<PathBuf, String> {
+    let path = mirror_path(root, product);
+    if offline {
+        return match path.exists() {
+            true => Ok(path),
+            false => Err(format!(
+                "There is no mirror of {} and running offline",
+                product
+            )),
+        };
+    }
+    let mut lock_path = root.join(".locks");
+    lock_path.push(format!("{}.lock", product));
+    let _lock = match MirrorLock::acquire(&lock_path) {
+        Ok(lock) => lock,
+        Err(e) if path.exists() => {
+            warn!("{}, using the mirror of {} as it is", e, product);
+            return Ok(path);
+        }
+        Err(e) => return Err(e),
+    };
+    if !path.exists() {
+        info!("Mirroring {} from {}", product, url);
+        // clone beside the mirror and move it into place, so an interrupted
+        // clone is never taken for a mirror
+        let staging = root.join(format!(".{}.git-{}", product, std::process::id()));
+        let mut command = std::process::Command::new("git");
+        command
+            // the mirror area is shared, so whoever updates a mirror next
+            // must be able to write the objects this clone creates
+            .args(&[
+                "clone",
+                "--mirror",
+                "--quiet",
+                "--config",
+                "core.sharedRepository=group",
+                "--",
+            ])
+            .arg(url)
+            .arg(&staging);
+        credentials::configure_command(
+            &mut command,
+            url,
+            limits.auth.as_ref(),
+            limits.host_keys.as_ref(),
+        )?;
+        if let Err(e) = run_git(&mut command, &format!("mirror {}", url)) {
+            let _ = std::fs::remove_dir_all(&staging);
+            return Err(e);
+        }
+        network::record_git(clone_backend::object_size(&staging));
+        std::fs::rename(&staging, &path)
+            .map_err(|e| format!("Could not move mirror to {}: {}", path.display(), e))?;
+        return Ok(path);
+    }
+    let repo = Repository::open_bare(&path)
+        .map_err(|e| format!("Could not open mirror {}: {}", path.display(), e))?;
+    let fetched = match max_age {
+        Some(age) => refresh::refresh_if_stale(
+            &repo,
+            "origin",
+            age,
+            limits.max_rate,
+            limits.host_keys.as_ref(),
+            limits.auth.as_ref(),
+        ),
+        None => refresh::fetch_repo(
+            &repo,
+            "origin",
+            limits.max_rate,
+            limits.host_keys.as_ref(),
+            limits.auth.as_ref(),
+        ),
+    };
+    // a stale mirror is still better than no build
+    if let Err(e) = fetched {
+        warn!("Could not update the mirror of {}: {}", product, e);
+    }
+    Ok(path)
+}
+
+/// Create a private checkout of a mirror at dest, which never writes to the
+/// mirror. Objects are taken from the mirror while cloning and copied once it
+/// is done, so a later prune of the mirror can not break the checkout.
+pub fn checkout(mirror: &Path, dest: &Path) -> Result<Repository, String> {
+    debug!("Checking out {} into {}", mirror.display(), dest.display());
+    let mut command = std::process::Command::new("git");
+    command
+        .args(&["clone", "--dissociate", "--quiet", "--reference"])
+        .arg(mirror)
+        .arg("--")
+        .arg(mirror)
+        .arg(dest)
+        .env("GIT_LFS_SKIP_SMUDGE", "1");
+    run_git(&mut command, &format!("check out {}", mirror.display()))?;
+    Repository::open(dest).map_err(|e| format!("Could not open {}: {}", dest.display(), e))
+}
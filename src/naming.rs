@@ -0,0 +1,68 @@
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+
+/// Template used to name run artifacts when none is configured
+pub const DEFAULT_TEMPLATE: &str = "{timestamp}-{run_id}";
+
+/// Identifies one run of regenerate. Names rendered from it sort by the time
+/// the run started, are the same in every timezone, and are safe to use as
+/// file names on any filesystem.
+#[derive(Clone, Debug)]
+pub struct RunName {
+    pub timestamp: String,
+    pub run_id: String,
+}
+
+impl RunName {
+    pub fn new() -> RunName {
+        let now = time::now_utc();
+        let timestamp = now
+            .strftime("%Y%m%dT%H%M%SZ")
+            .map(|t| t.to_string())
+            .unwrap_or_else(|_| now.to_timespec().sec.to_string());
+        let mut hasher = Sha1::new();
+        hasher.input_str(&format!(
+            "{}-{}-{}",
+            std::process::id(),
+            now.to_timespec().sec,
+            now.to_timespec().nsec
+        ));
+        RunName {
+            timestamp,
+            run_id: hasher.result_str()[..8].to_string(),
+        }
+    }
+
+    /// Render the name of this run from a template, in which {timestamp},
+    /// {date} and {run_id} are substituted. Any characters which are not safe
+    /// in file names are replaced.
+    pub fn render(&self, template: &str) -> String {
+        let rendered = template
+            .replace("{timestamp}", &self.timestamp)
+            .replace("{date}", &self.timestamp[..8.min(self.timestamp.len())])
+            .replace("{run_id}", &self.run_id);
+        sanitize(&rendered)
+    }
+
+    /// The file name of an artifact of this run, such as a log or a report
+    pub fn artifact(&self, template: &str, kind: &str, extension: &str) -> String {
+        format!("{}-{}.{}", kind, self.render(template), extension)
+    }
+}
+
+/// Replace anything other than ascii alphanumerics, dots, dashes and
+/// underscores with an underscore
+pub fn sanitize(name: &str) -> String {
+    let sanitized: String = name
+        .chars()
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() => c,
+            '.' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect();
+    match sanitized.trim_start_matches('.') {
+        "" => "run".to_string(),
+        s => s.to_string(),
+    }
+}
@@ -0,0 +1,112 @@
+use std::io::Read;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+// Bytes downloaded in this process, shared by every clone and fetch thread
+static GIT_BYTES: AtomicU64 = AtomicU64::new(0);
+static HTTP_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// Bytes downloaded over the network during a run
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NetworkUsage {
+    pub git_bytes: u64,
+    pub http_bytes: u64,
+}
+
+impl NetworkUsage {
+    pub fn total(&self) -> u64 {
+        self.git_bytes + self.http_bytes
+    }
+}
+
+pub fn record_git(bytes: u64) {
+    GIT_BYTES.fetch_add(bytes, Ordering::Relaxed);
+}
+
+pub fn record_http(bytes: u64) {
+    HTTP_BYTES.fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// Everything downloaded so far
+pub fn usage() -> NetworkUsage {
+    NetworkUsage {
+        git_bytes: GIT_BYTES.load(Ordering::Relaxed),
+        http_bytes: HTTP_BYTES.load(Ordering::Relaxed),
+    }
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} {}", bytes, units[0]),
+        _ => format!("{:.1} {}", value, units[unit]),
+    }
+}
+
+/// Keeps the average rate of a transfer at or below a cap by working out how
+/// long to wait before continuing
+pub struct Throttle {
+    max_rate: Option<u64>,
+    start: Instant,
+}
+
+impl Throttle {
+    pub fn new(max_rate: Option<u64>) -> Throttle {
+        Throttle {
+            max_rate,
+            start: Instant::now(),
+        }
+    }
+
+    /// How long the transfer must wait for bytes transferred so far to be
+    /// within the cap, if at all
+    pub fn delay(&self, bytes: u64) -> Option<Duration> {
+        let max_rate = self.max_rate?.max(1);
+        let required = Duration::from_millis(bytes.saturating_mul(1000) / max_rate);
+        let elapsed = self.start.elapsed();
+        match required > elapsed {
+            true => Some(required - elapsed),
+            false => None,
+        }
+    }
+
+    pub fn pace(&self, bytes: u64) {
+        if let Some(delay) = self.delay(bytes) {
+            std::thread::sleep(delay);
+        }
+    }
+}
+
+/// Wraps an http response body, counting the bytes read from it and holding
+/// reads back to keep under a bandwidth cap
+pub struct ThrottledReader<R: Read> {
+    inner: R,
+    throttle: Throttle,
+    bytes: u64,
+}
+
+impl<R: Read> ThrottledReader<R> {
+    pub fn new(inner: R, max_rate: Option<u64>) -> ThrottledReader<R> {
+        ThrottledReader {
+            inner,
+            throttle: Throttle::new(max_rate),
+            bytes: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for ThrottledReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.bytes += read as u64;
+        record_http(read as u64);
+        self.throttle.pace(self.bytes);
+        Ok(read)
+    }
+}
@@ -0,0 +1,148 @@
+use crate::clone_backend;
+use crate::repo_wrapper::{entry_key, entry_url};
+use log::debug;
+use yaml_rust::Yaml;
+
+/// Something about an entry of a local map which has drifted from the
+/// remote map it overlays
+#[derive(Clone, Debug)]
+pub enum OverlayIssue {
+    /// The remote map no longer lists the product
+    NotUpstream,
+    /// The ref the entry asks for no longer exists in the repository
+    MissingRef(String),
+    /// The ref could not be looked up, with why
+    UncheckedRef(String, String),
+    /// The entry says nothing the remote map does not, so it can be deleted
+    SameAsUpstream,
+}
+
+#[derive(Clone, Debug)]
+pub struct OverlayFinding {
+    pub product: String,
+    pub issue: OverlayIssue,
+}
+
+impl OverlayFinding {
+    /// True for findings which mean the overlay is wrong rather than just
+    /// redundant or unchecked
+    pub fn is_problem(&self) -> bool {
+        match self.issue {
+            OverlayIssue::NotUpstream | OverlayIssue::MissingRef(_) => true,
+            OverlayIssue::UncheckedRef(..) | OverlayIssue::SameAsUpstream => false,
+        }
+    }
+}
+
+impl std::fmt::Display for OverlayFinding {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.issue {
+            OverlayIssue::NotUpstream => write!(f, "{} is not in the remote map", self.product),
+            OverlayIssue::MissingRef(git_ref) => write!(
+                f,
+                "{} asks for ref {}, which no longer exists",
+                self.product, git_ref
+            ),
+            OverlayIssue::UncheckedRef(git_ref, why) => write!(
+                f,
+                "{} asks for ref {}, which could not be checked: {}",
+                self.product, git_ref, why
+            ),
+            OverlayIssue::SameAsUpstream => write!(
+                f,
+                "{} is the same as in the remote map and can be deleted",
+                self.product
+            ),
+        }
+    }
+}
+
+/// True when a local entry only repeats what the remote entry says, treating
+/// two spellings of the same url as equal
+fn same_as_upstream(local: &Yaml, remote: &Yaml) -> bool {
+    if local == remote {
+        return true;
+    }
+    // an entry giving nothing but a url, in either form
+    let url_only = |entry: &Yaml| match entry {
+        Yaml::String(_) => true,
+        Yaml::Hash(hash) => hash.len() == 1 && entry_key(entry, "url").is_some(),
+        _ => false,
+    };
+    match (entry_url(local), entry_url(remote)) {
+        (Some(a), Some(b)) => {
+            url_only(local)
+                && url_only(remote)
+                && clone_backend::normalize_url(a) == clone_backend::normalize_url(b)
+        }
+        _ => false,
+    }
+}
+
+/// Ask the repository at url whether it has a branch or tag named git_ref. A
+/// sha can not be looked up this way and is taken to exist.
+fn ref_exists(url: &str, git_ref: &str) -> Result<bool, String> {
+    if git_ref.len() >= 7 && git_ref.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Ok(true);
+    }
+    debug!("Looking for {} in {}", git_ref, url);
+    let output = std::process::Command::new("git")
+        .args(&["ls-remote", "--heads", "--tags", url, git_ref])
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .output()
+        .map_err(|e| format!("Could not run system git ls-remote: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    Ok(!output.stdout.is_empty())
+}
+
+/// Compare each entry of a local map with the remote map it overlays. Refs
+/// are looked up in their repositories when check_refs is set.
+pub fn check_overlay(local: &Yaml, remote: &Yaml, check_refs: bool) -> Vec<OverlayFinding> {
+    let mut findings = vec![];
+    let entries = match local.as_hash() {
+        Some(hash) => hash,
+        None => return findings,
+    };
+    for (name, entry) in entries.iter() {
+        let product = match name.as_str() {
+            Some(p) => p,
+            None => continue,
+        };
+        let mut found = |issue| {
+            findings.push(OverlayFinding {
+                product: product.to_string(),
+                issue,
+            })
+        };
+        let upstream = remote
+            .as_hash()
+            .and_then(|h| h.get(&Yaml::String(product.to_string())));
+        match upstream {
+            None => found(OverlayIssue::NotUpstream),
+            Some(upstream) if same_as_upstream(entry, upstream) => {
+                found(OverlayIssue::SameAsUpstream)
+            }
+            Some(_) => (),
+        }
+        let git_ref = match entry_key(entry, "ref")
+            .or_else(|| entry_key(entry, "tag"))
+            .and_then(|r| r.as_str())
+        {
+            Some(r) if check_refs => r,
+            _ => continue,
+        };
+        let url = entry_url(entry).or_else(|| upstream.and_then(entry_url));
+        let url = match url {
+            Some(url) => url,
+            None => continue,
+        };
+        match ref_exists(url, git_ref) {
+            Ok(true) => (),
+            Ok(false) => found(OverlayIssue::MissingRef(git_ref.to_string())),
+            Err(e) => found(OverlayIssue::UncheckedRef(git_ref.to_string(), e)),
+        }
+    }
+    findings
+}
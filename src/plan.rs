@@ -0,0 +1,197 @@
+use crate::network;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+
+/// What a run will do with a product
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlanAction {
+    /// An existing install with a matching id will be used
+    Reuse,
+    /// The product will be built from source
+    Build,
+}
+
+/// One product in a plan, along with why it will be handled that way
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlanStep {
+    pub product: String,
+    pub id: String,
+    pub sha: String,
+    pub action: PlanAction,
+    pub reason: String,
+    pub dependencies: Vec<String>,
+    /// The revision the product is held at, if it is held
+    pub held: Option<String>,
+    /// Whether the product had to be cloned, rather than being found on disk
+    #[serde(default)]
+    pub cloned: bool,
+    /// The branch, tag, or revision the product was checked out at
+    #[serde(default)]
+    pub checkout: Option<String>,
+    /// The product whose clone this one shares, being cloned from the same url
+    #[serde(default)]
+    pub shares: Option<String>,
+    /// How long building the product took last time, if it has been built
+    pub estimated_seconds: Option<u64>,
+    /// How large the product was when last installed
+    pub estimated_bytes: Option<u64>,
+}
+
+/// The expected cost of carrying out a plan, based on past builds
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Estimate {
+    pub builds: usize,
+    /// Products to build which have no history to estimate from
+    pub unknown: usize,
+    /// Products built at the same time
+    pub workers: usize,
+    pub wall_seconds: u64,
+    pub disk_bytes: u64,
+}
+
+impl Estimate {
+    pub fn render(&self) -> String {
+        let mut out = format!(
+            "{} products to build, est. {}h{:02}m wall ({} workers), {} disk",
+            self.builds,
+            self.wall_seconds / 3600,
+            (self.wall_seconds / 60) % 60,
+            self.workers,
+            network::format_bytes(self.disk_bytes)
+        );
+        if self.unknown > 0 {
+            out.push_str(&format!(
+                ", {} of which have never been built here",
+                self.unknown
+            ));
+        }
+        out
+    }
+}
+
+/// The ordered actions a run would take to install a product, with each
+/// product appearing after all of its dependencies
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Plan {
+    pub root: String,
+    pub steps: Vec<PlanStep>,
+}
+
+impl Plan {
+    /// Add the steps of another plan for products this one does not already
+    /// cover, so the plans of several products describe one combined run
+    pub fn merge(&mut self, other: Plan) {
+        self.root = match self.root.is_empty() {
+            true => other.root,
+            false => format!("{}, {}", self.root, other.root),
+        };
+        for step in other.steps {
+            if !self.steps.iter().any(|s| s.product == step.product) {
+                self.steps.push(step);
+            }
+        }
+    }
+
+    pub fn builds(&self) -> usize {
+        self.count(PlanAction::Build)
+    }
+
+    pub fn reuses(&self) -> usize {
+        self.count(PlanAction::Reuse)
+    }
+
+    fn count(&self, action: PlanAction) -> usize {
+        self.steps.iter().filter(|s| s.action == action).count()
+    }
+
+    /// Estimate the cost of the builds in this plan when workers products
+    /// are built at the same time. The build tool jobs of each product are
+    /// not counted, past build times already reflect them.
+    pub fn estimate(&self, workers: usize) -> Estimate {
+        let workers = workers.max(1);
+        let mut estimate = Estimate {
+            workers,
+            ..Estimate::default()
+        };
+        let mut total_seconds = 0;
+        for step in self.steps.iter().filter(|s| s.action == PlanAction::Build) {
+            estimate.builds += 1;
+            match (step.estimated_seconds, step.estimated_bytes) {
+                (Some(seconds), bytes) => {
+                    total_seconds += seconds;
+                    estimate.disk_bytes += bytes.unwrap_or(0);
+                }
+                _ => estimate.unknown += 1,
+            }
+        }
+        estimate.wall_seconds = total_seconds / workers as u64;
+        estimate
+    }
+
+    pub fn to_json(&self) -> Result<String, String> {
+        serde_json::to_string_pretty(self).map_err(|e| format!("Could not serialize plan: {}", e))
+    }
+
+    /// A short human readable rendering, one product per line
+    pub fn render(&self) -> String {
+        let mut out = format!(
+            "Plan for {}: {} to build, {} to reuse\n",
+            self.root,
+            self.builds(),
+            self.reuses()
+        );
+        for step in self.steps.iter() {
+            let action = match step.action {
+                PlanAction::Reuse => "reuse",
+                PlanAction::Build => "build",
+            };
+            let held = match step.held.as_ref() {
+                Some(pin) => format!(", held at {}", pin),
+                None => String::new(),
+            };
+            let checkout = match step.checkout.as_ref() {
+                Some(name) => format!("{} ", name),
+                None => String::new(),
+            };
+            let cloned = match step.cloned {
+                true => ", cloned",
+                false => "",
+            };
+            let shares = match step.shares.as_ref() {
+                Some(other) => format!(", shares the clone of {}", other),
+                None => String::new(),
+            };
+            out.push_str(&format!(
+                "  {:<6} {} at {}{} ({}{}{}{})\n",
+                action,
+                step.product,
+                checkout,
+                &step.sha[..step.sha.len().min(10)],
+                step.reason,
+                held,
+                cloned,
+                shares
+            ));
+        }
+        out
+    }
+}
+
+/// True when there is someone at a terminal to answer a confirmation
+pub fn can_confirm() -> bool {
+    unsafe { libc::isatty(libc::STDIN_FILENO) == 1 }
+}
+
+/// Ask on the terminal whether to go ahead, anything other than yes declines
+pub fn confirm(prompt: &str) -> Result<bool, String> {
+    print!("{} [y/N] ", prompt);
+    std::io::stdout().flush().map_err(|e| format!("{}", e))?;
+    let mut answer = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut answer)
+        .map_err(|e| format!("Could not read confirmation: {}", e))?;
+    let answer = answer.trim().to_lowercase();
+    Ok(answer == "y" || answer == "yes")
+}
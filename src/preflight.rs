@@ -0,0 +1,97 @@
+use crate::disk_space;
+use crate::network;
+use log::debug;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::time::Duration;
+
+/// How long to wait for the host of the remote map to accept a connection
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Check that files can be created in a root, or in the directory it will be
+/// created in when it does not exist yet. what names the root in messages.
+pub fn check_writable(path: &Path, what: &str) -> Result<(), String> {
+    let existing = path
+        .ancestors()
+        .find(|p| !p.as_os_str().is_empty() && p.exists())
+        .unwrap_or_else(|| Path::new("."));
+    if !existing.is_dir() {
+        return Err(format!(
+            "The {} {} is not a directory",
+            what,
+            existing.display()
+        ));
+    }
+    match tempdir::TempDir::new_in(existing, ".regenerate-preflight") {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!(
+            "The {} {} is not writable ({}), fix its permissions or choose another {}",
+            what,
+            existing.display(),
+            e,
+            what
+        )),
+    }
+}
+
+/// Check that the host serving url accepts connections, so an unreachable
+/// remote map is reported before any work starts. Local files only need to
+/// exist.
+pub fn check_reachable(url: &str) -> Result<(), String> {
+    let parsed = reqwest::Url::parse(url).map_err(|e| format!("Invalid url {}: {}", url, e))?;
+    if parsed.scheme() == "file" {
+        return match parsed.to_file_path() {
+            Ok(ref path) if path.exists() => Ok(()),
+            _ => Err(format!("The remote map {} does not exist", url)),
+        };
+    }
+    let host = parsed
+        .host_str()
+        .ok_or(format!("The url {} has no host", url))?;
+    let port = parsed
+        .port_or_known_default()
+        .ok_or(format!("The url {} has no port", url))?;
+    debug!("Checking that {}:{} is reachable", host, port);
+    let addrs = (host, port).to_socket_addrs().map_err(|e| {
+        format!(
+            "Could not resolve {} for the remote map ({}), check the network or \
+             DNS settings, or run with --offline",
+            host, e
+        )
+    })?;
+    let mut last_error = None;
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(_) => return Ok(()),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(format!(
+        "Could not connect to {} for the remote map ({}), check the network or a \
+         proxy, or run with --offline",
+        host,
+        last_error
+            .map(|e| e.to_string())
+            .unwrap_or_else(|| "no addresses".to_string())
+    ))
+}
+
+/// Check that the filesystem holding path has room for needed bytes on top
+/// of the margin which is to be kept free
+pub fn check_space(path: &Path, needed: u64, margin: u64) -> Result<(), String> {
+    let free = disk_space::free_bytes(path)?;
+    match free >= needed.saturating_add(margin) {
+        true => Ok(()),
+        false => Err(format!(
+            "{} has {} free but previous builds of the products to build took {}{}, \
+             free some space or choose another root",
+            path.display(),
+            network::format_bytes(free),
+            network::format_bytes(needed),
+            match margin {
+                0 => String::new(),
+                m => format!(" and {} is to be kept free", network::format_bytes(m)),
+            }
+        )),
+    }
+}
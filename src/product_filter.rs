@@ -0,0 +1,88 @@
+use regex::Regex;
+
+/// Shell style patterns limiting a run to part of a graph, such as meas_*.
+/// A product is included when it matches one of the only patterns, or there
+/// are none, and matches none of the exclude patterns.
+#[derive(Default)]
+pub struct ProductFilter {
+    only: Vec<Regex>,
+    exclude: Vec<Regex>,
+}
+
+/// Translate a pattern where * matches any text and ? a single character
+/// into an anchored regular expression
+fn compile(pattern: &str) -> Result<Regex, String> {
+    let mut expression = String::from("^");
+    for c in pattern.chars() {
+        match c {
+            '*' => expression.push_str(".*"),
+            '?' => expression.push('.'),
+            _ => expression.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    expression.push('$');
+    Regex::new(&expression).map_err(|e| format!("Invalid product pattern {}: {}", pattern, e))
+}
+
+/// A product which the products a filter selects are built against, as if
+/// their tables required it, such as the python environment of a stack
+pub struct ImplicitDep {
+    pub product: String,
+    pub applies_to: ProductFilter,
+}
+
+impl ImplicitDep {
+    pub fn new(product: &str, only: &[String], exclude: &[String]) -> Result<ImplicitDep, String> {
+        Ok(ImplicitDep {
+            product: product.to_string(),
+            applies_to: ProductFilter::new(only, exclude)?,
+        })
+    }
+
+    /// Parse PRODUCT or PRODUCT:PATTERN,... where patterns starting with !
+    /// exclude products and the rest limit the dependency to what they match
+    pub fn parse(spec: &str) -> Result<ImplicitDep, String> {
+        let mut parts = spec.splitn(2, ':');
+        let product = parts.next().unwrap_or_default();
+        if product.is_empty() {
+            return Err(format!("{} does not name an implicit dependency", spec));
+        }
+        let (mut only, mut exclude) = (vec![], vec![]);
+        for pattern in parts.next().unwrap_or_default().split(',') {
+            match pattern {
+                "" => (),
+                p if p.starts_with('!') => exclude.push(p[1..].to_string()),
+                p => only.push(p.to_string()),
+            }
+        }
+        ImplicitDep::new(product, &only, &exclude)
+    }
+
+    /// True when product is built against this dependency, which is never
+    /// the case for the dependency itself
+    pub fn applies(&self, product: &str) -> bool {
+        product != self.product && self.applies_to.includes(product)
+    }
+}
+
+impl ProductFilter {
+    pub fn new(only: &[String], exclude: &[String]) -> Result<ProductFilter, String> {
+        Ok(ProductFilter {
+            only: only.iter().map(|p| compile(p)).collect::<Result<_, _>>()?,
+            exclude: exclude
+                .iter()
+                .map(|p| compile(p))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// True when the filter lets every product through
+    pub fn is_empty(&self) -> bool {
+        self.only.is_empty() && self.exclude.is_empty()
+    }
+
+    pub fn includes(&self, product: &str) -> bool {
+        (self.only.is_empty() || self.only.iter().any(|r| r.is_match(product)))
+            && !self.exclude.iter().any(|r| r.is_match(product))
+    }
+}
@@ -0,0 +1,187 @@
+use crate::naming;
+use log::{debug, trace};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use yaml_rust::yaml::{Hash, Yaml};
+
+/// Width of the bar drawn for the longest entry of a profile
+const BAR_WIDTH: u64 = 40;
+
+/// Time one product spent in one phase, such as clone or verb build
+#[derive(Clone, Debug)]
+pub struct Timing {
+    pub product: String,
+    pub phase: String,
+    pub millis: u64,
+}
+
+/// Where the time of a phase goes, so a profile can show whether a run is
+/// waiting on git, on the build tool, or on regenerate itself
+fn category(phase: &str) -> &'static str {
+    match phase {
+        "clone" | "checkout" => "git",
+        p if p.starts_with("verb ") => "build",
+        _ => "regenerate",
+    }
+}
+
+/// Fine grained timings of one run, kept with the history of the workspace
+/// as .regenerate/profiles/<run id>.yaml
+pub struct Profile {
+    run_id: String,
+    timings: Vec<Timing>,
+}
+
+fn profile_path(install_root: &Path, run_id: &str) -> PathBuf {
+    let mut path = PathBuf::from(install_root);
+    path.push(".regenerate");
+    path.push("profiles");
+    path.push(format!("{}.yaml", naming::sanitize(run_id)));
+    path
+}
+
+fn bar(millis: u64, longest: u64) -> String {
+    "#".repeat((millis * BAR_WIDTH / longest.max(1)) as usize)
+}
+
+fn seconds(millis: u64) -> String {
+    format!("{}.{:01}s", millis / 1000, millis % 1000 / 100)
+}
+
+impl Profile {
+    pub fn new(run_id: &str) -> Profile {
+        Profile {
+            run_id: run_id.to_string(),
+            timings: vec![],
+        }
+    }
+
+    /// Add the time a product spent in a phase, adding to any time it already
+    /// spent there
+    pub fn record(&mut self, product: &str, phase: &str, duration: Duration) {
+        let millis = duration.as_secs() * 1000 + u64::from(duration.subsec_millis());
+        trace!("{} spent {}ms in {}", product, millis, phase);
+        match self
+            .timings
+            .iter_mut()
+            .find(|t| t.product == product && t.phase == phase)
+        {
+            Some(timing) => timing.millis += millis,
+            None => self.timings.push(Timing {
+                product: product.to_string(),
+                phase: phase.to_string(),
+                millis,
+            }),
+        }
+    }
+
+    /// Load the profile a run saved into the workspace at install_root
+    pub fn load(install_root: &Path, run_id: &str) -> Result<Profile, String> {
+        let path = profile_path(install_root, run_id);
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| format!("No profile of run {} in {}: {}", run_id, path.display(), e))?;
+        let docs = yaml_rust::YamlLoader::load_from_str(&text)
+            .map_err(|e| format!("Could not parse {}: {}", path.display(), e))?;
+        let mut profile = Profile::new(run_id);
+        for entry in docs.get(0).and_then(|d| d.as_vec()).into_iter().flatten() {
+            match (
+                entry["product"].as_str(),
+                entry["phase"].as_str(),
+                entry["millis"].as_i64(),
+            ) {
+                (Some(product), Some(phase), Some(millis)) => profile.timings.push(Timing {
+                    product: product.to_string(),
+                    phase: phase.to_string(),
+                    millis: millis as u64,
+                }),
+                _ => debug!("Skipping malformed profile entry {:?}", entry),
+            }
+        }
+        Ok(profile)
+    }
+
+    pub fn save(&self, install_root: &Path) -> Result<(), String> {
+        let path = profile_path(install_root, &self.run_id);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| format!("{}", e))?;
+        }
+        let entries = self
+            .timings
+            .iter()
+            .map(|t| {
+                let mut hash = Hash::new();
+                hash.insert(
+                    Yaml::String("product".to_string()),
+                    Yaml::String(t.product.clone()),
+                );
+                hash.insert(
+                    Yaml::String("phase".to_string()),
+                    Yaml::String(t.phase.clone()),
+                );
+                hash.insert(
+                    Yaml::String("millis".to_string()),
+                    Yaml::Integer(t.millis as i64),
+                );
+                Yaml::Hash(hash)
+            })
+            .collect();
+        let mut out = String::new();
+        yaml_rust::YamlEmitter::new(&mut out)
+            .dump(&Yaml::Array(entries))
+            .map_err(|e| format!("Could not serialize profile: {:?}", e))?;
+        std::fs::write(&path, out).map_err(|e| format!("Could not write {}: {}", path.display(), e))
+    }
+
+    /// Render the profile as a flame style breakdown, with where the time
+    /// went overall followed by each product and its phases, the slowest
+    /// first
+    pub fn render(&self) -> String {
+        let mut categories: BTreeMap<&str, u64> = BTreeMap::new();
+        let mut products: BTreeMap<&str, Vec<&Timing>> = BTreeMap::new();
+        for timing in self.timings.iter() {
+            *categories.entry(category(&timing.phase)).or_insert(0) += timing.millis;
+            products
+                .entry(timing.product.as_str())
+                .or_insert_with(Vec::new)
+                .push(timing);
+        }
+        let total: u64 = categories.values().sum();
+        let mut out = format!("Run {}: {} in total\n", self.run_id, seconds(total));
+        let mut categories: Vec<_> = categories.into_iter().collect();
+        categories.sort_by(|a, b| b.1.cmp(&a.1));
+        for (name, millis) in categories.iter() {
+            out.push_str(&format!(
+                "  {:<12} {:>9} {:>3}% {}\n",
+                name,
+                seconds(*millis),
+                millis * 100 / total.max(1),
+                bar(*millis, total)
+            ));
+        }
+        let mut products: Vec<(&str, u64, Vec<&Timing>)> = products
+            .into_iter()
+            .map(|(name, timings)| (name, timings.iter().map(|t| t.millis).sum(), timings))
+            .collect();
+        products.sort_by(|a, b| b.1.cmp(&a.1));
+        let longest = products.first().map(|p| p.1).unwrap_or(0);
+        for (name, millis, mut timings) in products.into_iter() {
+            out.push_str(&format!(
+                "\n{:<30} {:>9} {}\n",
+                name,
+                seconds(millis),
+                bar(millis, longest)
+            ));
+            timings.sort_by(|a, b| b.millis.cmp(&a.millis));
+            for timing in timings.iter() {
+                out.push_str(&format!(
+                    "  {:<28} {:>9} {}\n",
+                    timing.phase,
+                    seconds(timing.millis),
+                    bar(timing.millis, longest)
+                ));
+            }
+        }
+        out
+    }
+}
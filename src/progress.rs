@@ -0,0 +1,148 @@
+use log::debug;
+use serde::Serialize;
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// Where progress events are streamed to
+#[derive(Clone, Debug)]
+pub enum ProgressSink {
+    /// A listening unix domain socket
+    UnixSocket(PathBuf),
+    /// A named pipe, which some reader must have open
+    Fifo(PathBuf),
+}
+
+/// A single progress event, serialized as one line of json
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ProgressEvent {
+    RunStarted {
+        product: String,
+    },
+    CloneStarted {
+        product: String,
+    },
+    ProductStarted {
+        product: String,
+    },
+    VerbStarted {
+        product: String,
+        verb: String,
+    },
+    VerbFinished {
+        product: String,
+        verb: String,
+        success: bool,
+    },
+    ProductFinished {
+        product: String,
+        outcome: String,
+    },
+    RunFinished {
+        built: usize,
+        reused: usize,
+        failed: usize,
+    },
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    time: String,
+    #[serde(flatten)]
+    event: &'a ProgressEvent,
+}
+
+/// Minimum time between attempts to reconnect to a sink which went away
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Writes progress events to a sink without ever blocking the build. Events
+/// are dropped while nothing is listening, and the sink is reconnected to
+/// periodically so listeners may come and go.
+pub struct ProgressStream {
+    sink: Option<ProgressSink>,
+    connection: Option<Box<dyn Write>>,
+    last_attempt: Option<Instant>,
+}
+
+impl ProgressStream {
+    pub fn new(sink: Option<ProgressSink>) -> ProgressStream {
+        ProgressStream {
+            sink,
+            connection: None,
+            last_attempt: None,
+        }
+    }
+
+    fn connect(sink: &ProgressSink) -> std::io::Result<Box<dyn Write>> {
+        match sink {
+            ProgressSink::UnixSocket(path) => {
+                let stream = UnixStream::connect(path)?;
+                stream.set_nonblocking(true)?;
+                Ok(Box::new(stream))
+            }
+            ProgressSink::Fifo(path) => {
+                // opening a fifo for writing without blocking fails if there
+                // is no reader, which is treated like a refused connection
+                let file = std::fs::OpenOptions::new()
+                    .write(true)
+                    .custom_flags(libc::O_NONBLOCK)
+                    .open(path)?;
+                Ok(Box::new(file))
+            }
+        }
+    }
+
+    pub fn emit(&mut self, event: ProgressEvent) {
+        let sink = match self.sink.as_ref() {
+            Some(s) => s,
+            None => return,
+        };
+        if self.connection.is_none() {
+            let should_attempt = match self.last_attempt {
+                Some(t) => t.elapsed() > RECONNECT_INTERVAL,
+                None => true,
+            };
+            if !should_attempt {
+                return;
+            }
+            self.last_attempt = Some(Instant::now());
+            match ProgressStream::connect(sink) {
+                Ok(c) => self.connection = Some(c),
+                Err(e) => {
+                    debug!("Could not connect to progress sink {:?}: {}", sink, e);
+                    return;
+                }
+            }
+        }
+        let envelope = Envelope {
+            time: time::now_utc().rfc3339().to_string(),
+            event: &event,
+        };
+        let mut line = match serde_json::to_string(&envelope) {
+            Ok(l) => l,
+            Err(e) => {
+                debug!("Could not serialize progress event: {}", e);
+                return;
+            }
+        };
+        line.push('\n');
+        if let Some(connection) = self.connection.as_mut() {
+            match connection.write(line.as_bytes()) {
+                Ok(n) if n == line.len() => (),
+                // a partial line would corrupt the stream, so start over with
+                // a fresh connection
+                Ok(_) => self.connection = None,
+                // the listener is not keeping up, drop the event rather than
+                // stall the build
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
+                Err(e) => {
+                    debug!("Lost connection to progress sink: {}", e);
+                    self.connection = None;
+                }
+            }
+        }
+    }
+}
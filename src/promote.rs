@@ -0,0 +1,131 @@
+use crate::database::ProductDatabase;
+use crate::regenerate::reups;
+use crate::tags;
+use crate::verify::{external_prefix_warning, verify_product};
+use crate::workspace::Workspace;
+use log::{debug, info, warn};
+use std::path::{Path, PathBuf};
+
+pub struct PromoteOptions {
+    /// Tag in the scratch workspace selecting the products to promote
+    pub tag: String,
+    /// Tag to apply in the production workspace, defaults to tag
+    pub production_tag: Option<String>,
+    /// Hard link files into the production install root instead of copying
+    pub link: bool,
+}
+
+/// Recreate the directory tree at src under dest, either copying or hard
+/// linking each file
+fn transfer_tree(src: &Path, dest: &Path, link: bool) -> Result<(), String> {
+    std::fs::create_dir_all(dest).map_err(|e| format!("{}", e))?;
+    for entry in std::fs::read_dir(src).map_err(|e| format!("{}", e))? {
+        let entry = entry.map_err(|e| format!("{}", e))?;
+        let file_type = entry.file_type().map_err(|e| format!("{}", e))?;
+        let mut target = PathBuf::from(dest);
+        target.push(entry.file_name());
+        if file_type.is_dir() {
+            transfer_tree(&entry.path(), &target, link)?;
+        } else if file_type.is_symlink() {
+            let pointee = std::fs::read_link(entry.path()).map_err(|e| format!("{}", e))?;
+            std::os::unix::fs::symlink(pointee, &target).map_err(|e| format!("{}", e))?;
+        } else if link {
+            std::fs::hard_link(entry.path(), &target).map_err(|e| format!("{}", e))?;
+        } else {
+            std::fs::copy(entry.path(), &target).map_err(|e| format!("{}", e))?;
+        }
+    }
+    Ok(())
+}
+
+/// Promote every product carrying the given tag in the scratch workspace into
+/// the production workspace. Nothing is promoted if any tagged product fails
+/// verification. Returns the names of the products that were promoted.
+pub fn promote(
+    scratch: &Workspace,
+    production: &Workspace,
+    options: &PromoteOptions,
+) -> Result<Vec<String>, String> {
+    let scratch_db: Box<dyn ProductDatabase> = Box::new(scratch.open_db()?);
+    let mut production_db: Box<dyn ProductDatabase> = Box::new(production.open_db()?);
+    let production_tag = options
+        .production_tag
+        .as_ref()
+        .unwrap_or(&options.tag)
+        .clone();
+
+    // work out which products and versions the tag refers to
+    let mut to_promote = vec![];
+    for product in scratch_db.get_all_products() {
+        let versions = scratch_db.get_versions_from_tag(&product, vec![&options.tag]);
+        if let Some(version) = versions.into_iter().next() {
+            to_promote.push((product, version));
+        }
+    }
+    if to_promote.is_empty() {
+        return Err(format!(
+            "No products in the scratch workspace are tagged {}",
+            options.tag
+        ));
+    }
+
+    // refuse to promote anything unless every product verifies
+    let mut problems = vec![];
+    for (product, version) in to_promote.iter() {
+        problems.extend(verify_product(scratch_db.as_ref(), product, version));
+    }
+    if !problems.is_empty() {
+        return Err(format!(
+            "Refusing to promote {}, verification failed:\n{}",
+            options.tag,
+            problems.join("\n")
+        ));
+    }
+
+    let mut promoted = vec![];
+    for (product, version) in to_promote.iter() {
+        let src = scratch_db
+            .get_table_from_version(product, version)
+            .ok_or(format!("Could not look up table for {}", product))?
+            .product_dir;
+        // products with a fixed prefix are declared where they are
+        let dest = match external_prefix_warning(product, &src, &scratch.install_root) {
+            Some(warning) => {
+                warn!("{}, declaring it in place", warning);
+                src.clone()
+            }
+            None => production.product_dir(product, version),
+        };
+        if dest.exists() {
+            debug!(
+                "{} already exists in production, reusing it",
+                dest.display()
+            );
+        } else {
+            info!("Promoting {} {} to {}", product, version, dest.display());
+            transfer_tree(&src, &dest, options.link)?;
+        }
+
+        let mut table_path = dest.clone();
+        table_path.push("ups");
+        table_path.push(format!("{}.table", product));
+        let table = reups::table::Table::from_file(product.clone(), table_path, dest.clone())
+            .map_err(|e| format!("{}", e))?;
+        let ident = scratch_db.get_identity_from_version(product, version);
+        let declare_product = reups::DeclareInputs {
+            product,
+            prod_dir: &dest,
+            version,
+            tag: None,
+            ident: ident.as_ref().map(|x| x.as_str()),
+            flavor: Some(reups::SYSTEM_OS),
+            table: Some(table),
+            relative: false,
+        };
+        production_db.declare(vec![declare_product])?;
+        promoted.push(product.clone());
+    }
+    // tag everything together, so production never sees a partial promotion
+    tags::move_tag(production_db.as_mut(), &production_tag, &to_promote)?;
+    Ok(promoted)
+}
@@ -0,0 +1,208 @@
+use crate::host::HostFingerprint;
+use crate::metadata::ProductMetadata;
+use log::debug;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use yaml_rust::yaml::{Hash, Yaml};
+
+/// Record of how an installed product was produced, written alongside the
+/// install so it travels with the product directory
+#[derive(Clone, Debug, Default)]
+pub struct Provenance {
+    pub product: String,
+    pub version: String,
+    pub id: String,
+    /// Sha of the source the product was built from
+    pub sha: Option<String>,
+    /// Id given to the build by whatever drove the run, such as bNNNN
+    pub build_id: Option<String>,
+    pub metadata: ProductMetadata,
+    /// Tags the product was declared with
+    pub tags: Vec<String>,
+    /// Hash of the headers and shared libraries of the install
+    pub abi_hash: Option<String>,
+    /// ABI hashes of the direct dependencies the product was built against
+    pub dependency_abi: BTreeMap<String, String>,
+    /// Versions of the build tools and compilers in the build environment
+    pub tool_versions: BTreeMap<String, String>,
+    /// Variables the run added to the build environment
+    pub extra_env: BTreeMap<String, String>,
+    /// The machine the product was built on
+    pub host: Option<HostFingerprint>,
+}
+
+fn insert_str(hash: &mut Hash, key: &str, value: &str) {
+    hash.insert(
+        Yaml::String(key.to_string()),
+        Yaml::String(value.to_string()),
+    );
+}
+
+fn get_str(yaml: &Yaml, key: &str) -> Option<String> {
+    yaml[key].as_str().map(|s| s.to_string())
+}
+
+fn string_map(yaml: &Yaml) -> BTreeMap<String, String> {
+    yaml.as_hash()
+        .map(|h| {
+            h.iter()
+                .filter_map(|(k, v)| Some((k.as_str()?.to_string(), v.as_str()?.to_string())))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+impl Provenance {
+    /// Search the installs of a product under install_root for the one
+    /// built with the given id
+    pub fn find_install(install_root: &Path, product: &str, id: &str) -> Option<PathBuf> {
+        let mut product_root = PathBuf::from(install_root);
+        product_root.push(product);
+        std::fs::read_dir(&product_root)
+            .ok()?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .find(|dir| match Provenance::read(dir) {
+                Ok(provenance) => provenance.product == product && provenance.id == id,
+                Err(_) => false,
+            })
+    }
+
+    /// Location of the provenance file within a product directory
+    pub fn path(product_dir: &Path) -> PathBuf {
+        let mut path = PathBuf::from(product_dir);
+        path.push(".regenerate");
+        path.push("provenance.yaml");
+        path
+    }
+
+    pub fn to_yaml(&self) -> Yaml {
+        let mut hash = Hash::new();
+        insert_str(&mut hash, "product", &self.product);
+        insert_str(&mut hash, "version", &self.version);
+        insert_str(&mut hash, "id", &self.id);
+        if let Some(sha) = self.sha.as_ref() {
+            insert_str(&mut hash, "sha", sha);
+        }
+        if let Some(build_id) = self.build_id.as_ref() {
+            insert_str(&mut hash, "build_id", build_id);
+        }
+        if let Some(license) = self.metadata.license.as_ref() {
+            insert_str(&mut hash, "license", license);
+        }
+        if !self.metadata.license_files.is_empty() {
+            hash.insert(
+                Yaml::String("license_files".to_string()),
+                Yaml::Array(
+                    self.metadata
+                        .license_files
+                        .iter()
+                        .map(|f| Yaml::String(f.clone()))
+                        .collect(),
+                ),
+            );
+        }
+        if let Some(description) = self.metadata.description.as_ref() {
+            insert_str(&mut hash, "description", description);
+        }
+        if !self.tags.is_empty() {
+            hash.insert(
+                Yaml::String("tags".to_string()),
+                Yaml::Array(self.tags.iter().map(|t| Yaml::String(t.clone())).collect()),
+            );
+        }
+        if let Some(abi_hash) = self.abi_hash.as_ref() {
+            insert_str(&mut hash, "abi_hash", abi_hash);
+        }
+        if !self.dependency_abi.is_empty() {
+            let mut deps = Hash::new();
+            for (name, abi) in self.dependency_abi.iter() {
+                insert_str(&mut deps, name, abi);
+            }
+            hash.insert(Yaml::String("dependency_abi".to_string()), Yaml::Hash(deps));
+        }
+        if !self.tool_versions.is_empty() {
+            let mut tools = Hash::new();
+            for (tool, version) in self.tool_versions.iter() {
+                insert_str(&mut tools, tool, version);
+            }
+            hash.insert(Yaml::String("tool_versions".to_string()), Yaml::Hash(tools));
+        }
+        if !self.extra_env.is_empty() {
+            let mut env = Hash::new();
+            for (name, value) in self.extra_env.iter() {
+                insert_str(&mut env, name, value);
+            }
+            hash.insert(Yaml::String("extra_env".to_string()), Yaml::Hash(env));
+        }
+        if let Some(host) = self.host.as_ref() {
+            hash.insert(Yaml::String("host".to_string()), host.to_yaml());
+        }
+        Yaml::Hash(hash)
+    }
+
+    pub fn from_yaml(yaml: &Yaml) -> Result<Provenance, String> {
+        let required = |key: &str| {
+            get_str(yaml, key).ok_or(format!("Provenance is missing required key {}", key))
+        };
+        Ok(Provenance {
+            product: required("product")?,
+            version: required("version")?,
+            id: required("id")?,
+            sha: get_str(yaml, "sha"),
+            build_id: get_str(yaml, "build_id"),
+            metadata: ProductMetadata {
+                license: get_str(yaml, "license"),
+                license_files: yaml["license_files"]
+                    .as_vec()
+                    .map(|v| {
+                        v.iter()
+                            .filter_map(|f| f.as_str().map(|s| s.to_string()))
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                description: get_str(yaml, "description"),
+            },
+            tags: yaml["tags"]
+                .as_vec()
+                .map(|v| {
+                    v.iter()
+                        .filter_map(|t| t.as_str().map(|s| s.to_string()))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            abi_hash: get_str(yaml, "abi_hash"),
+            dependency_abi: string_map(&yaml["dependency_abi"]),
+            tool_versions: string_map(&yaml["tool_versions"]),
+            extra_env: string_map(&yaml["extra_env"]),
+            host: HostFingerprint::from_yaml(&yaml["host"]),
+        })
+    }
+
+    /// Write the provenance into the product directory
+    pub fn write(&self, product_dir: &Path) -> Result<(), String> {
+        let path = Provenance::path(product_dir);
+        debug!("Writing provenance to {}", path.display());
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| format!("{}", e))?;
+        }
+        let mut out = String::new();
+        yaml_rust::YamlEmitter::new(&mut out)
+            .dump(&self.to_yaml())
+            .map_err(|e| format!("Could not serialize provenance: {:?}", e))?;
+        std::fs::write(&path, out).map_err(|e| format!("Could not write {}: {}", path.display(), e))
+    }
+
+    /// Read the provenance recorded in a product directory
+    pub fn read(product_dir: &Path) -> Result<Provenance, String> {
+        let path = Provenance::path(product_dir);
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        let mut docs = yaml_rust::YamlLoader::load_from_str(&text)
+            .map_err(|e| format!("Could not parse {}: {}", path.display(), e))?;
+        if docs.is_empty() {
+            return Err(format!("{} is empty", path.display()));
+        }
+        Provenance::from_yaml(&docs.remove(0))
+    }
+}
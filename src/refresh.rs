@@ -0,0 +1,174 @@
+use crate::clone_backend;
+use crate::credentials::{self, GitAuth};
+use crate::host_keys::HostKeyPolicy;
+use crate::network::{self, Throttle};
+use git2::Repository;
+use log::{debug, info, warn};
+use std::cell::{Cell, RefCell};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// How long ago a repository was last fetched from its remote. Repositories
+/// which have never been fetched are aged from when they were cloned.
+pub fn last_fetch_age(repo: &Repository) -> Option<Duration> {
+    let mut marker = PathBuf::from(repo.path());
+    marker.push("FETCH_HEAD");
+    if !marker.exists() {
+        marker.pop();
+        marker.push("config");
+    }
+    let modified = std::fs::metadata(&marker).ok()?.modified().ok()?;
+    SystemTime::now().duration_since(modified).ok()
+}
+
+/// Fetch all the branches and tags of the named remote, keeping the transfer
+/// under max_rate bytes per second if given, verifying ssh host keys with
+/// host_keys, and authenticating with auth if given
+pub fn fetch_repo(
+    repo: &Repository,
+    remote_name: &str,
+    max_rate: Option<u64>,
+    host_keys: Option<&HostKeyPolicy>,
+    auth: Option<&GitAuth>,
+) -> Result<(), String> {
+    let workdir = repo.workdir().unwrap_or_else(|| repo.path());
+    debug!("Fetching {} in {}", remote_name, workdir.display());
+    // partial clones need the system git to negotiate the filter, and
+    // shallow ones to keep their history shallow
+    if clone_backend::is_partial_clone(repo) || clone_backend::is_shallow(repo) {
+        let size_before = clone_backend::object_size(repo.path());
+        let mut command = std::process::Command::new("git");
+        command
+            .args(&["fetch", "--quiet", "--tags", remote_name])
+            .current_dir(workdir);
+        let url = clone_backend::remote_url(repo, remote_name);
+        credentials::configure_command(&mut command, &url, auth, host_keys)?;
+        let output = command
+            .output()
+            .map_err(|e| format!("Could not run system git to fetch: {}", e))?;
+        if !output.status.success() {
+            return Err(format!(
+                "Failed to fetch {} in {}: {}",
+                remote_name,
+                workdir.display(),
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+        network::record_git(clone_backend::object_size(repo.path()).saturating_sub(size_before));
+        return Ok(());
+    }
+    let mut remote = repo
+        .find_remote(remote_name)
+        .map_err(|e| format!("No remote {} in {}: {}", remote_name, workdir.display(), e))?;
+    let throttle = Throttle::new(max_rate);
+    let counted = Cell::new(0);
+    let key_failure = RefCell::new(None);
+    let url = remote.url().unwrap_or("").to_string();
+    let mut callbacks = git2::RemoteCallbacks::new();
+    callbacks.credentials(credentials::git_credentials_with(auth, &url));
+    if let Some(policy) = host_keys {
+        policy.attach(&mut callbacks, &url, &key_failure);
+    }
+    callbacks.transfer_progress(|stats| {
+        let received = stats.received_bytes() as u64;
+        network::record_git(received - counted.get());
+        counted.set(received);
+        throttle.pace(received);
+        true
+    });
+    let mut options = git2::FetchOptions::new();
+    options.remote_callbacks(callbacks);
+    options.download_tags(git2::AutotagOption::All);
+    let result = remote.fetch(&[] as &[&str], Some(&mut options), None);
+    if let Some(reason) = key_failure.borrow().as_ref() {
+        return Err(format!("Refused to fetch {}: {}", url, reason));
+    }
+    result.map_err(|e| {
+        format!(
+            "Failed to fetch {} in {}: {}",
+            remote_name,
+            workdir.display(),
+            e
+        )
+    })
+}
+
+/// Fetch a repository if it has not been fetched within max_age
+pub fn refresh_if_stale(
+    repo: &Repository,
+    remote_name: &str,
+    max_age: Duration,
+    max_rate: Option<u64>,
+    host_keys: Option<&HostKeyPolicy>,
+    auth: Option<&GitAuth>,
+) -> Result<(), String> {
+    match last_fetch_age(repo) {
+        Some(age) if age <= max_age => Ok(()),
+        _ => {
+            info!(
+                "Clone at {} is older than {}s, fetching",
+                repo.path().display(),
+                max_age.as_secs()
+            );
+            fetch_repo(repo, remote_name, max_rate, host_keys, auth)
+        }
+    }
+}
+
+fn refresh_path(
+    path: &Path,
+    remote_name: &str,
+    max_rate: Option<u64>,
+    host_keys: Option<&HostKeyPolicy>,
+) -> Result<(), String> {
+    let repo =
+        Repository::open(path).map_err(|e| format!("Could not open {}: {}", path.display(), e))?;
+    fetch_repo(&repo, remote_name, max_rate, host_keys, None)
+}
+
+/// Fetch every repository found in clone_root using up to jobs threads,
+/// returning the result for each repository. A bandwidth cap is shared
+/// evenly between the threads.
+pub fn refresh_clones(
+    clone_root: &Path,
+    remote_name: &str,
+    jobs: usize,
+    max_rate: Option<u64>,
+    host_keys: Option<HostKeyPolicy>,
+) -> Result<Vec<(PathBuf, Result<(), String>)>, String> {
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(clone_root)
+        .map_err(|e| format!("Could not read {}: {}", clone_root.display(), e))?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.is_dir())
+        .collect();
+    paths.sort();
+    let thread_rate = max_rate.map(|r| r / jobs.max(1) as u64);
+    let mut results = vec![];
+    for chunk in paths.chunks(jobs.max(1)) {
+        let handles: Vec<_> = chunk
+            .iter()
+            .map(|path| {
+                let path = path.clone();
+                let remote_name = remote_name.to_string();
+                let host_keys = host_keys.clone();
+                std::thread::spawn(move || {
+                    let result = refresh_path(&path, &remote_name, thread_rate, host_keys.as_ref());
+                    (path, result)
+                })
+            })
+            .collect();
+        for handle in handles {
+            match handle.join() {
+                Ok((path, result)) => {
+                    if let Err(e) = result.as_ref() {
+                        warn!("{}", e);
+                    }
+                    results.push((path, result));
+                }
+                Err(_) => return Err("A refresh thread panicked".to_string()),
+            }
+        }
+    }
+    Ok(results)
+}
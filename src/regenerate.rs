@@ -1,4 +1,63 @@
-use crate::repo_wrapper::RepoSourceWrapper;
+use crate::abi;
+use crate::binary_cache;
+pub use crate::build_backend::Phase;
+use crate::build_backend::{self, BuildBackend, BuildContext, BuildStep, PhaseSettings};
+pub use crate::classify::OutputProcessor;
+use crate::classify::{self, Classifiers};
+use crate::clock_skew;
+use crate::clone_backend;
+pub use crate::clone_backend::{BackendKind, CloneLimits};
+use crate::compile_db;
+use crate::credentials;
+pub use crate::credentials::{GitAuth, GitAuthConfig};
+pub use crate::database::ProductDatabase;
+use crate::disk_space;
+use crate::environment::{self, ProvisionedEnvironment};
+pub use crate::error::RegenError;
+use crate::graph_export::SnapshotNode;
+pub use crate::graph_export::{GraphFormat, GraphSnapshot};
+use crate::graph_memo::GraphMemo;
+use crate::history::History;
+use crate::holds::Holds;
+use crate::host::HostFingerprint;
+use crate::host_keys::HostKeyPolicy;
+pub use crate::identity::{BuildIds, ContentIds, IdentityBackend};
+use crate::jenkins::{self, BuildStream, ManifestEntry};
+use crate::lfs;
+pub use crate::log_shipping::LogDestination;
+use crate::log_tail;
+pub use crate::log_tail::{LogTail, TailEvent};
+use crate::manifest;
+use crate::map_cache::{CachedMap, MapCache};
+use crate::metadata::{self, ProductMetadata};
+use crate::mirror;
+use crate::naming::{self, RunName};
+use crate::network;
+use crate::plan;
+pub use crate::plan::{Plan, PlanAction, PlanStep};
+use crate::preflight;
+pub use crate::product_filter::{ImplicitDep, ProductFilter};
+use crate::profile::Profile;
+pub use crate::progress::ProgressSink;
+use crate::progress::{ProgressEvent, ProgressStream};
+use crate::provenance::Provenance;
+use crate::refresh;
+pub use crate::release_manifest::ReleaseManifest;
+use crate::repo_wrapper;
+pub use crate::repo_wrapper::{RefPin, RepoEntry, RepoSourceWrapper};
+use crate::report;
+pub use crate::report::{
+    EmailSettings, ProductOutcome, ReportFormat, ReportOptions, RunReport, SmtpSecurity,
+};
+use crate::reproducible;
+use crate::run_state::RunState;
+use crate::safety;
+use crate::scheduler::{self, BuildJob, JobSummary, LiveOutput, Scheduler, WorkerEvent};
+use crate::store::{self, Store, StoreLock};
+use crate::table_gen;
+use crate::table_lint::{self, Severity};
+use crate::tags;
+use crate::toolchain;
 use crypto::digest::Digest;
 use crypto::sha1::Sha1;
 use fnv::FnvHashMap;
@@ -9,59 +68,910 @@ use log::{debug, info, warn};
 use reqwest;
 pub use reups::DBBuilderTrait;
 pub use reups_lib as reups;
-use std::collections::{HashMap, HashSet};
-use std::io::{BufWriter, Write};
-use std::iter::FromIterator;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::OsString;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
 pub use std::path::PathBuf;
+use std::rc::Rc;
 use std::str;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tempdir::TempDir;
 use time;
 use yaml_rust;
 
+/// Build directories untouched for this long are taken to be abandoned
+const ABANDONED_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How often free space is checked while builds are paused
+const DISK_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// What to do when the table of a product being reused can not be read from
+/// the database
+#[derive(Clone, Debug)]
+pub enum TableFallback {
+    /// Fail the run, this is the default
+    Abort,
+    /// Read the table from the directory the product was installed to
+    ReadInstalled,
+    /// Build the product from source again
+    Rebuild,
+}
+
+impl Default for TableFallback {
+    fn default() -> TableFallback {
+        TableFallback::Abort
+    }
+}
+
+/// What to do when a product which would be reused was built against
+/// dependencies whose headers or libraries have since changed
+#[derive(Clone, Debug, PartialEq)]
+pub enum AbiCheck {
+    /// Do not compare ABIs
+    Off,
+    /// Build the product again against the current dependencies, this is the
+    /// default
+    Rebuild,
+    /// Fail the run, describing the mismatch
+    Fail,
+}
+
+impl Default for AbiCheck {
+    fn default() -> AbiCheck {
+        AbiCheck::Rebuild
+    }
+}
+
+/// What to do when the database declares a product to be reused but its
+/// install is no longer on disk
+#[derive(Clone, Debug, PartialEq)]
+pub enum MissingInstall {
+    /// Build the product from source again, this is the default
+    Rebuild,
+    /// Fail the run, naming the missing install
+    Fail,
+}
+
+impl Default for MissingInstall {
+    fn default() -> MissingInstall {
+        MissingInstall::Rebuild
+    }
+}
+
+/// What to do when a product which would be reused was built on a host it
+/// may not run on, such as one with a newer glibc or CPU
+#[derive(Clone, Debug, PartialEq)]
+pub enum HostCheck {
+    /// Do not compare hosts
+    Off,
+    /// Reuse the product, warning about the difference, this is the default
+    Warn,
+    /// Build the product again on this host
+    Rebuild,
+    /// Fail the run, describing the difference
+    Fail,
+}
+
+impl Default for HostCheck {
+    fn default() -> HostCheck {
+        HostCheck::Warn
+    }
+}
+
+/// How the optional dependencies named in tables are treated
+#[derive(Clone, Debug, PartialEq)]
+pub enum OptionalPolicy {
+    /// Leave them out of the graph, this is the default
+    Ignore,
+    /// Build those which can be cloned, warning about any which can not
+    BuildIfAvailable,
+    /// Build them as if they were required
+    Require,
+}
+
+impl Default for OptionalPolicy {
+    fn default() -> OptionalPolicy {
+        OptionalPolicy::Ignore
+    }
+}
+
+/// What to do when two products of a run are cloned from the same url
+#[derive(Clone, Debug, PartialEq)]
+pub enum DuplicateUrls {
+    /// Check the later product out as a worktree of the first one's clone,
+    /// warning about it, this is the default
+    Share,
+    /// Fail the run, naming the products
+    Fail,
+}
+
+impl Default for DuplicateUrls {
+    fn default() -> DuplicateUrls {
+        DuplicateUrls::Share
+    }
+}
+
+/// Everything controlling how a run clones, builds, and declares products
 pub struct RegenOptions {
     pub branches: Option<Vec<String>>,
-    pub local_yaml: Option<PathBuf>,
+    /// Local repository maps overlaying the remote one, each a file or a
+    /// directory of *.yaml fragments read in name order. Later maps take
+    /// precedence over earlier ones.
+    pub local_yaml: Vec<PathBuf>,
     pub clone_root: String,
     pub install_root: String,
     pub version: String,
     pub build_tool: String,
     pub tag: Option<String>,
-    pub remote_package_url: String,
+    pub remote_package_url: Option<String>,
+    /// Urls serving copies of the remote package list, tried in order when
+    /// it can not be fetched from remote_package_url
+    pub remote_package_mirrors: Vec<String>,
+    pub allow_missing_remote: bool,
+    pub clone_backend: BackendKind,
+    pub host_clone_backends: HashMap<String, BackendKind>,
+    pub clone_limits: CloneLimits,
+    /// Prefix of the conda environment used for builds, defaults to the
+    /// CONDA_PREFIX environment variable
+    pub conda_prefix: Option<PathBuf>,
+    /// Mapping of eups product names to the conda packages which satisfy them
+    pub environment_products: HashMap<String, String>,
+    /// Explicit specification or lock file describing the environment, used
+    /// in place of the installed package list when computing its hash
+    pub environment_spec: Option<PathBuf>,
+    /// Credentials for cloning and fetching from particular hosts or products
+    pub git_auth: GitAuthConfig,
+    /// Reused clones are fetched from this remote before checking out
+    pub fetch_remote: String,
+    /// Only fetch reused clones last fetched longer ago than this, rather
+    /// than every time
+    pub max_clone_age: Option<std::time::Duration>,
+    /// Never touch the network, building from existing clones as they are,
+    /// the local product map, and the remote map as last cached
+    pub offline: bool,
+    /// Resolve the branch of each product to a commit without checking it
+    /// out, reading tables from the commits, so clones are left as they are
+    pub no_checkout: bool,
+    /// Leave the LFS files of checkouts as pointer files rather than fetching
+    /// their content, for when an LFS quota is used up
+    pub skip_lfs: bool,
+    /// Clone with only this many commits of history, fetching the rest if a
+    /// branch or revision is not found in it
+    pub clone_depth: Option<u32>,
+    /// Directory of bare mirrors shared between users, which clones are made
+    /// from rather than from the product urls. Clones borrow the objects of
+    /// the mirrors, so the clone root need only be a private scratch area.
+    pub mirror_root: Option<PathBuf>,
+    /// How to publish the run report, if at all
+    pub report: Option<ReportOptions>,
+    /// Number of times each build verb may be retried after failing
+    pub verb_retries: HashMap<String, u32>,
+    /// Rebuild from a fresh copy of the source before each retry
+    pub restage_on_retry: bool,
+    /// Where to stream progress events, if anywhere
+    pub progress_sink: Option<ProgressSink>,
+    /// Fail when a table has errors, rather than only reporting them
+    pub strict_tables: bool,
+    /// Program and arguments to prefix build tool invocations with, such as
+    /// nice -n19. It is run around any wrapper a product specifies.
+    pub command_wrapper: Option<Vec<String>>,
+    /// Warn when the clock of the filesystem holding the clone or install
+    /// root differs from the local clock by more than this
+    pub clock_skew_threshold: Option<Duration>,
+    /// Reset the modification times of checked out files to now, working
+    /// around filesystems with skewed clocks
+    pub normalize_mtimes: bool,
+    /// Only apply the tag once the whole run has succeeded, moving it for
+    /// every product at once rather than as each product is declared
+    pub atomic_tag: bool,
+    /// Gather compilation databases written by builds so IDEs can use them
+    pub collect_compile_commands: bool,
+    /// Template used to name the logs and reports of a run, see
+    /// naming::RunName::render
+    pub run_name_template: Option<String>,
+    /// Ask before starting a run which would build at least this many
+    /// products from source, when there is a terminal to ask on
+    pub confirm_threshold: Option<usize>,
+    /// Answer yes to any confirmation, for unattended runs
+    pub assume_yes: bool,
+    /// How to recover when a reused product's table can not be read
+    pub table_fallback: TableFallback,
+    /// How to handle reused products built against a different dependency ABI
+    pub abi_check: AbiCheck,
+    /// How to handle reused products whose install has been deleted
+    pub missing_installs: MissingInstall,
+    /// How to handle reused products built on an incompatible host
+    pub host_check: HostCheck,
+    /// Whether optional dependencies are built along with required ones
+    pub optional_dependencies: OptionalPolicy,
+    /// How products sharing a git url are cloned
+    pub duplicate_urls: DuplicateUrls,
+    /// Identities besides the content id that installs are known by
+    pub identity: Box<dyn IdentityBackend>,
+    /// Variables set in the environment of every build once the tables of
+    /// the product and its dependencies have been set up, so they take
+    /// precedence over anything a table sets. They are recorded in the
+    /// provenance of each build but do not change product ids.
+    pub extra_env: HashMap<String, String>,
+    /// Products added to the dependencies of the products they apply to
+    pub implicit_dependencies: Vec<ImplicitDep>,
+    /// The sha every product is checked out at, in place of branches, when
+    /// reproducing an earlier build exactly. When given, a product missing
+    /// from it is an error.
+    pub pinned_shas: BTreeMap<String, String>,
+    /// Fail, rather than warn, when products of a stack install the same file
+    pub fail_on_file_conflicts: bool,
+    /// Directory to write lsst_build style status events and a build
+    /// manifest to, for CI dashboards which parse them
+    pub build_stream: Option<PathBuf>,
+    /// File to write an lsst_build manifest of every product installed to
+    /// once a run succeeds, with the product ids written beside it
+    pub run_manifest: Option<PathBuf>,
+    /// Refuse to connect to ssh hosts whose key has not been recorded in the
+    /// workspace, rather than trusting them on first use
+    pub strict_host_keys: bool,
+    /// Named regular expressions counted in build output, replacing any
+    /// default classifier of the same name
+    pub output_classifiers: BTreeMap<String, String>,
+    /// Parallel jobs used within a single product build, where the backend
+    /// supports it
+    pub build_jobs: Option<usize>,
+    /// Toolchain file passed to cmake builds
+    pub cmake_toolchain_file: Option<PathBuf>,
+    /// Run product test suites as part of builds, where the backend has them
+    pub run_tests: bool,
+    /// Fail the install of a product whose tests fail, rather than only
+    /// recording the failure in the report
+    pub fail_on_tests: bool,
+    /// Echo the output of builds to the console as it is written, each line
+    /// prefixed with its product
+    pub stream_output: bool,
+    /// The build phases run for every product, in order, in place of those
+    /// of its backend. Empty to use the phases of the backend.
+    pub phases: Vec<Phase>,
+    /// Phases to run for particular products, taking precedence over phases
+    pub product_phases: HashMap<String, Vec<Phase>>,
+    /// How long a build verb may run before it is killed along with anything
+    /// it started. A product entry may give its own with build_timeout.
+    pub build_timeout: Option<Duration>,
+    /// Timeouts of particular verbs, taking precedence over build_timeout.
+    /// A product entry may give its own with verb_timeouts.
+    pub verb_timeouts: HashMap<String, Duration>,
+    /// Install products into a content addressed store at this path, with
+    /// install_root/product/version links pointing into it. The store may be
+    /// shared between workspaces.
+    pub store_root: Option<PathBuf>,
+    /// Mix the versions of the build tools and compilers found at the start
+    /// of the run into product ids, so a toolchain change rebuilds everything
+    pub strict_reproducibility: bool,
+    /// Build with normalized inputs: SOURCE_DATE_EPOCH set to the time of
+    /// the commit being built, a fixed locale, timezone, and umask, and the
+    /// times of installed files set to that commit time
+    pub reproducible: bool,
+    /// Pause before building a product while the install, clone, or temporary
+    /// directories have fewer than this many bytes free
+    pub min_free_space: Option<u64>,
+    /// Pause in the same way while any of those directories have fewer than
+    /// this many inodes free
+    pub min_free_inodes: Option<u64>,
+    /// How long to wait for space to be freed before stopping the run
+    pub disk_space_wait: Duration,
+    /// Start without first checking that the roots are writable, the build
+    /// tool exists, the remote map is reachable, and there is room for the
+    /// builds previous runs suggest are needed
+    pub skip_preflight: bool,
+    /// Number of products which may build at the same time, products which
+    /// do not depend on each other are built concurrently when above one
+    pub build_workers: usize,
+    /// Upload the log of each product built, and a bundle describing any
+    /// failure, here as products finish
+    pub log_upload: Option<LogDestination>,
+    /// Experimental. A remote binary cache at an s3://, gs://, or http(s)
+    /// url, which store entries are pushed to once built and pulled from
+    /// rather than building, as content defined chunks so an update of a
+    /// stack only transfers what changed. Requires a store.
+    pub binary_cache: Option<LogDestination>,
+    /// Limits the run to the products of the graph it matches, anything
+    /// filtered out which they need must be reusable from an existing install
+    pub product_filter: ProductFilter,
+    /// When a product fails, skip the products depending on it but go on
+    /// building the rest of the graph rather than stopping the run
+    pub keep_going: bool,
+    /// Ignore the products an interrupted run completed, rather than
+    /// resuming from where it stopped
+    pub fresh: bool,
+}
+
+/// The python environment of the LSST stack, which everything but the
+/// environment and its base conda is built against
+pub fn default_implicit_dependencies() -> Vec<ImplicitDep> {
+    let base = ["miniconda_lsst".to_string()];
+    vec![ImplicitDep::new("scipipe_conda", &[], &base).expect("the default patterns are valid")]
+}
+
+impl RegenOptions {
+    /// Options for a run installing into install_root with clones kept in
+    /// clone_root, building products as version with build_tool. Products are
+    /// found through the local map or RepoEntry overrides only, and the run
+    /// never stops to ask for confirmation. Every other option is off or at
+    /// its default, and may be changed through the public fields.
+    pub fn new(
+        install_root: &str,
+        clone_root: &str,
+        version: &str,
+        build_tool: &str,
+    ) -> RegenOptions {
+        RegenOptions {
+            branches: None,
+            local_yaml: vec![],
+            clone_root: clone_root.to_string(),
+            install_root: install_root.to_string(),
+            version: version.to_string(),
+            build_tool: build_tool.to_string(),
+            tag: None,
+            remote_package_url: None,
+            remote_package_mirrors: vec![],
+            allow_missing_remote: false,
+            clone_backend: BackendKind::Git2,
+            host_clone_backends: HashMap::new(),
+            clone_limits: CloneLimits::default(),
+            conda_prefix: None,
+            environment_products: HashMap::new(),
+            environment_spec: None,
+            git_auth: GitAuthConfig::default(),
+            fetch_remote: "origin".to_string(),
+            max_clone_age: None,
+            offline: false,
+            no_checkout: false,
+            skip_lfs: false,
+            clone_depth: None,
+            mirror_root: None,
+            report: None,
+            verb_retries: HashMap::new(),
+            restage_on_retry: false,
+            progress_sink: None,
+            strict_tables: false,
+            command_wrapper: None,
+            clock_skew_threshold: Some(Duration::from_secs(2)),
+            normalize_mtimes: false,
+            atomic_tag: false,
+            collect_compile_commands: false,
+            run_name_template: None,
+            confirm_threshold: None,
+            assume_yes: true,
+            table_fallback: TableFallback::default(),
+            abi_check: AbiCheck::default(),
+            missing_installs: MissingInstall::default(),
+            host_check: HostCheck::default(),
+            optional_dependencies: OptionalPolicy::default(),
+            duplicate_urls: DuplicateUrls::default(),
+            identity: Box::new(ContentIds),
+            extra_env: HashMap::new(),
+            implicit_dependencies: default_implicit_dependencies(),
+            pinned_shas: BTreeMap::new(),
+            fail_on_file_conflicts: false,
+            build_stream: None,
+            run_manifest: None,
+            strict_host_keys: false,
+            output_classifiers: BTreeMap::new(),
+            build_jobs: None,
+            cmake_toolchain_file: None,
+            run_tests: false,
+            fail_on_tests: false,
+            stream_output: false,
+            phases: vec![],
+            product_phases: HashMap::new(),
+            build_timeout: None,
+            verb_timeouts: HashMap::new(),
+            store_root: None,
+            strict_reproducibility: false,
+            reproducible: false,
+            min_free_space: None,
+            min_free_inodes: None,
+            disk_space_wait: Duration::from_secs(600),
+            skip_preflight: false,
+            build_workers: 1,
+            log_upload: None,
+            binary_cache: None,
+            product_filter: ProductFilter::default(),
+            keep_going: false,
+            fresh: false,
+        }
+    }
+}
+
+/// The GitHub contents API url serving the same file as a
+/// raw.githubusercontent.com url, which keeps working when the raw host does
+/// not
+fn github_api_url(url: &str) -> Option<String> {
+    let prefix = "https://raw.githubusercontent.com/";
+    if !url.starts_with(prefix) {
+        return None;
+    }
+    let parts: Vec<&str> = url[prefix.len()..].splitn(4, '/').collect();
+    match parts.as_slice() {
+        [owner, repo, reference, path] if !path.is_empty() => Some(format!(
+            "https://api.github.com/repos/{}/{}/contents/{}?ref={}",
+            owner, repo, path, reference
+        )),
+        _ => None,
+    }
+}
+
+/// Why a request was refused when the server says it was rate limited
+fn rate_limit_note(response: &reqwest::Response) -> String {
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string())
+    };
+    if header("x-ratelimit-remaining").as_ref().map(|v| v.as_str()) == Some("0") {
+        match header("x-ratelimit-reset") {
+            Some(reset) => format!(", rate limited until {} (unix time)", reset),
+            None => ", rate limited".to_string(),
+        }
+    } else if let Some(after) = header("retry-after") {
+        format!(", rate limited, retry after {}s", after)
+    } else {
+        String::new()
+    }
+}
+
+/// What fetching the remote map from one source returned
+enum FetchedMap {
+    /// The text of the map, with the validators the server sent for it
+    Text(CachedMap),
+    /// The cached copy is still current
+    NotModified,
+}
+
+/// Fetch the text of the remote product to url mapping from one source. When
+/// cached is the copy last fetched from this source, the server is asked to
+/// only send the map if it changed since.
+fn fetch_mapping_text(
+    url: &str,
+    headers: &[(&str, String)],
+    max_rate: Option<u64>,
+    cached: Option<&CachedMap>,
+) -> Result<FetchedMap, String> {
+    let mut request = reqwest::Client::new().get(url);
+    for (name, value) in headers.iter() {
+        request = request.header(*name, value.as_str());
+    }
+    if let Some(cached) = cached {
+        if let Some(etag) = cached.etag.as_ref() {
+            request = request.header("If-None-Match", etag.as_str());
+        }
+        if let Some(modified) = cached.last_modified.as_ref() {
+            request = request.header("If-Modified-Since", modified.as_str());
+        }
+    }
+    let response = request
+        .send()
+        .map_err(|e| format!("Could not fetch remote package list {}: {}", url, e))?;
+    if cached.is_some() && response.status() == reqwest::StatusCode::NOT_MODIFIED {
+        return Ok(FetchedMap::NotModified);
+    }
+    if !response.status().is_success() {
+        return Err(format!(
+            "There was a problem fetching the remote map {}, status {}{}",
+            url,
+            response.status(),
+            rate_limit_note(&response)
+        ));
+    }
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string())
+    };
+    let (etag, last_modified) = (header("etag"), header("last-modified"));
+    let mut body = String::new();
+    network::ThrottledReader::new(response, max_rate)
+        .read_to_string(&mut body)
+        .map_err(|e| format!("Could not read remote package list {}: {}", url, e))?;
+    if body.trim().is_empty() {
+        return Err(format!("The remote map {} is empty", url));
+    }
+    Ok(FetchedMap::Text(CachedMap {
+        source: url.to_string(),
+        text: body,
+        etag,
+        last_modified,
+        fetched: time::now_utc().rfc3339().to_string(),
+    }))
+}
+
+/// Fetch and parse the remote product to url mapping. Should the url fail,
+/// as raw.githubusercontent.com does when its CDN has trouble or rate
+/// limits, the same file is asked for from the GitHub contents API, using
+/// GITHUB_TOKEN or the keyring token for github.com when there is one, and
+/// then from each mirror in turn. With a cache, the map is only downloaded
+/// when it changed since it was cached, and the cached copy is used with a
+/// warning when no source can be reached.
+pub(crate) fn fetch_remote_mapping(
+    url: &str,
+    mirrors: &[String],
+    max_rate: Option<u64>,
+    cache: Option<&MapCache>,
+) -> Result<yaml_rust::yaml::Yaml, String> {
+    debug!("Fetching remote package list");
+    let cached = cache.and_then(|c| c.load(url));
+    let mut sources = vec![(url.to_string(), vec![])];
+    if let Some(api_url) = github_api_url(url) {
+        let mut headers = vec![("Accept", "application/vnd.github.v3.raw".to_string())];
+        let token = std::env::var("GITHUB_TOKEN")
+            .ok()
+            .or_else(|| credentials::lookup_token("github.com"));
+        if let Some(token) = token {
+            headers.push(("Authorization", format!("token {}", token)));
+        }
+        sources.push((api_url, headers));
+    }
+    sources.extend(mirrors.iter().map(|m| (m.clone(), vec![])));
+    let mut problems = vec![];
+    for (source, headers) in sources.iter() {
+        let validators = cached.as_ref().filter(|c| &c.source == source);
+        match fetch_mapping_text(source, headers, max_rate, validators) {
+            Ok(FetchedMap::Text(fetched)) => {
+                if !problems.is_empty() {
+                    warn!(
+                        "Fetched the remote package list from {} instead: {}",
+                        source,
+                        problems.join("; ")
+                    );
+                }
+                let mapping = repo_wrapper::parse_map(&fetched.text, source)?;
+                if let Some(cache) = cache {
+                    if let Err(e) = cache.store(url, &fetched) {
+                        warn!("Could not cache the remote package list: {}", e);
+                    }
+                }
+                return Ok(mapping);
+            }
+            Ok(FetchedMap::NotModified) => {
+                debug!("The remote package list at {} is unchanged", source);
+                break;
+            }
+            Err(e) => {
+                debug!("{}", e);
+                problems.push(e);
+            }
+        }
+    }
+    match cached {
+        Some(cached) => {
+            if !problems.is_empty() {
+                warn!(
+                    "Using the remote package list cached from {} at {}: {}",
+                    cached.source,
+                    cached.fetched,
+                    problems.join("; ")
+                );
+            }
+            repo_wrapper::parse_map(&cached.text, &cached.source)
+        }
+        None => Err(problems.join("; ")),
+    }
+}
+
+/// True when a build verb ran and exited successfully
+fn verb_succeeded(output: &Result<std::process::Output, String>) -> bool {
+    match output.as_ref() {
+        Ok(o) => o.status.success(),
+        Err(_) => false,
+    }
+}
+
+/// Names of the required dependencies listed in a table
+fn table_dependencies(name: &str, table: &reups::table::Table) -> Result<Vec<String>, String> {
+    let inexact = table
+        .inexact
+        .as_ref()
+        .ok_or(format!("Table for {} has no dependency section", name))?;
+    Ok(inexact.required.keys().cloned().collect())
+}
+
+fn optional_dependencies(table: &reups::table::Table) -> Vec<String> {
+    table
+        .inexact
+        .as_ref()
+        .map(|inexact| inexact.optional.keys().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// Build the dependency graph of root from table texts alone, the way
+/// graph_repo does from clones, writing each table under dir to parse it.
+/// Every dependency must have a table. This is the entry point of the
+/// table fuzz target, and is not otherwise part of the API.
+#[doc(hidden)]
+pub fn graph_from_tables(
+    root: &str,
+    tables: &HashMap<String, String>,
+    dir: &Path,
+) -> Result<reups::graph::Graph, String> {
+    use reups::graph::NodeType;
+    let mut graph = reups::graph::Graph::new();
+    let mut pending = vec![root.to_string()];
+    graph.add_or_update_product(root.to_string(), NodeType::Required);
+    while let Some(name) = pending.pop() {
+        safety::validate_product_name(&name)?;
+        let text = tables.get(&name).ok_or(format!("{} has no table", name))?;
+        let location = dir.join(&name);
+        let table_file = location.join("ups").join(format!("{}.table", name));
+        std::fs::create_dir_all(location.join("ups"))
+            .and_then(|_| std::fs::write(&table_file, text))
+            .map_err(|e| format!("Could not write {}: {}", table_file.display(), e))?;
+        let table =
+            reups::table::Table::from_file(name.clone(), table_file.clone(), location.clone())
+                .map_err(|e| format!("Could not read table {}: {}", table_file.display(), e))?;
+        let issues = table_lint::lint_table(&name, &table_file, &location, |dep| {
+            tables.contains_key(dep)
+        })?;
+        if let Some(issue) = issues.iter().find(|i| i.severity == Severity::Error) {
+            return Err(format!("{}", issue));
+        }
+        for dep_name in table_dependencies(&name, &table)?.iter() {
+            if !graph.has_product(dep_name) {
+                graph.add_or_update_product(dep_name.clone(), NodeType::Required);
+                pending.push(dep_name.clone());
+            }
+            let _ = graph.connect_products(&name, dep_name, String::new());
+        }
+    }
+    Ok(graph)
 }
 
 pub struct Regenerate<'a> {
     product_urls: RepoSourceWrapper,
     graph: reups::graph::Graph,
-    db: &'a mut reups::DB,
+    db: &'a mut dyn ProductDatabase,
     repo_map: HashMap<String, Repository>,
     branches: Vec<String>,
     options: RegenOptions,
     build_completed: HashSet<String>,
     build_log: BufWriter<std::fs::File>,
+    // one line per finished product, naming its outcome and build log
+    build_index: BufWriter<std::fs::File>,
+    environment: Option<ProvisionedEnvironment>,
+    // products with dependencies satisfied by the environment
+    environment_dependents: HashSet<String>,
+    // hash of the resolved environment, mixed into the ids of products
+    // which depend on the environment
+    environment_hash: Option<String>,
+    report: RunReport,
+    history: History,
+    progress: ProgressStream,
+    // products each product directly depends on
+    dependencies: HashMap<String, Vec<String>>,
+    run_name: RunName,
+    holds: Holds,
+    // products completed by this and interrupted runs, to resume from
+    run_state: RunState,
+    output_processors: Vec<Box<dyn OutputProcessor>>,
+    graph_memo: GraphMemo,
+    // installed files of product directories seen so far
+    manifests: HashMap<PathBuf, Vec<String>>,
+    // product directories declared by this run, checked for file conflicts
+    // with each product declared after them
+    install_set: BTreeMap<String, PathBuf>,
+    build_stream: Option<BuildStream>,
+    // hash of the build tool versions, mixed into every product id in
+    // strict reproducibility mode
+    toolchain_hash: Option<String>,
+    // tool versions probed so far, by the PATH they were probed with
+    tool_versions: HashMap<String, BTreeMap<String, String>>,
+    // the machine this run builds on, compared with reused builds
+    host: HostFingerprint,
+    // the machine as seen from each build environment, by its PATH and CC
+    hosts: HashMap<(String, String), HostFingerprint>,
+    // products cloned by this run, rather than found on disk
+    cloned: HashSet<String>,
+    // the first product cloned from each normalized url
+    clone_urls: HashMap<String, String>,
+    // the branch, tag, or revision each product was checked out at
+    checkouts: HashMap<String, String>,
+    // the commit each product's branch resolved to, for products left as
+    // they are on disk as no_checkout asks
+    resolved: HashMap<String, git2::Oid>,
+    // what each product being built has written to the build log
+    product_logs: HashMap<String, Vec<u8>>,
+    // where the build log of each product which failed to build was written
+    failure_logs: HashMap<String, PathBuf>,
+    // time spent in each phase of each product
+    profile: Profile,
+}
+
+/// A product ready to build, with its install directory created, source
+/// staged, and environment set up
+struct StagedBuild {
+    product_id: String,
+    metadata: ProductMetadata,
+    product_dir: PathBuf,
+    repo_path: PathBuf,
+    // temporary copy of an upstream product's source, removed when dropped
+    _tmp_dir: Option<TempDir>,
+    // keeps other runs out of the store entry until the build is complete
+    _store_lock: Option<StoreLock>,
+    env_vars: FnvHashMap<String, String>,
+    tool_versions: BTreeMap<String, String>,
+    // the machine as the build environment sees it
+    host: HostFingerprint,
+    backend: Box<dyn BuildBackend>,
+    build_start: Instant,
+}
+
+/// A build running on a worker, and the verb output collected from it
+struct RunningBuild {
+    staged: StagedBuild,
+    start: Instant,
+    failures_before: usize,
+    outputs: Vec<(String, Result<std::process::Output, String>)>,
 }
 
 impl<'a> Regenerate<'a> {
-    pub fn new(db: &'a mut reups::DB, options: RegenOptions) -> Result<Regenerate<'a>, String> {
-        // get the mapping from defined url
-        debug!("Fetching remote package list");
-        let mut response = reqwest::get(options.remote_package_url.as_str()).unwrap();
-        let mapping = if response.status().is_success() {
-            let body = response.text().unwrap();
-            let mut parsed = yaml_rust::YamlLoader::load_from_str(&body).unwrap();
-            // This is not using multi paged yaml, so just take the first
-            parsed.remove(0)
-        } else {
-            return Err("There was a problem fetch or parsing the remote map".to_string());
+    /// Start a run declaring products into db. The remote map, if any, is
+    /// fetched and the build environment inspected here, so an error means
+    /// nothing could be installed with these options.
+    pub fn new(
+        db: &'a mut dyn ProductDatabase,
+        options: RegenOptions,
+    ) -> Result<Regenerate<'a>, String> {
+        let mut options = options;
+        if options.clone_limits.host_keys.is_none() {
+            options.clone_limits.host_keys = Some(HostKeyPolicy::new(
+                &PathBuf::from(&options.install_root),
+                options.strict_host_keys,
+            ));
+        }
+        let map_cache = MapCache::new(&PathBuf::from(&options.install_root));
+        // an unreachable remote map is reported at once rather than after the
+        // fetch times out, unless there is somewhere else to get it from
+        if let (Some(url), false, false) = (
+            options.remote_package_url.as_ref(),
+            options.offline,
+            options.skip_preflight,
+        ) {
+            if let Err(e) = preflight::check_reachable(url) {
+                if !options.allow_missing_remote
+                    && options.remote_package_mirrors.is_empty()
+                    && map_cache.load(url).is_none()
+                {
+                    return Err(e);
+                }
+                warn!("{}", e);
+            }
+        }
+        // get the mapping from defined url, if there is one
+        let mapping = match options.remote_package_url.as_ref() {
+            Some(url) if options.offline => match map_cache.load(url) {
+                Some(cached) => {
+                    warn!(
+                        "Running offline, using the remote package list cached at {}",
+                        cached.fetched
+                    );
+                    repo_wrapper::parse_map(&cached.text, &cached.source)?
+                }
+                None => {
+                    warn!("Running offline, resolving products from the local map only");
+                    yaml_rust::yaml::Yaml::Hash(yaml_rust::yaml::Hash::new())
+                }
+            },
+            Some(url) => match fetch_remote_mapping(
+                url,
+                &options.remote_package_mirrors,
+                options.clone_limits.max_rate,
+                Some(&map_cache),
+            ) {
+                Ok(mapping) => mapping,
+                Err(e) => {
+                    if !options.allow_missing_remote {
+                        return Err(e);
+                    }
+                    warn!("{}, resolving products from the local map only", e);
+                    yaml_rust::yaml::Yaml::Hash(yaml_rust::yaml::Hash::new())
+                }
+            },
+            None => {
+                debug!("No remote package url, resolving products from the local map only");
+                yaml_rust::yaml::Yaml::Hash(yaml_rust::yaml::Hash::new())
+            }
         };
         let repo_map = HashMap::new();
         let mut br = vec!["master".to_string()];
         if let Some(in_br) = options.branches.as_ref() {
             br = [&in_br[..], &br[..]].concat();
         }
-        let f = std::fs::File::create(format!("build_log-{}.log", time::now().rfc3339()))
+        let run_name = RunName::new();
+        let template = options
+            .run_name_template
+            .as_ref()
+            .map(|t| t.as_str())
+            .unwrap_or(naming::DEFAULT_TEMPLATE);
+        let f = std::fs::File::create(run_name.artifact(template, "build_log", "log"))
             .or_else(|e| return Err(format!("{}", e)))?;
+        let index = std::fs::File::create(run_name.artifact(template, "build_index", "log"))
+            .or_else(|e| return Err(format!("{}", e)))?;
+        info!(
+            "Starting run {} with id {}",
+            run_name.render(template),
+            run_name.run_id
+        );
+        // the environment is only required if some products may come from it
+        let prefix = options
+            .conda_prefix
+            .clone()
+            .or_else(|| std::env::var("CONDA_PREFIX").ok().map(PathBuf::from));
+        let environment = match prefix {
+            Some(p) => Some(ProvisionedEnvironment::from_prefix(&p)?),
+            None if options.environment_products.is_empty() => None,
+            None => {
+                return Err(
+                    "Environment products were configured but no conda prefix is set".to_string(),
+                )
+            }
+        };
+        let environment_hash = match (options.environment_spec.as_ref(), environment.as_ref()) {
+            (Some(spec), _) => Some(environment::hash_spec_file(spec)?),
+            (None, Some(env)) => Some(env.resolved_hash()),
+            (None, None) => None,
+        };
+        if let Some(hash) = environment_hash.as_ref() {
+            info!("Using environment with specification hash {}", hash);
+        }
+        let history = History::open(&PathBuf::from(&options.install_root))?;
+        let holds = Holds::open(&PathBuf::from(&options.install_root))?;
+        let run_state = RunState::open(
+            &PathBuf::from(&options.install_root),
+            &options.version,
+            options.fresh,
+        );
+        let build_stream = match options.build_stream.as_ref() {
+            Some(dir) => {
+                let manifest_id = jenkins::next_manifest_id(&PathBuf::from(&options.install_root))?;
+                Some(BuildStream::new(dir, &manifest_id)?)
+            }
+            None => None,
+        };
+        // tools are probed with the variables every build gets, a toolchain
+        // set up from a product reaches the ids of its dependents through
+        // the id of that product
+        let build_env: FnvHashMap<String, String> = options
+            .extra_env
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        let toolchain_hash = match options.strict_reproducibility {
+            true => {
+                let versions = toolchain::probe(&options.build_tool, &build_env);
+                info!("Product ids include the build tools {:?}", versions);
+                Some(toolchain::hash(&versions))
+            }
+            false => None,
+        };
+        let mut patterns = classify::default_patterns();
+        patterns.extend(options.output_classifiers.clone());
+        let classifiers: Box<dyn OutputProcessor> = Box::new(Classifiers::new(&patterns)?);
+        let mut report = RunReport::new();
+        report.held = holds.all().clone();
+        let progress = ProgressStream::new(options.progress_sink.clone());
+        if let Some(threshold) = options.clock_skew_threshold {
+            let clone_root = PathBuf::from(&options.clone_root);
+            let install_root = PathBuf::from(&options.install_root);
+            let problems = clock_skew::check_roots(&[&clone_root, &install_root], threshold);
+            if !problems.is_empty() && !options.normalize_mtimes {
+                warn!("Consider enabling mtime normalization to work around clock skew");
+            }
+        }
+        let profile = Profile::new(&run_name.run_id);
         Ok(Regenerate {
-            product_urls: RepoSourceWrapper::new(mapping, &options.local_yaml),
+            product_urls: RepoSourceWrapper::new(mapping, &options.local_yaml)?,
             db: db,
             graph: reups::graph::Graph::new(),
             repo_map,
@@ -69,48 +979,345 @@ impl<'a> Regenerate<'a> {
             options: options,
             build_completed: HashSet::new(),
             build_log: BufWriter::new(f),
+            build_index: BufWriter::new(index),
+            environment,
+            environment_dependents: HashSet::new(),
+            environment_hash,
+            report,
+            history,
+            progress,
+            dependencies: HashMap::new(),
+            run_name,
+            holds,
+            run_state,
+            output_processors: vec![classifiers],
+            graph_memo: GraphMemo::new(),
+            manifests: HashMap::new(),
+            install_set: BTreeMap::new(),
+            build_stream,
+            toolchain_hash,
+            tool_versions: HashMap::new(),
+            host: HostFingerprint::probe(&build_env),
+            hosts: HashMap::new(),
+            cloned: HashSet::new(),
+            clone_urls: HashMap::new(),
+            checkouts: HashMap::new(),
+            resolved: HashMap::new(),
+            product_logs: HashMap::new(),
+            failure_logs: HashMap::new(),
+            profile,
         })
     }
 
-    fn get_or_clone_repo(&mut self, product: &str) -> Result<(), String> {
+    /// Check a product out at the given revision for this run only, as a hold
+    /// would, without recording it in the workspace
+    pub fn pin(&mut self, product: &str, revision: &str) {
+        self.holds.hold(product, revision);
+    }
+
+    /// Add a processor which will be shown the output of every build verb
+    pub fn add_output_processor(&mut self, processor: Box<dyn OutputProcessor>) {
+        self.output_processors.push(processor);
+    }
+
+    /// Override where a product is sourced from, taking precedence over both
+    /// the local and remote maps. This must be called before the product is
+    /// cloned to have any effect.
+    pub fn override_source(&mut self, product: &str, entry: RepoEntry) {
+        if self.repo_map.contains_key(product) {
+            warn!(
+                "Overriding the source of {} after it was already cloned",
+                product
+            );
+        }
+        self.product_urls.insert(product, entry);
+    }
+
+    /// Determine if a product is satisfied by the provisioned environment rather
+    /// than needing to be cloned and built
+    fn is_environment_provided(&self, product: &str) -> bool {
+        let package = match self.options.environment_products.get(product) {
+            Some(p) => p,
+            None => return false,
+        };
+        match self.environment.as_ref() {
+            Some(env) if env.provides(package) => true,
+            Some(env) => {
+                warn!(
+                    "{} is mapped to conda package {} which is not in {}, building it instead",
+                    product,
+                    package,
+                    env.prefix().display()
+                );
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// The limits and credentials to clone or fetch a product from url with
+    fn clone_limits(&self, product: &str, url: &str) -> CloneLimits {
+        let mut limits = self
+            .product_urls
+            .clone_limits(product, &self.options.clone_limits);
+        limits.auth = self
+            .options
+            .git_auth
+            .lookup(product, url)
+            .cloned()
+            .or(limits.auth);
+        limits
+    }
+
+    fn get_or_clone_repo(&mut self, product: &str) -> Result<(), RegenError> {
+        safety::validate_product_name(product)?;
         let repo_src = match self.product_urls.get_url(product) {
-            Some(x) => x,
-            None => return Err("No url for associated product".to_string()),
+            Some(x) => x.to_string(),
+            None => {
+                return Err(RegenError::Clone {
+                    product: product.to_string(),
+                    message: "no url in either the local or remote product map".to_string(),
+                })
+            }
         };
+        safety::validate_url(&repo_src)
+            .map_err(|e| format!("Refusing to clone {}: {}", product, e))?;
+        let backend = clone_backend::backend_for_url(
+            &repo_src,
+            &self.options.clone_backend,
+            &self.options.host_clone_backends,
+            self.product_urls.partial_clone(product),
+            match self.product_urls.clone_depth(product) {
+                Some(0) => None,
+                Some(depth) => Some(depth),
+                None => self.options.clone_depth,
+            },
+        );
+        let limits = self.clone_limits(product, &repo_src);
         let mut on_disk = PathBuf::from(&self.options.clone_root);
         on_disk.push(product);
+        safety::ensure_under(&PathBuf::from(&self.options.clone_root), &on_disk)?;
+        let shared = self.duplicate_source(product, &repo_src);
+        // with a shared mirror area clones come from the mirror, which is
+        // brought up to date first
+        let mirror = match self.options.mirror_root.as_ref() {
+            Some(_) if shared.is_some() => None,
+            Some(root) => Some(
+                mirror::update(
+                    root,
+                    product,
+                    &repo_src,
+                    &limits,
+                    self.options.max_clone_age,
+                    self.options.offline,
+                )
+                .map_err(|message| RegenError::Clone {
+                    product: product.to_string(),
+                    message,
+                })?,
+            ),
+            None => None,
+        };
+        let clone_repo = || match (shared.as_ref(), mirror.as_ref()) {
+            (Some(source), _) => clone_backend::add_worktree(source, &on_disk),
+            (None, Some(path)) => mirror::checkout(path, &on_disk),
+            (None, None) => backend.clone_repo(&repo_src, &on_disk, &limits),
+        };
+        let mut cloned = true;
         let repo = match if on_disk.exists() {
             debug!(
                 "Using repo found on disk for {} at {}",
                 product,
-                &on_disk.to_str().unwrap()
+                on_disk.display()
             );
             match Repository::open(&on_disk) {
-                Ok(x) => Ok(x),
+                Ok(x) => {
+                    let remote = self.options.fetch_remote.as_str();
+                    let max_rate = self.options.clone_limits.max_rate;
+                    let host_keys = self.options.clone_limits.host_keys.as_ref();
+                    let auth = limits.auth.as_ref();
+                    // branches created since the clone was made are only
+                    // seen once fetched
+                    let fetched = match self.options.max_clone_age {
+                        _ if self.options.offline => Ok(()),
+                        Some(max_age) if mirror.is_none() => refresh::refresh_if_stale(
+                            &x, remote, max_age, max_rate, host_keys, auth,
+                        ),
+                        _ => refresh::fetch_repo(&x, remote, max_rate, host_keys, auth),
+                    };
+                    if let Err(e) = fetched {
+                        warn!("Could not fetch the clone of {}: {}", product, e);
+                    }
+                    cloned = false;
+                    Ok(x)
+                }
+                Err(_) if self.options.offline => Err(format!(
+                    "the clone at {} could not be opened and running offline",
+                    on_disk.display()
+                )),
                 Err(_) => {
                     warn!("There was a problem opening the on disk repo for {}, removing and re-cloning", product);
                     let _ = remove(&on_disk);
-                    Repository::clone(repo_src, on_disk)
-                        .or_else(|e| panic!("Failed to clone: {}", e))
+                    clone_repo()
                 }
             }
+        } else if self.options.offline {
+            Err("it has not been cloned and running offline".to_string())
         } else {
             debug!("Cloning {} from {}", product, repo_src);
-            Repository::clone(repo_src, on_disk)
+            self.progress.emit(ProgressEvent::CloneStarted {
+                product: product.to_string(),
+            });
+            clone_repo()
         } {
             Ok(repo) => repo,
-            Err(e) => panic!("Failed to clone: {}", e),
+            Err(e) => {
+                return Err(RegenError::Clone {
+                    product: product.to_string(),
+                    message: format!("{}", e),
+                })
+            }
         };
+        match self.product_urls.sparse_checkout(product) {
+            Some(directories) => clone_backend::set_sparse_checkout(&repo, &directories)
+                .map_err(|e| format!("Could not restrict the checkout of {}: {}", product, e))?,
+            None if clone_backend::is_sparse_checkout(&repo) => {
+                info!("{} is no longer sparse, checking out all of it", product);
+                clone_backend::disable_sparse_checkout(&repo)?
+            }
+            None => (),
+        }
+        if cloned {
+            self.cloned.insert(product.to_string());
+        }
         self.repo_map.insert(product.to_string(), repo);
         Ok(())
     }
 
-    fn checkout_branch(&self, repo_name: &str) -> Result<(), String> {
-        let repo = self.repo_map.get(repo_name).unwrap();
-        let mut success = false;
+    /// Find another product of the run cloned from the same url as product,
+    /// returning its clone for product to share if the duplicate url policy
+    /// allows it. Duplicates the policy forbids are reported together once
+    /// the graph is resolved, by check_duplicate_sources.
+    fn duplicate_source(&mut self, product: &str, url: &str) -> Option<PathBuf> {
+        let key = clone_backend::normalize_url(url);
+        let other = match self.clone_urls.get(&key) {
+            Some(other) if other != product => other.clone(),
+            _ => {
+                self.clone_urls.insert(key, product.to_string());
+                return None;
+            }
+        };
+        if self.options.duplicate_urls != DuplicateUrls::Share {
+            return None;
+        }
+        warn!(
+            "{} is cloned from {} like {}, checking it out as a worktree of that clone",
+            product, url, other
+        );
+        self.repo_map
+            .get(&other)
+            .and_then(|r| r.workdir())
+            .map(|p| p.to_path_buf())
+    }
+
+    /// The product whose clone product shares, as another product of the run
+    /// is cloned from the same url
+    fn shared_clone_of(&self, product: &str) -> Option<String> {
+        if self.options.duplicate_urls != DuplicateUrls::Share {
+            return None;
+        }
+        let url = self.product_urls.get_url(product)?;
+        self.clone_urls
+            .get(&clone_backend::normalize_url(url))
+            .filter(|other| *other != product)
+            .cloned()
+    }
+
+    /// Find the products in the resolved graphs of products which are cloned
+    /// from the same url, recording them in the report. Unless clones may be
+    /// shared they are an error, naming every duplicate before anything is
+    /// built.
+    fn check_duplicate_sources(&mut self, products: &[String]) -> Result<(), String> {
+        let mut by_url: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for product in products.iter() {
+            for name in self.subtree(product)?.iter() {
+                if let Some(url) = self.product_urls.get_url(name) {
+                    let names = by_url
+                        .entry(clone_backend::normalize_url(url))
+                        .or_insert_with(Vec::new);
+                    if !names.contains(name) {
+                        names.push(name.clone());
+                    }
+                }
+            }
+        }
+        let mut duplicates = vec![];
+        for (url, names) in by_url.iter().filter(|(_, names)| names.len() > 1) {
+            for name in names[1..].iter() {
+                self.report.record_shared_url(url, &names[0], name);
+            }
+            duplicates.push(format!("{} are cloned from {}", names.join(", "), url));
+        }
+        if duplicates.is_empty() || self.options.duplicate_urls == DuplicateUrls::Share {
+            return Ok(());
+        }
+        Err(format!(
+            "Products of the run share a url, give each its own url or allow shared clones:\n{}",
+            duplicates.join("\n")
+        ))
+    }
+
+    /// Check out the first of the branches which exists in a product,
+    /// returning the one used. With no_checkout the branch is only resolved,
+    /// and the commit it resolved to is returned with it.
+    fn checkout_branch(&self, repo_name: &str) -> Result<(String, Option<git2::Oid>), String> {
+        // ids depend on the checked out shas
+        self.graph_memo.invalidate();
+        let repo = self
+            .repo_map
+            .get(repo_name)
+            .ok_or_else(|| format!("{} has no clone to check out", repo_name))?;
+        let mut checked_out = None;
+        // why each branch tried was passed over, for when none can be used
+        let mut skipped = vec![];
+        // a product pinned by an exact manifest, or held, is only ever
+        // checked out at its pin
+        let pinned = self.options.pinned_shas.get(repo_name);
+        if pinned.is_none() && !self.options.pinned_shas.is_empty() {
+            return Err(format!(
+                "{} is not in the manifest being built exactly",
+                repo_name
+            ));
+        }
+        let held = pinned.or_else(|| self.holds.get(repo_name));
+        let map_pin = self.map_pin(repo_name);
+        // tags and commits, like holds, are checked out with a detached HEAD
+        let detached = held.is_some()
+            || match map_pin {
+                Some(RefPin::Tag(_)) | Some(RefPin::Commit(_)) => true,
+                _ => false,
+            };
         // if the product is not based on master, replace the branches list
         // with one that contains the base branch instead of master
-        let branches = if let Some(name) = self.product_urls.has_ref(repo_name) {
+        let branches = if let Some(pin) = pinned {
+            info!("{} is pinned at {} by the manifest", repo_name, pin);
+            vec![pin.clone()]
+        } else if let Some(pin) = held {
+            info!("{} is held at {}", repo_name, pin);
+            vec![pin.clone()]
+        } else if let Some(RefPin::Tag(tag)) = map_pin.as_ref() {
+            info!(
+                "{} is pinned to tag {} by the repository map",
+                repo_name, tag
+            );
+            vec![format!("refs/tags/{}", tag)]
+        } else if let Some(RefPin::Commit(sha)) = map_pin.as_ref() {
+            info!(
+                "{} is pinned to commit {} by the repository map",
+                repo_name, sha
+            );
+            vec![sha.clone()]
+        } else if let Some(RefPin::Branch(name)) = map_pin.as_ref() {
             let mut b: Vec<String> = self
                 .branches
                 .iter()
@@ -122,33 +1329,73 @@ impl<'a> Regenerate<'a> {
                     }
                 })
                 .collect();
-            b.push(name);
+            b.push(name.clone());
             b
         } else {
             self.branches.clone()
         };
+        let url = self.product_urls.get_url(repo_name).unwrap_or_default();
+        let limits = self.clone_limits(repo_name, url);
         for name in branches.iter() {
-            debug!(
-                "Trying to checkout {} in {}",
-                name,
-                repo.workdir().unwrap().to_str().unwrap()
-            );
+            debug!("Trying to checkout {} in {}", name, repo.path().display());
             let tree = match repo.revparse_single(name) {
                 Ok(x) => x,
-                Err(_) => continue,
+                Err(_) => {
+                    debug!("{} has no {}, trying the next branch", repo_name, name);
+                    skipped.push(format!("{} does not exist", name));
+                    continue;
+                }
             };
-            match repo.checkout_tree(&tree, None) {
-                Ok(_) => (),
-                Err(_) => continue,
+            if self.options.no_checkout {
+                match tree.peel_to_commit() {
+                    Ok(commit) => {
+                        checked_out = Some((name.clone(), Some(commit.id())));
+                        break;
+                    }
+                    Err(e) => {
+                        skipped.push(format!("{} is not a commit: {}", name, e));
+                        continue;
+                    }
+                }
+            }
+            let checkout = if clone_backend::is_partial_clone(repo)
+                || clone_backend::is_sparse_checkout(repo)
+            {
+                clone_backend::checkout_partial(repo, &format!("{}", tree.id()), url, &limits)
+            } else {
+                repo.checkout_tree(&tree, None)
+                    .map_err(|e| format!("{}", e))
             };
-            let head = match tree.kind() {
-                Some(k) => match k {
-                    git2::ObjectType::Tag => format!("refs/tags/{}", name),
-                    _ => format!("refs/remotes/{}", name),
-                },
-                None => panic!("No target for specified name"),
+            if let Err(e) = checkout {
+                warn!(
+                    "Could not check out {} of {}, trying the next branch: {}",
+                    name, repo_name, e
+                );
+                skipped.push(format!("{} could not be checked out: {}", name, e));
+                continue;
+            }
+            let set_head = if detached {
+                tree.peel_to_commit()
+                    .and_then(|commit| repo.set_head_detached(commit.id()))
+            } else {
+                let head = match tree.kind() {
+                    Some(k) => match k {
+                        git2::ObjectType::Tag => format!("refs/tags/{}", name),
+                        _ => format!("refs/remotes/{}", name),
+                    },
+                    // nothing to point HEAD at, try the next branch
+                    None => {
+                        warn!(
+                            "{} of {} is not a branch or tag, trying the next branch",
+                            name, repo_name
+                        );
+                        skipped.push(format!("{} is not a branch or tag", name));
+                        continue;
+                    }
+                };
+                repo.set_head(&head)
             };
-            match repo.set_head(&head) {
+            match set_head {
                 Ok(x) => x,
                 Err(e) => {
                     return Err(format!(
@@ -157,95 +1404,525 @@ impl<'a> Regenerate<'a> {
                     ))
                 }
             }
-            success = true;
+            checked_out = Some((name.clone(), None));
             break;
         }
-        match success {
-            true => Ok(()),
-            false => Err(format!("Could not find branch to checkout")),
+        let checked_out = match checked_out {
+            Some(name) => name,
+            None => {
+                if let Some(pin) = held {
+                    return Err(format!(
+                        "Could not find revision {} which {} is held at",
+                        pin, repo_name
+                    ));
+                }
+                if let Some(pin) = map_pin.filter(|_| detached) {
+                    return Err(format!(
+                        "Could not find {} which {} is pinned to by the repository map",
+                        pin, repo_name
+                    ));
+                }
+                return Err(format!(
+                    "Could not find a branch of {} to check out: {}",
+                    repo_name,
+                    skipped.join(", ")
+                ));
+            }
+        };
+        if self.options.normalize_mtimes && !self.options.no_checkout {
+            let workdir = repo
+                .workdir()
+                .ok_or(format!("{} has no working directory", repo_name))?;
+            clock_skew::normalize_mtimes(workdir)?;
+        }
+        Ok(checked_out)
+    }
+
+    /// The pin the repository map gives a product, unless an exact manifest
+    /// or a hold decides what it is checked out at instead
+    fn map_pin(&self, product: &str) -> Option<RefPin> {
+        if self.options.pinned_shas.contains_key(product) || self.holds.get(product).is_some() {
+            return None;
         }
+        self.product_urls.pin(product)
     }
 
     fn get_sha_of_head(&self, name: &str) -> Result<String, String> {
-        let repo = self.repo_map.get(name).unwrap();
+        if let Some(commit) = self.resolved.get(name) {
+            return Ok(format!("{}", commit));
+        }
+        let repo = self
+            .repo_map
+            .get(name)
+            .ok_or(format!("{} has not been cloned", name))?;
 
         let head = match repo.head() {
             Ok(v) => v,
             Err(e) => return Err(format!("{}", e)),
         };
-        let target = head.target().unwrap();
+        let target = head
+            .target()
+            .ok_or(format!("HEAD of {} does not point at a commit", name))?;
         Ok(format!("{}", target))
     }
 
-    fn graph_repo(&mut self, name: &str, node_type: reups::graph::NodeType) {
-        let location = {
-            let repo = self.repo_map.get(name).unwrap();
-            self.graph
-                .add_or_update_product(name.to_string(), node_type);
-            repo.workdir().unwrap().clone().to_path_buf()
+    /// The file creation mask build tools run with, fixed when builds are
+    /// reproducible and otherwise inherited from regenerate
+    fn build_umask(&self) -> Option<u32> {
+        match self.options.reproducible {
+            true => Some(reproducible::UMASK),
+            false => None,
+        }
+    }
+
+    /// The commit time of the checked out source of a product, which builds
+    /// stamp into their outputs in place of the time they ran
+    fn source_date_epoch(&self, name: &str) -> Result<i64, String> {
+        let repo = self
+            .repo_map
+            .get(name)
+            .ok_or(format!("{} has not been cloned", name))?;
+        repo.head()
+            .and_then(|head| head.peel_to_commit())
+            .map(|commit| commit.time().seconds())
+            .map_err(|e| format!("Could not find the commit of {}: {}", name, e))
+    }
+
+    /// Check a table for problems, recording them in the run report and
+    /// failing if tables are treated strictly
+    fn lint_table(
+        &mut self,
+        name: &str,
+        table_file: &PathBuf,
+        location: &PathBuf,
+    ) -> Result<(), String> {
+        let issues = {
+            let product_urls = &self.product_urls;
+            let environment_products = &self.options.environment_products;
+            table_lint::lint_table(name, table_file, location, |dep| {
+                product_urls.get_url(dep).is_some() || environment_products.contains_key(dep)
+            })?
         };
-        let mut table_file = location.clone();
-        table_file.push(format!("ups/{}.table", name));
-        let table =
-            reups::table::Table::from_file(name.to_string(), table_file, location.to_path_buf())
-                .unwrap();
-        use reups::graph::NodeType;
-        for (dep_map, node_type) in vec![
-            &table.inexact.as_ref().unwrap().required,
-            //&table.inexact.as_ref().unwrap().optional,
-        ]
-        .iter()
-        .zip(vec![
-            NodeType::Required,
-            //   NodeType::Optional
-        ]) {
-            for (dep_name, _) in dep_map.iter() {
-                let product_added = self.graph.has_product(dep_name);
-                if !product_added {
-                    let _ = self.get_or_clone_repo(dep_name);
-                    let _ = self.checkout_branch(dep_name);
-                    self.graph_repo(dep_name, node_type.clone())
-                }
-                let sha = self.get_sha_of_head(dep_name).unwrap();
-                let _ = self
-                    .graph
-                    .connect_products(&name.to_string(), dep_name, sha);
+        for issue in issues.iter() {
+            warn!("{}", issue);
+        }
+        let errors: Vec<String> = issues
+            .iter()
+            .filter(|i| i.severity == Severity::Error)
+            .map(|i| format!("{}", i))
+            .collect();
+        self.report.table_lints.extend(issues);
+        if self.options.strict_tables && !errors.is_empty() {
+            return Err(format!(
+                "Table for {} has errors:\n{}",
+                name,
+                errors.join("\n")
+            ));
+        }
+        Ok(())
+    }
+
+    /// Clone or fetch a product and check out its branch, timing each. A
+    /// product whose branch can not be found is an error only if required,
+    /// or if an exact manifest is being built, as then every product must be
+    /// checked out at its pin.
+    fn clone_and_checkout(&mut self, product: &str, required: bool) -> Result<(), RegenError> {
+        let clock = Instant::now();
+        self.get_or_clone_repo(product)?;
+        self.profile.record(product, "clone", clock.elapsed());
+        let clock = Instant::now();
+        let mut branch = self.checkout_branch(product);
+        // what is wanted may be older than the history of a shallow clone
+        if branch.is_err() && !self.options.offline {
+            let repo = &self.repo_map[product];
+            if clone_backend::is_shallow(repo) {
+                info!(
+                    "Fetching the full history of {} to find its branch",
+                    product
+                );
+                let url = self.product_urls.get_url(product).unwrap_or_default();
+                let limits = self.clone_limits(product, url);
+                clone_backend::unshallow(repo, &self.options.fetch_remote, &limits)?;
+                branch = self.checkout_branch(product);
+            }
+        }
+        self.profile.record(product, "checkout", clock.elapsed());
+        match branch {
+            Ok((branch, Some(commit))) => {
+                self.checkouts.insert(product.to_string(), branch);
+                self.resolved.insert(product.to_string(), commit);
             }
+            Ok((branch, None)) => {
+                self.checkouts.insert(product.to_string(), branch);
+                self.fetch_lfs(product)?;
+            }
+            Err(e) if required || !self.options.pinned_shas.is_empty() => return Err(e.into()),
+            Err(e) => debug!("Could not check out {}: {}", product, e),
         }
+        Ok(())
     }
 
-    fn make_product_id(&self, product: &str) -> Result<String, String> {
-        let mut hasher = Sha1::new();
-        for node in self.graph.dfs_post_order(product)? {
-            let hashes = self.graph.product_versions(&self.graph.get_name(node));
-            let hash = match hashes.len() {
-                0 => {
-                    let name = self.graph.get_name(node);
-                    self.get_sha_of_head(&name).unwrap()
+    /// Replace the LFS pointer files of a checkout with their content, which
+    /// clones leave alone so failures to fetch it can be explained here
+    /// rather than surfacing as a puzzling build error
+    fn fetch_lfs(&mut self, product: &str) -> Result<(), RegenError> {
+        if self.product_urls.lfs(product) == Some(false) {
+            return Ok(());
+        }
+        let workdir = match self.repo_map.get(product).and_then(|r| r.workdir()) {
+            Some(workdir) if lfs::uses_lfs(workdir) => workdir.to_path_buf(),
+            _ => return Ok(()),
+        };
+        let pointers = lfs::pointer_files(&workdir);
+        if pointers.is_empty() {
+            return Ok(());
+        }
+        if self.options.skip_lfs || self.options.offline {
+            warn!(
+                "{} has {} files left as LFS pointers{}",
+                product,
+                pointers.len(),
+                match self.options.offline {
+                    true => " as running offline",
+                    false => "",
                 }
-                _ => hashes[0].clone(),
-            };
-            hasher.input(hash.as_bytes());
+            );
+            return Ok(());
         }
-        let id = hasher.result_str();
-        Ok(id)
+        info!("Fetching the LFS content of {}", product);
+        let url = self.product_urls.get_url(product).unwrap_or_default();
+        let limits = self.clone_limits(product, url);
+        lfs::pull(&workdir, &self.options.fetch_remote, url, &limits).map_err(|message| {
+            RegenError::Clone {
+                product: product.to_string(),
+                message,
+            }
+        })
     }
 
-    fn accumulate_env(
-        &self,
+    /// The product to use for a dependency of referenced_by, following the
+    /// replaced_by entries of the repo map from product and recording any
+    /// deprecated product which is still required
+    fn follow_replacements(
+        &mut self,
+        referenced_by: &str,
         product: &str,
-        product_repo: &PathBuf,
-        products: &Vec<String>,
-    ) -> Result<FnvHashMap<String, String>, String> {
-        debug!("Building env for {}", product);
-        let mut env_vars = FnvHashMap::default();
-        dbg!(product_repo);
-        for node_name in products.iter() {
-            debug!("Looking at node {}", node_name);
-            let node_id = self.make_product_id(node_name)?;
-            // get the table for the node, this presupposes all products have been
-            // declared except the product being installed
-            let (table, db_path) = if node_name == product {
+    ) -> Result<String, String> {
+        let mut current = product.to_string();
+        let mut seen = HashSet::new();
+        while let Some(next) = self.product_urls.replaced_by(&current) {
+            if !seen.insert(current.clone()) {
+                return Err(format!("The replacements of {} form a cycle", product));
+            }
+            warn!(
+                "{} requires {}, which has been replaced by {}",
+                referenced_by, current, next
+            );
+            self.report
+                .record_deprecated(&current, &next, referenced_by);
+            current = next;
+        }
+        Ok(current)
+    }
+
+    /// Write the table of a product which does not ship one into its source,
+    /// where the graph and builds read it, if the repository map says how
+    fn ensure_source_table(&self, product: &str, source: &Path) -> Result<(), String> {
+        let table = source.join("ups").join(format!("{}.table", product));
+        if table.exists() {
+            return Ok(());
+        }
+        if let Some(template) = self.product_urls.table_template(product) {
+            info!(
+                "Generating the table of {} from the repository map",
+                product
+            );
+            table_gen::write(&table, &template, None)?;
+        }
+        Ok(())
+    }
+
+    fn graph_repo(
+        &mut self,
+        name: &str,
+        node_type: reups::graph::NodeType,
+    ) -> Result<(), RegenError> {
+        let clock = Instant::now();
+        self.graph_memo.invalidate();
+        let location = {
+            let repo = self
+                .repo_map
+                .get(name)
+                .ok_or(format!("{} has not been cloned", name))?;
+            self.graph
+                .add_or_update_product(name.to_string(), node_type);
+            repo.workdir()
+                .ok_or(format!("The clone of {} has no working directory", name))?
+                .to_path_buf()
+        };
+        // a product which was not checked out has its table read from the
+        // commit its branch resolved to, through a copy outside the clone
+        let mut table_copy = None;
+        let table_file = match self.resolved.get(name) {
+            Some(commit) => {
+                let dir = TempDir::new(&format!("regenerate-{}", name)).map_err(|e| {
+                    format!(
+                        "Could not make a directory to read the table of {} in: {}",
+                        name, e
+                    )
+                })?;
+                let table_file = dir.path().join(format!("{}.table", name));
+                let in_repo = Path::new("ups").join(format!("{}.table", name));
+                match clone_backend::read_blob(&self.repo_map[name], *commit, &in_repo)? {
+                    Some(contents) => std::fs::write(&table_file, contents)
+                        .map_err(|e| format!("Could not copy the table of {}: {}", name, e))?,
+                    None => {
+                        if let Some(template) = self.product_urls.table_template(name) {
+                            table_gen::write(&table_file, &template, None)?;
+                        }
+                    }
+                }
+                table_copy = Some(dir);
+                table_file
+            }
+            None => {
+                self.ensure_source_table(name, &location)?;
+                location.join("ups").join(format!("{}.table", name))
+            }
+        };
+        let table = reups::table::Table::from_file(
+            name.to_string(),
+            table_file.clone(),
+            location.to_path_buf(),
+        )
+        .map_err(|e| format!("Could not read table {}: {}", table_file.display(), e))?;
+        self.lint_table(name, &table_file, &location)?;
+        drop(table_copy);
+        self.profile.record(name, "graph", clock.elapsed());
+        use reups::graph::NodeType;
+        let mut sections = vec![(table_dependencies(name, &table)?, NodeType::Required)];
+        match self.options.optional_dependencies {
+            OptionalPolicy::Ignore => (),
+            OptionalPolicy::BuildIfAvailable => {
+                sections.push((optional_dependencies(&table), NodeType::Optional))
+            }
+            OptionalPolicy::Require => {
+                sections.push((optional_dependencies(&table), NodeType::Required))
+            }
+        }
+        for (dep_names, node_type) in sections.iter() {
+            for dep_name in dep_names.iter() {
+                let dep_name = &self.follow_replacements(name, dep_name)?;
+                if self.is_environment_provided(dep_name) {
+                    debug!(
+                        "Dependency {} of {} is provided by the environment",
+                        dep_name, name
+                    );
+                    self.environment_dependents.insert(name.to_string());
+                    continue;
+                }
+                let product_added = self.graph.has_product(dep_name);
+                if !product_added {
+                    let optional = match node_type {
+                        NodeType::Optional => true,
+                        _ => false,
+                    };
+                    let added = self
+                        .clone_and_checkout(dep_name, false)
+                        .and_then(|_| self.graph_repo(dep_name, node_type.clone()));
+                    match added {
+                        // a missing optional dependency is built without,
+                        // unless the manifest being built exactly pins it
+                        Err(e) if optional && !self.options.pinned_shas.contains_key(dep_name) => {
+                            warn!(
+                                "Leaving out optional dependency {} of {}: {}",
+                                dep_name, name, e
+                            );
+                            self.report.recoveries.push(format!(
+                                "Built {} without optional dependency {}",
+                                name, dep_name
+                            ));
+                            continue;
+                        }
+                        added => added?,
+                    }
+                }
+                let sha = self.get_sha_of_head(dep_name)?;
+                let _ = self
+                    .graph
+                    .connect_products(&name.to_string(), dep_name, sha);
+                self.dependencies
+                    .entry(name.to_string())
+                    .or_insert_with(Vec::new)
+                    .push(dep_name.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Determine if a product is built against the conda environment. This
+    /// mirrors the implicit dependencies added in build_dependencies, along
+    /// with any dependencies the environment provides directly.
+    fn depends_on_environment(&self, product: &str) -> Result<bool, String> {
+        if self
+            .options
+            .implicit_dependencies
+            .iter()
+            .any(|dep| dep.product == product || dep.applies(product))
+        {
+            return Ok(true);
+        }
+        Ok(self
+            .subtree(product)?
+            .iter()
+            .any(|name| self.environment_dependents.contains(name)))
+    }
+
+    /// Names of a product and everything it depends on, in dfs post order so
+    /// that dependencies come before the products which need them
+    fn subtree(&self, product: &str) -> Result<Rc<Vec<String>>, String> {
+        self.graph_memo.subtree(product, || {
+            Ok(self
+                .graph
+                .dfs_post_order(product)?
+                .into_iter()
+                .map(|node| self.graph.get_name(node))
+                .collect())
+        })
+    }
+
+    fn make_product_id(&self, product: &str) -> Result<String, String> {
+        self.graph_memo.id(product, || {
+            let mut hasher = Sha1::new();
+            for name in self.subtree(product)?.iter() {
+                hasher.input(self.node_hash(name)?.as_bytes());
+            }
+            if let Some(hash) = self.environment_hash.as_ref() {
+                if self.depends_on_environment(product)? {
+                    hasher.input(hash.as_bytes());
+                }
+            }
+            if let Some(hash) = self.toolchain_hash.as_ref() {
+                hasher.input(hash.as_bytes());
+            }
+            // run supplied variables are set over every build, so an install
+            // built with different ones is a different install
+            let extra_env: BTreeMap<_, _> = self.options.extra_env.iter().collect();
+            for (name, value) in extra_env {
+                hasher.input(format!("{}={}\0", name, value).as_bytes());
+            }
+            Ok(hasher.result_str())
+        })
+    }
+
+    /// What a single product contributes to the id of itself and of every
+    /// product depending on it
+    fn node_hash(&self, name: &str) -> Result<String, String> {
+        self.graph_memo.node_hash(name, || {
+            let mut contribution = String::new();
+            let hashes = self.graph.product_versions(name);
+            let hash = match hashes.len() {
+                0 => self.get_sha_of_head(name)?,
+                _ => hashes[0].clone(),
+            };
+            contribution.push_str(&hash);
+            // some backends build from more than what the sha covers
+            if let Some(source) = self.repo_map.get(name).and_then(|r| r.workdir()) {
+                let backend = build_backend::backend_for_name(
+                    self.product_urls
+                        .build_backend(name)
+                        .as_ref()
+                        .map(|s| s.as_str()),
+                )?;
+                for input in backend.id_inputs(source) {
+                    contribution.push_str(&input);
+                }
+            }
+            // products using the environment must change identity when the
+            // environment does
+            if self.environment_dependents.contains(name) {
+                if let Some(env) = self.environment.as_ref() {
+                    contribution
+                        .push_str(&env.spec_hash(self.options.environment_products.values()));
+                }
+            }
+            Ok(contribution)
+        })
+    }
+
+    /// The products whose tables are set up to build a product, in setup
+    /// order and ending with the product itself
+    fn build_dependencies(&self, product: &str) -> Result<Vec<String>, String> {
+        let mut names = (*self.subtree(product)?).clone();
+        // implicit dependencies come first, in the order they were given, so
+        // the environment they provide is set up before anything else
+        let mut position = 0;
+        for dep in self.options.implicit_dependencies.iter() {
+            if dep.applies(product) && !names.contains(&dep.product) {
+                names.insert(position, dep.product.clone());
+                position += 1;
+            }
+        }
+        Ok(names)
+    }
+
+    /// The environment a product would be built in, resolving its graph as
+    /// needed. Its dependencies must already be installed.
+    pub fn build_environment(&mut self, product: &str) -> Result<BTreeMap<String, String>, String> {
+        self.resolve_graph(product)?;
+        let names = self.build_dependencies(product)?;
+        let repo_path = self
+            .repo_map
+            .get(product)
+            .and_then(|r| r.workdir())
+            .ok_or(format!("The clone of {} has no working directory", product))?
+            .to_path_buf();
+        Ok(self
+            .accumulate_env(product, &repo_path, &names)?
+            .into_iter()
+            .collect())
+    }
+
+    /// Versions of the build tools found in a build environment, probing
+    /// only once for each distinct search path
+    fn tool_versions(&mut self, env_vars: &FnvHashMap<String, String>) -> BTreeMap<String, String> {
+        let key = env_vars.get("PATH").cloned().unwrap_or_default();
+        if let Some(versions) = self.tool_versions.get(&key) {
+            return versions.clone();
+        }
+        let versions = toolchain::probe(&self.options.build_tool, env_vars);
+        self.tool_versions.insert(key, versions.clone());
+        versions
+    }
+
+    /// The machine as a build environment sees it, whose compiler may be one
+    /// set up from a product rather than the one of the run
+    fn host_for(&mut self, env_vars: &FnvHashMap<String, String>) -> HostFingerprint {
+        let key = (
+            env_vars.get("PATH").cloned().unwrap_or_default(),
+            env_vars.get("CC").cloned().unwrap_or_default(),
+        );
+        self.hosts
+            .entry(key)
+            .or_insert_with(|| HostFingerprint::probe(env_vars))
+            .clone()
+    }
+
+    fn accumulate_env(
+        &self,
+        product: &str,
+        product_repo: &PathBuf,
+        products: &Vec<String>,
+    ) -> Result<FnvHashMap<String, String>, String> {
+        debug!("Building env for {}", product);
+        let mut env_vars = FnvHashMap::default();
+        dbg!(product_repo);
+        for node_name in products.iter() {
+            debug!("Looking at node {}", node_name);
+            let node_id = self.make_product_id(node_name)?;
+            // get the table for the node, this presupposes all products have been
+            // declared except the product being installed
+            let (table, db_path) = if node_name == product {
                 debug!("Product not in db, local setup");
                 let mut table_path = product_repo.clone();
                 table_path.push("ups");
@@ -255,15 +1932,11 @@ impl<'a> Regenerate<'a> {
                     table_path.clone(),
                     product_repo.clone(),
                 ) {
-                    Ok(x) => (
-                        x,
-                        PathBuf::from(format!(
-                            "LOCAL:{}",
-                            table_path
-                                .to_str()
-                                .ok_or("cant convert table path to str")?
-                        )),
-                    ),
+                    Ok(x) => {
+                        let mut local = OsString::from("LOCAL:");
+                        local.push(&table_path);
+                        (x, PathBuf::from(local))
+                    }
                     Err(e) => return Err(format!("{}", e)),
                 }
             } else {
@@ -288,66 +1961,365 @@ impl<'a> Regenerate<'a> {
                 false,
             );
         }
+        for (name, value) in self.options.extra_env.iter() {
+            debug!("Setting {} from the run options", name);
+            env_vars.insert(name.clone(), value.clone());
+        }
         Ok(env_vars)
     }
 
+    /// The command the build tool of a product is run under, if any. The
+    /// wrapper of the run goes outside that of the product, so a product can
+    /// not step out of a sandbox the site runs builds in.
+    fn command_wrapper(&self, product: &str) -> Vec<String> {
+        let mut wrapper = self.options.command_wrapper.clone().unwrap_or_default();
+        wrapper.extend(
+            self.product_urls
+                .command_wrapper(product)
+                .unwrap_or_default(),
+        );
+        wrapper
+    }
+
+    /// Run a single build tool verb, recording its output in the build log
+    fn run_verb(
+        &mut self,
+        product: &str,
+        step: &BuildStep,
+        repo_path: &PathBuf,
+        env_vars: &FnvHashMap<String, String>,
+    ) -> Result<(), String> {
+        let verb = step.verb.as_str();
+        debug!("Running build tool verb {}", verb);
+        self.progress.emit(ProgressEvent::VerbStarted {
+            product: product.to_string(),
+            verb: verb.to_string(),
+        });
+        let env: Vec<(String, String)> = env_vars
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        // run the build tool through the wrapper if one is configured
+        let clock = Instant::now();
+        let output = scheduler::run_step(
+            step,
+            &self.command_wrapper(product),
+            repo_path,
+            &env,
+            self.build_umask(),
+            &self.live_output(product),
+        );
+        self.profile
+            .record(product, &format!("verb {}", verb), clock.elapsed());
+        self.progress.emit(ProgressEvent::VerbFinished {
+            product: product.to_string(),
+            verb: verb.to_string(),
+            success: verb_succeeded(&output),
+        });
+        self.record_verb(product, verb, &output)
+    }
+
+    /// Write to the build log of the run, keeping a copy of what each product
+    /// wrote until it finishes
+    fn write_log(&mut self, product: &str, bytes: &[u8]) {
+        let _ = self.build_log.write_all(bytes);
+        self.product_logs
+            .entry(product.to_string())
+            .or_insert_with(Vec::new)
+            .extend_from_slice(bytes);
+    }
+
+    /// Write the output of a verb to the build log and pass it through the
+    /// output processors, returning an error if the verb failed
+    fn record_verb(
+        &mut self,
+        product: &str,
+        verb: &str,
+        output: &Result<std::process::Output, String>,
+    ) -> Result<(), String> {
+        self.write_log(
+            product,
+            format!("Running build tool verb {}\n", verb).as_bytes(),
+        );
+        match output {
+            Ok(o) => {
+                self.write_log(
+                    product,
+                    format!("Process exited with status {}\n", o.status).as_bytes(),
+                );
+                self.write_log(product, "Process stdout:\n".as_bytes());
+                self.write_log(product, &o.stdout);
+                self.write_log(product, "\n".as_bytes());
+                self.write_log(product, "Process stderr:\n".as_bytes());
+                self.write_log(product, &o.stderr);
+                self.write_log(product, "\n".as_bytes());
+                for stream in [&o.stdout, &o.stderr].iter() {
+                    classify::process_output(&mut self.output_processors, product, verb, stream);
+                }
+                if verb == Phase::Test.name() {
+                    self.report.record_test(product, o.status.success());
+                    if !o.status.success() && !self.options.fail_on_tests {
+                        warn!("The tests of {} failed, installing it anyway", product);
+                        return Ok(());
+                    }
+                }
+                if !o.status.success() {
+                    Err(scheduler::failure_message(o))
+                } else {
+                    debug!("{:#?}", o.status);
+                    Ok(())
+                }
+            }
+            Err(e) => {
+                self.write_log(product, format!("{}\n", e).as_bytes());
+                Err(e.clone())
+            }
+        }
+    }
+
+    /// The source directory of a product's clone
+    fn source_dir(&self, product: &str) -> Result<PathBuf, String> {
+        Ok(self
+            .repo_map
+            .get(product)
+            .ok_or("no product of specified name found")?
+            .workdir()
+            .ok_or("The speficied product has no working directory")?
+            .to_path_buf())
+    }
+
+    /// Make a fresh copy of a product's source in a temporary directory, so a
+    /// build can be retried without any state left by a failed attempt
+    fn restage(&self, product: &str) -> Result<(TempDir, PathBuf), String> {
+        scheduler::restage(product, &self.source_dir(product)?)
+    }
+
     fn build_product(
         &mut self,
         product: &str,
+        backend: &dyn BuildBackend,
         product_dir: &PathBuf,
         repo_path: &PathBuf,
         env_vars: &FnvHashMap<String, String>,
-    ) {
+    ) -> Result<(), RegenError> {
         info!("Building {}", product);
         debug!("Using environment {:#?} for building", env_vars);
-        let _ = self
-            .build_log
-            .write_all(format!("Building {}\n", product).as_bytes());
+        self.write_log(product, format!("Building {}\n", product).as_bytes());
 
         dbg!(product_dir);
         dbg!(&repo_path);
-        for verb in ["fetch", "prep", "config", "build", "install"].iter() {
-            debug!("Running build tool verb {}", verb);
-            let _ = self
-                .build_log
-                .write_all(format!("Running build tool verb {}\n", verb).as_bytes());
-            let output = std::process::Command::new(&self.options.build_tool)
-                .args(&[
-                    format!("PRODUCT={}", product),
-                    format!("VERSION={}", self.options.version),
-                    format!("FLAVOR={}", reups::SYSTEM_OS),
-                    format!("PREFIX={}", &product_dir.to_str().unwrap()),
-                    verb.to_string(),
-                ])
-                .current_dir(&repo_path)
-                .envs(env_vars)
-                .output();
-            match output {
-                Ok(o) => {
-                    let _ = self
-                        .build_log
-                        .write_all(format!("Process exited with status {}\n", o.status).as_bytes());
-                    let _ = self.build_log.write_all("Process stdout:\n".as_bytes());
-                    let _ = self.build_log.write_all(&o.stdout);
-                    let _ = self.build_log.write_all("\n".as_bytes());
-                    let _ = self.build_log.write_all("Process stderr:\n".as_bytes());
-                    let _ = self.build_log.write_all(&o.stderr);
-                    let _ = self.build_log.write_all("\n".as_bytes());
-                    if !o.status.success() {
-                        panic!("{:#?}", o);
-                    } else {
-                        debug!("{:#?}", o.status);
-                        ()
-                    }
-                }
-                Err(e) => {
-                    panic!("Building failed with error {}", e);
+        debug!("Building {} with the {} backend", product, backend.name());
+        let steps = self
+            .build_steps(product, backend, product_dir, repo_path)
+            .map_err(|message| RegenError::Build {
+                product: product.to_string(),
+                message,
+            })?;
+        // directories created by restaging must outlive the build
+        let mut restaged = vec![];
+        let mut build_path = repo_path.clone();
+        let mut retries_used: HashMap<String, u32> = HashMap::new();
+        let mut index = 0;
+        while index < steps.len() {
+            let step = &steps[index];
+            let verb = step.verb.as_str();
+            let error = match self.run_verb(product, step, &build_path, env_vars) {
+                Ok(_) => {
+                    index += 1;
+                    continue;
                 }
+                Err(e) => e,
+            };
+            let allowed = *self.options.verb_retries.get(verb).unwrap_or(&0);
+            let used = retries_used.entry(verb.to_string()).or_insert(0);
+            if *used >= allowed {
+                return Err(RegenError::Build {
+                    product: product.to_string(),
+                    message: error,
+                });
+            }
+            *used += 1;
+            warn!(
+                "Verb {} failed for {}, retrying (retry {} of {})",
+                verb, product, used, allowed
+            );
+            if self.options.restage_on_retry && self.product_urls.clean_build(product) {
+                clone_backend::clean_tree(&build_path).map_err(|e| RegenError::Build {
+                    product: product.to_string(),
+                    message: format!("could not clean: {}", e),
+                })?;
+                index = 0;
+            } else if self.options.restage_on_retry {
+                let (tmp_dir, path) = self.restage(product).map_err(|e| RegenError::Build {
+                    product: product.to_string(),
+                    message: format!("could not restage: {}", e),
+                })?;
+                restaged.push(tmp_dir);
+                build_path = path;
+                index = 0;
+            }
+        }
+        self.finish_build(product, backend, product_dir, &build_path, &retries_used)
+    }
+
+    /// How the phases of a product's build are to be run, from its entry in
+    /// the repos file and the phases given for the run
+    fn phase_settings(&self, product: &str) -> Result<PhaseSettings, String> {
+        let mut settings = self
+            .product_urls
+            .phase_settings(product, self.options.run_tests)?;
+        settings.sequence = self
+            .options
+            .product_phases
+            .get(product)
+            .unwrap_or(&self.options.phases)
+            .clone();
+        Ok(settings)
+    }
+
+    /// Build a product a second time, once it and everything it needs are
+    /// installed, and compare the contents of the two builds, returning the
+    /// files which differ. The second build is made where the first was
+    /// installed, so paths baked into files match, with the first set aside
+    /// meanwhile and put back afterwards.
+    pub fn check_reproducible(&mut self, product: &str) -> Result<Vec<String>, RegenError> {
+        self.install_product(product)?;
+        let product_id = self.make_product_id(product)?;
+        let installed = self
+            .db
+            .get_table_from_identity(product, &product_id)
+            .ok_or(format!(
+                "{} was not declared with id {}",
+                product, product_id
+            ))?
+            .product_dir
+            .canonicalize()
+            .map_err(|e| format!("Could not find the install of {}: {}", product, e))?;
+        let first = reproducible::content_manifest(&installed);
+        let aside = installed.with_extension("first-build");
+        std::fs::rename(&installed, &aside)
+            .map_err(|e| format!("Could not set {} aside: {}", installed.display(), e))?;
+        info!("Building {} again to compare with its install", product);
+        let rebuilt = self.rebuild(product, &product_id);
+        let second = reproducible::content_manifest(&installed);
+        // put the declared install back whatever happened to the rebuild
+        if installed.exists() {
+            std::fs::remove_dir_all(&installed)
+                .map_err(|e| format!("Could not remove {}: {}", installed.display(), e))?;
+        }
+        std::fs::rename(&aside, &installed)
+            .map_err(|e| format!("Could not restore {}: {}", installed.display(), e))?;
+        rebuilt?;
+        Ok(reproducible::differences(&first, &second))
+    }
+
+    /// Build a product from source into its install directory without
+    /// declaring it
+    fn rebuild(&mut self, product: &str, product_id: &str) -> Result<(), RegenError> {
+        let metadata = match self.repo_map.get(product).and_then(|r| r.workdir()) {
+            Some(path) => metadata::harvest(path),
+            None => ProductMetadata::default(),
+        };
+        let names = self.build_dependencies(product)?;
+        let staged = self.stage_build(product, product_id, metadata, &names)?;
+        self.build_product(
+            product,
+            staged.backend.as_ref(),
+            &staged.product_dir,
+            &staged.repo_path,
+            &staged.env_vars,
+        )?;
+        // as complete_build does, so the two installs hold the same entries
+        let git_path = staged.product_dir.join(".git");
+        if git_path.exists() {
+            remove(git_path).map_err(|e| format!("{}", e))?;
+        }
+        if self.options.reproducible {
+            reproducible::normalize_install(&staged.product_dir, self.source_date_epoch(product)?)?;
+        }
+        Ok(())
+    }
+
+    /// The commands building a product with a backend
+    fn build_steps(
+        &self,
+        product: &str,
+        backend: &dyn BuildBackend,
+        product_dir: &PathBuf,
+        build_path: &PathBuf,
+    ) -> Result<Vec<BuildStep>, String> {
+        let settings = self.phase_settings(product)?;
+        let mut steps = build_backend::build_steps(
+            backend,
+            &settings,
+            &BuildContext {
+                product,
+                version: &self.options.version,
+                product_dir,
+                build_path,
+                build_tool: &self.options.build_tool,
+                jobs: self.options.build_jobs,
+                toolchain_file: self
+                    .options
+                    .cmake_toolchain_file
+                    .as_ref()
+                    .map(|p| p.as_path()),
+            },
+        )?;
+        for step in steps.iter_mut() {
+            let default = self
+                .options
+                .verb_timeouts
+                .get(&step.verb)
+                .cloned()
+                .or(self.options.build_timeout);
+            step.timeout = self
+                .product_urls
+                .build_timeout(product, &step.verb, default);
+        }
+        Ok(steps)
+    }
+
+    /// Let the backend complete an install once its steps have run, and
+    /// remember any verbs which needed retries
+    fn finish_build(
+        &mut self,
+        product: &str,
+        backend: &dyn BuildBackend,
+        product_dir: &PathBuf,
+        build_path: &PathBuf,
+        retries_used: &HashMap<String, u32>,
+    ) -> Result<(), RegenError> {
+        let context = BuildContext {
+            product,
+            version: &self.options.version,
+            product_dir,
+            build_path,
+            build_tool: &self.options.build_tool,
+            jobs: self.options.build_jobs,
+            toolchain_file: self
+                .options
+                .cmake_toolchain_file
+                .as_ref()
+                .map(|p| p.as_path()),
+        };
+        backend.finish(&context).map_err(|e| RegenError::Build {
+            product: product.to_string(),
+            message: format!("could not finish the install: {}", e),
+        })?;
+        // the build succeeded, but remember any verbs which needed retries
+        if !retries_used.is_empty() {
+            for verb in retries_used.keys() {
+                self.history.mark_flaky(product, verb);
+            }
+            if let Err(e) = self.history.save() {
+                warn!("Could not save build history: {}", e);
             }
         }
+        Ok(())
     }
 
-    pub fn install_product(&mut self, product: &str) -> Result<(), String> {
+    pub fn install_product(&mut self, product: &str) -> Result<(), RegenError> {
         // clone product
         // checkout branch
         // graph repo (VERIFY BRANCH IS PRESENT IN AT LEAST ONE RPO)
@@ -359,179 +2331,2016 @@ impl<'a> Regenerate<'a> {
         // issue eupspkg build comamnds
         // declare to systemdb
         // declare to remote db?
+        self.install_products(&[product.to_string()])
+    }
 
-        info!("Installing product {}", product);
-        self.get_or_clone_repo(product)?;
-        self.checkout_branch(product)?;
-        self.graph_repo(product, reups::graph::NodeType::Required);
-        self.install_product_impl(product)
+    /// Install several products in one run. Their graphs are resolved into
+    /// one before anything is built, so dependencies they share are built
+    /// once and every id is worked out from the same combined graph.
+    pub fn install_products(&mut self, products: &[String]) -> Result<(), RegenError> {
+        match products.len() {
+            1 => info!("Installing product {}", products[0]),
+            _ => info!("Installing products {}", products.join(", ")),
+        }
+        self.progress.emit(ProgressEvent::RunStarted {
+            product: products.join(","),
+        });
+        let result = self.install_products_setup(products);
+        self.finish_run(products, result)
     }
 
-    fn install_product_impl(&mut self, product: &str) -> Result<(), String> {
-        // short circuit if this has already been built
-        if self.build_completed.contains(product) {
-            return Ok(());
+    /// Install the products of a resolved manifest in the order and at the
+    /// shas it gives. No table is read, the graph is taken from the manifest
+    /// as it is, and each product is only checked to have its sha. The last
+    /// product of the manifest is treated as the product of the run.
+    pub fn install_manifest(&mut self, manifest: &ReleaseManifest) -> Result<(), RegenError> {
+        let top = manifest.top().to_string();
+        info!(
+            "Installing {} products from a manifest",
+            manifest.products.len()
+        );
+        if let Some(build) = manifest.build.as_ref() {
+            info!("The manifest is of build {}", build);
         }
-        let product_id = self.make_product_id(product)?;
-        let table = if self.db.has_identity(product, &product_id) {
-            info!(
-                "Database has product {} with id {}, using that for the build",
-                product, &product_id
-            );
-            // Get the path to an existing product if that is to be used
-            self.db
-                .get_table_from_identity(product, &product_id)
-                .ok_or(format!(
-                    "Error retrieving up table for {} in database",
-                    product
-                ))?
-        } else {
-            info!("Doing a source build for {}", product);
-
-            // record all dependencies into a vector, as it is cheaper to loop through
-            // that than do a dfs iteration multiple times
-            let mut names = vec![];
-            let mut has_python = false;
-            for node in self.graph.dfs_post_order(product)? {
-                let node_name = self.graph.get_name(node);
-                if node_name == "scipipe_conda" {
-                    has_python = true
-                }
-                names.push(node_name);
-            }
-            // for now force the python env to be a dependency of everything except
-            // the environment and base conda, this ensures the environment is setup
-            // this is not a good long terms solution but is useful for just testing
-            if !HashSet::<&&str>::from_iter(["miniconda_lsst", "scipipe_conda"].iter())
-                .contains(&product)
-                && !has_python
-            {
-                names.insert(0, "scipipe_conda".to_string())
+        self.progress.emit(ProgressEvent::RunStarted {
+            product: top.clone(),
+        });
+        let result = self.graph_manifest(manifest).and_then(|_| {
+            let products: Vec<String> = manifest
+                .products
+                .iter()
+                .map(|p| p.product.clone())
+                .collect();
+            self.install_resolved(&products)
+        });
+        self.finish_run(&[top], result)
+    }
+
+    /// Check out every product of a manifest at its sha and build the graph
+    /// from the dependencies it lists
+    fn graph_manifest(&mut self, manifest: &ReleaseManifest) -> Result<(), RegenError> {
+        use reups::graph::NodeType;
+        self.graph_memo.invalidate();
+        for entry in manifest.products.iter() {
+            self.pin(&entry.product, &entry.sha);
+            self.clone_and_checkout(&entry.product, true)?;
+            let head = self.get_sha_of_head(&entry.product)?;
+            if !head.starts_with(&entry.sha) {
+                return Err(format!(
+                    "{} is at {} rather than {} given by the manifest",
+                    entry.product, head, entry.sha
+                )
+                .into());
+            }
+            self.graph
+                .add_or_update_product(entry.product.clone(), NodeType::Required);
+            for dep in entry.dependencies.iter() {
+                let sha = self.get_sha_of_head(dep)?;
+                let _ = self.graph.connect_products(&entry.product, dep, sha);
+                self.dependencies
+                    .entry(entry.product.clone())
+                    .or_insert_with(Vec::new)
+                    .push(dep.clone());
             }
+        }
+        Ok(())
+    }
 
-            debug!("Product {} has dependencies {:?}", product, &names);
+    /// Tag, report, and announce the end of a run installing products
+    fn finish_run(
+        &mut self,
+        products: &[String],
+        mut result: Result<(), RegenError>,
+    ) -> Result<(), RegenError> {
+        if result.is_ok() && self.options.atomic_tag {
+            result = self.apply_tag(products).map_err(RegenError::from);
+        }
+        // nothing is left to resume once a run succeeds
+        if result.is_ok() {
+            if let Err(e) = self.run_state.clear() {
+                warn!("Could not clear the run state: {}", e);
+            }
+            if let Err(e) = self.write_run_manifest(products) {
+                result = Err(RegenError::from(e));
+            }
+        }
+        if let Err(e) = self.write_build_manifest(products) {
+            warn!("Could not write the build manifest: {}", e);
+        }
+        self.report.network = network::usage();
+        self.report.output_counts = classify::merged_counts(&self.output_processors);
+        let (hits, misses) = self.graph_memo.stats();
+        debug!(
+            "Graph memo answered {} lookups and computed {}",
+            hits, misses
+        );
+        self.progress.emit(ProgressEvent::RunFinished {
+            built: self.report.built(),
+            reused: self.report.reused(),
+            failed: self.report.failed(),
+        });
+        result
+    }
 
-            // make sure all the dependencies are already installed, making sure
-            // to skip the product currently being installed (ie the last element
-            // in the dfs
-            for name in names.iter() {
-                // this product will be in the dfs graph, so skip it and finish
-                // this function
-                info!("Processing dependency {}", name);
-                if name != product {
-                    self.install_product_impl(&name)?;
+    fn install_products_setup(&mut self, products: &[String]) -> Result<(), RegenError> {
+        if !self.options.skip_preflight {
+            self.check_prerequisites()?;
+        }
+        // cloning takes space and inodes too, so check before any of it
+        self.ensure_disk_space("cloning")?;
+        for product in products.iter() {
+            self.resolve_graph(product)?;
+        }
+        self.check_duplicate_sources(products)?;
+        if !self.options.skip_preflight {
+            self.check_estimated_space(products)?;
+        }
+        if let (Some(threshold), false) = (self.options.confirm_threshold, self.options.assume_yes)
+        {
+            let mut plan = Plan::default();
+            for product in products.iter() {
+                plan.merge(self.plan_resolved(product)?);
+            }
+            let estimate = plan.estimate(self.options.build_workers);
+            if estimate.builds >= threshold {
+                // unattended runs, as in ci, have no one to ask
+                if !plan::can_confirm() {
+                    info!(
+                        "{}, not asking to confirm as there is no terminal",
+                        estimate.render()
+                    );
+                } else if !plan::confirm(&format!("{}. Continue?", estimate.render()))? {
+                    return Err("Run was not confirmed".into());
+                }
+            }
+        }
+        let mut selected: Vec<String> = vec![];
+        for product in products.iter() {
+            let names = match self.options.product_filter.is_empty() {
+                true => vec![product.to_string()],
+                false => self.filtered_products(product)?,
+            };
+            for name in names {
+                if !selected.contains(&name) {
+                    selected.push(name);
                 }
             }
+        }
+        self.install_resolved(&selected)
+    }
 
-            // determine the product directory to install to, and make sure it is
-            // created
-            let mut product_dir = PathBuf::from(&self.options.install_root);
-            product_dir.push(product);
-            product_dir.push(&self.options.version);
+    /// Install products whose graph has been resolved, along with anything
+    /// they depend on
+    fn install_resolved(&mut self, products: &[String]) -> Result<(), RegenError> {
+        match self.options.build_workers {
+            0 | 1 if self.options.keep_going => self.install_keep_going(products),
+            0 | 1 => {
+                for name in products.iter() {
+                    self.install_product_impl(name)?;
+                }
+                Ok(())
+            }
+            _ => self.install_scheduled(products),
+        }
+    }
 
-            debug!(
-                "Creating directory {} for {} installation",
-                product_dir.to_str().unwrap(),
+    /// The products in the graph of product passing the product filter.
+    /// Every product they need which was filtered out must have an install
+    /// which can be reused, as it will not be built.
+    fn filtered_products(&mut self, product: &str) -> Result<Vec<String>, String> {
+        let included: Vec<String> = self
+            .build_dependencies(product)?
+            .into_iter()
+            .filter(|name| self.options.product_filter.includes(name))
+            .collect();
+        if included.is_empty() {
+            return Err(format!(
+                "No product in the graph of {} matches the product filters",
                 product
-            );
+            ));
+        }
+        let mut checked = HashSet::new();
+        let mut missing = vec![];
+        for name in included.iter() {
+            for dep in self.build_dependencies(name)?.iter() {
+                if self.options.product_filter.includes(dep) || !checked.insert(dep.clone()) {
+                    continue;
+                }
+                let product_id = self.make_product_id(dep)?;
+                if self.reusable_table(dep, &product_id)?.is_none() {
+                    missing.push(format!("{} (needed by {})", dep, name));
+                }
+            }
+        }
+        if !missing.is_empty() {
+            return Err(format!(
+                "Products excluded by the filters have no install to reuse: {}",
+                missing.join(", ")
+            ));
+        }
+        info!(
+            "Filters limit the run to {} of the graph of {}",
+            included.join(", "),
+            product
+        );
+        Ok(included)
+    }
 
-            match std::fs::create_dir_all(&product_dir) {
-                Ok(_) => (),
-                Err(e) => return Err(format!("{}", e)),
+    /// Move the tag to every product in the graphs of installed products, all
+    /// at once
+    fn apply_tag(&mut self, installed: &[String]) -> Result<(), String> {
+        let tag = match self.options.tag.as_ref() {
+            Some(t) => t,
+            None => return Ok(()),
+        };
+        let mut products = vec![];
+        for product in installed.iter() {
+            // products left out by the filters were not installed by this run
+            for name in self
+                .subtree(product)?
+                .iter()
+                .filter(|name| self.options.product_filter.includes(name))
+            {
+                let entry = (name.clone(), self.options.version.clone());
+                if !products.contains(&entry) {
+                    products.push(entry);
+                }
             }
-            debug!("Done creating");
+        }
+        tags::move_tag(&mut *self.db, tag, &products)
+    }
 
-            product_dir = product_dir
-                .canonicalize()
-                .or_else(|e| return Err(format!("{}", e)))?;
+    /// Clone and checkout a product and all of its dependencies, building up
+    /// the dependency graph without installing anything
+    pub fn resolve_graph(&mut self, product: &str) -> Result<(), RegenError> {
+        self.clone_and_checkout(product, true)?;
+        self.graph_repo(product, reups::graph::NodeType::Required)
+    }
 
-            // get the path to the build directory
-            let repo_path = self
-                .repo_map
-                .get(product)
-                .ok_or("no product of specified name found")?
-                .workdir()
-                .ok_or("The speficied product has no working directory")?
-                .canonicalize()
-                .or_else(|_| return Err(format!("Problem expanding abs path for {}", product)))?
-                .to_str()
-                .ok_or("Problem turning path into str")?
-                .to_string();
-            // look if the product should be built in a temporary path
-            let mut upstream = PathBuf::from(&repo_path);
-            upstream.push("upstream");
-            let tmp_dir = TempDir::new(product).unwrap();
-            let mut tmp_dir_path = PathBuf::from(tmp_dir.path());
-            let repo_path = if upstream.exists() {
-                debug!("Product is a upstream build, copy to tmp directory");
-                let _ = copy(repo_path, &tmp_dir_path, &CopyOptions::new());
-                tmp_dir_path.push(product);
-                tmp_dir_path
-            } else {
-                drop(tmp_dir);
-                PathBuf::from(repo_path)
+    /// Capture the resolved graph of a product so it may be saved and compared
+    /// with other runs, resolve_graph must have been called first
+    pub fn snapshot_graph(&self, product: &str) -> Result<GraphSnapshot, String> {
+        let mut snapshot = GraphSnapshot {
+            root: product.to_string(),
+            ..GraphSnapshot::default()
+        };
+        for name in self.subtree(product)?.iter().cloned() {
+            let node = SnapshotNode {
+                sha: self.get_sha_of_head(&name)?,
+                id: self.make_product_id(&name)?,
             };
-            // accumulate the environment varibales
-            let env_vars = self.accumulate_env(product, &repo_path, &names)?;
-            // remove and trace that this might have been previously prepaired
-            let mut prep_path = PathBuf::from(&repo_path);
-            prep_path.push("upstream");
-            prep_path.push("prepared");
-            if prep_path.exists() {
-                let _ = std::fs::remove_file(prep_path);
-            }
-            // issue the build commands
-            self.build_product(product, &product_dir, &repo_path, &env_vars);
-            // remove the git folder form product_dir
-            let mut git_path = product_dir.clone();
-            git_path.push(".git");
-            if git_path.exists() {
-                debug!("Removing git directory from installation");
-                match remove(git_path) {
-                    Ok(_) => (),
-                    Err(e) => return Err(format!("{}", e)),
-                };
+            if let Some(deps) = self.dependencies.get(&name) {
+                for dep in deps.iter() {
+                    snapshot.edges.insert((name.clone(), dep.clone()));
+                }
             }
-            let product_pathbuf = PathBuf::from(&product_dir);
-            let mut table_path = product_pathbuf.clone();
-            table_path.push("ups");
-            table_path.push(format!("{}.table", product));
-            let table = match reups::table::Table::from_file(
-                product.to_string(),
-                table_path.clone(),
-                product_pathbuf,
-            ) {
-                Ok(x) => x,
-                Err(e) => return Err(format!("{}", e)),
-            };
-            table
-        };
-        // get the table for the product
+            snapshot.nodes.insert(name, node);
+        }
+        Ok(snapshot)
+    }
 
-        // declare the results to the database
-        let tmp_tag = match self.options.tag.as_ref() {
-            Some(t) => Some(t.as_str()),
-            None => None,
-        };
+    /// Resolve the graph of a product and render it, with the sha and id of
+    /// each product, so it can be seen why something would be rebuilt
+    pub fn export_graph(
+        &mut self,
+        product: &str,
+        format: GraphFormat,
+    ) -> Result<String, RegenError> {
+        self.resolve_graph(product)?;
+        Ok(self.snapshot_graph(product)?.render(format)?)
+    }
 
-        info!("Declaring {}", product);
-        let product_dir = table.product_dir.clone();
-        let declare_product = reups::DeclareInputs {
-            product,
-            prod_dir: &product_dir,
-            version: &self.options.version,
-            tag: tmp_tag,
-            ident: Some(product_id.as_str()),
-            flavor: Some(reups::SYSTEM_OS),
+    /// Resolve the graph of a product and return every product it depends on,
+    /// directly or through other products
+    pub fn dependency_closure(&mut self, product: &str) -> Result<BTreeSet<String>, RegenError> {
+        self.resolve_graph(product)?;
+        Ok(self
+            .subtree(product)?
+            .iter()
+            .filter(|name| *name != product)
+            .cloned()
+            .collect())
+    }
+
+    /// Work out what installing a product would do without building anything,
+    /// resolving the graph as needed
+    pub fn plan(&mut self, product: &str) -> Result<Plan, String> {
+        self.resolve_graph(product)?;
+        self.check_duplicate_sources(&[product.to_string()])?;
+        self.plan_resolved(product)
+    }
+
+    fn plan_resolved(&self, product: &str) -> Result<Plan, String> {
+        let mut plan = Plan {
+            root: product.to_string(),
+            steps: vec![],
+        };
+        for name in self.subtree(product)?.iter().cloned() {
+            let id = self.make_product_id(&name)?;
+            let dependencies = self.dependencies.get(&name).cloned().unwrap_or_default();
+            let rebuilt_dep = dependencies.iter().find(|d| {
+                plan.steps
+                    .iter()
+                    .any(|s| &s.product == *d && s.action == PlanAction::Build)
+            });
+            let (abi_problems, host_problems) = match self.db.get_table_from_identity(&name, &id) {
+                Some(table) => (
+                    self.abi_mismatches(&name, &table.product_dir),
+                    self.host_mismatches(&table.product_dir),
+                ),
+                None => (vec![], vec![]),
+            };
+            if !abi_problems.is_empty() && self.options.abi_check == AbiCheck::Fail {
+                return Err(format!(
+                    "The install of {} does not match its dependencies: {}",
+                    name,
+                    abi_problems.join("; ")
+                ));
+            }
+            if !host_problems.is_empty() && self.options.host_check == HostCheck::Fail {
+                return Err(format!(
+                    "The install of {} may not run on this host: {}",
+                    name,
+                    host_problems.join("; ")
+                ));
+            }
+            let (action, reason) = if !abi_problems.is_empty() {
+                (
+                    PlanAction::Build,
+                    format!(
+                        "the install with id {} does not match its dependencies: {}",
+                        id,
+                        abi_problems.join("; ")
+                    ),
+                )
+            } else if !host_problems.is_empty() && self.options.host_check == HostCheck::Rebuild {
+                (
+                    PlanAction::Build,
+                    format!(
+                        "the install with id {} may not run on this host: {}",
+                        id,
+                        host_problems.join("; ")
+                    ),
+                )
+            } else if self.db.has_identity(&name, &id) {
+                (
+                    PlanAction::Reuse,
+                    match host_problems.is_empty() {
+                        true => format!("the database has an install with id {}", id),
+                        false => format!(
+                            "the database has an install with id {}, though it may not run on \
+                             this host: {}",
+                            id,
+                            host_problems.join("; ")
+                        ),
+                    },
+                )
+            } else if let Some(dep) = rebuilt_dep {
+                (
+                    PlanAction::Build,
+                    format!("dependency {} will be rebuilt", dep),
+                )
+            } else {
+                (
+                    PlanAction::Build,
+                    format!("no install with id {} is in the database", id),
+                )
+            };
+            // make sure the build can be carried out before anything starts
+            if action == PlanAction::Build {
+                let backend = build_backend::backend_for_name(
+                    self.product_urls
+                        .build_backend(&name)
+                        .as_ref()
+                        .map(|s| s.as_str()),
+                )?;
+                let settings = self.phase_settings(&name)?;
+                build_backend::select_phases(backend.as_ref(), &settings)
+                    .map_err(|e| format!("Can not build {}: {}", name, e))?;
+            }
+            let history = self.history.get(&name);
+            plan.steps.push(PlanStep {
+                cloned: self.cloned.contains(&name),
+                checkout: self.checkouts.get(&name).cloned(),
+                shares: self.shared_clone_of(&name),
+                held: self.holds.get(&name).cloned(),
+                estimated_seconds: history.and_then(|h| h.build_seconds),
+                estimated_bytes: history.and_then(|h| h.install_bytes),
+                sha: self.get_sha_of_head(&name)?,
+                product: name,
+                id,
+                action,
+                reason,
+                dependencies,
+            });
+        }
+        Ok(plan)
+    }
+
+    /// The report of everything installed so far in this run
+    pub fn report(&self) -> &RunReport {
+        &self.report
+    }
+
+    /// Where the build log of a product which failed to build in this run
+    /// was written, if it did fail
+    pub fn failure_log(&self, product: &str) -> Option<&PathBuf> {
+        self.failure_logs.get(product)
+    }
+
+    /// Render and deliver the run report according to the report options
+    pub fn publish_report(&self) -> Result<(), String> {
+        match self.options.report.as_ref() {
+            Some(options) => {
+                let template = self
+                    .options
+                    .run_name_template
+                    .as_ref()
+                    .map(|t| t.as_str())
+                    .unwrap_or(naming::DEFAULT_TEMPLATE);
+                self.report.publish(options, &self.run_name, template)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn install_product_impl(&mut self, product: &str) -> Result<(), RegenError> {
+        // short circuit if this has already been built
+        if self.build_completed.contains(product) || self.resume(product)? {
+            return Ok(());
+        }
+        let mut start = Instant::now();
+        let failures_before = self.report.failed();
+        self.product_started(product);
+        let result = self.install_single_product(product, &mut start);
+        self.product_finished(product, start, failures_before, &result);
+        result
+    }
+
+    /// Skip a product an interrupted run installed, as long as it would
+    /// still be installed with the same id from the same sha
+    fn resume(&mut self, product: &str) -> Result<bool, String> {
+        let completed = match self.run_state.get(product) {
+            Some(c) => c.clone(),
+            None => return Ok(false),
+        };
+        let id = self.make_product_id(product)?;
+        let sha = self.get_sha_of_head(product).unwrap_or_default();
+        // an install deleted since is left to the checks made on reuse
+        let installed = self
+            .db
+            .get_table_from_identity(product, &id)
+            .map(|t| t.product_dir.is_dir())
+            .unwrap_or(false);
+        if completed.id != id || completed.sha != sha || !installed {
+            debug!(
+                "{} has changed since it was completed, installing it",
+                product
+            );
+            return Ok(false);
+        }
+        info!(
+            "{} was completed by an interrupted run, skipping it",
+            product
+        );
+        self.build_completed.insert(product.to_string());
+        self.report.record(
+            product,
+            ProductOutcome::Reused,
+            Duration::from_secs(0),
+            None,
+        );
+        Ok(true)
+    }
+
+    fn product_started(&mut self, product: &str) {
+        self.progress.emit(ProgressEvent::ProductStarted {
+            product: product.to_string(),
+        });
+        if let Some(stream) = self.build_stream.as_mut() {
+            stream.started(product, &self.options.version);
+        }
+    }
+
+    /// Record how the install of a product ended, failures_before being the
+    /// number of failures when it started
+    fn product_finished(
+        &mut self,
+        product: &str,
+        start: Instant,
+        failures_before: usize,
+        result: &Result<(), RegenError>,
+    ) {
+        // only attribute the failure to this product if it did not come from
+        // one of its dependencies
+        let own_failure = result.is_err() && self.report.failed() == failures_before;
+        if let Err(e) = result.as_ref() {
+            if own_failure {
+                let excerpt = self
+                    .product_logs
+                    .get(product)
+                    .and_then(|log| report::log_excerpt(log, report::EXCERPT_LINES));
+                self.report.record(
+                    product,
+                    ProductOutcome::Failed(format!("{}", e)),
+                    start.elapsed(),
+                    excerpt,
+                );
+            }
+        }
+        let outcome = match result.as_ref() {
+            Ok(_) => self
+                .report
+                .records
+                .last()
+                .map(|r| r.outcome.name())
+                .unwrap_or("unknown"),
+            Err(_) => "failed",
+        };
+        self.progress.emit(ProgressEvent::ProductFinished {
+            product: product.to_string(),
+            outcome: outcome.to_string(),
+        });
+        if let Some(stream) = self.build_stream.as_mut() {
+            match result.as_ref() {
+                Ok(_) => stream.succeeded(product),
+                // products whose dependencies failed were never attempted
+                Err(_) if own_failure => stream.failed(product),
+                Err(_) => (),
+            }
+        }
+        let install_root = PathBuf::from(&self.options.install_root);
+        if let Err(e) = self.profile.save(&install_root) {
+            warn!("Could not save the profile of the run: {}", e);
+        }
+        // only products which were built have a log
+        if let Some(log) = self.product_logs.remove(product) {
+            let error = match result.as_ref() {
+                Err(e) if own_failure => Some(e),
+                _ => None,
+            };
+            self.save_log(product, outcome, &log, error.is_some());
+            self.ship_logs(product, &log, error);
+        } else {
+            // a build which failed before writing anything is finished too
+            log_tail::clear_live(&self.live_log_path(product));
+        }
+    }
+
+    /// The build log of a product, written to while it builds and kept once
+    /// it is finished, outside the versioned directory which may be a link
+    /// into the store shared with other runs
+    fn live_log_path(&self, product: &str) -> PathBuf {
+        log_tail::live_log_path(
+            Path::new(&self.options.install_root),
+            product,
+            &self.options.version,
+        )
+    }
+
+    /// Where the output of a product's build goes as it is written. It is
+    /// streamed to its live build log, which is replaced by the full
+    /// transcript once the build finishes.
+    fn live_output(&self, product: &str) -> Arc<LiveOutput> {
+        Arc::new(LiveOutput::new(
+            product,
+            Some(&self.live_log_path(product)),
+            self.options.stream_output,
+        ))
+    }
+
+    /// Write the log of a product over its live build log, then add it to the
+    /// index of the run. Failing to save is only warned about as the run
+    /// build log remains.
+    fn save_log(&mut self, product: &str, outcome: &str, log: &[u8], failed: bool) {
+        let live = self.live_log_path(product);
+        // the live log is finished before the transcript is moved over it,
+        // so followers stop with what they have rather than replaying it
+        log_tail::clear_live(&live);
+        let staging = live.with_extension("log.tmp");
+        let saved = live
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(&staging, log))
+            .and_then(|_| std::fs::rename(&staging, &live));
+        if let Err(e) = saved {
+            warn!("Could not write the build log of {}: {}", product, e);
+            return;
+        }
+        debug!("Wrote the build log of {} to {}", product, live.display());
+        let _ = writeln!(
+            self.build_index,
+            "{}\t{}\t{}",
+            product,
+            outcome,
+            live.display()
+        );
+        let _ = self.build_index.flush();
+        if failed {
+            self.failure_logs.insert(product.to_string(), live);
+        }
+    }
+
+    /// Upload the log of a product, and a bundle describing its failure if it
+    /// failed, to the log destination. The urls are added to the report,
+    /// failed uploads are only warned about as the run build log remains.
+    fn ship_logs(&mut self, product: &str, log: &[u8], error: Option<&RegenError>) {
+        let destination = match self.options.log_upload.as_ref() {
+            Some(d) => d,
+            None => return,
+        };
+        let template = self
+            .options
+            .run_name_template
+            .as_ref()
+            .map(|t| t.as_str())
+            .unwrap_or(naming::DEFAULT_TEMPLATE);
+        let run = self.run_name.render(template);
+        let mut uploads = vec![(format!("{}/{}.log", run, product), log.to_vec())];
+        if let Some(e) = error {
+            uploads.push((
+                format!("{}/{}-failure.txt", run, product),
+                self.failure_bundle(product, e, log).into_bytes(),
+            ));
+        }
+        for (name, contents) in uploads.iter() {
+            match destination.upload(name, contents) {
+                Ok(url) => {
+                    info!("Uploaded {} to {}", name, url);
+                    self.report
+                        .log_urls
+                        .entry(product.to_string())
+                        .or_insert_with(Vec::new)
+                        .push(url);
+                }
+                Err(e) => warn!("{}", e),
+            }
+        }
+    }
+
+    /// Everything needed to look into a failed build once the machine which
+    /// ran it is gone
+    fn failure_bundle(&self, product: &str, error: &RegenError, log: &[u8]) -> String {
+        let mut out = format!(
+            "Product: {}\nVersion: {}\nError: {}\n",
+            product, self.options.version, error
+        );
+        if let Ok(sha) = self.get_sha_of_head(product) {
+            out.push_str(&format!("Revision: {}\n", sha));
+        }
+        if let Some(versions) = self.report.tool_versions.get(product) {
+            out.push_str("Build tools:\n");
+            for (tool, version) in versions.iter() {
+                out.push_str(&format!("  {}: {}\n", tool, version));
+            }
+        }
+        out.push_str("\nBuild log:\n");
+        out.push_str(&String::from_utf8_lossy(log));
+        out
+    }
+
+    /// Every product needed to build product, dependencies first, along
+    /// with the products each of them needs
+    fn schedule_order(
+        &self,
+        product: &str,
+        order: &mut Vec<String>,
+        dependencies: &mut HashMap<String, Vec<String>>,
+    ) -> Result<(), String> {
+        if dependencies.contains_key(product) {
+            return Ok(());
+        }
+        let names = self.build_dependencies(product)?;
+        dependencies.insert(product.to_string(), names.clone());
+        for name in names.iter().filter(|n| *n != product) {
+            self.schedule_order(name, order, dependencies)?;
+        }
+        order.push(product.to_string());
+        Ok(())
+    }
+
+    /// Install products and their dependencies one at a time, dependencies
+    /// first. A product which fails only stops the products depending on it,
+    /// which are skipped, and the first failure is returned once everything
+    /// else has been installed.
+    fn install_keep_going(&mut self, products: &[String]) -> Result<(), RegenError> {
+        let mut order = vec![];
+        let mut dependencies = HashMap::new();
+        for product in products.iter() {
+            self.schedule_order(product, &mut order, &mut dependencies)?;
+        }
+        // products which failed or were skipped, and the failed product
+        // responsible
+        let mut blocked: HashMap<String, String> = HashMap::new();
+        let mut failure = None;
+        for name in order.iter() {
+            let cause = dependencies
+                .get(name)
+                .into_iter()
+                .flatten()
+                .filter(|d| *d != name)
+                .find_map(|d| blocked.get(d))
+                .cloned();
+            if let Some(cause) = cause {
+                self.skip_product(name, &cause);
+                blocked.insert(name.clone(), cause);
+                continue;
+            }
+            if let Err(e) = self.install_product_impl(name) {
+                blocked.insert(name.clone(), name.clone());
+                if failure.is_none() {
+                    failure = Some(e);
+                }
+            }
+        }
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Record that a product was never attempted because failed, which it
+    /// depends on, could not be installed
+    fn skip_product(&mut self, product: &str, failed: &str) {
+        warn!("Skipping {} as {} could not be installed", product, failed);
+        self.report.record(
+            product,
+            ProductOutcome::Skipped(format!("{} could not be installed", failed)),
+            Duration::from_secs(0),
+            None,
+        );
+        self.progress.emit(ProgressEvent::ProductFinished {
+            product: product.to_string(),
+            outcome: "skipped".to_string(),
+        });
+    }
+
+    /// Install products and their dependencies, building products which do
+    /// not depend on each other at the same time on up to build_workers
+    /// threads. Only the verbs of a build run on a worker, everything
+    /// touching the database or the report happens on this thread.
+    fn install_scheduled(&mut self, products: &[String]) -> Result<(), RegenError> {
+        let mut order = vec![];
+        let mut dependencies = HashMap::new();
+        for product in products.iter() {
+            self.schedule_order(product, &mut order, &mut dependencies)?;
+        }
+        let mut scheduler = Scheduler::new(order, dependencies);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut running: HashMap<String, RunningBuild> = HashMap::new();
+        // when the verb each product is running started
+        let mut verb_clocks: HashMap<String, Instant> = HashMap::new();
+        let mut failure = None;
+        loop {
+            // start everything which is ready, until the workers are busy
+            while (failure.is_none() || self.options.keep_going)
+                && running.len() < self.options.build_workers
+            {
+                let name = match scheduler.next_ready() {
+                    Some(n) => n,
+                    None => break,
+                };
+                if self.build_completed.contains(&name) || self.resume(&name)? {
+                    scheduler.finish(&name);
+                    continue;
+                }
+                let start = Instant::now();
+                let failures_before = self.report.failed();
+                self.product_started(&name);
+                match self.start_build(&name, start, &sender) {
+                    Ok(Some(staged)) => {
+                        running.insert(
+                            name,
+                            RunningBuild {
+                                staged,
+                                start,
+                                failures_before,
+                                outputs: vec![],
+                            },
+                        );
+                    }
+                    Ok(None) => {
+                        self.product_finished(&name, start, failures_before, &Ok(()));
+                        scheduler.finish(&name);
+                    }
+                    Err(e) => {
+                        self.product_finished(&name, start, failures_before, &Err(e.clone()));
+                        self.scheduled_failure(&mut scheduler, &name);
+                        if failure.is_none() {
+                            failure = Some(e);
+                        }
+                    }
+                }
+            }
+            // after a failure, wait for the running builds and stop unless
+            // keeping going
+            if running.is_empty() {
+                break;
+            }
+            let event = receiver
+                .recv()
+                .map_err(|e| format!("Lost contact with the build workers: {}", e))?;
+            match event {
+                WorkerEvent::VerbStarted { product, verb } => {
+                    verb_clocks.insert(product.clone(), Instant::now());
+                    self.progress
+                        .emit(ProgressEvent::VerbStarted { product, verb });
+                }
+                WorkerEvent::VerbFinished {
+                    product,
+                    verb,
+                    output,
+                } => {
+                    if let Some(clock) = verb_clocks.remove(&product) {
+                        self.profile
+                            .record(&product, &format!("verb {}", verb), clock.elapsed());
+                    }
+                    self.progress.emit(ProgressEvent::VerbFinished {
+                        product: product.clone(),
+                        verb: verb.clone(),
+                        success: verb_succeeded(&output),
+                    });
+                    if let Some(build) = running.get_mut(&product) {
+                        build.outputs.push((verb, output));
+                    }
+                }
+                WorkerEvent::Done { product, result } => {
+                    let build = running.remove(&product).ok_or(format!(
+                        "A worker finished {}, which was not building",
+                        product
+                    ))?;
+                    let result = self.finish_scheduled(
+                        &product,
+                        build.staged,
+                        build.outputs,
+                        result,
+                        build.start,
+                    );
+                    self.product_finished(&product, build.start, build.failures_before, &result);
+                    match result {
+                        Ok(_) => scheduler.finish(&product),
+                        Err(e) => {
+                            self.scheduled_failure(&mut scheduler, &product);
+                            if failure.is_none() {
+                                failure = Some(e);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        match failure {
+            Some(e) => Err(e),
+            None if !scheduler.remaining().is_empty() => Err(RegenError::Other(format!(
+                "Could not work out an order to build {} in",
+                scheduler.remaining().join(", ")
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Skip everything waiting on a product which failed, when the run keeps
+    /// going past failures
+    fn scheduled_failure(&mut self, scheduler: &mut Scheduler, product: &str) {
+        if self.options.keep_going {
+            for name in scheduler.fail(product).iter() {
+                self.skip_product(name, product);
+            }
+        }
+    }
+
+    /// Begin installing a product whose dependencies are installed, either
+    /// declaring an existing install or handing its build to a worker. The
+    /// staged build is returned while the worker runs.
+    fn start_build(
+        &mut self,
+        product: &str,
+        start: Instant,
+        events: &std::sync::mpsc::Sender<WorkerEvent>,
+    ) -> Result<Option<StagedBuild>, RegenError> {
+        let (product_id, metadata) = match self.try_reuse(product, start)? {
+            Some(build) => build,
+            None => return Ok(None),
+        };
+        let names = self.build_dependencies(product)?;
+        let staged = self.stage_build(product, &product_id, metadata, &names)?;
+        let steps = self
+            .build_steps(
+                product,
+                staged.backend.as_ref(),
+                &staged.product_dir,
+                &staged.repo_path,
+            )
+            .map_err(|message| RegenError::Build {
+                product: product.to_string(),
+                message,
+            })?;
+        let restage_from = match self.options.restage_on_retry {
+            true => Some(self.source_dir(product)?),
+            false => None,
+        };
+        info!("Building {}", product);
+        debug!("Using environment {:#?} for building", staged.env_vars);
+        scheduler::spawn(
+            BuildJob {
+                product: product.to_string(),
+                live: self.live_output(product),
+                steps,
+                build_path: staged.repo_path.clone(),
+                env: staged
+                    .env_vars
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect(),
+                wrapper: self.command_wrapper(product),
+                retries: self.options.verb_retries.clone(),
+                restage_from,
+                clean_build: self.product_urls.clean_build(product),
+                umask: self.build_umask(),
+                fail_on_tests: self.options.fail_on_tests,
+            },
+            events.clone(),
+        );
+        Ok(Some(staged))
+    }
+
+    /// Complete and declare a product once its worker is done. The output
+    /// of its verbs was held back until now so it is written to the build
+    /// log in one piece rather than interleaved with other builds.
+    fn finish_scheduled(
+        &mut self,
+        product: &str,
+        staged: StagedBuild,
+        outputs: Vec<(String, Result<std::process::Output, String>)>,
+        result: Result<JobSummary, String>,
+        start: Instant,
+    ) -> Result<(), RegenError> {
+        self.write_log(product, format!("Building {}\n", product).as_bytes());
+        for (verb, output) in outputs.iter() {
+            // a failure is reported through the result of the job
+            let _ = self.record_verb(product, verb, output);
+        }
+        let summary = result.map_err(|message| RegenError::Build {
+            product: product.to_string(),
+            message,
+        })?;
+        self.finish_build(
+            product,
+            staged.backend.as_ref(),
+            &staged.product_dir,
+            &summary.build_path,
+            &summary.retries_used,
+        )?;
+        let product_id = staged.product_id.clone();
+        let table = self.complete_build(product, staged, Some(&summary.build_path))?;
+        self.declare_installed(product, &product_id, table, false, start)
+            .map_err(RegenError::from)
+    }
+
+    /// Record the products making up the stacks of products in the build
+    /// manifest, if a build stream is being written
+    fn manifest_entries(&self, products: &[String]) -> Result<Vec<ManifestEntry>, String> {
+        let mut entries: Vec<ManifestEntry> = vec![];
+        for product in products.iter() {
+            for name in self.subtree(product)?.iter() {
+                if entries.iter().any(|e| &e.product == name) {
+                    continue;
+                }
+                entries.push(ManifestEntry {
+                    product: name.clone(),
+                    sha: self.get_sha_of_head(name)?,
+                    version: self.options.version.clone(),
+                    dependencies: self.dependencies.get(name).cloned().unwrap_or_default(),
+                    id: self.make_product_id(name)?,
+                    pin: self.map_pin(name),
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    fn write_build_manifest(&self, products: &[String]) -> Result<(), String> {
+        match self.build_stream.as_ref() {
+            Some(stream) => stream.write_manifest(&self.manifest_entries(products)?),
+            None => Ok(()),
+        }
+    }
+
+    /// Write the manifest of a successful run, if one was asked for, naming
+    /// it with the id of the build stream or else the build id of the run
+    fn write_run_manifest(&self, products: &[String]) -> Result<(), String> {
+        let path = match self.options.run_manifest.as_ref() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let manifest_id = match self.build_stream.as_ref() {
+            Some(stream) => Some(stream.manifest_id().to_string()),
+            None => products
+                .first()
+                .and_then(|p| self.options.identity.build_id(p)),
+        };
+        info!("Writing the manifest of the run to {}", path.display());
+        jenkins::write_run_manifest(
+            path,
+            manifest_id.as_ref().map(|id| id.as_str()),
+            &self.manifest_entries(products)?,
+        )
+    }
+
+    /// The ABI hash of the install of a product with its current id
+    fn installed_abi(&self, product: &str) -> Option<String> {
+        let id = self.make_product_id(product).ok()?;
+        let table = self.db.get_table_from_identity(product, &id)?;
+        match Provenance::read(&table.product_dir) {
+            Ok(provenance) => provenance.abi_hash,
+            Err(_) => abi::abi_hash(&table.product_dir),
+        }
+    }
+
+    /// ABI hashes of the installed direct dependencies of a product
+    fn dependency_abi(&self, product: &str) -> BTreeMap<String, String> {
+        let mut hashes = BTreeMap::new();
+        for dep in self.dependencies.get(product).into_iter().flatten() {
+            if let Some(hash) = self.installed_abi(dep) {
+                hashes.insert(dep.clone(), hash);
+            }
+        }
+        hashes
+    }
+
+    /// Problems with the dependency ABIs of the install of a product in
+    /// product_dir, an install without provenance can not be checked
+    fn abi_mismatches(&self, product: &str, product_dir: &Path) -> Vec<String> {
+        if self.options.abi_check == AbiCheck::Off {
+            return vec![];
+        }
+        match Provenance::read(product_dir) {
+            Ok(provenance) => {
+                abi::mismatches(&provenance.dependency_abi, &self.dependency_abi(product))
+            }
+            Err(_) => vec![],
+        }
+    }
+
+    /// Make sure a product about to be reused was built against the ABI of its
+    /// dependencies as they are installed now, giving back the table if it
+    /// may be reused
+    fn check_abi(
+        &mut self,
+        product: &str,
+        table: reups::table::Table,
+    ) -> Result<Option<reups::table::Table>, String> {
+        let problems = self.abi_mismatches(product, &table.product_dir);
+        if problems.is_empty() {
+            return Ok(Some(table));
+        }
+        let diagnosis = format!(
+            "The install of {} in {} does not match its dependencies: {}",
+            product,
+            table.product_dir.display(),
+            problems.join("; ")
+        );
+        match self.options.abi_check {
+            AbiCheck::Fail => Err(diagnosis),
+            _ => {
+                warn!("{}, rebuilding it", diagnosis);
+                self.report
+                    .recoveries
+                    .push(format!("{}, rebuilt it from source", diagnosis));
+                Ok(None)
+            }
+        }
+    }
+
+    /// Reasons the install of a product in product_dir may not run on this
+    /// host, an install without a recorded host can not be checked
+    fn host_mismatches(&self, product_dir: &Path) -> Vec<String> {
+        if self.options.host_check == HostCheck::Off {
+            return vec![];
+        }
+        match Provenance::read(product_dir).map(|p| p.host) {
+            Ok(Some(built)) => built.incompatibilities(&self.host),
+            _ => vec![],
+        }
+    }
+
+    /// Apply the host check policy to a product about to be reused, giving
+    /// back the table if it may be reused
+    fn check_host(
+        &mut self,
+        product: &str,
+        table: reups::table::Table,
+    ) -> Result<Option<reups::table::Table>, String> {
+        let problems = self.host_mismatches(&table.product_dir);
+        if problems.is_empty() {
+            return Ok(Some(table));
+        }
+        let diagnosis = format!(
+            "The install of {} in {} may not run on this host: {}",
+            product,
+            table.product_dir.display(),
+            problems.join("; ")
+        );
+        match self.options.host_check {
+            HostCheck::Fail => Err(diagnosis),
+            HostCheck::Rebuild => {
+                warn!("{}, rebuilding it", diagnosis);
+                self.report
+                    .recoveries
+                    .push(format!("{}, rebuilt it from source", diagnosis));
+                Ok(None)
+            }
+            _ => {
+                warn!("{}, reusing it anyway", diagnosis);
+                Ok(Some(table))
+            }
+        }
+    }
+
+    /// Make sure the install a product is declared with is still on disk, as
+    /// the database keeps declarations of installs which were deleted and
+    /// building against them fails far from the cause
+    fn check_installed(
+        &mut self,
+        product: &str,
+        table: reups::table::Table,
+    ) -> Result<Option<reups::table::Table>, String> {
+        let mut table_path = table.product_dir.clone();
+        table_path.push("ups");
+        table_path.push(format!("{}.table", product));
+        let missing = if !table.product_dir.is_dir() {
+            table.product_dir.clone()
+        } else if !table_path.is_file() {
+            table_path
+        } else {
+            return Ok(Some(table));
+        };
+        let diagnosis = format!(
+            "{} is declared with an install at {}, but {} does not exist",
+            product,
+            table.product_dir.display(),
+            missing.display()
+        );
+        self.report.dangling.push(diagnosis.clone());
+        match self.options.missing_installs {
+            MissingInstall::Fail => Err(diagnosis),
+            MissingInstall::Rebuild => {
+                warn!("{}, rebuilding it", diagnosis);
+                self.report
+                    .recoveries
+                    .push(format!("{}, rebuilt it from source", diagnosis));
+                Ok(None)
+            }
+        }
+    }
+
+    fn installed_files(&mut self, product_dir: &Path) -> &Vec<String> {
+        self.manifests
+            .entry(product_dir.to_path_buf())
+            .or_insert_with(|| manifest::read(product_dir))
+    }
+
+    /// Look for files installed both by a product and by anything else in the
+    /// stack, its dependencies and everything this run declared before it, as
+    /// which one is found then depends on setup order
+    fn check_file_conflicts(&mut self, product: &str, product_dir: &Path) -> Result<(), String> {
+        let mut others = self.install_set.clone();
+        for dep in self.subtree(product)?.iter() {
+            if others.contains_key(dep) {
+                continue;
+            }
+            let dep_dir = match self
+                .make_product_id(dep)
+                .ok()
+                .and_then(|id| self.db.get_table_from_identity(dep, &id))
+            {
+                Some(table) => table.product_dir,
+                None => continue,
+            };
+            others.insert(dep.clone(), dep_dir);
+        }
+        others.remove(product);
+        let mut manifests = vec![(
+            product.to_string(),
+            product_dir.to_path_buf(),
+            self.installed_files(product_dir).clone(),
+        )];
+        for (other, dir) in others.into_iter() {
+            let files = self.installed_files(&dir).clone();
+            manifests.push((other, dir, files));
+        }
+        let conflicts: Vec<String> = manifest::conflicts(&manifests)
+            .iter()
+            .filter(|c| c.owners.iter().any(|o| o == product))
+            .map(|c| format!("{}", c))
+            .collect();
+        if conflicts.is_empty() {
+            return Ok(());
+        }
+        if self.options.fail_on_file_conflicts {
+            return Err(format!(
+                "{} installs files which other products of the stack also install:\n{}",
+                product,
+                conflicts.join("\n")
+            ));
+        }
+        for conflict in conflicts.iter() {
+            warn!("{}", conflict);
+        }
+        self.report.file_conflicts.extend(conflicts);
+        Ok(())
+    }
+
+    /// Check, before anything is cloned, that the roots can be written and
+    /// the build tool can be found, reporting every problem at once
+    fn check_prerequisites(&self) -> Result<(), String> {
+        let mut roots = vec![
+            (PathBuf::from(&self.options.install_root), "install root"),
+            (PathBuf::from(&self.options.clone_root), "clone root"),
+        ];
+        if let Some(root) = self.options.store_root.as_ref() {
+            roots.push((root.clone(), "store root"));
+        }
+        let mut problems: Vec<String> = roots
+            .iter()
+            .filter_map(|(path, what)| preflight::check_writable(path, what).err())
+            .collect();
+        let tool = &self.options.build_tool;
+        if !tool.is_empty() && toolchain::find_program(tool, &FnvHashMap::default()).is_none() {
+            problems.push(format!(
+                "The build tool {} was not found on PATH, install it or give its path \
+                 with --build-tool",
+                tool
+            ));
+        }
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(format!(
+                "Preflight checks failed:\n  {}",
+                problems.join("\n  ")
+            )),
+        }
+    }
+
+    /// Check that the installs of the products about to be built fit, going
+    /// by how large they were when last built. Products never built before
+    /// are not counted.
+    fn check_estimated_space(&self, products: &[String]) -> Result<(), String> {
+        let mut plan = Plan::default();
+        for product in products.iter() {
+            plan.merge(self.plan_resolved(product)?);
+        }
+        let estimate = plan.estimate(self.options.build_workers);
+        if estimate.unknown > 0 {
+            debug!(
+                "{} products to build have no previous build to estimate their size from",
+                estimate.unknown
+            );
+        }
+        let root = self
+            .options
+            .store_root
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(&self.options.install_root));
+        preflight::check_space(
+            &root,
+            estimate.disk_bytes,
+            self.options.min_free_space.unwrap_or(0),
+        )
+    }
+
+    /// Directories a build writes to, whose free space and inodes are watched
+    fn disk_paths(&self) -> Vec<PathBuf> {
+        let mut paths = vec![
+            PathBuf::from(&self.options.install_root),
+            PathBuf::from(&self.options.clone_root),
+            std::env::temp_dir(),
+        ];
+        if let Some(root) = self.options.store_root.as_ref() {
+            paths.push(root.clone());
+        }
+        paths
+    }
+
+    /// Remove temporary build directories and store entries abandoned by
+    /// interrupted runs, returning the number of bytes freed
+    fn reclaim_disk_space(&self) -> u64 {
+        let mut abandoned = disk_space::stale_dirs(
+            &std::env::temp_dir(),
+            disk_space::TEMP_PREFIX,
+            ABANDONED_AGE,
+        );
+        if let Some(store) = self.store() {
+            match store.abandoned_entries(ABANDONED_AGE) {
+                Ok(entries) => abandoned.extend(entries),
+                Err(e) => warn!("{}", e),
+            }
+        }
+        let mut freed = 0;
+        for dir in abandoned.iter() {
+            let size = clone_backend::dir_size(dir);
+            info!("Removing abandoned build directory {}", dir.display());
+            match std::fs::remove_dir_all(dir) {
+                Ok(_) => freed += size,
+                Err(e) => warn!("Could not remove {}: {}", dir.display(), e),
+            }
+        }
+        freed
+    }
+
+    /// Wait until every directory a build writes to has the configured free
+    /// space and inodes, first removing abandoned build directories. If space
+    /// does not come back in time the run stops before the step described by
+    /// before; everything installed so far is declared, so running again
+    /// picks up from there.
+    fn ensure_disk_space(&mut self, before: &str) -> Result<(), String> {
+        let (min_free, min_inodes) = (self.options.min_free_space, self.options.min_free_inodes);
+        if min_free.is_none() && min_inodes.is_none() {
+            return Ok(());
+        }
+        let paths = self.disk_paths();
+        let low = disk_space::low_space(&paths, min_free, min_inodes);
+        if low.is_empty() {
+            return Ok(());
+        }
+        warn!(
+            "Pausing before {} as {}",
+            before,
+            disk_space::describe(&low)
+        );
+        let paused = Instant::now();
+        let freed = self.reclaim_disk_space();
+        if freed > 0 {
+            info!(
+                "Reclaimed {} from abandoned builds",
+                network::format_bytes(freed)
+            );
+        }
+        loop {
+            let low = disk_space::low_space(&paths, min_free, min_inodes);
+            if low.is_empty() {
+                info!("Disk space is available again, resuming");
+                self.report.recoveries.push(format!(
+                    "Paused for {}s before {} until disk space was available",
+                    paused.elapsed().as_secs(),
+                    before
+                ));
+                return Ok(());
+            }
+            let waited = paused.elapsed();
+            if waited >= self.options.disk_space_wait {
+                return Err(format!(
+                    "Stopping before {} as {}. Products installed so far are \
+                     declared and will be reused when the run is started again",
+                    before,
+                    disk_space::describe(&low)
+                ));
+            }
+            std::thread::sleep(DISK_POLL_INTERVAL.min(self.options.disk_space_wait - waited));
+        }
+    }
+
+    /// The version path below the install root a product installed in the
+    /// store entry product_dir is seen through, None when it is not in the
+    /// store
+    fn store_view(&self, product: &str, product_dir: &Path) -> Result<Option<PathBuf>, String> {
+        let store = match self.store() {
+            Some(store) => store,
+            None => return Ok(None),
+        };
+        let store_root = store
+            .root
+            .canonicalize()
+            .unwrap_or_else(|_| store.root.clone());
+        if !product_dir.starts_with(&store_root) && !product_dir.starts_with(&store.root) {
+            return Ok(None);
+        }
+        let install_root = PathBuf::from(&self.options.install_root);
+        let mut view = install_root.clone();
+        view.push(product);
+        // the view itself is a link out of the install root once linked, so
+        // only its directory is resolved and the version checked as a name
+        safety::ensure_under(&install_root, &view)?;
+        let mut components = Path::new(&self.options.version).components();
+        match (components.next(), components.next()) {
+            (Some(std::path::Component::Normal(_)), None) => (),
+            _ => {
+                return Err(format!(
+                    "The version {} is not a plain directory name",
+                    self.options.version
+                ))
+            }
+        }
+        view.push(&self.options.version);
+        Ok(Some(view))
+    }
+
+    /// What sets a build of product apart in the store besides its id, the
+    /// ABI of its installed dependencies and the host it is built on
+    fn store_variant(&self, product: &str) -> String {
+        let mut hasher = Sha1::new();
+        for (dependency, hash) in self.dependency_abi(product).iter() {
+            hasher.input_str(&format!("{}={}\n", dependency, hash));
+        }
+        hasher.input_str(&format!(
+            "{}\n{:?}\n{:?}\n{:?}\n",
+            self.host.os, self.host.glibc, self.host.cpu, self.host.triple
+        ));
+        hasher.result_str()[..12].to_string()
+    }
+
+    /// The binary cache store entries are pushed to and pulled from, unless
+    /// the run is offline
+    fn binary_cache(&self) -> Option<&LogDestination> {
+        match self.options.offline {
+            true => None,
+            false => self.options.binary_cache.as_ref(),
+        }
+    }
+
+    /// The store used for installs, if this run installs into one
+    fn store(&self) -> Option<Store> {
+        self.options
+            .store_root
+            .as_ref()
+            .map(|root| Store::new(root))
+    }
+
+    /// Look for a finished install of product_id in the store, possibly built
+    /// by another workspace, which can be declared without building again
+    fn store_table(
+        &mut self,
+        product: &str,
+        product_id: &str,
+    ) -> Result<Option<reups::table::Table>, String> {
+        if self.product_urls.install_prefix(product)?.is_some() {
+            return Ok(None);
+        }
+        let store = match self.store() {
+            Some(store) => store,
+            None => return Ok(None),
+        };
+        // prefer a build matching the dependencies and host as they are now,
+        // otherwise the reuse checks decide about whichever there is
+        let mut candidates = store.complete_entries(product_id)?;
+        // failing that, one built elsewhere for the same dependencies and host
+        if let (true, Some(cache)) = (candidates.is_empty(), self.binary_cache()) {
+            let variant = self.store_variant(product);
+            match binary_cache::pull(cache, &store, product_id, &variant) {
+                Ok(true) => candidates = store.complete_entries(product_id)?,
+                Ok(false) => debug!("{} is not in the binary cache", product),
+                Err(e) => warn!("Could not pull {} from the binary cache: {}", product, e),
+            }
+        }
+        let product_dir = match candidates
+            .iter()
+            .find(|dir| {
+                self.abi_mismatches(product, dir).is_empty() && self.host_mismatches(dir).is_empty()
+            })
+            .or_else(|| candidates.first())
+        {
+            Some(dir) => dir.clone(),
+            None => return Ok(None),
+        };
+        let mut table_path = product_dir.clone();
+        table_path.push("ups");
+        table_path.push(format!("{}.table", product));
+        let table =
+            reups::table::Table::from_file(product.to_string(), table_path, product_dir.clone())
+                .map_err(|e| {
+                    format!(
+                        "Could not read the table of {} from the store entry {}: {}",
+                        product,
+                        product_dir.display(),
+                        e
+                    )
+                })?;
+        info!(
+            "Store already has {} with id {} at {}",
+            product,
+            product_id,
+            product_dir.display()
+        );
+        Ok(Some(table))
+    }
+
+    /// Get the table of a product which is to be reused, applying the table
+    /// fallback policy if the database can not provide it. None means the
+    /// product should be built from source instead.
+    fn reused_table(
+        &mut self,
+        product: &str,
+        product_id: &str,
+    ) -> Result<Option<reups::table::Table>, String> {
+        if let Some(table) = self.db.get_table_from_identity(product, product_id) {
+            return Ok(Some(table));
+        }
+        let problem = format!(
+            "Could not read the table of {} with id {} from the database",
+            product, product_id
+        );
+        match self.options.table_fallback {
+            TableFallback::Abort => Err(problem),
+            TableFallback::ReadInstalled => {
+                let install_root = PathBuf::from(&self.options.install_root);
+                let product_dir = self
+                    .product_urls
+                    .install_prefix(product)?
+                    .filter(|dir| {
+                        Provenance::read(dir)
+                            .map(|p| p.id == product_id)
+                            .unwrap_or(false)
+                    })
+                    .or_else(|| Provenance::find_install(&install_root, product, product_id))
+                    .ok_or(format!(
+                        "{}, and no install with that id was found",
+                        problem
+                    ))?;
+                let mut table_path = product_dir.clone();
+                table_path.push("ups");
+                table_path.push(format!("{}.table", product));
+                let table = reups::table::Table::from_file(
+                    product.to_string(),
+                    table_path,
+                    product_dir.clone(),
+                )
+                .map_err(|e| format!("{}, reading it from the install failed: {}", problem, e))?;
+                warn!(
+                    "{}, read it from {} instead",
+                    problem,
+                    product_dir.display()
+                );
+                self.report.recoveries.push(format!(
+                    "{}, read it from {}",
+                    problem,
+                    product_dir.display()
+                ));
+                Ok(Some(table))
+            }
+            TableFallback::Rebuild => {
+                warn!("{}, rebuilding it", problem);
+                self.report
+                    .recoveries
+                    .push(format!("{}, rebuilt it from source", problem));
+                Ok(None)
+            }
+        }
+    }
+
+    /// A finished install of a product with the given id, from the database
+    /// or the store, which can be declared instead of building
+    fn reusable_table(
+        &mut self,
+        product: &str,
+        product_id: &str,
+    ) -> Result<Option<reups::table::Table>, String> {
+        let mut reused_table = match self.db.has_identity(product, product_id) {
+            true => self.reused_table(product, product_id)?,
+            false => None,
+        };
+        if reused_table.is_none() {
+            for id in self.options.identity.accepted_ids(product, product_id) {
+                if self.db.has_identity(product, &id) {
+                    info!("Database has {} with build id {}", product, id);
+                    reused_table = self.reused_table(product, &id)?;
+                    break;
+                }
+            }
+        }
+        if let Some(table) = self.checked_table(product, reused_table)? {
+            return Ok(Some(table));
+        }
+        // the store may hold a build the declared one can be replaced with,
+        // such as one made against the dependencies as they are now
+        let stored = self.store_table(product, product_id)?;
+        self.checked_table(product, stored)
+    }
+
+    /// Apply the checks an install must pass to be reused to table
+    fn checked_table(
+        &mut self,
+        product: &str,
+        table: Option<reups::table::Table>,
+    ) -> Result<Option<reups::table::Table>, String> {
+        match table {
+            Some(table) => match self.check_installed(product, table)? {
+                Some(table) => match self.check_abi(product, table)? {
+                    Some(table) => self.check_host(product, table),
+                    None => Ok(None),
+                },
+                None => Ok(None),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Declare a product from an existing install if there is one, otherwise
+    /// return the id and metadata it is to be built with
+    fn try_reuse(
+        &mut self,
+        product: &str,
+        start: Instant,
+    ) -> Result<Option<(String, ProductMetadata)>, String> {
+        let product_id = self.make_product_id(product)?;
+        let metadata = match self.repo_map.get(product).and_then(|r| r.workdir()) {
+            Some(path) => metadata::harvest(path),
+            None => ProductMetadata::default(),
+        };
+        self.report.record_metadata(product, metadata.clone());
+        if let Some(table) = self.reusable_table(product, &product_id)? {
+            info!(
+                "Database has product {} with id {}, using that for the build",
+                product, &product_id
+            );
+            self.declare_installed(product, &product_id, table, true, start)?;
+            return Ok(None);
+        }
+        info!("Doing a source build for {}", product);
+        Ok(Some((product_id, metadata)))
+    }
+
+    /// Install a product, building its dependencies first. Once they are
+    /// installed start is reset, so the product is timed on its own.
+    fn install_single_product(
+        &mut self,
+        product: &str,
+        start: &mut Instant,
+    ) -> Result<(), RegenError> {
+        let (product_id, metadata) = match self.try_reuse(product, *start)? {
+            Some(build) => build,
+            None => return Ok(()),
+        };
+
+        // record all dependencies into a vector, as it is cheaper to loop through
+        // that than do a dfs iteration multiple times
+        let names = self.build_dependencies(product)?;
+        debug!("Product {} has dependencies {:?}", product, &names);
+
+        // make sure all the dependencies are already installed, making sure
+        // to skip the product currently being installed (ie the last element
+        // in the dfs
+        for name in names.iter() {
+            // this product will be in the dfs graph, so skip it and finish
+            // this function
+            info!("Processing dependency {}", name);
+            if name != product {
+                self.install_product_impl(&name)?;
+            }
+        }
+        *start = Instant::now();
+        let staged = self.stage_build(product, &product_id, metadata, &names)?;
+        self.build_product(
+            product,
+            staged.backend.as_ref(),
+            &staged.product_dir,
+            &staged.repo_path,
+            &staged.env_vars,
+        )?;
+        let table = self.complete_build(product, staged, None)?;
+        self.declare_installed(product, &product_id, table, false, *start)
+            .map_err(RegenError::from)
+    }
+
+    /// Prepare to build a product whose dependencies are all installed:
+    /// create its install directory, stage its source, and set up the
+    /// environment it builds in
+    fn stage_build(
+        &mut self,
+        product: &str,
+        product_id: &str,
+        metadata: ProductMetadata,
+        names: &Vec<String>,
+    ) -> Result<StagedBuild, String> {
+        // everything this product needs is installed, hold off building it
+        // until there is room
+        self.ensure_disk_space(&format!("building {}", product))?;
+        // output is streamed into the live build log, start it afresh and
+        // mark it live for anyone following it
+        let _ = std::fs::remove_file(self.live_log_path(product));
+        log_tail::mark_live(&self.live_log_path(product));
+
+        // determine the product directory to install to, and make sure it is
+        // created. Products with a fixed prefix install there instead of
+        // under the install root
+        let mut store_lock = None;
+        let mut product_dir = match self.product_urls.install_prefix(product)? {
+            Some(prefix) => {
+                warn!(
+                    "{} has a fixed install prefix {}, installing outside of the install root",
+                    product,
+                    prefix.display()
+                );
+                prefix
+            }
+            None => match self.store() {
+                Some(store) => {
+                    let dir = store.entry_path(product_id, &self.store_variant(product));
+                    safety::ensure_under(&store.root, &dir)?;
+                    // another workspace may be building or using the entry
+                    let lock = store.lock(&dir)?;
+                    if Store::is_complete(&dir, product_id) {
+                        return Err(format!(
+                            "Another run finished {} in the store at {} while this one \
+                             was starting, run again to reuse it",
+                            product,
+                            dir.display()
+                        ));
+                    }
+                    // an entry without provenance is left over from a failed
+                    // build, start it over
+                    if dir.exists() {
+                        debug!("Removing incomplete store entry {}", dir.display());
+                        std::fs::remove_dir_all(&dir)
+                            .map_err(|e| format!("Could not remove {}: {}", dir.display(), e))?;
+                    }
+                    store_lock = Some(lock);
+                    dir
+                }
+                None => {
+                    let mut dir = PathBuf::from(&self.options.install_root);
+                    dir.push(product);
+                    dir.push(&self.options.version);
+                    safety::ensure_under(&PathBuf::from(&self.options.install_root), &dir)?;
+                    dir
+                }
+            },
+        };
+
+        debug!(
+            "Creating directory {} for {} installation",
+            product_dir.display(),
+            product
+        );
+
+        match std::fs::create_dir_all(&product_dir) {
+            Ok(_) => (),
+            Err(e) => return Err(format!("{}", e)),
+        }
+        debug!("Done creating");
+
+        product_dir = product_dir
+            .canonicalize()
+            .or_else(|e| return Err(format!("{}", e)))?;
+
+        // get the path to the build directory
+        let repo_path = self
+            .source_dir(product)?
+            .canonicalize()
+            .or_else(|_| return Err(format!("Problem expanding abs path for {}", product)))?;
+        // look if the product should be built in a temporary path
+        let upstream = repo_path.join("upstream");
+        let (repo_path, tmp_dir) = if self.product_urls.clean_build(product) {
+            debug!("Product is a clean build, cleaning the clone in place");
+            clone_backend::clean_tree(&repo_path)?;
+            (repo_path, None)
+        } else if upstream.exists() {
+            debug!("Product is a upstream build, copy to tmp directory");
+            let tmp_dir = TempDir::new(&format!("{}{}", disk_space::TEMP_PREFIX, product))
+                .map_err(|e| {
+                    format!("Could not make a directory to build {} in: {}", product, e)
+                })?;
+            let mut tmp_dir_path = PathBuf::from(tmp_dir.path());
+            copy(&repo_path, &tmp_dir_path, &CopyOptions::new())
+                .map_err(|e| format!("Could not copy {} to build it: {}", product, e))?;
+            tmp_dir_path.push(product);
+            (tmp_dir_path, Some(tmp_dir))
+        } else {
+            (repo_path, None)
+        };
+        // cleaning the clone removes a generated table
+        self.ensure_source_table(product, &repo_path)?;
+        // accumulate the environment varibales
+        let clock = Instant::now();
+        let mut env_vars = self.accumulate_env(product, &repo_path, names)?;
+        if self.options.reproducible {
+            reproducible::normalize_env(&mut env_vars, self.source_date_epoch(product)?);
+        }
+        self.profile.record(product, "env", clock.elapsed());
+        let tool_versions = self.tool_versions(&env_vars);
+        self.report
+            .record_tool_versions(product, tool_versions.clone());
+        let host = self.host_for(&env_vars);
+        // remove and trace that this might have been previously prepaired
+        let mut prep_path = PathBuf::from(&repo_path);
+        prep_path.push("upstream");
+        prep_path.push("prepared");
+        if prep_path.exists() {
+            let _ = std::fs::remove_file(prep_path);
+        }
+        let backend = build_backend::backend_for_name(
+            self.product_urls
+                .build_backend(product)
+                .as_ref()
+                .map(|s| s.as_str()),
+        )?;
+        Ok(StagedBuild {
+            product_id: product_id.to_string(),
+            metadata,
+            product_dir,
+            repo_path,
+            _tmp_dir: tmp_dir,
+            _store_lock: store_lock,
+            env_vars,
+            tool_versions,
+            host,
+            backend,
+            build_start: Instant::now(),
+        })
+    }
+
+    /// Record a finished build, clean its install, and write its manifest
+    /// and provenance, returning the table of the install. build_path is
+    /// where the build last ran, if a retry moved it away from the staged
+    /// source.
+    fn complete_build(
+        &mut self,
+        product: &str,
+        staged: StagedBuild,
+        build_path: Option<&PathBuf>,
+    ) -> Result<reups::table::Table, String> {
+        let StagedBuild {
+            product_id,
+            metadata,
+            product_dir,
+            repo_path,
+            tool_versions,
+            host,
+            build_start,
+            ..
+        } = staged;
+        let build_path = build_path.unwrap_or(&repo_path);
+        self.history.record_build(
+            product,
+            build_start.elapsed(),
+            clone_backend::dir_size(&product_dir),
+        );
+        if let Err(e) = self.history.save() {
+            warn!("Could not save build history: {}", e);
+        }
+        if self.options.collect_compile_commands {
+            let install_root = PathBuf::from(&self.options.install_root);
+            if let Err(e) = compile_db::collect(product, build_path, &install_root) {
+                warn!("{}", e);
+            }
+        }
+        // remove the git folder form product_dir
+        let mut git_path = product_dir.clone();
+        git_path.push(".git");
+        if git_path.exists() {
+            debug!("Removing git directory from installation");
+            match remove(git_path) {
+                Ok(_) => (),
+                Err(e) => return Err(format!("{}", e)),
+            };
+        }
+        let product_pathbuf = PathBuf::from(&product_dir);
+        let mut table_path = product_pathbuf.clone();
+        table_path.push("ups");
+        table_path.push(format!("{}.table", product));
+        // a generated table is written again now the install shows which
+        // directories to put on the environment
+        if let Some(template) = self.product_urls.table_template(product) {
+            if !table_path.exists() || table_gen::is_generated(&table_path) {
+                debug!("Generating the installed table of {}", product);
+                table_gen::write(&table_path, &template, Some(product_dir.as_path()))?;
+            }
+        }
+        let table = match reups::table::Table::from_file(
+            product.to_string(),
+            table_path.clone(),
+            product_pathbuf,
+        ) {
+            Ok(x) => x,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        if self.options.reproducible {
+            reproducible::normalize_install(&product_dir, self.source_date_epoch(product)?)?;
+        }
+        // provenance is written last, as it marks the install complete
+        if let Err(e) = manifest::write(&product_dir) {
+            warn!("Could not record the files of {}: {}", product, e);
+        }
+        let provenance = Provenance {
+            product: product.to_string(),
+            version: self.options.version.clone(),
+            id: product_id,
+            sha: self.get_sha_of_head(product).ok(),
+            build_id: self.options.identity.build_id(product),
+            metadata,
+            tags: self.options.tag.iter().cloned().collect(),
+            abi_hash: abi::abi_hash(&product_dir),
+            dependency_abi: self.dependency_abi(product),
+            tool_versions,
+            extra_env: self
+                .options
+                .extra_env
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            host: Some(host),
+        };
+        if let Err(e) = provenance.write(&product_dir) {
+            warn!("Could not record provenance for {}: {}", product, e);
+        } else if let (Some(cache), Some(store)) = (self.binary_cache(), self.store()) {
+            if product_dir.starts_with(&store.root) {
+                if let Err(e) = binary_cache::push(cache, product, &product_dir) {
+                    warn!("Could not push {} to the binary cache: {}", product, e);
+                }
+            }
+        }
+        Ok(table)
+    }
+
+    /// Declare an installed product to the database, whether it was just
+    /// built or is being reused
+    fn declare_installed(
+        &mut self,
+        product: &str,
+        product_id: &str,
+        table: reups::table::Table,
+        reused: bool,
+        start: Instant,
+    ) -> Result<(), String> {
+        // declare the results to the database
+        // an atomic tag is applied once the whole run is finished
+        let tmp_tag = match self.options.tag.as_ref() {
+            Some(t) if !self.options.atomic_tag => Some(t.as_str()),
+            _ => None,
+        };
+
+        self.check_file_conflicts(product, &table.product_dir)?;
+        self.install_set
+            .insert(product.to_string(), table.product_dir.clone());
+        // an install in the store is declared through the human friendly
+        // version path, pointed at the store entry first, so rolling the
+        // link forward or back changes what is set up
+        let table = match self.store_view(product, &table.product_dir)? {
+            Some(view) => {
+                store::link_view(&view, &table.product_dir)?;
+                let table_path = view.join("ups").join(format!("{}.table", product));
+                reups::table::Table::from_file(product.to_string(), table_path, view.clone())
+                    .map_err(|e| format!("Could not read the table of {}: {}", product, e))?
+            }
+            None => table,
+        };
+        info!("Declaring {}", product);
+        let product_dir = table.product_dir.clone();
+        let declare_product = reups::DeclareInputs {
+            product,
+            prod_dir: &product_dir,
+            version: &self.options.version,
+            tag: tmp_tag,
+            ident: Some(product_id),
+            flavor: Some(reups::SYSTEM_OS),
             table: Some(table),
             relative: false,
         };
-        let res = self.db.declare(vec![declare_product], None);
+        let clock = Instant::now();
+        let res = self.db.declare(vec![declare_product]);
         debug!("The results of declare are{:#?}", res);
+        if let (Some(build_id), false) = (self.options.identity.build_id(product), reused) {
+            // the build id is an identity of its own, declared under a version
+            // of the same name, so the install can be found by either id
+            let table_path = product_dir.join("ups").join(format!("{}.table", product));
+            let declared = reups::table::Table::from_file(
+                product.to_string(),
+                table_path,
+                product_dir.clone(),
+            )
+            .map_err(|e| format!("Could not read the table: {}", e))
+            .and_then(|table| {
+                self.db.declare(vec![reups::DeclareInputs {
+                    product,
+                    prod_dir: &product_dir,
+                    version: &build_id,
+                    tag: None,
+                    ident: Some(&build_id),
+                    flavor: Some(reups::SYSTEM_OS),
+                    table: Some(table),
+                    relative: false,
+                }])
+            });
+            if let Err(e) = declared {
+                warn!(
+                    "Could not declare {} with build id {}: {}",
+                    product, build_id, e
+                );
+            }
+        }
+        self.profile.record(product, "declare", clock.elapsed());
         // add this product to the build completed set, so that when
         // multiple packages depend on this package it will not be
         // built twice
         self.build_completed.insert(product.to_string());
+        let sha = self.get_sha_of_head(product).unwrap_or_default();
+        if let Err(e) = self.run_state.complete(product, product_id, &sha) {
+            warn!("Could not save the run state: {}", e);
+        }
+        let outcome = match reused {
+            true => ProductOutcome::Reused,
+            false => ProductOutcome::Built,
+        };
+        self.report.record(product, outcome, start.elapsed(), None);
         Ok(())
     }
 }
@@ -0,0 +1,186 @@
+use crate::safety;
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+/// One product of a release manifest
+#[derive(Clone, Debug)]
+pub struct ManifestProduct {
+    pub product: String,
+    pub sha: String,
+    /// Version the product was declared with in the release
+    pub version: String,
+    /// Products this one depends on directly, all listed before it
+    pub dependencies: Vec<String>,
+}
+
+/// A fully resolved stack in the format of lsst_build, listing each product
+/// with the sha to build, its version, and what it depends on, dependencies
+/// first. Each line is `PRODUCT SHA VERSION [DEPENDENCY,DEPENDENCY...]`,
+/// blank lines and # comments are skipped, and KEY=VALUE lines are settings
+/// such as the BUILD= line naming the release build.
+#[derive(Clone, Debug, Default)]
+pub struct ReleaseManifest {
+    /// The build id given by the BUILD= line, if there is one
+    pub build: Option<String>,
+    pub products: Vec<ManifestProduct>,
+}
+
+fn validate_sha(sha: &str, number: usize) -> Result<(), String> {
+    match !sha.is_empty() && sha.chars().all(|c| c.is_ascii_hexdigit()) {
+        true => Ok(()),
+        false => Err(format!(
+            "{} is not a sha, on line {} of the manifest",
+            sha,
+            number + 1
+        )),
+    }
+}
+
+/// A line of an lsst_build manifest which is not blank or a comment
+enum ManifestLine<'a> {
+    /// A KEY=VALUE setting, such as BUILD=b1234
+    Setting(&'a str, &'a str),
+    Product(ManifestProduct),
+}
+
+/// Parse line number (counting from 0) of an lsst_build manifest, None if it
+/// is blank or a comment. Both manifest readers go through here, so they
+/// agree on what a manifest is.
+fn parse_line(line: &str, number: usize) -> Result<Option<ManifestLine>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let (product, sha, version, dependencies) = match fields.as_slice() {
+        [setting] if setting.contains('=') => {
+            let mut parts = setting.splitn(2, '=');
+            let key = parts.next().unwrap_or_default();
+            let value = parts.next().unwrap_or_default();
+            return Ok(Some(ManifestLine::Setting(key, value)));
+        }
+        [product, sha, version] => (product, sha, version, vec![]),
+        [product, sha, version, deps] => (
+            product,
+            sha,
+            version,
+            deps.split(',')
+                .filter(|d| !d.is_empty())
+                .map(|d| d.to_string())
+                .collect(),
+        ),
+        _ => {
+            return Err(format!(
+                "Line {} of the manifest is not PRODUCT SHA VERSION [DEPENDENCIES]",
+                number + 1
+            ))
+        }
+    };
+    safety::validate_product_name(product)?;
+    validate_sha(sha, number)?;
+    Ok(Some(ManifestLine::Product(ManifestProduct {
+        product: product.to_string(),
+        sha: sha.to_lowercase(),
+        version: version.to_string(),
+        dependencies,
+    })))
+}
+
+/// Read the sha of each product from an lsst_build manifest. The versions
+/// and dependencies are not needed to check products out.
+pub fn load_pins(path: &Path) -> Result<BTreeMap<String, String>, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    let mut pins = BTreeMap::new();
+    for (number, line) in text.lines().enumerate() {
+        let product =
+            match parse_line(line, number).map_err(|e| format!("{}: {}", path.display(), e))? {
+                Some(ManifestLine::Product(product)) => product,
+                _ => continue,
+            };
+        if pins.insert(product.product.clone(), product.sha).is_some() {
+            return Err(format!(
+                "{} is listed twice in {}",
+                product.product,
+                path.display()
+            ));
+        }
+    }
+    Ok(pins)
+}
+
+impl ReleaseManifest {
+    pub fn parse(text: &str) -> Result<ReleaseManifest, String> {
+        let mut manifest = ReleaseManifest::default();
+        let mut seen = HashSet::new();
+        for (number, line) in text.lines().enumerate() {
+            let product = match parse_line(line, number)? {
+                Some(ManifestLine::Product(product)) => product,
+                Some(ManifestLine::Setting("BUILD", build)) => {
+                    manifest.build = Some(build.to_string());
+                    continue;
+                }
+                _ => continue,
+            };
+            if let Some(dep) = product.dependencies.iter().find(|d| !seen.contains(*d)) {
+                return Err(format!(
+                    "{} depends on {}, which is not listed before it in the manifest",
+                    product.product, dep
+                ));
+            }
+            if !seen.insert(product.product.clone()) {
+                return Err(format!(
+                    "{} is listed twice in the manifest",
+                    product.product
+                ));
+            }
+            manifest.products.push(product);
+        }
+        if manifest.products.is_empty() {
+            return Err("The manifest lists no products".to_string());
+        }
+        Ok(manifest)
+    }
+
+    pub fn load(path: &Path) -> Result<ReleaseManifest, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        ReleaseManifest::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// The last product listed, taken to be the top of the stack
+    pub fn top(&self) -> &str {
+        self.products
+            .last()
+            .map(|p| p.product.as_str())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &str = "\
+# lsst_build manifest
+BUILD=b1234
+# product                      SHA1                                     Version                  Deps
+base                           0123456789abcdef0123456789abcdef01234567 19.0.0                   
+sconsUtils                     89abcdef0123456789abcdef0123456789abcdef 19.0.0+1                 base
+";
+
+    #[test]
+    fn manifests_are_read_in_the_lsst_build_format() {
+        let manifest = ReleaseManifest::parse(MANIFEST).unwrap();
+        assert_eq!(manifest.build.as_ref().map(|b| b.as_str()), Some("b1234"));
+        assert_eq!(manifest.products.len(), 2);
+        assert_eq!(manifest.products[1].version, "19.0.0+1");
+        assert_eq!(manifest.products[1].dependencies, vec!["base".to_string()]);
+        assert_eq!(manifest.top(), "sconsUtils");
+    }
+
+    #[test]
+    fn lines_without_a_version_are_rejected() {
+        assert!(ReleaseManifest::parse("base 0123456789abcdef\n").is_err());
+    }
+}
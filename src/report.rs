@@ -0,0 +1,702 @@
+use crate::metadata::ProductMetadata;
+use crate::naming::RunName;
+use crate::network::{self, NetworkUsage};
+use crate::table_lint::LintIssue;
+use lettre::smtp::authentication::Credentials;
+use lettre::{ClientSecurity, ClientTlsParameters, SmtpClient, Transport};
+use lettre_email::EmailBuilder;
+use log::debug;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Lines of build output kept with a failure in the report
+pub const EXCERPT_LINES: usize = 40;
+
+/// What happened to a product during a run
+#[derive(Clone, Debug)]
+pub enum ProductOutcome {
+    /// Built from source
+    Built,
+    /// An existing install with a matching id was reused
+    Reused,
+    /// The product could not be installed, with the reason why
+    Failed(String),
+    /// The product was never attempted as something it depends on failed,
+    /// with the reason why
+    Skipped(String),
+}
+
+impl ProductOutcome {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProductOutcome::Built => "built",
+            ProductOutcome::Reused => "reused",
+            ProductOutcome::Failed(_) => "failed",
+            ProductOutcome::Skipped(_) => "skipped",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ProductRecord {
+    pub product: String,
+    pub outcome: ProductOutcome,
+    pub duration: Duration,
+    /// The tail of any output relevant to a failure
+    pub log_excerpt: Option<String>,
+}
+
+/// A summary of everything that happened in one run
+#[derive(Clone, Debug, Default)]
+pub struct RunReport {
+    pub records: Vec<ProductRecord>,
+    /// Metadata harvested from the source of each product
+    pub metadata: BTreeMap<String, ProductMetadata>,
+    /// Problems found in table files while building the graph
+    pub table_lints: Vec<LintIssue>,
+    /// Bytes downloaded over the course of the run
+    pub network: NetworkUsage,
+    /// Problems which were worked around rather than failing the run
+    pub recoveries: Vec<String>,
+    /// Products held at a fixed revision, and the revision
+    pub held: BTreeMap<String, String>,
+    /// Classified lines of build output, by product then classifier
+    pub output_counts: BTreeMap<String, BTreeMap<String, u64>>,
+    /// Paths installed by more than one product of the stack
+    pub file_conflicts: Vec<String>,
+    /// Versions of the build tools each built product was built with
+    pub tool_versions: BTreeMap<String, BTreeMap<String, String>>,
+    /// Where the logs of each product were uploaded to
+    pub log_urls: BTreeMap<String, Vec<String>>,
+    /// Deprecated products still required by tables, with what replaced
+    /// each and the products whose tables require it
+    pub deprecated: BTreeMap<String, (String, BTreeSet<String>)>,
+    /// Git urls more than one product is cloned from, with the products
+    pub shared_urls: BTreeMap<String, BTreeSet<String>>,
+    /// Declarations in the database whose install is no longer on disk
+    pub dangling: Vec<String>,
+    /// Whether the tests of each product whose tests were run passed
+    pub tests: BTreeMap<String, bool>,
+}
+
+#[derive(Clone, Debug)]
+pub enum ReportFormat {
+    Markdown,
+    Html,
+}
+
+/// How the connection to an smtp server is secured
+#[derive(Clone, Debug)]
+pub enum SmtpSecurity {
+    /// No encryption, for site local relays
+    Plain,
+    /// Upgrade the connection with STARTTLS, failing if the server can not
+    StartTls,
+    /// Connect over tls from the start, as on port 465
+    Tls,
+}
+
+impl SmtpSecurity {
+    /// The port servers usually accept this kind of connection on
+    pub fn default_port(&self) -> u16 {
+        match self {
+            SmtpSecurity::Plain => 25,
+            SmtpSecurity::StartTls => 587,
+            SmtpSecurity::Tls => 465,
+        }
+    }
+}
+
+/// Settings for sending a report through an smtp server
+#[derive(Clone, Debug)]
+pub struct EmailSettings {
+    pub server: String,
+    pub port: u16,
+    pub security: SmtpSecurity,
+    /// User and password to authenticate with, if the server requires it
+    pub credentials: Option<(String, String)>,
+    pub from: String,
+    pub to: Vec<String>,
+    pub subject: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct ReportOptions {
+    pub format: ReportFormat,
+    /// Write the rendered report to this file, e.g. for ci artifact upload.
+    /// If this is a directory the report is written into it, named after
+    /// the run.
+    pub path: Option<PathBuf>,
+    pub email: Option<EmailSettings>,
+}
+
+fn format_duration(duration: &Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}h{:02}m{:02}s", secs / 3600, (secs / 60) % 60, secs % 60)
+}
+
+fn format_counts(counts: &BTreeMap<String, u64>) -> String {
+    counts
+        .iter()
+        .map(|(name, count)| format!("{} {}", count, name))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The last lines of a build log, to show with a failure
+pub fn log_excerpt(log: &[u8], lines: usize) -> Option<String> {
+    let text = String::from_utf8_lossy(log);
+    let all: Vec<&str> = text.lines().collect();
+    match all.is_empty() {
+        true => None,
+        false => Some(all[all.len().saturating_sub(lines)..].join("\n")),
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+impl RunReport {
+    pub fn new() -> RunReport {
+        RunReport::default()
+    }
+
+    pub fn record(
+        &mut self,
+        product: &str,
+        outcome: ProductOutcome,
+        duration: Duration,
+        log_excerpt: Option<String>,
+    ) {
+        self.records.push(ProductRecord {
+            product: product.to_string(),
+            outcome,
+            duration,
+            log_excerpt,
+        });
+    }
+
+    pub fn record_metadata(&mut self, product: &str, metadata: ProductMetadata) {
+        self.metadata.insert(product.to_string(), metadata);
+    }
+
+    pub fn record_tool_versions(&mut self, product: &str, versions: BTreeMap<String, String>) {
+        self.tool_versions.insert(product.to_string(), versions);
+    }
+
+    /// Record that the table of referenced_by requires product, which has
+    /// been replaced by replacement
+    pub fn record_deprecated(&mut self, product: &str, replacement: &str, referenced_by: &str) {
+        self.deprecated
+            .entry(product.to_string())
+            .or_insert_with(|| (replacement.to_string(), BTreeSet::new()))
+            .1
+            .insert(referenced_by.to_string());
+    }
+
+    pub fn record_test(&mut self, product: &str, passed: bool) {
+        self.tests.insert(product.to_string(), passed);
+    }
+
+    /// Note that product was found to be cloned from the same url as other
+    pub fn record_shared_url(&mut self, url: &str, other: &str, product: &str) {
+        let products = self
+            .shared_urls
+            .entry(url.to_string())
+            .or_insert_with(BTreeSet::new);
+        products.insert(other.to_string());
+        products.insert(product.to_string());
+    }
+
+    /// Each tool with the versions it was seen at, and the products built
+    /// with each version
+    pub fn tool_summary(&self) -> BTreeMap<String, BTreeMap<String, Vec<String>>> {
+        let mut summary: BTreeMap<String, BTreeMap<String, Vec<String>>> = BTreeMap::new();
+        for (product, versions) in self.tool_versions.iter() {
+            for (tool, version) in versions.iter() {
+                summary
+                    .entry(tool.clone())
+                    .or_insert_with(BTreeMap::new)
+                    .entry(version.clone())
+                    .or_insert_with(Vec::new)
+                    .push(product.clone());
+            }
+        }
+        summary
+    }
+
+    /// Products grouped by the license they are distributed under
+    pub fn license_summary(&self) -> BTreeMap<String, Vec<String>> {
+        let mut summary: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for (product, metadata) in self.metadata.iter() {
+            let license = metadata.license.clone().unwrap_or_else(|| {
+                match metadata.license_files.is_empty() {
+                    true => "no license file".to_string(),
+                    false => "unrecognized".to_string(),
+                }
+            });
+            summary
+                .entry(license)
+                .or_insert_with(Vec::new)
+                .push(product.clone());
+        }
+        summary
+    }
+
+    fn count<F: Fn(&ProductOutcome) -> bool>(&self, predicate: F) -> usize {
+        self.records
+            .iter()
+            .filter(|r| predicate(&r.outcome))
+            .count()
+    }
+
+    pub fn built(&self) -> usize {
+        self.count(|o| match o {
+            ProductOutcome::Built => true,
+            _ => false,
+        })
+    }
+
+    pub fn reused(&self) -> usize {
+        self.count(|o| match o {
+            ProductOutcome::Reused => true,
+            _ => false,
+        })
+    }
+
+    pub fn failed(&self) -> usize {
+        self.count(|o| match o {
+            ProductOutcome::Failed(_) => true,
+            _ => false,
+        })
+    }
+
+    pub fn skipped(&self) -> usize {
+        self.count(|o| match o {
+            ProductOutcome::Skipped(_) => true,
+            _ => false,
+        })
+    }
+
+    /// The products of the run grouped by whether they succeeded, failed, or
+    /// were skipped because a dependency failed, one group per line
+    pub fn render_outcomes(&self) -> String {
+        let mut groups: [(&str, Vec<&str>); 3] = [
+            ("Succeeded", vec![]),
+            ("Failed", vec![]),
+            ("Skipped", vec![]),
+        ];
+        for record in self.records.iter() {
+            let group = match record.outcome {
+                ProductOutcome::Built | ProductOutcome::Reused => 0,
+                ProductOutcome::Failed(_) => 1,
+                ProductOutcome::Skipped(_) => 2,
+            };
+            groups[group].1.push(&record.product);
+        }
+        groups
+            .iter()
+            .map(|(name, products)| match products.is_empty() {
+                true => format!("{}: none\n", name),
+                false => format!("{} ({}): {}\n", name, products.len(), products.join(", ")),
+            })
+            .collect()
+    }
+
+    /// Fraction of installed products which were reused rather than built
+    pub fn cache_hit_rate(&self) -> f64 {
+        let total = self.built() + self.reused();
+        match total {
+            0 => 0.0,
+            _ => self.reused() as f64 / total as f64,
+        }
+    }
+
+    fn network_summary(&self) -> String {
+        format!(
+            "{} (git {}, http {})",
+            network::format_bytes(self.network.total()),
+            network::format_bytes(self.network.git_bytes),
+            network::format_bytes(self.network.http_bytes)
+        )
+    }
+
+    pub fn render(&self, format: &ReportFormat) -> String {
+        match format {
+            ReportFormat::Markdown => self.render_markdown(),
+            ReportFormat::Html => self.render_html(),
+        }
+    }
+
+    pub fn render_markdown(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# Regenerate run report\n\n");
+        out.push_str(&format!(
+            "Built: {}, reused: {}, failed: {}, skipped: {}, cache hit rate: {:.1}%\n\n",
+            self.built(),
+            self.reused(),
+            self.failed(),
+            self.skipped(),
+            self.cache_hit_rate() * 100.0
+        ));
+        out.push_str(&format!("Downloaded: {}\n\n", self.network_summary()));
+        out.push_str("| Product | Outcome | Duration |\n|---|---|---|\n");
+        for record in self.records.iter() {
+            let outcome = record.outcome.name();
+            out.push_str(&format!(
+                "| {} | {} | {} |\n",
+                record.product,
+                outcome,
+                format_duration(&record.duration)
+            ));
+        }
+        for record in self.records.iter() {
+            if let ProductOutcome::Failed(reason) = &record.outcome {
+                out.push_str(&format!("\n## {} failed\n\n{}\n", record.product, reason));
+                if let Some(excerpt) = record.log_excerpt.as_ref() {
+                    out.push_str(&format!("\n```\n{}\n```\n", excerpt));
+                }
+            }
+        }
+        if !self.output_counts.is_empty() {
+            out.push_str("\n## Build output\n\n");
+            for (product, counts) in self.output_counts.iter() {
+                out.push_str(&format!("* {}: {}\n", product, format_counts(counts)));
+            }
+        }
+        if !self.held.is_empty() {
+            out.push_str("\n## Held products\n\n");
+            for (product, pin) in self.held.iter() {
+                out.push_str(&format!("* {} held at {}\n", product, pin));
+            }
+        }
+        if !self.file_conflicts.is_empty() {
+            out.push_str("\n## File conflicts\n\n");
+            for conflict in self.file_conflicts.iter() {
+                out.push_str(&format!("* {}\n", conflict));
+            }
+        }
+        if !self.tests.is_empty() {
+            out.push_str("\n## Tests\n\n");
+            for (product, passed) in self.tests.iter() {
+                let result = if *passed { "passed" } else { "failed" };
+                out.push_str(&format!("* {} {}\n", product, result));
+            }
+        }
+        if !self.dangling.is_empty() {
+            out.push_str("\n## Dangling declarations\n\n");
+            for declaration in self.dangling.iter() {
+                out.push_str(&format!("* {}\n", declaration));
+            }
+        }
+        if !self.recoveries.is_empty() {
+            out.push_str("\n## Recoveries\n\n");
+            for recovery in self.recoveries.iter() {
+                out.push_str(&format!("* {}\n", recovery));
+            }
+        }
+        if !self.table_lints.is_empty() {
+            out.push_str("\n## Table issues\n\n");
+            for issue in self.table_lints.iter() {
+                out.push_str(&format!("* {}\n", issue));
+            }
+        }
+        if !self.deprecated.is_empty() {
+            out.push_str("\n## Deprecated products\n\n");
+            for (product, (replacement, users)) in self.deprecated.iter() {
+                out.push_str(&format!(
+                    "* {} is replaced by {}, still required by {}\n",
+                    product,
+                    replacement,
+                    users.iter().cloned().collect::<Vec<_>>().join(", ")
+                ));
+            }
+        }
+        if !self.shared_urls.is_empty() {
+            out.push_str("\n## Shared repositories\n\n");
+            for (url, products) in self.shared_urls.iter() {
+                out.push_str(&format!(
+                    "* {} is cloned from {}\n",
+                    products.iter().cloned().collect::<Vec<_>>().join(", "),
+                    url
+                ));
+            }
+        }
+        let tools = self.tool_summary();
+        if !tools.is_empty() {
+            out.push_str("\n## Build tools\n\n");
+            for (tool, versions) in tools.iter() {
+                // only name the products when they did not all agree
+                for (version, products) in versions.iter() {
+                    match versions.len() {
+                        1 => out.push_str(&format!("* {}: {}\n", tool, version)),
+                        _ => out.push_str(&format!(
+                            "* {}: {} ({})\n",
+                            tool,
+                            version,
+                            products.join(", ")
+                        )),
+                    }
+                }
+            }
+        }
+        if !self.log_urls.is_empty() {
+            out.push_str("\n## Logs\n\n");
+            for (product, urls) in self.log_urls.iter() {
+                out.push_str(&format!("* {}: {}\n", product, urls.join(", ")));
+            }
+        }
+        let licenses = self.license_summary();
+        if !licenses.is_empty() {
+            out.push_str("\n## Licenses\n\n");
+            for (license, products) in licenses.iter() {
+                out.push_str(&format!("* {}: {}\n", license, products.join(", ")));
+            }
+        }
+        out
+    }
+
+    pub fn render_html(&self) -> String {
+        let mut out = String::new();
+        out.push_str("<html><body>\n<h1>Regenerate run report</h1>\n");
+        out.push_str(&format!(
+            "<p>Built: {}, reused: {}, failed: {}, skipped: {}, cache hit rate: {:.1}%</p>\n",
+            self.built(),
+            self.reused(),
+            self.failed(),
+            self.skipped(),
+            self.cache_hit_rate() * 100.0
+        ));
+        out.push_str(&format!("<p>Downloaded: {}</p>\n", self.network_summary()));
+        out.push_str("<table>\n<tr><th>Product</th><th>Outcome</th><th>Duration</th></tr>\n");
+        for record in self.records.iter() {
+            let outcome = record.outcome.name();
+            out.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                escape_html(&record.product),
+                outcome,
+                format_duration(&record.duration)
+            ));
+        }
+        out.push_str("</table>\n");
+        for record in self.records.iter() {
+            if let ProductOutcome::Failed(reason) = &record.outcome {
+                out.push_str(&format!(
+                    "<h2>{} failed</h2>\n<p>{}</p>\n",
+                    escape_html(&record.product),
+                    escape_html(reason)
+                ));
+                if let Some(excerpt) = record.log_excerpt.as_ref() {
+                    out.push_str(&format!("<pre>{}</pre>\n", escape_html(excerpt)));
+                }
+            }
+        }
+        if !self.output_counts.is_empty() {
+            out.push_str("<h2>Build output</h2>\n<ul>\n");
+            for (product, counts) in self.output_counts.iter() {
+                out.push_str(&format!(
+                    "<li>{}: {}</li>\n",
+                    escape_html(product),
+                    escape_html(&format_counts(counts))
+                ));
+            }
+            out.push_str("</ul>\n");
+        }
+        if !self.held.is_empty() {
+            out.push_str("<h2>Held products</h2>\n<ul>\n");
+            for (product, pin) in self.held.iter() {
+                out.push_str(&format!(
+                    "<li>{} held at {}</li>\n",
+                    escape_html(product),
+                    escape_html(pin)
+                ));
+            }
+            out.push_str("</ul>\n");
+        }
+        if !self.file_conflicts.is_empty() {
+            out.push_str("<h2>File conflicts</h2>\n<ul>\n");
+            for conflict in self.file_conflicts.iter() {
+                out.push_str(&format!("<li>{}</li>\n", escape_html(conflict)));
+            }
+            out.push_str("</ul>\n");
+        }
+        if !self.tests.is_empty() {
+            out.push_str("<h2>Tests</h2>\n<ul>\n");
+            for (product, passed) in self.tests.iter() {
+                let result = if *passed { "passed" } else { "failed" };
+                out.push_str(&format!("<li>{} {}</li>\n", escape_html(product), result));
+            }
+            out.push_str("</ul>\n");
+        }
+        if !self.dangling.is_empty() {
+            out.push_str("<h2>Dangling declarations</h2>\n<ul>\n");
+            for declaration in self.dangling.iter() {
+                out.push_str(&format!("<li>{}</li>\n", escape_html(declaration)));
+            }
+            out.push_str("</ul>\n");
+        }
+        if !self.recoveries.is_empty() {
+            out.push_str("<h2>Recoveries</h2>\n<ul>\n");
+            for recovery in self.recoveries.iter() {
+                out.push_str(&format!("<li>{}</li>\n", escape_html(recovery)));
+            }
+            out.push_str("</ul>\n");
+        }
+        if !self.table_lints.is_empty() {
+            out.push_str("<h2>Table issues</h2>\n<ul>\n");
+            for issue in self.table_lints.iter() {
+                out.push_str(&format!(
+                    "<li>{}</li>\n",
+                    escape_html(&format!("{}", issue))
+                ));
+            }
+            out.push_str("</ul>\n");
+        }
+        if !self.deprecated.is_empty() {
+            out.push_str("<h2>Deprecated products</h2>\n<ul>\n");
+            for (product, (replacement, users)) in self.deprecated.iter() {
+                let line = format!(
+                    "{} is replaced by {}, still required by {}",
+                    product,
+                    replacement,
+                    users.iter().cloned().collect::<Vec<_>>().join(", ")
+                );
+                out.push_str(&format!("<li>{}</li>\n", escape_html(&line)));
+            }
+            out.push_str("</ul>\n");
+        }
+        if !self.shared_urls.is_empty() {
+            out.push_str("<h2>Shared repositories</h2>\n<ul>\n");
+            for (url, products) in self.shared_urls.iter() {
+                let line = format!(
+                    "{} is cloned from {}",
+                    products.iter().cloned().collect::<Vec<_>>().join(", "),
+                    url
+                );
+                out.push_str(&format!("<li>{}</li>\n", escape_html(&line)));
+            }
+            out.push_str("</ul>\n");
+        }
+        let tools = self.tool_summary();
+        if !tools.is_empty() {
+            out.push_str("<h2>Build tools</h2>\n<ul>\n");
+            for (tool, versions) in tools.iter() {
+                for (version, products) in versions.iter() {
+                    let line = match versions.len() {
+                        1 => format!("{}: {}", tool, version),
+                        _ => format!("{}: {} ({})", tool, version, products.join(", ")),
+                    };
+                    out.push_str(&format!("<li>{}</li>\n", escape_html(&line)));
+                }
+            }
+            out.push_str("</ul>\n");
+        }
+        if !self.log_urls.is_empty() {
+            out.push_str("<h2>Logs</h2>\n<ul>\n");
+            for (product, urls) in self.log_urls.iter() {
+                let links = urls
+                    .iter()
+                    .map(|url| format!("<a href=\"{0}\">{0}</a>", escape_html(url)))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                out.push_str(&format!("<li>{}: {}</li>\n", escape_html(product), links));
+            }
+            out.push_str("</ul>\n");
+        }
+        let licenses = self.license_summary();
+        if !licenses.is_empty() {
+            out.push_str("<h2>Licenses</h2>\n<ul>\n");
+            for (license, products) in licenses.iter() {
+                out.push_str(&format!(
+                    "<li>{}: {}</li>\n",
+                    escape_html(license),
+                    escape_html(&products.join(", "))
+                ));
+            }
+            out.push_str("</ul>\n");
+        }
+        out.push_str("</body></html>\n");
+        out
+    }
+
+    /// Render the report and deliver it to every destination in the options
+    pub fn publish(
+        &self,
+        options: &ReportOptions,
+        run_name: &RunName,
+        template: &str,
+    ) -> Result<(), String> {
+        let rendered = self.render(&options.format);
+        if let Some(path) = options.path.as_ref() {
+            let mut path = path.clone();
+            if path.is_dir() {
+                let extension = match options.format {
+                    ReportFormat::Markdown => "md",
+                    ReportFormat::Html => "html",
+                };
+                path.push(run_name.artifact(template, "report", extension));
+            }
+            debug!("Writing run report to {}", path.display());
+            std::fs::write(&path, &rendered)
+                .map_err(|e| format!("Could not write report to {}: {}", path.display(), e))?;
+        }
+        if let Some(email) = options.email.as_ref() {
+            send_email(email, &options.format, &rendered)?;
+        }
+        Ok(())
+    }
+}
+
+fn tls_parameters(server: &str) -> Result<ClientTlsParameters, String> {
+    let connector = native_tls::TlsConnector::new()
+        .map_err(|e| format!("Could not set up tls to {}: {}", server, e))?;
+    Ok(ClientTlsParameters::new(server.to_string(), connector))
+}
+
+fn send_email(settings: &EmailSettings, format: &ReportFormat, body: &str) -> Result<(), String> {
+    debug!(
+        "Sending run report through {}:{}",
+        settings.server, settings.port
+    );
+    let mut builder = EmailBuilder::new()
+        .from(settings.from.clone())
+        .subject(settings.subject.clone());
+    for to in settings.to.iter() {
+        builder = builder.to(to.clone());
+    }
+    let email = match format {
+        ReportFormat::Markdown => builder.text(body),
+        ReportFormat::Html => builder.html(body),
+    }
+    .build()
+    .map_err(|e| format!("Could not write the report email: {}", e))?;
+    let security = match settings.security {
+        SmtpSecurity::Plain => ClientSecurity::None,
+        SmtpSecurity::StartTls => ClientSecurity::Required(tls_parameters(&settings.server)?),
+        SmtpSecurity::Tls => ClientSecurity::Wrapper(tls_parameters(&settings.server)?),
+    };
+    let mut client =
+        SmtpClient::new((settings.server.as_str(), settings.port), security).map_err(|e| {
+            format!(
+                "Could not connect to smtp server {}: {}",
+                settings.server, e
+            )
+        })?;
+    if let Some((user, password)) = settings.credentials.as_ref() {
+        client = client.credentials(Credentials::new(user.clone(), password.clone()));
+    }
+    client
+        .transport()
+        .send(email.into())
+        .map(|_| ())
+        .map_err(|e| {
+            format!(
+                "Could not send the report through {}: {}",
+                settings.server, e
+            )
+        })
+}
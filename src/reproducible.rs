@@ -0,0 +1,120 @@
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use filetime::FileTime;
+use fnv::FnvHashMap;
+use std::collections::BTreeMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+/// The umask builds run under, so installed files get the same permissions
+/// whoever runs the build
+pub const UMASK: u32 = 0o022;
+
+/// Entries of an install which are written by regenerate itself rather than
+/// by the build, and so differ between builds of the same source
+const OWN_ENTRIES: [&str; 2] = [".regenerate", "build.log"];
+
+/// Pin the variables which commonly leak into build outputs: the time tools
+/// stamp into files, the locale of messages and sorting, the timezone, and
+/// python hash randomization
+pub fn normalize_env(env_vars: &mut FnvHashMap<String, String>, source_date_epoch: i64) {
+    let pinned = [
+        ("SOURCE_DATE_EPOCH", source_date_epoch.to_string()),
+        ("LC_ALL", "C".to_string()),
+        ("LANG", "C".to_string()),
+        ("TZ", "UTC".to_string()),
+        ("PYTHONHASHSEED", "0".to_string()),
+    ];
+    for (name, value) in pinned.iter() {
+        env_vars.insert(name.to_string(), value.clone());
+    }
+}
+
+/// Set the modification and access times of every file of an install to
+/// source_date_epoch, as archives and caches of it would otherwise differ
+/// with when it was built. Symbolic links are left alone.
+pub fn normalize_install(dir: &Path, source_date_epoch: i64) -> Result<(), String> {
+    let time = FileTime::from_unix_time(source_date_epoch, 0);
+    let entries =
+        std::fs::read_dir(dir).map_err(|e| format!("Could not read {}: {}", dir.display(), e))?;
+    for entry in entries.filter_map(|e| e.ok()) {
+        let file_type = entry.file_type().map_err(|e| format!("{}", e))?;
+        if file_type.is_dir() {
+            normalize_install(&entry.path(), source_date_epoch)?;
+        } else if file_type.is_file() {
+            filetime::set_file_times(entry.path(), time, time)
+                .map_err(|e| format!("Could not set times of {}: {}", entry.path().display(), e))?;
+        }
+    }
+    Ok(())
+}
+
+fn hash_entries(root: &Path, dir: &Path, out: &mut BTreeMap<String, String>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        let relative = match path.strip_prefix(root) {
+            Ok(r) => r.to_string_lossy().to_string(),
+            Err(_) => continue,
+        };
+        if dir == root && OWN_ENTRIES.contains(&relative.as_str()) {
+            continue;
+        }
+        let metadata = match std::fs::symlink_metadata(&path) {
+            Ok(m) => m,
+            Err(_) => continue,
+        };
+        let file_type = metadata.file_type();
+        let digest = if file_type.is_dir() {
+            hash_entries(root, &path, out);
+            continue;
+        } else if file_type.is_symlink() {
+            match std::fs::read_link(&path) {
+                Ok(target) => format!("link {}", target.display()),
+                Err(_) => continue,
+            }
+        } else {
+            let mut hasher = Sha1::new();
+            match std::fs::read(&path) {
+                Ok(contents) => hasher.input(&contents),
+                Err(_) => continue,
+            }
+            format!(
+                "{:o} {}",
+                metadata.permissions().mode() & 0o7777,
+                hasher.result_str()
+            )
+        };
+        out.insert(relative, digest);
+    }
+}
+
+/// The content hash and permissions of every file of an install, and the
+/// target of every link, by path relative to the install
+pub fn content_manifest(product_dir: &Path) -> BTreeMap<String, String> {
+    let mut out = BTreeMap::new();
+    hash_entries(product_dir, product_dir, &mut out);
+    out
+}
+
+/// Describe how two content manifests of the same product differ
+pub fn differences(
+    first: &BTreeMap<String, String>,
+    second: &BTreeMap<String, String>,
+) -> Vec<String> {
+    let mut found = vec![];
+    for (path, digest) in first.iter() {
+        match second.get(path) {
+            None => found.push(format!("{} is only in the first build", path)),
+            Some(other) if other != digest => found.push(format!("{} differs", path)),
+            Some(_) => (),
+        }
+    }
+    for path in second.keys().filter(|p| !first.contains_key(*p)) {
+        found.push(format!("{} is only in the second build", path));
+    }
+    found
+}
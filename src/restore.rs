@@ -0,0 +1,217 @@
+use crate::database::ProductDatabase;
+use crate::provenance::Provenance;
+use crate::regenerate::reups;
+use crate::safety;
+use crate::table_lint::{self, LintIssue, Severity};
+use crate::tags;
+use crate::workspace::Workspace;
+use log::{debug, info, warn};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// A product install found under an install root, which can be declared again
+/// from its provenance
+#[derive(Clone, Debug)]
+pub struct Restoration {
+    pub product_dir: PathBuf,
+    pub provenance: Provenance,
+}
+
+/// Find every install under install_root/product/version which recorded its
+/// provenance. Directories without provenance, such as those made by other
+/// tools, are passed over.
+pub fn scan(install_root: &PathBuf) -> Result<Vec<Restoration>, String> {
+    let mut found = vec![];
+    let products = std::fs::read_dir(install_root)
+        .map_err(|e| format!("Could not read {}: {}", install_root.display(), e))?;
+    for product in products.filter_map(|e| e.ok()) {
+        // workspace state and the store live in hidden directories
+        if product.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let versions = match std::fs::read_dir(product.path()) {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+        for version in versions.filter_map(|e| e.ok()) {
+            let product_dir = version.path();
+            match Provenance::read(&product_dir) {
+                Ok(provenance) => found.push(Restoration {
+                    product_dir,
+                    provenance,
+                }),
+                Err(e) => debug!("Skipping {}: {}", product_dir.display(), e),
+            }
+        }
+    }
+    found.sort_by(|a, b| {
+        (&a.provenance.product, &a.provenance.version)
+            .cmp(&(&b.provenance.product, &b.provenance.version))
+    });
+    Ok(found)
+}
+
+/// Declare every install of a workspace found by scan which its database does
+/// not already know about, then apply the tags recorded for them. With
+/// dry_run nothing is declared. Returns what was, or would be, restored.
+pub fn restore_db(workspace: &Workspace, dry_run: bool) -> Result<Vec<Restoration>, String> {
+    let mut db: Box<dyn ProductDatabase> = Box::new(workspace.open_db()?);
+    let mut restored = vec![];
+    for restoration in scan(&workspace.install_root)? {
+        let provenance = &restoration.provenance;
+        if db
+            .get_identity_from_version(&provenance.product, &provenance.version)
+            .is_some()
+        {
+            debug!(
+                "{} {} is already declared",
+                provenance.product, provenance.version
+            );
+            continue;
+        }
+        if dry_run {
+            restored.push(restoration);
+            continue;
+        }
+        let mut table_path = restoration.product_dir.clone();
+        table_path.push("ups");
+        table_path.push(format!("{}.table", provenance.product));
+        let table = reups::table::Table::from_file(
+            provenance.product.clone(),
+            table_path,
+            restoration.product_dir.clone(),
+        )
+        .map_err(|e| format!("Could not read the table of {}: {}", provenance.product, e))?;
+        info!(
+            "Restoring {} {} from {}",
+            provenance.product,
+            provenance.version,
+            restoration.product_dir.display()
+        );
+        let declare_product = reups::DeclareInputs {
+            product: &provenance.product,
+            prod_dir: &restoration.product_dir,
+            version: &provenance.version,
+            tag: None,
+            ident: Some(provenance.id.as_str()),
+            flavor: Some(reups::SYSTEM_OS),
+            table: Some(table),
+            relative: false,
+        };
+        db.declare(vec![declare_product])?;
+        restored.push(restoration);
+    }
+
+    // a tag may only point at one version of a product, so when several
+    // restored installs claim it the last one declared wins
+    let mut tagged: BTreeMap<&str, BTreeMap<&str, &str>> = BTreeMap::new();
+    for restoration in restored.iter() {
+        let provenance = &restoration.provenance;
+        for tag in provenance.tags.iter() {
+            tagged
+                .entry(tag.as_str())
+                .or_insert_with(BTreeMap::new)
+                .insert(&provenance.product, &provenance.version);
+        }
+    }
+    if !dry_run {
+        for (tag, products) in tagged.iter() {
+            let products: Vec<(String, String)> = products
+                .iter()
+                .map(|(p, v)| (p.to_string(), v.to_string()))
+                .collect();
+            if let Err(e) = tags::move_tag(db.as_mut(), tag, &products) {
+                warn!("Could not restore tag {}: {}", tag, e);
+            }
+        }
+    }
+    Ok(restored)
+}
+
+/// An install produced outside regenerate, such as one unpacked from a CI
+/// tarball, to be declared as it is
+#[derive(Clone, Debug)]
+pub struct ExternalInstall {
+    pub product: String,
+    pub product_dir: PathBuf,
+    pub version: String,
+    pub id: Option<String>,
+    pub tag: Option<String>,
+}
+
+/// Declare an install which regenerate did not build, without cloning or
+/// building anything. Its table is checked first, with the dependencies it
+/// names looked up in the database, and errors in it stop the declare.
+/// Returns the warnings found in the table.
+pub fn declare_external(
+    workspace: &Workspace,
+    install: &ExternalInstall,
+) -> Result<Vec<LintIssue>, String> {
+    let product = install.product.as_str();
+    safety::validate_product_name(product)?;
+    let product_dir = install
+        .product_dir
+        .canonicalize()
+        .map_err(|e| format!("Could not find {}: {}", install.product_dir.display(), e))?;
+    let mut table_path = product_dir.clone();
+    table_path.push("ups");
+    table_path.push(format!("{}.table", product));
+    if !table_path.is_file() {
+        return Err(format!(
+            "{} has no table, {} does not exist",
+            product_dir.display(),
+            table_path.display()
+        ));
+    }
+    let mut db: Box<dyn ProductDatabase> = Box::new(workspace.open_db()?);
+    if let Some(id) = db.get_identity_from_version(product, &install.version) {
+        return Err(format!(
+            "{} {} is already declared with id {}",
+            product, install.version, id
+        ));
+    }
+    let declared = db.get_all_products();
+    let issues = table_lint::lint_table(product, &table_path, &product_dir, |dep| {
+        declared.iter().any(|p| p == dep)
+    })?;
+    let errors: Vec<String> = issues
+        .iter()
+        .filter(|i| i.severity == Severity::Error)
+        .map(|i| format!("{}", i))
+        .collect();
+    if !errors.is_empty() {
+        return Err(format!(
+            "The table of {} has errors, not declaring it:\n  {}",
+            product,
+            errors.join("\n  ")
+        ));
+    }
+    let table =
+        reups::table::Table::from_file(product.to_string(), table_path, product_dir.clone())
+            .map_err(|e| format!("Could not read the table of {}: {}", product, e))?;
+    // an install regenerate built elsewhere keeps the id it recorded
+    let id = install.id.clone().or_else(|| {
+        Provenance::read(&product_dir)
+            .ok()
+            .filter(|p| p.product == product)
+            .map(|p| p.id)
+    });
+    info!(
+        "Declaring {} {} from {}",
+        product,
+        install.version,
+        product_dir.display()
+    );
+    let declare_product = reups::DeclareInputs {
+        product,
+        prod_dir: &product_dir,
+        version: &install.version,
+        tag: install.tag.as_ref().map(|t| t.as_str()),
+        ident: id.as_ref().map(|i| i.as_str()),
+        flavor: Some(reups::SYSTEM_OS),
+        table: Some(table),
+        relative: false,
+    };
+    db.declare(vec![declare_product])?;
+    Ok(issues)
+}
@@ -0,0 +1,91 @@
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// A product an interrupted run finished installing
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CompletedProduct {
+    pub id: String,
+    pub sha: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StateFile {
+    version: String,
+    completed: BTreeMap<String, CompletedProduct>,
+}
+
+/// Products installed so far by the runs of a workspace, kept in
+/// .regenerate-state.json at the install root so a run which was killed
+/// can pick up where it stopped. The state is dropped once a run succeeds.
+pub struct RunState {
+    path: PathBuf,
+    state: StateFile,
+}
+
+impl RunState {
+    /// Open the state of runs installing version into install_root. A state
+    /// left by runs of another version is ignored, as is any state at all
+    /// when starting fresh.
+    pub fn open(install_root: &Path, version: &str, fresh: bool) -> RunState {
+        let path = install_root.join(".regenerate-state.json");
+        let mut state = StateFile {
+            version: version.to_string(),
+            completed: BTreeMap::new(),
+        };
+        if !fresh {
+            match std::fs::read_to_string(&path) {
+                Ok(text) => match serde_json::from_str::<StateFile>(&text) {
+                    Ok(saved) if saved.version == version => {
+                        debug!(
+                            "Resuming with {} products already completed",
+                            saved.completed.len()
+                        );
+                        state = saved;
+                    }
+                    Ok(_) => debug!("Ignoring the state of runs of another version"),
+                    Err(e) => warn!("Ignoring unreadable run state {}: {}", path.display(), e),
+                },
+                Err(_) => debug!("There is no run state to resume from"),
+            }
+        }
+        RunState { path, state }
+    }
+
+    pub fn get(&self, product: &str) -> Option<&CompletedProduct> {
+        self.state.completed.get(product)
+    }
+
+    /// Record a completed product and save the state straight away, so it
+    /// survives the process being killed
+    pub fn complete(&mut self, product: &str, id: &str, sha: &str) -> Result<(), String> {
+        self.state.completed.insert(
+            product.to_string(),
+            CompletedProduct {
+                id: id.to_string(),
+                sha: sha.to_string(),
+            },
+        );
+        let text = serde_json::to_string_pretty(&self.state)
+            .map_err(|e| format!("Could not serialize run state: {}", e))?;
+        // write beside the state and move it into place, so a kill part way
+        // through never leaves half a file
+        let staging = self.path.with_extension("json.tmp");
+        std::fs::write(&staging, text)
+            .map_err(|e| format!("Could not write {}: {}", staging.display(), e))?;
+        std::fs::rename(&staging, &self.path)
+            .map_err(|e| format!("Could not write {}: {}", self.path.display(), e))
+    }
+
+    /// Forget every completed product, once there is nothing left to resume
+    pub fn clear(&mut self) -> Result<(), String> {
+        self.state.completed.clear();
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(format!("Could not remove {}: {}", self.path.display(), e))
+            }
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use std::path::{Component, Path, PathBuf};
+
+/// Check that a product name from a repository map or table is safe to use as
+/// a single path component and as a command argument
+pub fn validate_product_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("Product names may not be empty".to_string());
+    }
+    if name == "." || name == ".." {
+        return Err(format!("{} is not a valid product name", name));
+    }
+    if name.starts_with('-') {
+        return Err(format!("Product name {} may not start with a dash", name));
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || "_.+-".contains(*c)))
+    {
+        return Err(format!(
+            "Product name {:?} contains the disallowed character {:?}",
+            name, c
+        ));
+    }
+    Ok(())
+}
+
+/// Check that a repository url is safe to hand to git. Urls which could be
+/// mistaken for options, or which use the ext transport to run arbitrary
+/// commands, are rejected.
+pub fn validate_url(url: &str) -> Result<(), String> {
+    if url.is_empty() {
+        return Err("Repository urls may not be empty".to_string());
+    }
+    if url.starts_with('-') {
+        return Err(format!("Repository url {} may not start with a dash", url));
+    }
+    // git treats <transport>::<address> as a request to run a helper program
+    if let Some(pos) = url.find("::") {
+        let transport = &url[..pos];
+        if !transport.contains(|c: char| c == '/' || c == ':' || c == '[') {
+            return Err(format!(
+                "Repository url {} uses a transport helper, which is not allowed",
+                url
+            ));
+        }
+    }
+    if url.chars().any(|c| c.is_control()) {
+        return Err(format!(
+            "Repository url {:?} contains control characters",
+            url
+        ));
+    }
+    Ok(())
+}
+
+/// Resolve a path as far as it exists on disk, appending the not yet existing
+/// remainder lexically. Any parent directory components in the remainder are
+/// rejected as they can not be resolved safely.
+fn resolve(path: &Path) -> Result<PathBuf, String> {
+    let mut existing = PathBuf::from(path);
+    let mut remainder = vec![];
+    while !existing.exists() {
+        match existing.file_name() {
+            Some(name) => remainder.push(name.to_os_string()),
+            None => break,
+        }
+        if !existing.pop() {
+            break;
+        }
+    }
+    let mut resolved = if existing.as_os_str().is_empty() {
+        std::env::current_dir().map_err(|e| format!("{}", e))?
+    } else {
+        existing
+            .canonicalize()
+            .map_err(|e| format!("Could not resolve {}: {}", existing.display(), e))?
+    };
+    for name in remainder.iter().rev() {
+        resolved.push(name);
+    }
+    if resolved.components().any(|c| c == Component::ParentDir) {
+        return Err(format!(
+            "{} contains unresolvable components",
+            path.display()
+        ));
+    }
+    Ok(resolved)
+}
+
+/// Ensure that path, once symlinks are resolved, lies within root
+pub fn ensure_under(root: &Path, path: &Path) -> Result<(), String> {
+    let root_resolved = resolve(root)?;
+    let path_resolved = resolve(path)?;
+    if !path_resolved.starts_with(&root_resolved) {
+        return Err(format!(
+            "{} resolves to {} which is outside of {}",
+            path.display(),
+            path_resolved.display(),
+            root_resolved.display()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn product_names_which_escape_or_inject_are_rejected() {
+        for name in &[
+            "",
+            ".",
+            "..",
+            "../etc",
+            "a/b",
+            "/abs",
+            "-rf",
+            "--upload-pack=x",
+        ] {
+            assert!(
+                validate_product_name(name).is_err(),
+                "{:?} was accepted",
+                name
+            );
+        }
+        for name in &["a\nb", "a\tb", "a\0b", "a\x1bb"] {
+            assert!(
+                validate_product_name(name).is_err(),
+                "{:?} was accepted",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn ordinary_product_names_are_accepted() {
+        for name in &["base", "sconsUtils", "python_future", "eigen3.3", "g++"] {
+            assert!(
+                validate_product_name(name).is_ok(),
+                "{:?} was rejected",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn urls_which_run_commands_are_rejected() {
+        for url in &[
+            "ext::sh -c touch% /tmp/pwned",
+            "fd::17",
+            "-uhttps://github.com/lsst/base",
+            "--upload-pack=touch /tmp/pwned",
+            "https://github.com/lsst/base\nhttps://evil.example",
+            "",
+        ] {
+            assert!(validate_url(url).is_err(), "{:?} was accepted", url);
+        }
+    }
+
+    #[test]
+    fn ordinary_urls_are_accepted() {
+        for url in &[
+            "https://github.com/lsst/base",
+            "git@github.com:lsst/base.git",
+            "ssh://git@github.com/lsst/base",
+            "file:///srv/git/base",
+            "/srv/git/base",
+        ] {
+            assert!(validate_url(url).is_ok(), "{:?} was rejected", url);
+        }
+    }
+
+    #[test]
+    fn symlinks_out_of_the_root_are_rejected() {
+        let root = TempDir::new("safety-root").unwrap();
+        let outside = TempDir::new("safety-outside").unwrap();
+        let link = root.path().join("escape");
+        std::os::unix::fs::symlink(outside.path(), &link).unwrap();
+        assert!(ensure_under(root.path(), &link).is_err());
+        assert!(ensure_under(root.path(), &link.join("product")).is_err());
+    }
+
+    #[test]
+    fn parent_components_in_missing_paths_are_rejected() {
+        let root = TempDir::new("safety-root").unwrap();
+        let path = root
+            .path()
+            .join("missing")
+            .join("..")
+            .join("..")
+            .join("etc");
+        assert!(ensure_under(root.path(), &path).is_err());
+    }
+
+    #[test]
+    fn paths_inside_the_root_are_accepted() {
+        let root = TempDir::new("safety-root").unwrap();
+        std::fs::create_dir(root.path().join("base")).unwrap();
+        assert!(ensure_under(root.path(), &root.path().join("base")).is_ok());
+        assert!(ensure_under(root.path(), &root.path().join("base").join("new")).is_ok());
+    }
+}
@@ -0,0 +1,497 @@
+use crate::build_backend::{BuildStep, Phase};
+use crate::clone_backend;
+use crate::disk_space;
+use fs_extra::dir::{copy, CopyOptions};
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, Once};
+use std::time::{Duration, Instant};
+use tempdir::TempDir;
+
+/// How often a build step with a timeout is checked on
+const TIMEOUT_POLL: Duration = Duration::from_millis(200);
+
+/// How long the output of a finished or killed step is waited on, after
+/// which a process which escaped its group and kept the pipes open is left
+/// holding them
+const READER_GRACE: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    /// The process groups of the steps running now. Each step has a group of
+    /// its own, out of reach of the terminal, so they are signalled here.
+    static ref GROUPS: Mutex<HashSet<libc::pid_t>> = Mutex::new(HashSet::new());
+}
+
+static FORWARD_SIGNALS: Once = Once::new();
+
+/// Pass an interrupt or termination of regenerate on to the groups of the
+/// steps running, then exit, so no build tool is left running orphaned
+fn forward_signals() {
+    FORWARD_SIGNALS.call_once(|| {
+        let signals =
+            match signal_hook::iterator::Signals::new(&[signal_hook::SIGINT, signal_hook::SIGTERM])
+            {
+                Ok(signals) => signals,
+                Err(e) => {
+                    warn!(
+                        "Could not handle interrupts, build tools may outlive regenerate: {}",
+                        e
+                    );
+                    return;
+                }
+            };
+        std::thread::spawn(move || {
+            if let Some(signal) = signals.forever().next() {
+                for group in GROUPS.lock().unwrap().iter() {
+                    unsafe {
+                        libc::killpg(*group, signal);
+                    }
+                }
+                std::process::exit(128 + signal);
+            }
+        });
+    });
+}
+
+/// Orders the products of a graph so each is only started once all of its
+/// dependencies have finished, allowing independent products to run at the
+/// same time
+pub struct Scheduler {
+    // products not yet started, dependencies first
+    pending: Vec<String>,
+    dependencies: HashMap<String, Vec<String>>,
+    done: HashSet<String>,
+}
+
+impl Scheduler {
+    /// Schedule products given in an order where dependencies come first,
+    /// along with the products each one depends on
+    pub fn new(order: Vec<String>, dependencies: HashMap<String, Vec<String>>) -> Scheduler {
+        Scheduler {
+            pending: order,
+            dependencies,
+            done: HashSet::new(),
+        }
+    }
+
+    /// Take the next product whose dependencies have all finished
+    /// successfully, if there is one
+    pub fn next_ready(&mut self) -> Option<String> {
+        let done = &self.done;
+        let position = self.pending.iter().position(|product| {
+            self.dependencies
+                .get(product)
+                .map(|deps| deps.iter().all(|d| d == product || done.contains(d)))
+                .unwrap_or(true)
+        })?;
+        Some(self.pending.remove(position))
+    }
+
+    /// Mark a product as successfully finished, a product which failed is
+    /// never marked so nothing depending on it is started
+    pub fn finish(&mut self, product: &str) {
+        self.done.insert(product.to_string());
+    }
+
+    /// Drop every pending product which depends on a failed product, even
+    /// through other products, returning the ones dropped so the rest of the
+    /// graph can go on building
+    pub fn fail(&mut self, product: &str) -> Vec<String> {
+        let mut failed: HashSet<String> = HashSet::new();
+        failed.insert(product.to_string());
+        let dependencies = &self.dependencies;
+        // pending is ordered dependencies first, so one pass finds them all
+        let mut dropped = vec![];
+        self.pending.retain(|name| {
+            let blocked = dependencies
+                .get(name)
+                .map(|deps| deps.iter().any(|d| d != name && failed.contains(d)))
+                .unwrap_or(false);
+            if blocked {
+                failed.insert(name.clone());
+                dropped.push(name.clone());
+            }
+            !blocked
+        });
+        dropped
+    }
+
+    /// Products which were never started
+    pub fn remaining(&self) -> &[String] {
+        &self.pending
+    }
+}
+
+/// The verbs of one product's build, carried out on a worker thread
+pub struct BuildJob {
+    pub product: String,
+    /// Where output is streamed to as the build runs
+    pub live: Arc<LiveOutput>,
+    pub steps: Vec<BuildStep>,
+    pub build_path: PathBuf,
+    pub env: Vec<(String, String)>,
+    /// Command the build tool is run under, if any
+    pub wrapper: Vec<String>,
+    /// Number of times each verb may be retried
+    pub retries: HashMap<String, u32>,
+    /// Source to copy again before retrying a verb, if restaging is enabled
+    pub restage_from: Option<PathBuf>,
+    /// Restage by cleaning the source in place rather than copying it
+    pub clean_build: bool,
+    /// File creation mask the build tool runs with, rather than that of
+    /// regenerate
+    pub umask: Option<u32>,
+    /// Whether a failing test verb fails the job, rather than being recorded
+    /// and passed over
+    pub fail_on_tests: bool,
+}
+
+/// Result of a finished job
+pub struct JobSummary {
+    /// Path the last attempt was built in
+    pub build_path: PathBuf,
+    /// Retries each verb needed before succeeding
+    pub retries_used: HashMap<String, u32>,
+    /// Copies of the source made for retries, which must outlive the install
+    pub restaged: Vec<TempDir>,
+}
+
+/// Messages sent from workers to the thread scheduling them
+pub enum WorkerEvent {
+    VerbStarted {
+        product: String,
+        verb: String,
+    },
+    VerbFinished {
+        product: String,
+        verb: String,
+        output: Result<Output, String>,
+    },
+    Done {
+        product: String,
+        result: Result<JobSummary, String>,
+    },
+}
+
+/// Where the output of a build is copied line by line as it is written, so
+/// a long build can be followed while it runs rather than only once each
+/// verb has finished
+pub struct LiveOutput {
+    product: String,
+    log: Option<Mutex<std::fs::File>>,
+    echo: bool,
+}
+
+impl LiveOutput {
+    /// Append output to the file at log, if given, and echo it to the
+    /// console prefixed with the product when echo is set
+    pub fn new(product: &str, log: Option<&Path>, echo: bool) -> LiveOutput {
+        let log = log.and_then(|path| {
+            let opened = path
+                .parent()
+                .map_or(Ok(()), std::fs::create_dir_all)
+                .and_then(|_| {
+                    std::fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)
+                });
+            match opened {
+                Ok(file) => Some(Mutex::new(file)),
+                Err(e) => {
+                    warn!(
+                        "Could not open {} to stream output to: {}",
+                        path.display(),
+                        e
+                    );
+                    None
+                }
+            }
+        });
+        LiveOutput {
+            product: product.to_string(),
+            log,
+            echo,
+        }
+    }
+
+    pub fn write(&self, bytes: &[u8]) {
+        if let Some(log) = self.log.as_ref() {
+            if let Ok(mut file) = log.lock() {
+                let _ = file.write_all(bytes);
+            }
+        }
+        if self.echo {
+            let text = String::from_utf8_lossy(bytes);
+            eprintln!("[{}] {}", self.product, text.trim_end_matches('\n'));
+        }
+    }
+}
+
+/// Run a build step in build_path, through the wrapper if there is one,
+/// passing each line it writes to live as it arrives
+pub fn run_step(
+    step: &BuildStep,
+    wrapper: &[String],
+    build_path: &PathBuf,
+    env: &[(String, String)],
+    umask: Option<u32>,
+    live: &Arc<LiveOutput>,
+) -> Result<Output, String> {
+    let mut command = match wrapper.split_first() {
+        Some((program, wrapper_args)) => {
+            debug!("Wrapping build tool with {:?}", wrapper);
+            let mut c = std::process::Command::new(program);
+            c.args(wrapper_args).arg(&step.program);
+            c
+        }
+        None => std::process::Command::new(&step.program),
+    };
+    command
+        .args(&step.args)
+        .current_dir(build_path)
+        .envs(env.iter().map(|(k, v)| (k, v)));
+    // only the child's mask is set, regenerate may be embedded in a program
+    // with its own
+    if let Some(mask) = umask {
+        unsafe {
+            command.pre_exec(move || {
+                libc::umask(mask as libc::mode_t);
+                Ok(())
+            });
+        }
+    }
+    live.write(format!("Running build tool verb {}\n", step.verb).as_bytes());
+    run_streamed(command, &step.verb, step.timeout, live)
+}
+
+/// What the reader of a pipe has kept so far, and word of when it is done
+struct Collected {
+    buffer: Arc<Mutex<Vec<u8>>>,
+    done: Receiver<()>,
+}
+
+impl Collected {
+    /// Everything read once the pipe closes, or what had been read by the
+    /// deadline if something still holds it open
+    fn finish(self, deadline: Instant) -> Vec<u8> {
+        let wait = deadline.saturating_duration_since(Instant::now());
+        if self.done.recv_timeout(wait).is_err() {
+            warn!("Giving up on output still being written after the build tool exited");
+        }
+        std::mem::replace(&mut *self.buffer.lock().unwrap(), vec![])
+    }
+}
+
+/// Read a pipe of a child line by line on a thread of its own, so neither of
+/// its pipes can fill up and stall it, keeping everything read
+fn collect<R: Read + Send + 'static>(pipe: Option<R>, live: Arc<LiveOutput>) -> Collected {
+    let buffer = Arc::new(Mutex::new(vec![]));
+    let (sender, done) = std::sync::mpsc::channel();
+    let kept = buffer.clone();
+    std::thread::spawn(move || {
+        if let Some(pipe) = pipe {
+            let mut reader = BufReader::new(pipe);
+            let mut line = vec![];
+            loop {
+                line.clear();
+                match reader.read_until(b'\n', &mut line) {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {
+                        live.write(&line);
+                        kept.lock().unwrap().extend_from_slice(&line);
+                    }
+                }
+            }
+        }
+        let _ = sender.send(());
+    });
+    Collected { buffer, done }
+}
+
+/// Run a command, streaming its output. It runs in a process group of its
+/// own, which is signalled if regenerate is interrupted, and with a timeout
+/// the whole group is killed if it has not finished in time, so nothing it
+/// started is left behind, and the error carries whatever it had written.
+fn run_streamed(
+    mut command: Command,
+    verb: &str,
+    timeout: Option<Duration>,
+    live: &Arc<LiveOutput>,
+) -> Result<Output, String> {
+    forward_signals();
+    unsafe {
+        command.pre_exec(|| match libc::setpgid(0, 0) {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        });
+    }
+    // held while spawning so an interrupt can not pass over a new group
+    let mut groups = GROUPS.lock().unwrap();
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Building failed with error {}", e))?;
+    let group = child.id() as libc::pid_t;
+    groups.insert(group);
+    drop(groups);
+    let stdout = collect(child.stdout.take(), live.clone());
+    let stderr = collect(child.stderr.take(), live.clone());
+    let start = Instant::now();
+    let status = match timeout {
+        None => child.wait().map(Some),
+        Some(timeout) => loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break Ok(Some(status)),
+                Ok(None) if start.elapsed() >= timeout => break Ok(None),
+                Ok(None) => std::thread::sleep(TIMEOUT_POLL),
+                Err(e) => break Err(e),
+            }
+        },
+    };
+    let secs = timeout.map(|t| t.as_secs()).unwrap_or_default();
+    if let Ok(None) = status {
+        warn!("Killing {} after it ran for longer than {}s", verb, secs);
+        unsafe {
+            libc::killpg(group, libc::SIGKILL);
+        }
+        let _ = child.wait();
+    }
+    GROUPS.lock().unwrap().remove(&group);
+    let status = status.map_err(|e| format!("Building failed with error {}", e))?;
+    let deadline = Instant::now() + READER_GRACE;
+    let stdout = stdout.finish(deadline);
+    let stderr = stderr.finish(deadline);
+    match status {
+        Some(status) => Ok(Output {
+            status,
+            stdout,
+            stderr,
+        }),
+        None => Err(format!(
+            "{} timed out after {}s and was killed, stdout:\n{}\nstderr:\n{}",
+            verb,
+            secs,
+            String::from_utf8_lossy(&stdout),
+            String::from_utf8_lossy(&stderr)
+        )),
+    }
+}
+
+/// Describe a failed build step by its exit status and what it wrote to
+/// stderr, decoded lossily as compilers may write anything
+pub fn failure_message(output: &Output) -> String {
+    format!(
+        "Process exited with {}, stderr:\n{}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr)
+    )
+}
+
+/// Copy a product's source into a new temporary directory, clearing the
+/// sentinel an upstream build leaves once prepared
+pub fn restage(product: &str, source: &PathBuf) -> Result<(TempDir, PathBuf), String> {
+    let tmp_dir = TempDir::new(&format!("{}{}", disk_space::TEMP_PREFIX, product))
+        .map_err(|e| format!("{}", e))?;
+    copy(source, tmp_dir.path(), &CopyOptions::new()).map_err(|e| format!("{}", e))?;
+    let mut build_path = PathBuf::from(tmp_dir.path());
+    build_path.push(product);
+    let mut prep_path = build_path.clone();
+    prep_path.push("upstream");
+    prep_path.push("prepared");
+    if prep_path.exists() {
+        let _ = std::fs::remove_file(prep_path);
+    }
+    debug!("Restaged {} into {}", product, build_path.display());
+    Ok((tmp_dir, build_path))
+}
+
+fn run_steps(job: &BuildJob, events: &Sender<WorkerEvent>) -> Result<JobSummary, String> {
+    let mut summary = JobSummary {
+        build_path: job.build_path.clone(),
+        retries_used: HashMap::new(),
+        restaged: vec![],
+    };
+    let mut index = 0;
+    while index < job.steps.len() {
+        let step = &job.steps[index];
+        let verb = step.verb.as_str();
+        let _ = events.send(WorkerEvent::VerbStarted {
+            product: job.product.clone(),
+            verb: verb.to_string(),
+        });
+        let output = run_step(
+            step,
+            &job.wrapper,
+            &summary.build_path,
+            &job.env,
+            job.umask,
+            &job.live,
+        );
+        let error = match output.as_ref() {
+            Ok(o) if o.status.success() => None,
+            Ok(o) => Some(failure_message(o)),
+            Err(e) => Some(e.clone()),
+        };
+        let _ = events.send(WorkerEvent::VerbFinished {
+            product: job.product.clone(),
+            verb: verb.to_string(),
+            output,
+        });
+        let error = match error {
+            None => {
+                index += 1;
+                continue;
+            }
+            Some(e) if verb == Phase::Test.name() && !job.fail_on_tests => {
+                warn!("The tests of {} failed: {}", job.product, e);
+                index += 1;
+                continue;
+            }
+            Some(e) => e,
+        };
+        let allowed = *job.retries.get(verb).unwrap_or(&0);
+        let used = summary.retries_used.entry(verb.to_string()).or_insert(0);
+        if *used >= allowed {
+            return Err(error);
+        }
+        *used += 1;
+        warn!(
+            "Verb {} failed for {}, retrying (retry {} of {})",
+            verb, job.product, used, allowed
+        );
+        if let Some(source) = job.restage_from.as_ref().filter(|_| job.clean_build) {
+            clone_backend::clean_tree(source)
+                .map_err(|e| format!("Could not clean {}: {}", job.product, e))?;
+            summary.build_path = source.clone();
+            index = 0;
+        } else if let Some(source) = job.restage_from.as_ref() {
+            let (tmp_dir, path) = restage(&job.product, source)
+                .map_err(|e| format!("Could not restage {}: {}", job.product, e))?;
+            summary.restaged.push(tmp_dir);
+            summary.build_path = path;
+            index = 0;
+        }
+    }
+    Ok(summary)
+}
+
+/// Run a job on a new thread, reporting its progress and result through
+/// events
+pub fn spawn(job: BuildJob, events: Sender<WorkerEvent>) {
+    std::thread::spawn(move || {
+        let result = run_steps(&job, &events);
+        let _ = events.send(WorkerEvent::Done {
+            product: job.product.clone(),
+            result,
+        });
+    });
+}
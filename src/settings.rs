@@ -0,0 +1,136 @@
+use log::debug;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use yaml_rust::yaml::{Array, Hash, Yaml};
+
+/// Settings which may be stored in a workspace, and whether each holds a list
+const KEYS: [(&str, bool); 9] = [
+    ("branches", true),
+    ("build_tool", false),
+    ("clone_root", false),
+    ("confirm_threshold", false),
+    ("local_yaml", true),
+    ("mirror_root", false),
+    ("remote_url", false),
+    ("tag", false),
+    ("version", false),
+];
+
+/// Defaults stored in a workspace, used by every run in it unless overridden
+/// on the command line. Keeping them with the workspace means two terminals
+/// building into it agree on what is built.
+pub struct WorkspaceSettings {
+    path: PathBuf,
+    values: BTreeMap<String, Vec<String>>,
+}
+
+fn is_list(key: &str) -> Result<bool, String> {
+    KEYS.iter()
+        .find(|(k, _)| *k == key)
+        .map(|(_, list)| *list)
+        .ok_or(format!(
+            "Unknown setting {}, known settings are {}",
+            key,
+            KEYS.iter().map(|(k, _)| *k).collect::<Vec<_>>().join(", ")
+        ))
+}
+
+impl WorkspaceSettings {
+    /// Open the settings of the workspace rooted at install_root
+    pub fn open(install_root: &Path) -> Result<WorkspaceSettings, String> {
+        let mut path = PathBuf::from(install_root);
+        path.push(".regenerate");
+        path.push("settings.yaml");
+        let mut values = BTreeMap::new();
+        if path.exists() {
+            debug!("Loading workspace settings from {}", path.display());
+            let text = std::fs::read_to_string(&path)
+                .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+            let docs = yaml_rust::YamlLoader::load_from_str(&text)
+                .map_err(|e| format!("Could not parse {}: {}", path.display(), e))?;
+            if let Some(Yaml::Hash(hash)) = docs.get(0) {
+                for (key, value) in hash.iter() {
+                    let key = match key.as_str() {
+                        Some(k) => k.to_string(),
+                        None => continue,
+                    };
+                    let value = match value {
+                        Yaml::String(s) => vec![s.clone()],
+                        Yaml::Array(a) => a
+                            .iter()
+                            .filter_map(|v| v.as_str().map(|s| s.to_string()))
+                            .collect(),
+                        _ => {
+                            return Err(format!(
+                                "Setting {} in {} is not text",
+                                key,
+                                path.display()
+                            ))
+                        }
+                    };
+                    values.insert(key, value);
+                }
+            }
+        }
+        Ok(WorkspaceSettings { path, values })
+    }
+
+    /// A setting holding a single value
+    pub fn get(&self, key: &str) -> Option<&String> {
+        self.values.get(key).and_then(|v| v.first())
+    }
+
+    /// A setting holding a list of values
+    pub fn get_list(&self, key: &str) -> Option<&Vec<String>> {
+        self.values.get(key)
+    }
+
+    pub fn set(&mut self, key: &str, values: &[String]) -> Result<(), String> {
+        let list = is_list(key)?;
+        if values.is_empty() {
+            return Err(format!("No value given for {}", key));
+        }
+        if !list && values.len() > 1 {
+            return Err(format!("{} takes a single value", key));
+        }
+        self.values.insert(key.to_string(), values.to_vec());
+        Ok(())
+    }
+
+    /// Remove a setting, returning if it was set
+    pub fn unset(&mut self, key: &str) -> Result<bool, String> {
+        is_list(key)?;
+        Ok(self.values.remove(key).is_some())
+    }
+
+    /// Render the settings one per line, as key: value
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for (key, values) in self.values.iter() {
+            out.push_str(&format!("{}: {}\n", key, values.join(" ")));
+        }
+        out
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| format!("{}", e))?;
+        }
+        let mut hash = Hash::new();
+        for (key, values) in self.values.iter() {
+            let value = match is_list(key) {
+                Ok(true) => {
+                    Yaml::Array(values.iter().cloned().map(Yaml::String).collect::<Array>())
+                }
+                _ => Yaml::String(values.join(" ")),
+            };
+            hash.insert(Yaml::String(key.clone()), value);
+        }
+        let mut out = String::new();
+        yaml_rust::YamlEmitter::new(&mut out)
+            .dump(&Yaml::Hash(hash))
+            .map_err(|e| format!("Could not serialize workspace settings: {:?}", e))?;
+        std::fs::write(&self.path, out)
+            .map_err(|e| format!("Could not write {}: {}", self.path.display(), e))
+    }
+}
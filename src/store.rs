@@ -0,0 +1,221 @@
+use crate::provenance::Provenance;
+use log::{debug, info};
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// An exclusive lock on one entry of the store, held across processes and
+/// workspaces from the start of a build into it until it is complete
+pub struct StoreLock {
+    _file: File,
+}
+
+/// A content addressed store of installed products. Each install lives in a
+/// directory named by its product id, and the usual install_root/product/
+/// version paths are symlinks into the store. Since an id fully describes
+/// what was built, entries can be shared between workspaces, and rolling back
+/// is a matter of pointing a link at an older entry.
+pub struct Store {
+    pub root: PathBuf,
+}
+
+impl Store {
+    pub fn new(root: &Path) -> Store {
+        Store {
+            root: PathBuf::from(root),
+        }
+    }
+
+    /// Directory holding the install of the given product id built as
+    /// variant. The variant tells apart builds of one id which differ in
+    /// what the id does not cover, such as the ABI of their dependencies, so
+    /// a rebuild never replaces an entry another workspace may be using.
+    pub fn entry_path(&self, id: &str, variant: &str) -> PathBuf {
+        self.root.join(format!("{}-{}", id, variant))
+    }
+
+    /// An entry is complete once its provenance has been written, which is
+    /// the last thing done after a successful build
+    pub fn is_complete(entry: &Path, id: &str) -> bool {
+        match Provenance::read(entry) {
+            Ok(provenance) => provenance.id == id,
+            Err(_) => false,
+        }
+    }
+
+    /// The complete entries holding builds of id, of any variant
+    pub fn complete_entries(&self, id: &str) -> Result<Vec<PathBuf>, String> {
+        let variant_prefix = format!("{}-", id);
+        Ok(self
+            .entries()?
+            .into_iter()
+            .filter(|entry| {
+                let name = entry
+                    .file_name()
+                    .map(|n| n.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                name == id || name.starts_with(&variant_prefix)
+            })
+            .filter(|entry| Store::is_complete(entry, id))
+            .collect())
+    }
+
+    /// Wait until no other build holds entry, then take it
+    pub fn lock(&self, entry: &Path) -> Result<StoreLock, String> {
+        let name = entry
+            .file_name()
+            .ok_or(format!("{} is not a store entry", entry.display()))?;
+        let mut path = self.root.join(".locks");
+        std::fs::create_dir_all(&path)
+            .map_err(|e| format!("Could not create {}: {}", path.display(), e))?;
+        path.push(format!("{}.lock", name.to_string_lossy()));
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(&path)
+            .map_err(|e| format!("Could not open lock {}: {}", path.display(), e))?;
+        debug!("Waiting for lock {}", path.display());
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(format!(
+                "Could not lock {}: {}",
+                path.display(),
+                std::io::Error::last_os_error()
+            ));
+        }
+        Ok(StoreLock { _file: file })
+    }
+
+    /// All of the entries in the store
+    pub fn entries(&self) -> Result<Vec<PathBuf>, String> {
+        let mut entries: Vec<PathBuf> = std::fs::read_dir(&self.root)
+            .map_err(|e| format!("Could not read store {}: {}", self.root.display(), e))?
+            .filter_map(|e| e.ok())
+            // the locks are kept beside the entries
+            .filter(|e| !e.file_name().to_string_lossy().starts_with('.'))
+            .map(|e| e.path())
+            .filter(|p| p.is_dir())
+            .collect();
+        entries.sort();
+        Ok(entries)
+    }
+
+    /// Entries left behind by builds which never finished, and which have
+    /// not been touched for at least age so no running build owns them
+    pub fn abandoned_entries(&self, age: Duration) -> Result<Vec<PathBuf>, String> {
+        let now = SystemTime::now();
+        Ok(self
+            .entries()?
+            .into_iter()
+            .filter(|entry| Provenance::read(entry).is_err())
+            .filter(|entry| {
+                std::fs::metadata(entry)
+                    .and_then(|m| m.modified())
+                    .ok()
+                    .and_then(|modified| now.duration_since(modified).ok())
+                    .map(|elapsed| elapsed >= age)
+                    .unwrap_or(false)
+            })
+            .collect())
+    }
+
+    /// Complete entries holding builds of a product, oldest first, which a
+    /// version link can be pointed back at to roll back to an earlier build
+    pub fn builds_of(&self, product: &str) -> Result<Vec<(PathBuf, Provenance)>, String> {
+        let mut builds = vec![];
+        for entry in self.entries()? {
+            if let Ok(provenance) = Provenance::read(&entry) {
+                if provenance.product == product {
+                    let modified = std::fs::metadata(Provenance::path(&entry))
+                        .and_then(|m| m.modified())
+                        .ok();
+                    builds.push((modified, entry, provenance));
+                }
+            }
+        }
+        builds.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(builds.into_iter().map(|(_, e, p)| (e, p)).collect())
+    }
+
+    /// Remove every entry not referenced by a version link in any of the
+    /// given install roots, returning the entries removed. Nothing is removed
+    /// when dry_run is set.
+    pub fn collect_garbage(
+        &self,
+        install_roots: &[PathBuf],
+        dry_run: bool,
+    ) -> Result<Vec<PathBuf>, String> {
+        let mut referenced = HashSet::new();
+        for root in install_roots.iter() {
+            referenced.extend(view_targets(root));
+        }
+        let mut removed = vec![];
+        for entry in self.entries()? {
+            let resolved = entry.canonicalize().unwrap_or_else(|_| entry.clone());
+            if referenced.contains(&resolved) {
+                continue;
+            }
+            info!("Removing unreferenced store entry {}", entry.display());
+            if !dry_run {
+                std::fs::remove_dir_all(&entry)
+                    .map_err(|e| format!("Could not remove {}: {}", entry.display(), e))?;
+            }
+            removed.push(entry);
+        }
+        Ok(removed)
+    }
+}
+
+/// Resolved targets of every install_root/product/version link
+fn view_targets(install_root: &Path) -> Vec<PathBuf> {
+    let mut targets = vec![];
+    let products = match std::fs::read_dir(install_root) {
+        Ok(p) => p,
+        Err(_) => return targets,
+    };
+    for product in products.filter_map(|e| e.ok()) {
+        let versions = match std::fs::read_dir(product.path()) {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+        for version in versions.filter_map(|e| e.ok()) {
+            let is_link = version.file_type().map(|t| t.is_symlink()).unwrap_or(false);
+            if is_link {
+                if let Ok(target) = version.path().canonicalize() {
+                    targets.push(target);
+                }
+            }
+        }
+    }
+    targets
+}
+
+/// Point a version view at a store entry, replacing any existing link in a
+/// single rename so the view is never missing
+pub fn link_view(view: &Path, entry: &Path) -> Result<(), String> {
+    if let Some(parent) = view.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| format!("Could not create {}: {}", parent.display(), e))?;
+    }
+    if view.exists()
+        && !view
+            .symlink_metadata()
+            .map(|m| m.file_type().is_symlink())
+            .unwrap_or(false)
+    {
+        return Err(format!(
+            "{} is a real directory, not replacing it with a link into the store",
+            view.display()
+        ));
+    }
+    let mut staged = PathBuf::from(view);
+    staged.set_extension("regenerate-link");
+    let _ = std::fs::remove_file(&staged);
+    std::os::unix::fs::symlink(entry, &staged)
+        .map_err(|e| format!("Could not link {}: {}", view.display(), e))?;
+    std::fs::rename(&staged, view)
+        .map_err(|e| format!("Could not link {}: {}", view.display(), e))?;
+    debug!("Pointed {} at {}", view.display(), entry.display());
+    Ok(())
+}
@@ -0,0 +1,130 @@
+use std::path::Path;
+use yaml_rust::Yaml;
+
+/// First line of every generated table, marking it as safe to regenerate
+const HEADER: &str =
+    "# Generated by regenerate from the generate_table entry of the repository map";
+
+/// Directories prepended to environment variables when a template does not
+/// list its own, each only when the install has it
+const DEFAULT_ENV: [(&str, &str); 5] = [
+    ("PATH", "bin"),
+    ("LD_LIBRARY_PATH", "lib"),
+    ("DYLD_LIBRARY_PATH", "lib"),
+    ("PYTHONPATH", "python"),
+    ("PKG_CONFIG_PATH", "lib/pkgconfig"),
+];
+
+/// How to write the table of a product which does not ship one, from the
+/// generate_table key of its map entry. The key is either true, for a table
+/// with no dependencies and the default environment, or a mapping with
+/// dependencies and optional lists of products and an env mapping of
+/// variables to the directory, or list of directories, prepended to them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TableTemplate {
+    pub dependencies: Vec<String>,
+    pub optional: Vec<String>,
+    /// Variables with directories relative to the install, or the defaults
+    /// when None
+    pub env: Option<Vec<(String, String)>>,
+}
+
+fn names(value: &Yaml, key: &str) -> Result<Vec<String>, String> {
+    match value {
+        Yaml::BadValue => Ok(vec![]),
+        Yaml::Array(list) => list
+            .iter()
+            .map(|x| {
+                x.as_str()
+                    .map(|s| s.to_string())
+                    .ok_or(format!("{} of generate_table must be product names", key))
+            })
+            .collect(),
+        _ => Err(format!("{} of generate_table must be a list", key)),
+    }
+}
+
+impl TableTemplate {
+    pub fn from_yaml(value: &Yaml) -> Result<Option<TableTemplate>, String> {
+        match value {
+            Yaml::Boolean(false) => return Ok(None),
+            Yaml::Boolean(true) => return Ok(Some(TableTemplate::default())),
+            Yaml::Hash(_) => (),
+            _ => return Err("generate_table must be true, false, or a mapping".to_string()),
+        }
+        let env = match &value["env"] {
+            Yaml::BadValue => None,
+            Yaml::Hash(hash) => {
+                let mut env = vec![];
+                for (variable, dirs) in hash.iter() {
+                    let variable = variable
+                        .as_str()
+                        .ok_or("The env of generate_table must be keyed by variable names")?;
+                    let dirs = match dirs {
+                        Yaml::String(dir) => vec![dir.clone()],
+                        _ => names(dirs, "env")?,
+                    };
+                    env.extend(dirs.into_iter().map(|d| (variable.to_string(), d)));
+                }
+                Some(env)
+            }
+            _ => return Err("The env of generate_table must be a mapping".to_string()),
+        };
+        Ok(Some(TableTemplate {
+            dependencies: names(&value["dependencies"], "dependencies")?,
+            optional: names(&value["optional"], "optional")?,
+            env,
+        }))
+    }
+
+    /// The text of the table. With an install directory, only directories it
+    /// has are prepended, while without one, for the table builds are set up
+    /// from, nothing is.
+    pub fn render(&self, product_dir: Option<&Path>) -> String {
+        let mut text = format!("{}\n", HEADER);
+        for dep in self.dependencies.iter() {
+            text.push_str(&format!("setupRequired({})\n", dep));
+        }
+        for dep in self.optional.iter() {
+            text.push_str(&format!("setupOptional({})\n", dep));
+        }
+        let product_dir = match product_dir {
+            Some(dir) => dir,
+            None => return text,
+        };
+        let defaults: Vec<(String, String)> = DEFAULT_ENV
+            .iter()
+            .map(|(v, d)| (v.to_string(), d.to_string()))
+            .collect();
+        for (variable, dir) in self.env.as_ref().unwrap_or(&defaults).iter() {
+            if product_dir.join(dir).is_dir() {
+                text.push_str(&format!(
+                    "envPrepend({}, ${{PRODUCT_DIR}}/{})\n",
+                    variable, dir
+                ));
+            }
+        }
+        text
+    }
+}
+
+/// True for tables regenerate wrote, which may be written again
+pub fn is_generated(table: &Path) -> bool {
+    std::fs::read_to_string(table)
+        .map(|text| text.starts_with(HEADER))
+        .unwrap_or(false)
+}
+
+/// Write a table from a template, creating its ups directory
+pub fn write(
+    table: &Path,
+    template: &TableTemplate,
+    product_dir: Option<&Path>,
+) -> Result<(), String> {
+    if let Some(ups) = table.parent() {
+        std::fs::create_dir_all(ups)
+            .map_err(|e| format!("Could not create {}: {}", ups.display(), e))?;
+    }
+    std::fs::write(table, template.render(product_dir))
+        .map_err(|e| format!("Could not write {}: {}", table.display(), e))
+}
@@ -0,0 +1,144 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Severity {
+    /// Suspicious but the product may still build
+    Warning,
+    /// The table is broken, and builds relying on it will fail
+    Error,
+}
+
+/// A problem found in a product table file
+#[derive(Clone, Debug)]
+pub struct LintIssue {
+    pub product: String,
+    pub line: usize,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl fmt::Display for LintIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(
+            f,
+            "{}.table:{}: {}: {}",
+            self.product, self.line, severity, self.message
+        )
+    }
+}
+
+/// Directives which eups still accepts but which have been superseded
+const DEPRECATED_DIRECTIVES: [(&str, &str); 5] = [
+    ("pathAppend", "envAppend"),
+    ("pathPrepend", "envPrepend"),
+    ("pathSet", "envSet"),
+    ("setupEnv", "setupRequired"),
+    ("prodDir", "nothing, it is implied"),
+];
+
+/// Split a directive line such as envPrepend(PATH, ${PRODUCT_DIR}/bin) into
+/// its name and arguments
+fn parse_directive(line: &str) -> Option<(&str, Vec<&str>)> {
+    let open = line.find('(')?;
+    let close = line.rfind(')')?;
+    if close < open {
+        return None;
+    }
+    let name = line[..open].trim();
+    let args = line[open + 1..close]
+        .split(',')
+        .map(|a| a.trim().trim_matches('"').trim())
+        .collect();
+    Some((name, args))
+}
+
+/// Check the table of a product for problems. The has_source closure reports
+/// if a dependency can be resolved to a source or is otherwise satisfied.
+pub fn lint_table<F: Fn(&str) -> bool>(
+    product: &str,
+    table_path: &Path,
+    product_dir: &Path,
+    has_source: F,
+) -> Result<Vec<LintIssue>, String> {
+    let text = std::fs::read_to_string(table_path)
+        .map_err(|e| format!("Could not read {}: {}", table_path.display(), e))?;
+    let mut issues = vec![];
+    let mut issue = |line: usize, severity: Severity, message: String| {
+        issues.push(LintIssue {
+            product: product.to_string(),
+            line,
+            severity,
+            message,
+        })
+    };
+    for (number, raw) in text.lines().enumerate() {
+        let number = number + 1;
+        let line = match raw.find('#') {
+            Some(pos) => &raw[..pos],
+            None => raw,
+        }
+        .trim();
+        let (name, args) = match parse_directive(line) {
+            Some(x) => x,
+            None => continue,
+        };
+        if let Some((_, replacement)) = DEPRECATED_DIRECTIVES.iter().find(|(d, _)| *d == name) {
+            issue(
+                number,
+                Severity::Warning,
+                format!("{} is deprecated, use {}", name, replacement),
+            );
+        }
+        match name {
+            "setupRequired" | "setupOptional" => {
+                let dep = match args.get(0).and_then(|a| a.split_whitespace().next()) {
+                    Some(d) => d,
+                    None => continue,
+                };
+                if dep == product {
+                    issue(
+                        number,
+                        Severity::Error,
+                        format!("{} depends on itself", product),
+                    );
+                } else if !has_source(dep) {
+                    let severity = match name {
+                        "setupRequired" => Severity::Error,
+                        _ => Severity::Warning,
+                    };
+                    issue(
+                        number,
+                        severity,
+                        format!("dependency {} has no entry in the repository maps", dep),
+                    );
+                }
+            }
+            "envPrepend" | "envAppend" | "pathPrepend" | "pathAppend" => {
+                let value = match args.get(1) {
+                    Some(v) => v,
+                    None => continue,
+                };
+                if !value.contains("${PRODUCT_DIR}") {
+                    continue;
+                }
+                let dir = PathBuf::from(
+                    value.replace("${PRODUCT_DIR}", product_dir.to_str().unwrap_or_default()),
+                );
+                if !dir.exists() {
+                    issue(
+                        number,
+                        Severity::Warning,
+                        format!("{} adds {} which does not exist", name, value),
+                    );
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(issues)
+}
@@ -0,0 +1,75 @@
+use crate::database::ProductDatabase;
+use log::{debug, info, warn};
+
+/// A pending assignment of a tag to one product version
+struct TagUpdate {
+    product: String,
+    version: String,
+    /// The version the tag pointed at before the update, if any
+    previous: Option<String>,
+}
+
+/// Put every tag back the way it was before any updates were made
+fn restore(db: &mut dyn ProductDatabase, tag: &str, committed: &[TagUpdate]) {
+    for update in committed.iter().rev() {
+        let result = match update.previous.as_ref() {
+            Some(previous) => db.set_tag(&update.product, previous, tag),
+            None => db.remove_tag(&update.product, &update.version, tag),
+        };
+        if let Err(e) = result {
+            warn!(
+                "Could not restore tag {} of {}, it must be fixed by hand: {}",
+                tag, update.product, e
+            );
+        }
+    }
+}
+
+/// Point a moving tag, such as current, at the given product versions as a
+/// single operation. Every assignment is validated before any is made, and
+/// should one fail part way through all the previous assignments are
+/// restored, so the tag never refers to a mix of old and new versions.
+pub fn move_tag(
+    db: &mut dyn ProductDatabase,
+    tag: &str,
+    products: &[(String, String)],
+) -> Result<(), String> {
+    // prepare and validate all of the updates
+    let mut updates = vec![];
+    let mut problems = vec![];
+    for (product, version) in products.iter() {
+        if db.get_table_from_version(product, version).is_none() {
+            problems.push(format!("{} {} is not declared", product, version));
+            continue;
+        }
+        updates.push(TagUpdate {
+            product: product.clone(),
+            version: version.clone(),
+            previous: db.get_versions_from_tag(product, vec![tag]).pop(),
+        });
+    }
+    if !problems.is_empty() {
+        return Err(format!(
+            "Not moving tag {}, validation failed:\n{}",
+            tag,
+            problems.join("\n")
+        ));
+    }
+
+    // commit the updates, rolling back on the first failure
+    let mut committed = vec![];
+    for update in updates.into_iter() {
+        debug!("Moving tag {} for {}", tag, update.product);
+        if let Err(e) = db.set_tag(&update.product, &update.version, tag) {
+            let message = format!(
+                "Could not move tag {} for {}, restoring previous assignments: {}",
+                tag, update.product, e
+            );
+            restore(db, tag, &committed);
+            return Err(message);
+        }
+        committed.push(update);
+    }
+    info!("Moved tag {} for {} products", tag, committed.len());
+    Ok(())
+}
@@ -0,0 +1,101 @@
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use fnv::FnvHashMap;
+use log::debug;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// Tools whose versions are recorded, each printing its version when given
+/// --version
+const TOOLS: [&str; 6] = ["scons", "cmake", "make", "cc", "c++", "gfortran"];
+
+/// Compiler variables which, when set, name the compiler actually used
+const COMPILER_VARIABLES: [&str; 3] = ["CC", "CXX", "FC"];
+
+/// Find an executable the way the shell would, using the PATH of env if it
+/// sets one
+pub fn find_program(program: &str, env: &FnvHashMap<String, String>) -> Option<PathBuf> {
+    if program.contains('/') {
+        let path = PathBuf::from(program);
+        return match path.is_file() {
+            true => Some(path),
+            false => None,
+        };
+    }
+    let search = env
+        .get("PATH")
+        .cloned()
+        .or_else(|| std::env::var("PATH").ok())?;
+    search
+        .split(':')
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| PathBuf::from(dir).join(program))
+        .find(|path| path.is_file())
+}
+
+/// The first line of a program's --version output mentioning a number, which
+/// is where tools put their version even when it follows a banner
+fn version_of(program: &str, env: &FnvHashMap<String, String>) -> Option<String> {
+    find_program(program, env)?;
+    let output = std::process::Command::new(program)
+        .arg("--version")
+        .envs(env)
+        .output()
+        .ok()?;
+    let text = match output.stdout.is_empty() {
+        true => String::from_utf8_lossy(&output.stderr).to_string(),
+        false => String::from_utf8_lossy(&output.stdout).to_string(),
+    };
+    text.lines()
+        .map(|l| l.trim())
+        .find(|l| l.chars().any(|c| c.is_ascii_digit()))
+        .map(|l| l.to_string())
+}
+
+/// The build tool has no version of its own, so it is identified by the
+/// hash of the script
+fn build_tool_hash(build_tool: &str, env: &FnvHashMap<String, String>) -> Option<String> {
+    let path = find_program(build_tool, env)?;
+    let contents = std::fs::read(&path).ok()?;
+    let mut hasher = Sha1::new();
+    hasher.input(&contents);
+    Some(format!("sha1:{}", hasher.result_str()))
+}
+
+/// Probe the versions of the build tool, build systems, and compilers found
+/// in an environment. Tools which are not installed are left out.
+pub fn probe(build_tool: &str, env: &FnvHashMap<String, String>) -> BTreeMap<String, String> {
+    let mut versions = BTreeMap::new();
+    if let Some(hash) = build_tool_hash(build_tool, env) {
+        versions.insert(build_tool.to_string(), hash);
+    }
+    for tool in TOOLS.iter() {
+        if let Some(version) = version_of(tool, env) {
+            versions.insert(tool.to_string(), version);
+        }
+    }
+    for variable in COMPILER_VARIABLES.iter() {
+        let compiler = env
+            .get(*variable)
+            .cloned()
+            .or_else(|| std::env::var(variable).ok());
+        // only the program, any flags in the variable do not change the version
+        let program = compiler
+            .as_ref()
+            .and_then(|c| c.split_whitespace().next().map(|p| p.to_string()));
+        if let Some(version) = program.and_then(|p| version_of(&p, env)) {
+            versions.insert(variable.to_string(), version);
+        }
+    }
+    debug!("Found build tools {:?}", versions);
+    versions
+}
+
+/// Hash of a set of tool versions, to mix into product ids
+pub fn hash(versions: &BTreeMap<String, String>) -> String {
+    let mut hasher = Sha1::new();
+    for (tool, version) in versions.iter() {
+        hasher.input_str(&format!("{}={}\n", tool, version));
+    }
+    hasher.result_str()
+}
@@ -0,0 +1,72 @@
+use crate::database::ProductDatabase;
+use crate::regenerate::reups;
+use log::debug;
+use std::path::{Path, PathBuf};
+
+/// Check that a declared product version is actually usable, returning a list
+/// of problems that were found. An empty list means the product verified.
+pub fn verify_product(db: &dyn ProductDatabase, product: &str, version: &str) -> Vec<String> {
+    debug!("Verifying {} version {}", product, version);
+    let mut problems = vec![];
+    let table = match db.get_table_from_version(product, version) {
+        Some(t) => t,
+        None => {
+            problems.push(format!(
+                "{} version {} has no table in the database",
+                product, version
+            ));
+            return problems;
+        }
+    };
+    if db.get_identity_from_version(product, version).is_none() {
+        problems.push(format!(
+            "{} version {} was declared without a product id",
+            product, version
+        ));
+    }
+    let product_dir = table.product_dir.clone();
+    if !product_dir.is_dir() {
+        problems.push(format!(
+            "{} version {} is missing its product directory {}",
+            product,
+            version,
+            product_dir.display()
+        ));
+        return problems;
+    }
+    let mut table_path = PathBuf::from(&product_dir);
+    table_path.push("ups");
+    table_path.push(format!("{}.table", product));
+    if let Err(e) =
+        reups::table::Table::from_file(product.to_string(), table_path.clone(), product_dir)
+    {
+        problems.push(format!(
+            "{} version {} has an unreadable table {}: {}",
+            product,
+            version,
+            table_path.display(),
+            e
+        ));
+    }
+    problems
+}
+
+/// Products installed to a fixed prefix live outside of the install root, and
+/// so are not managed along with the rest of the workspace. Returns a warning
+/// describing this if the product directory is such a location.
+pub fn external_prefix_warning(
+    product: &str,
+    product_dir: &Path,
+    install_root: &Path,
+) -> Option<String> {
+    let canonical = |p: &Path| p.canonicalize().unwrap_or_else(|_| PathBuf::from(p));
+    if canonical(product_dir).starts_with(canonical(install_root)) {
+        return None;
+    }
+    Some(format!(
+        "{} is installed to {} outside of the install root {}, it must be managed manually",
+        product,
+        product_dir.display(),
+        install_root.display()
+    ))
+}
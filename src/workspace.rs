@@ -0,0 +1,48 @@
+use crate::log_tail;
+use crate::regenerate::reups;
+use crate::regenerate::DBBuilderTrait;
+use std::path::PathBuf;
+
+/// A workspace is an install root along with the database products installed
+/// there are declared into. Workspaces are used to separate scratch areas from
+/// production installs.
+pub struct Workspace {
+    pub install_root: PathBuf,
+    pub db_path: PathBuf,
+}
+
+impl Workspace {
+    pub fn new(install_root: &str, db_path: &str) -> Workspace {
+        Workspace {
+            install_root: PathBuf::from(install_root),
+            db_path: PathBuf::from(db_path),
+        }
+    }
+
+    /// Open the database associated with this workspace
+    pub fn open_db(&self) -> Result<reups::DB, String> {
+        reups::DBBuilder::new()
+            .add_eups_user(false)
+            .add_path_str(
+                self.db_path
+                    .to_str()
+                    .ok_or("Workspace database path is not valid unicode")?,
+            )
+            .allow_empty(true)
+            .build()
+            .map_err(|e| format!("Could not open workspace database: {}", e))
+    }
+
+    /// The directory a given product version is installed to in this workspace
+    pub fn product_dir(&self, product: &str, version: &str) -> PathBuf {
+        let mut dir = self.install_root.clone();
+        dir.push(product);
+        dir.push(version);
+        dir
+    }
+
+    /// The build log of a given product version, written to as it builds
+    pub fn build_log(&self, product: &str, version: &str) -> PathBuf {
+        log_tail::live_log_path(&self.install_root, product, version)
+    }
+}